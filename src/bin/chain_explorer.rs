@@ -0,0 +1,12 @@
+//! Print trees of chapter 2 rich-state blocks and compare fork choice rules on them.
+//! Run `cargo run --bin chain_explorer -- help` for usage.
+
+fn main() {
+    match diy_blockchain::chain_explorer::run(std::env::args().skip(1)) {
+        Ok(out) => print!("{}", out),
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -4,9 +4,13 @@
 mod p1_switches;
 mod p2_laundry_machine;
 mod p3_atm;
-mod p4_accounted_currency;
+pub mod p4_accounted_currency;
 mod p5_digital_cash;
 mod p6_open_ended;
+mod p7_smart_contracts;
+mod p8_multi_asset;
+mod p9_prediction_market;
+mod p10_token_curated_registry;
 
 /// A state machine - Generic over the transition type
 pub trait StateMachine {
@@ -0,0 +1,724 @@
+//! A token-curated registry (TCR) is a list whose contents are decided by token holders rather than
+//! by a central curator. Anyone may apply to add an entry by locking up a deposit. If nobody objects
+//! during the application period, the entry joins the list. Anyone who thinks an entry does not
+//! belong may challenge it by matching its deposit. Token holders then vote, and whichever side loses
+//! forfeits its deposit to the winners.
+//!
+//! The economic idea is that token holders want the list to be high quality, because a useful list
+//! makes their tokens more valuable. Applicants are discouraged from submitting junk because they
+//! would lose their deposit, and challengers are discouraged from attacking good entries for the
+//! same reason.
+//!
+//! Just like in the accounted currency, tokens are never created or destroyed here. They only move
+//! between user balances and the various deposits and stakes locked up in the registry.
+
+use anyhow::{Error, Result};
+use std::collections::HashMap;
+
+use super::{StateMachine, User};
+
+/// Entries in the registry are identified by an arbitrary number chosen by the applicant. In a real
+/// registry this would typically be the hash of a name or url.
+pub type ListingId = u64;
+
+/// The smallest deposit that an applicant may lock up.
+pub const MIN_DEPOSIT: u64 = 100;
+
+/// The number of blocks an application must go unchallenged before it joins the registry.
+pub const APPLICATION_PERIOD: u64 = 3;
+
+/// The number of blocks that voting on a challenge stays open.
+pub const VOTING_PERIOD: u64 = 3;
+
+/// The percentage of the losing side's deposit that goes to the winning party. The rest is shared
+/// among the voters who sided with the winner, in proportion to their stake.
+pub const DISPENSATION_PERCENT: u64 = 50;
+
+/// This state machine models a multi-user currency along with a token-curated registry.
+pub struct TokenCuratedRegistry;
+
+/// Where a listing is in its lifecycle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListingStatus {
+    /// The listing has applied and joins the registry at the given height unless challenged first.
+    Applied { ends: u64 },
+    /// The listing is in the registry.
+    Listed,
+    /// The listing has been challenged and is waiting on the result of a vote. A listing that had
+    /// already been accepted stays in the registry during the vote.
+    Challenged {
+        challenge: Challenge,
+        was_listed: bool,
+    },
+}
+
+/// An open challenge against a listing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    /// The user who made the challenge.
+    challenger: User,
+    /// The deposit locked by the challenger. Always equal to the listing's deposit.
+    stake: u64,
+    /// The height at which voting closes and the challenge is resolved.
+    ends: u64,
+    /// Each voter's choice (true to keep the listing) and the tokens they locked behind it.
+    votes: HashMap<User, (bool, u64)>,
+}
+
+impl Challenge {
+    /// Total stake voting to keep (true) or remove (false) the listing.
+    fn weight(&self, keep: bool) -> u64 {
+        self.votes
+            .values()
+            .filter(|(k, _)| *k == keep)
+            .map(|(_, stake)| stake)
+            .sum()
+    }
+}
+
+/// A single entry in the registry, whether or not it has been accepted yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listing {
+    /// The user who applied for this listing.
+    owner: User,
+    /// The deposit locked by the owner.
+    deposit: u64,
+    /// Where the listing is in its lifecycle.
+    status: ListingStatus,
+}
+
+/// The complete state of the registry.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct State {
+    /// The free token balance of each user. Zero balances are removed.
+    balances: HashMap<User, u64>,
+    /// Every listing that has applied and not yet been removed or exited.
+    listings: HashMap<ListingId, Listing>,
+    /// The current block height. Periods are measured against this.
+    height: u64,
+}
+
+impl State {
+    /// Create a state in which the given users hold the given balances and the registry is empty.
+    pub fn with_balances<const N: usize>(balances: [(User, u64); N]) -> Self {
+        State {
+            balances: balances.into_iter().filter(|(_, b)| *b > 0).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The free token balance of the given user.
+    pub fn balance_of(&self, user: &User) -> u64 {
+        self.balances.get(user).copied().unwrap_or(0)
+    }
+
+    /// Whether the given listing is currently accepted into the registry. A listing that is being
+    /// challenged after having been accepted remains in the registry until it loses.
+    pub fn is_listed(&self, id: ListingId) -> bool {
+        matches!(
+            self.listings.get(&id).map(|l| &l.status),
+            Some(
+                ListingStatus::Listed
+                    | ListingStatus::Challenged {
+                        was_listed: true,
+                        ..
+                    }
+            )
+        )
+    }
+
+    /// Look up a listing by id.
+    pub fn listing(&self, id: ListingId) -> Option<&Listing> {
+        self.listings.get(&id)
+    }
+
+    /// The total tokens in the system. This is the sum of all free balances, listing deposits,
+    /// challenge deposits, and voting stakes. No transition ever changes it.
+    pub fn total_value(&self) -> u64 {
+        let locked: u64 = self
+            .listings
+            .values()
+            .map(|l| {
+                l.deposit
+                    + match &l.status {
+                        ListingStatus::Challenged { challenge: c, .. } => {
+                            c.stake + c.weight(true) + c.weight(false)
+                        }
+                        _ => 0,
+                    }
+            })
+            .sum();
+        self.balances.values().sum::<u64>() + locked
+    }
+
+    fn debit(&mut self, user: &User, amount: u64) -> Result<()> {
+        let balance = self.balance_of(user);
+        if balance < amount {
+            return Err(Error::msg("insufficient balance"));
+        }
+        self.set_balance(user, balance - amount);
+        Ok(())
+    }
+
+    fn credit(&mut self, user: &User, amount: u64) {
+        let balance = self.balance_of(user);
+        self.set_balance(user, balance + amount);
+    }
+
+    fn set_balance(&mut self, user: &User, amount: u64) {
+        if amount == 0 {
+            self.balances.remove(user);
+        } else {
+            self.balances.insert(*user, amount);
+        }
+    }
+
+    /// Tally the votes on a finished challenge and pay everybody out. The listing is kept unless
+    /// strictly more stake voted to remove it than to keep it. A kept listing goes back to where it
+    /// was before the challenge. An application still has to wait out a fresh application period.
+    fn resolve_challenge(&mut self, id: ListingId, challenge: Challenge, was_listed: bool) {
+        let listing = self.listings[&id].clone();
+        let keep = challenge.weight(false) <= challenge.weight(true);
+
+        // Every voter gets their stake back regardless of how they voted.
+        for (voter, (_, stake)) in &challenge.votes {
+            self.credit(voter, *stake);
+        }
+
+        // The losing side's deposit is split between the winning party and the winning voters.
+        let (winner, forfeit) = if keep {
+            (listing.owner, challenge.stake)
+        } else {
+            (challenge.challenger, listing.deposit)
+        };
+        let voter_pool = forfeit - forfeit * DISPENSATION_PERCENT / 100;
+        let winning_weight = challenge.weight(keep);
+        let mut paid = 0;
+        if winning_weight > 0 {
+            for (voter, (choice, stake)) in &challenge.votes {
+                if *choice == keep {
+                    let reward =
+                        (voter_pool as u128 * *stake as u128 / winning_weight as u128) as u64;
+                    self.credit(voter, reward);
+                    paid += reward;
+                }
+            }
+        }
+        // Whatever rounding (or a lack of winning voters) leaves over goes to the winning party.
+        self.credit(&winner, forfeit - paid);
+
+        if keep {
+            self.listings.get_mut(&id).unwrap().status = if was_listed {
+                ListingStatus::Listed
+            } else {
+                ListingStatus::Applied {
+                    ends: self.height + APPLICATION_PERIOD,
+                }
+            };
+        } else {
+            self.credit(&challenge.challenger, challenge.stake);
+            self.listings.remove(&id);
+        }
+    }
+}
+
+/// The state transitions that users can make in the registry.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RegistryTransaction {
+    /// Apply to add a new listing by locking up a deposit of at least `MIN_DEPOSIT`.
+    Apply {
+        applicant: User,
+        listing: ListingId,
+        deposit: u64,
+    },
+    /// Challenge a pending or accepted listing by locking up a deposit equal to the listing's.
+    Challenge {
+        challenger: User,
+        listing: ListingId,
+    },
+    /// Lock up `stake` tokens behind a vote to keep or remove a challenged listing.
+    /// Each user may vote only once per challenge.
+    Vote {
+        voter: User,
+        listing: ListingId,
+        keep: bool,
+        stake: u64,
+    },
+    /// Remove an accepted, unchallenged listing from the registry and reclaim its deposit.
+    Exit { owner: User, listing: ListingId },
+    /// Advance the block height, accepting unchallenged applications and resolving challenges
+    /// whose periods have ended.
+    NewBlock,
+}
+
+/// We model the token-curated registry as a state machine with five possible transitions
+impl StateMachine for TokenCuratedRegistry {
+    type State = State;
+    type Transition = RegistryTransaction;
+
+    fn next_state(starting_state: &State, t: &RegistryTransaction) -> State {
+        let mut new_state = starting_state.clone();
+
+        let process_transaction = |new_state: &mut State| -> Result<()> {
+            match t {
+                RegistryTransaction::Apply {
+                    applicant,
+                    listing,
+                    deposit,
+                } => {
+                    if new_state.listings.contains_key(listing) {
+                        return Err(Error::msg("listing already exists"));
+                    }
+                    if *deposit < MIN_DEPOSIT {
+                        return Err(Error::msg("deposit too small"));
+                    }
+                    new_state.debit(applicant, *deposit)?;
+                    new_state.listings.insert(
+                        *listing,
+                        Listing {
+                            owner: *applicant,
+                            deposit: *deposit,
+                            status: ListingStatus::Applied {
+                                ends: new_state.height + APPLICATION_PERIOD,
+                            },
+                        },
+                    );
+                }
+                RegistryTransaction::Challenge {
+                    challenger,
+                    listing,
+                } => {
+                    let l = new_state
+                        .listings
+                        .get(listing)
+                        .ok_or(Error::msg("listing does not exist"))?;
+                    if matches!(l.status, ListingStatus::Challenged { .. }) {
+                        return Err(Error::msg("listing is already challenged"));
+                    }
+                    let was_listed = l.status == ListingStatus::Listed;
+                    let stake = l.deposit;
+                    new_state.debit(challenger, stake)?;
+                    let ends = new_state.height + VOTING_PERIOD;
+                    new_state.listings.get_mut(listing).unwrap().status =
+                        ListingStatus::Challenged {
+                            challenge: Challenge {
+                                challenger: *challenger,
+                                stake,
+                                ends,
+                                votes: HashMap::new(),
+                            },
+                            was_listed,
+                        };
+                }
+                RegistryTransaction::Vote {
+                    voter,
+                    listing,
+                    keep,
+                    stake,
+                } => {
+                    if *stake == 0 {
+                        return Err(Error::msg("cannot vote with zero stake"));
+                    }
+                    new_state.debit(voter, *stake)?;
+                    let l = new_state
+                        .listings
+                        .get_mut(listing)
+                        .ok_or(Error::msg("listing does not exist"))?;
+                    let ListingStatus::Challenged { challenge, .. } = &mut l.status else {
+                        return Err(Error::msg("listing is not challenged"));
+                    };
+                    if challenge.votes.contains_key(voter) {
+                        return Err(Error::msg("already voted"));
+                    }
+                    challenge.votes.insert(*voter, (*keep, *stake));
+                }
+                RegistryTransaction::Exit { owner, listing } => {
+                    let l = new_state
+                        .listings
+                        .get(listing)
+                        .ok_or(Error::msg("listing does not exist"))?;
+                    if l.owner != *owner {
+                        return Err(Error::msg("only the owner may exit"));
+                    }
+                    if l.status != ListingStatus::Listed {
+                        return Err(Error::msg("only accepted, unchallenged listings may exit"));
+                    }
+                    let deposit = l.deposit;
+                    new_state.listings.remove(listing);
+                    new_state.credit(owner, deposit);
+                }
+                RegistryTransaction::NewBlock => {
+                    new_state.height += 1;
+                    let height = new_state.height;
+                    let mut ids: Vec<ListingId> = new_state.listings.keys().copied().collect();
+                    // Resolve in a deterministic order so rounding is reproducible
+                    ids.sort();
+                    for id in ids {
+                        match new_state.listings[&id].status.clone() {
+                            ListingStatus::Applied { ends } if ends <= height => {
+                                new_state.listings.get_mut(&id).unwrap().status =
+                                    ListingStatus::Listed;
+                            }
+                            ListingStatus::Challenged {
+                                challenge,
+                                was_listed,
+                            } if challenge.ends <= height => {
+                                new_state.resolve_challenge(id, challenge, was_listed);
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        match process_transaction(&mut new_state) {
+            Ok(_) => new_state,
+            Err(_) => starting_state.clone(),
+        }
+    }
+
+    fn human_name() -> String {
+        "Token-Curated Registry".into()
+    }
+}
+
+#[cfg(test)]
+fn apply_all(state: &State, transactions: &[RegistryTransaction]) -> State {
+    let total = state.total_value();
+    transactions.iter().fold(state.clone(), |state, t| {
+        let next = TokenCuratedRegistry::next_state(&state, t);
+        assert_eq!(next.total_value(), total);
+        next
+    })
+}
+
+#[cfg(test)]
+fn genesis() -> State {
+    State::with_balances([
+        (User::Alice, 1000),
+        (User::Bob, 1000),
+        (User::Charlie, 1000),
+    ])
+}
+
+#[cfg(test)]
+fn challenged_genesis() -> State {
+    apply_all(
+        &genesis(),
+        &[
+            RegistryTransaction::Apply {
+                applicant: User::Alice,
+                listing: 7,
+                deposit: 200,
+            },
+            RegistryTransaction::Challenge {
+                challenger: User::Bob,
+                listing: 7,
+            },
+        ],
+    )
+}
+
+#[test]
+fn sm_10_unchallenged_application_is_listed() {
+    let mut state = apply_all(
+        &genesis(),
+        &[RegistryTransaction::Apply {
+            applicant: User::Alice,
+            listing: 7,
+            deposit: 200,
+        }],
+    );
+    assert_eq!(state.balance_of(&User::Alice), 800);
+
+    for _ in 0..APPLICATION_PERIOD - 1 {
+        state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+        assert!(!state.is_listed(7));
+    }
+    state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+    assert!(state.is_listed(7));
+}
+
+#[test]
+fn sm_10_small_deposit_rejected() {
+    let start = genesis();
+    let end = TokenCuratedRegistry::next_state(
+        &start,
+        &RegistryTransaction::Apply {
+            applicant: User::Alice,
+            listing: 7,
+            deposit: MIN_DEPOSIT - 1,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_10_duplicate_application_rejected() {
+    let start = apply_all(
+        &genesis(),
+        &[RegistryTransaction::Apply {
+            applicant: User::Alice,
+            listing: 7,
+            deposit: 200,
+        }],
+    );
+    let end = TokenCuratedRegistry::next_state(
+        &start,
+        &RegistryTransaction::Apply {
+            applicant: User::Bob,
+            listing: 7,
+            deposit: 200,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_10_challenge_matches_deposit() {
+    let state = challenged_genesis();
+
+    assert_eq!(state.balance_of(&User::Bob), 800);
+    assert!(matches!(
+        state.listing(7).unwrap().status,
+        ListingStatus::Challenged {
+            was_listed: false,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn sm_10_cannot_vote_twice() {
+    let start = apply_all(
+        &challenged_genesis(),
+        &[RegistryTransaction::Vote {
+            voter: User::Charlie,
+            listing: 7,
+            keep: true,
+            stake: 10,
+        }],
+    );
+    let end = TokenCuratedRegistry::next_state(
+        &start,
+        &RegistryTransaction::Vote {
+            voter: User::Charlie,
+            listing: 7,
+            keep: false,
+            stake: 10,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_10_challenge_succeeds() {
+    let mut state = apply_all(
+        &challenged_genesis(),
+        &[RegistryTransaction::Vote {
+            voter: User::Charlie,
+            listing: 7,
+            keep: false,
+            stake: 50,
+        }],
+    );
+    for _ in 0..VOTING_PERIOD {
+        state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+    }
+
+    // Alice loses her deposit of 200. Half goes to Bob, and half to Charlie who voted with him.
+    assert!(state.listing(7).is_none());
+    assert_eq!(state.balance_of(&User::Alice), 800);
+    assert_eq!(state.balance_of(&User::Bob), 1100);
+    assert_eq!(state.balance_of(&User::Charlie), 1100);
+}
+
+#[test]
+fn sm_10_challenge_fails() {
+    let mut state = apply_all(
+        &challenged_genesis(),
+        &[
+            RegistryTransaction::Vote {
+                voter: User::Charlie,
+                listing: 7,
+                keep: true,
+                stake: 30,
+            },
+            RegistryTransaction::Vote {
+                voter: User::Alice,
+                listing: 7,
+                keep: true,
+                stake: 70,
+            },
+        ],
+    );
+    for _ in 0..VOTING_PERIOD {
+        state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+    }
+
+    // Bob loses his 200 stake. Alice gets 100 as the owner plus 70 of the voter pool,
+    // and Charlie gets the other 30.
+    assert_eq!(state.balance_of(&User::Alice), 800 + 100 + 70);
+    assert_eq!(state.balance_of(&User::Bob), 800);
+    assert_eq!(state.balance_of(&User::Charlie), 1030);
+
+    // The application survived, but still has to wait out an application period.
+    assert!(!state.is_listed(7));
+    for _ in 0..APPLICATION_PERIOD {
+        state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+    }
+    assert!(state.is_listed(7));
+}
+
+#[test]
+fn sm_10_tie_keeps_listing() {
+    let mut state = apply_all(
+        &challenged_genesis(),
+        &[
+            RegistryTransaction::Vote {
+                voter: User::Charlie,
+                listing: 7,
+                keep: true,
+                stake: 40,
+            },
+            RegistryTransaction::Vote {
+                voter: User::Bob,
+                listing: 7,
+                keep: false,
+                stake: 40,
+            },
+        ],
+    );
+    for _ in 0..VOTING_PERIOD {
+        state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+    }
+
+    assert!(matches!(
+        state.listing(7).unwrap().status,
+        ListingStatus::Applied { .. }
+    ));
+}
+
+#[test]
+fn sm_10_challenged_listing_stays_listed_until_it_loses() {
+    let mut listed = apply_all(
+        &genesis(),
+        &[RegistryTransaction::Apply {
+            applicant: User::Alice,
+            listing: 7,
+            deposit: 200,
+        }],
+    );
+    for _ in 0..APPLICATION_PERIOD {
+        listed = apply_all(&listed, &[RegistryTransaction::NewBlock]);
+    }
+    let challenged = apply_all(
+        &listed,
+        &[RegistryTransaction::Challenge {
+            challenger: User::Bob,
+            listing: 7,
+        }],
+    );
+    assert!(challenged.is_listed(7));
+
+    for keep in [true, false] {
+        let mut state = apply_all(
+            &challenged,
+            &[RegistryTransaction::Vote {
+                voter: User::Charlie,
+                listing: 7,
+                keep,
+                stake: 50,
+            }],
+        );
+        for _ in 0..VOTING_PERIOD - 1 {
+            state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+            assert!(state.is_listed(7));
+        }
+        state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+
+        // A kept listing goes straight back to being listed, with no new application period.
+        assert_eq!(state.is_listed(7), keep);
+        assert_eq!(
+            state.listing(7).map(|l| &l.status),
+            keep.then_some(&ListingStatus::Listed)
+        );
+    }
+}
+
+#[test]
+fn sm_10_exit_returns_deposit() {
+    let mut state = apply_all(
+        &genesis(),
+        &[RegistryTransaction::Apply {
+            applicant: User::Alice,
+            listing: 7,
+            deposit: 200,
+        }],
+    );
+    for _ in 0..APPLICATION_PERIOD {
+        state = apply_all(&state, &[RegistryTransaction::NewBlock]);
+    }
+
+    // Only the owner may exit
+    let bob_exit = RegistryTransaction::Exit {
+        owner: User::Bob,
+        listing: 7,
+    };
+    assert_eq!(TokenCuratedRegistry::next_state(&state, &bob_exit), state);
+
+    state = apply_all(
+        &state,
+        &[RegistryTransaction::Exit {
+            owner: User::Alice,
+            listing: 7,
+        }],
+    );
+    assert!(state.listing(7).is_none());
+    assert_eq!(state.balance_of(&User::Alice), 1000);
+}
+
+#[test]
+fn sm_10_value_conserved_through_random_activity() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(10);
+    let users = [User::Alice, User::Bob, User::Charlie];
+    let start = genesis();
+    let total = start.total_value();
+    let mut state = start;
+
+    for _ in 0..1000 {
+        let user = users[rng.gen_range(0..3)];
+        let listing = rng.gen_range(0..5);
+        let t = match rng.gen_range(0..5) {
+            0 => RegistryTransaction::Apply {
+                applicant: user,
+                listing,
+                deposit: rng.gen_range(MIN_DEPOSIT..2 * MIN_DEPOSIT),
+            },
+            1 => RegistryTransaction::Challenge {
+                challenger: user,
+                listing,
+            },
+            2 => RegistryTransaction::Vote {
+                voter: user,
+                listing,
+                keep: rng.gen(),
+                stake: rng.gen_range(1..100),
+            },
+            3 => RegistryTransaction::Exit {
+                owner: user,
+                listing,
+            },
+            _ => RegistryTransaction::NewBlock,
+        };
+        state = TokenCuratedRegistry::next_state(&state, &t);
+        assert_eq!(state.total_value(), total);
+    }
+}
@@ -0,0 +1,945 @@
+//! Every state machine we have written so far has a fixed set of transitions. The laundry machine
+//! can only be worn, washed, and dried. The currency can only mint, burn, and transfer. If users
+//! want any new behavior, the state machine itself must be changed.
+//!
+//! Smart contract platforms such as Ethereum take a different approach. The state machine is a
+//! tiny, deterministic virtual machine. Users deploy programs, called contracts, into the state,
+//! and later call them. The set of things users can do is now open ended, while the state machine
+//! itself stays small and fixed.
+//!
+//! In this module we build a stack-based bytecode VM as a state machine. Each contract account has
+//! some code, some storage, and a balance. Execution is metered with gas so that every call is
+//! guaranteed to terminate. If a call runs out of gas, or fails for any other reason, the entire
+//! transition is reverted and the state is left untouched.
+//!
+//! Writing bytecode by hand is tedious, so we also include a small assembler that turns a text
+//! form into instructions.
+
+use anyhow::{Error, Result};
+use std::collections::HashMap;
+
+use super::{StateMachine, User};
+
+/// Contracts are identified by a simple counter that is incremented on every deployment.
+pub type ContractId = u64;
+
+/// The maximum number of words that may be on the stack at any one time.
+const MAX_STACK_DEPTH: usize = 1024;
+
+/// The most gas a single call may use. Callers don't pay for gas here, so without a cap anyone
+/// could make a call that runs for as long as they liked.
+pub const MAX_GAS_PER_CALL: u64 = 1_000_000;
+
+/// This state machine models a simple smart contract platform. Users hold balances, deploy
+/// contracts, and call them with some value attached.
+pub struct ContractVm;
+
+/// A single VM instruction. All values on the stack are `u64` words.
+///
+/// Whenever an instruction below says it pops `a` then `b`, `a` is the word that was on top
+/// of the stack.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// Push a constant word onto the stack
+    Push(u64),
+    /// Discard the top word
+    Pop,
+    /// Duplicate the top word
+    Dup,
+    /// Swap the top two words
+    Swap,
+    /// Pop `a` then `b` and push `b + a`. Overflow is an error.
+    Add,
+    /// Pop `a` then `b` and push `b - a`. Underflow is an error.
+    Sub,
+    /// Pop `a` then `b` and push `b * a`. Overflow is an error.
+    Mul,
+    /// Pop `a` then `b` and push `b / a`. Dividing by zero is an error.
+    Div,
+    /// Pop `a` then `b` and push `b % a`. Dividing by zero is an error.
+    Mod,
+    /// Pop `a` then `b` and push 1 if `b == a` or 0 otherwise.
+    Eq,
+    /// Pop `a` then `b` and push 1 if `b < a` or 0 otherwise.
+    Lt,
+    /// Pop `a` then `b` and push 1 if `b > a` or 0 otherwise.
+    Gt,
+    /// Pop `a` and push 1 if it is zero or 0 otherwise.
+    Not,
+    /// Continue execution at the given instruction index.
+    Jump(usize),
+    /// Pop a condition and continue execution at the given index if it is non-zero.
+    JumpIf(usize),
+    /// Pop a key and push the value stored at that key in the contract's storage.
+    /// Missing keys read as zero.
+    SLoad,
+    /// Pop a key then a value and write the value into the contract's storage.
+    SStore,
+    /// Push the id of the user who made the call. See `user_to_word`.
+    Caller,
+    /// Push the amount of value that was sent with the call.
+    CallValue,
+    /// Push the input word at the given index. Missing words read as zero.
+    CallData(usize),
+    /// Push the contract's own balance.
+    SelfBalance,
+    /// Pop a recipient user id then an amount and transfer that amount from the contract to the user.
+    Transfer,
+    /// Stop execution successfully. Running past the last instruction also stops successfully.
+    Stop,
+    /// Stop execution and revert all changes made by the call.
+    Revert,
+}
+
+impl Instruction {
+    /// The amount of gas charged for executing this instruction.
+    ///
+    /// Cheap stack shuffling is cheapest, storage access and value transfer are the most expensive
+    /// because they touch the persistent state.
+    pub fn gas_cost(&self) -> u64 {
+        match self {
+            Instruction::Stop | Instruction::Revert => 0,
+            Instruction::Push(_) | Instruction::Pop | Instruction::Dup | Instruction::Swap => 1,
+            Instruction::Caller
+            | Instruction::CallValue
+            | Instruction::CallData(_)
+            | Instruction::SelfBalance => 2,
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Eq
+            | Instruction::Lt
+            | Instruction::Gt
+            | Instruction::Not => 3,
+            Instruction::Mul | Instruction::Div | Instruction::Mod => 5,
+            Instruction::Jump(_) => 8,
+            Instruction::JumpIf(_) => 10,
+            Instruction::SLoad => 50,
+            Instruction::SStore => 100,
+            Instruction::Transfer => 200,
+        }
+    }
+}
+
+/// A deployed contract. Contracts have code, storage, and can hold a balance just like users.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Contract {
+    code: Vec<Instruction>,
+    storage: HashMap<u64, u64>,
+    balance: u64,
+}
+
+/// The complete state of the contract platform.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct State {
+    /// The balance of each user. Like in the accounted currency, an account is removed
+    /// when its balance falls to zero.
+    balances: HashMap<User, u64>,
+    /// All deployed contracts indexed by their id.
+    contracts: HashMap<ContractId, Contract>,
+    /// The id that will be given to the next deployed contract.
+    next_contract_id: ContractId,
+}
+
+impl State {
+    /// Create a state in which the given users hold the given balances and no contracts exist yet.
+    pub fn with_balances<const N: usize>(balances: [(User, u64); N]) -> Self {
+        State {
+            balances: balances.into_iter().filter(|(_, b)| *b > 0).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The balance of the given user.
+    pub fn balance_of(&self, user: &User) -> u64 {
+        self.balances.get(user).copied().unwrap_or(0)
+    }
+
+    /// The balance held by the given contract.
+    pub fn contract_balance(&self, contract: ContractId) -> u64 {
+        self.contracts
+            .get(&contract)
+            .map(|c| c.balance)
+            .unwrap_or(0)
+    }
+
+    /// Read a single storage word of the given contract. Missing keys read as zero.
+    pub fn storage_at(&self, contract: ContractId, key: u64) -> u64 {
+        self.contracts
+            .get(&contract)
+            .and_then(|c| c.storage.get(&key).copied())
+            .unwrap_or(0)
+    }
+
+    /// The id that will be given to the next deployed contract.
+    pub fn next_contract_id(&self) -> ContractId {
+        self.next_contract_id
+    }
+
+    fn debit_user(&mut self, user: &User, amount: u64) -> Result<()> {
+        let balance = self.balance_of(user);
+        if balance < amount {
+            return Err(Error::msg("insufficient balance"));
+        }
+        if balance == amount {
+            self.balances.remove(user);
+        } else {
+            self.balances.insert(*user, balance - amount);
+        }
+        Ok(())
+    }
+
+    fn credit_user(&mut self, user: &User, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+        let balance = self
+            .balance_of(user)
+            .checked_add(amount)
+            .ok_or(Error::msg("balance overflow"))?;
+        self.balances.insert(*user, balance);
+        Ok(())
+    }
+}
+
+/// The state transitions that users can make on the contract platform.
+pub enum ContractTransaction {
+    /// Deploy the given code as a new contract, funding it with `value` from the deployer.
+    /// The new contract receives the id `State::next_contract_id`.
+    Deploy {
+        deployer: User,
+        code: Vec<Instruction>,
+        value: u64,
+    },
+    /// Call an existing contract, sending `value` from the caller to the contract before
+    /// the code runs. Execution may use at most `gas_limit` gas, which must not be more than
+    /// `MAX_GAS_PER_CALL`.
+    Call {
+        caller: User,
+        contract: ContractId,
+        value: u64,
+        input: Vec<u64>,
+        gas_limit: u64,
+    },
+}
+
+/// Encode a user as a word so that contracts can reason about identities.
+pub fn user_to_word(user: &User) -> u64 {
+    match user {
+        User::Alice => 0,
+        User::Bob => 1,
+        User::Charlie => 2,
+    }
+}
+
+/// Decode a word back into a user. Returns None if the word does not name a user.
+pub fn word_to_user(word: u64) -> Option<User> {
+    match word {
+        0 => Some(User::Alice),
+        1 => Some(User::Bob),
+        2 => Some(User::Charlie),
+        _ => None,
+    }
+}
+
+/// The environment a single call executes in.
+struct CallContext<'a> {
+    caller: User,
+    value: u64,
+    input: &'a [u64],
+    gas_limit: u64,
+}
+
+fn pop(stack: &mut Vec<u64>) -> Result<u64> {
+    stack.pop().ok_or(Error::msg("stack underflow"))
+}
+
+fn push(stack: &mut Vec<u64>, word: u64) -> Result<()> {
+    if stack.len() >= MAX_STACK_DEPTH {
+        return Err(Error::msg("stack overflow"));
+    }
+    stack.push(word);
+    Ok(())
+}
+
+/// Run the code of the given contract to completion, mutating the state as it goes.
+///
+/// The caller is responsible for throwing the state away if this returns an error.
+fn execute(state: &mut State, id: ContractId, ctx: &CallContext) -> Result<()> {
+    let code = state.contracts[&id].code.clone();
+    let mut stack: Vec<u64> = Vec::new();
+    let mut pc = 0;
+    let mut gas_used: u64 = 0;
+
+    while let Some(instruction) = code.get(pc) {
+        gas_used += instruction.gas_cost();
+        if gas_used > ctx.gas_limit {
+            return Err(Error::msg("out of gas"));
+        }
+        pc += 1;
+
+        match instruction {
+            Instruction::Push(word) => push(&mut stack, *word)?,
+            Instruction::Pop => {
+                pop(&mut stack)?;
+            }
+            Instruction::Dup => {
+                let a = pop(&mut stack)?;
+                push(&mut stack, a)?;
+                push(&mut stack, a)?;
+            }
+            Instruction::Swap => {
+                let a = pop(&mut stack)?;
+                let b = pop(&mut stack)?;
+                push(&mut stack, a)?;
+                push(&mut stack, b)?;
+            }
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod
+            | Instruction::Eq
+            | Instruction::Lt
+            | Instruction::Gt => {
+                let a = pop(&mut stack)?;
+                let b = pop(&mut stack)?;
+                let result = match instruction {
+                    Instruction::Add => b.checked_add(a),
+                    Instruction::Sub => b.checked_sub(a),
+                    Instruction::Mul => b.checked_mul(a),
+                    Instruction::Div => b.checked_div(a),
+                    Instruction::Mod => b.checked_rem(a),
+                    Instruction::Eq => Some((b == a) as u64),
+                    Instruction::Lt => Some((b < a) as u64),
+                    _ => Some((b > a) as u64),
+                };
+                push(&mut stack, result.ok_or(Error::msg("arithmetic error"))?)?;
+            }
+            Instruction::Not => {
+                let a = pop(&mut stack)?;
+                push(&mut stack, (a == 0) as u64)?;
+            }
+            Instruction::Jump(target) => {
+                if *target >= code.len() {
+                    return Err(Error::msg("invalid jump destination"));
+                }
+                pc = *target;
+            }
+            Instruction::JumpIf(target) => {
+                if pop(&mut stack)? != 0 {
+                    if *target >= code.len() {
+                        return Err(Error::msg("invalid jump destination"));
+                    }
+                    pc = *target;
+                }
+            }
+            Instruction::SLoad => {
+                let key = pop(&mut stack)?;
+                push(&mut stack, state.storage_at(id, key))?;
+            }
+            Instruction::SStore => {
+                let key = pop(&mut stack)?;
+                let value = pop(&mut stack)?;
+                let storage = &mut state.contracts.get_mut(&id).unwrap().storage;
+                // Zero is the default value, so there is no point in storing it.
+                if value == 0 {
+                    storage.remove(&key);
+                } else {
+                    storage.insert(key, value);
+                }
+            }
+            Instruction::Caller => push(&mut stack, user_to_word(&ctx.caller))?,
+            Instruction::CallValue => push(&mut stack, ctx.value)?,
+            Instruction::CallData(i) => push(&mut stack, ctx.input.get(*i).copied().unwrap_or(0))?,
+            Instruction::SelfBalance => push(&mut stack, state.contract_balance(id))?,
+            Instruction::Transfer => {
+                let recipient =
+                    word_to_user(pop(&mut stack)?).ok_or(Error::msg("invalid recipient"))?;
+                let amount = pop(&mut stack)?;
+                let contract = state.contracts.get_mut(&id).unwrap();
+                if contract.balance < amount {
+                    return Err(Error::msg("insufficient contract balance"));
+                }
+                contract.balance -= amount;
+                state.credit_user(&recipient, amount)?;
+            }
+            Instruction::Stop => return Ok(()),
+            Instruction::Revert => return Err(Error::msg("reverted")),
+        }
+    }
+    Ok(())
+}
+
+/// We model the contract platform as a state machine with two possible transitions
+impl StateMachine for ContractVm {
+    type State = State;
+    type Transition = ContractTransaction;
+
+    fn next_state(starting_state: &State, t: &ContractTransaction) -> State {
+        let mut new_state = starting_state.clone();
+
+        let process_transaction = |new_state: &mut State| -> Result<()> {
+            match t {
+                ContractTransaction::Deploy {
+                    deployer,
+                    code,
+                    value,
+                } => {
+                    new_state.debit_user(deployer, *value)?;
+                    let id = new_state.next_contract_id;
+                    new_state.contracts.insert(
+                        id,
+                        Contract {
+                            code: code.clone(),
+                            storage: HashMap::new(),
+                            balance: *value,
+                        },
+                    );
+                    new_state.next_contract_id += 1;
+                }
+                ContractTransaction::Call {
+                    caller,
+                    contract,
+                    value,
+                    input,
+                    gas_limit,
+                } => {
+                    if *gas_limit > MAX_GAS_PER_CALL {
+                        return Err(Error::msg("gas limit too high"));
+                    }
+                    if !new_state.contracts.contains_key(contract) {
+                        return Err(Error::msg("contract does not exist"));
+                    }
+                    new_state.debit_user(caller, *value)?;
+                    let contract_account = new_state.contracts.get_mut(contract).unwrap();
+                    contract_account.balance = contract_account
+                        .balance
+                        .checked_add(*value)
+                        .ok_or(Error::msg("balance overflow"))?;
+
+                    let ctx = CallContext {
+                        caller: *caller,
+                        value: *value,
+                        input,
+                        gas_limit: *gas_limit,
+                    };
+                    execute(new_state, *contract, &ctx)?;
+                }
+            }
+            Ok(())
+        };
+
+        match process_transaction(&mut new_state) {
+            Ok(_) => new_state,
+            Err(_) => starting_state.clone(),
+        }
+    }
+
+    fn human_name() -> String {
+        "Stack-based Contract VM".into()
+    }
+}
+
+/// Assemble the text form of a contract into instructions.
+///
+/// The text form has one instruction per line, written as a lowercase mnemonic followed by
+/// an argument where one is needed. Everything after a `;` is a comment. A line of the form
+/// `name:` defines a label, and jump instructions may use a label or an instruction index as
+/// their target.
+///
+/// ```text
+/// ; Increment the counter stored at key 0
+///     push 0
+///     sload
+///     push 1
+///     add
+///     push 0
+///     sstore
+/// ```
+pub fn assemble(source: &str) -> Result<Vec<Instruction>> {
+    // Strip comments and blank lines, remembering the original line number for error messages.
+    let lines: Vec<(usize, &str)> = source
+        .lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.split(';').next().unwrap().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+
+    // First pass: find the instruction index of every label.
+    let mut labels = HashMap::new();
+    let mut index = 0;
+    for (n, line) in lines.iter() {
+        if let Some(label) = line.strip_suffix(':') {
+            if labels.insert(label.trim(), index).is_some() {
+                return Err(Error::msg(format!(
+                    "line {}: duplicate label `{}`",
+                    n, label
+                )));
+            }
+        } else {
+            index += 1;
+        }
+    }
+
+    // Second pass: translate the mnemonics.
+    let mut code = Vec::new();
+    for (n, line) in lines.iter().filter(|(_, line)| !line.ends_with(':')) {
+        let mut parts = line.split_whitespace();
+        let mnemonic = parts.next().unwrap().to_lowercase();
+        let argument = parts.next();
+        if parts.next().is_some() {
+            return Err(Error::msg(format!("line {}: too many arguments", n)));
+        }
+
+        let word = || -> Result<u64> {
+            argument
+                .ok_or(Error::msg(format!(
+                    "line {}: `{}` needs an argument",
+                    n, mnemonic
+                )))?
+                .parse::<u64>()
+                .map_err(|_| Error::msg(format!("line {}: invalid number", n)))
+        };
+        let target = || -> Result<usize> {
+            let argument = argument.ok_or(Error::msg(format!(
+                "line {}: `{}` needs a target",
+                n, mnemonic
+            )))?;
+            match labels.get(argument) {
+                Some(index) => Ok(*index),
+                None => argument
+                    .parse::<usize>()
+                    .map_err(|_| Error::msg(format!("line {}: unknown label `{}`", n, argument))),
+            }
+        };
+
+        let instruction = match mnemonic.as_str() {
+            "push" => Instruction::Push(word()?),
+            "jump" => Instruction::Jump(target()?),
+            "jumpi" => Instruction::JumpIf(target()?),
+            "calldata" => Instruction::CallData(word()? as usize),
+            other => {
+                if argument.is_some() {
+                    return Err(Error::msg(format!(
+                        "line {}: `{}` takes no argument",
+                        n, other
+                    )));
+                }
+                match other {
+                    "pop" => Instruction::Pop,
+                    "dup" => Instruction::Dup,
+                    "swap" => Instruction::Swap,
+                    "add" => Instruction::Add,
+                    "sub" => Instruction::Sub,
+                    "mul" => Instruction::Mul,
+                    "div" => Instruction::Div,
+                    "mod" => Instruction::Mod,
+                    "eq" => Instruction::Eq,
+                    "lt" => Instruction::Lt,
+                    "gt" => Instruction::Gt,
+                    "not" => Instruction::Not,
+                    "sload" => Instruction::SLoad,
+                    "sstore" => Instruction::SStore,
+                    "caller" => Instruction::Caller,
+                    "callvalue" => Instruction::CallValue,
+                    "balance" => Instruction::SelfBalance,
+                    "transfer" => Instruction::Transfer,
+                    "stop" => Instruction::Stop,
+                    "revert" => Instruction::Revert,
+                    _ => {
+                        return Err(Error::msg(format!(
+                            "line {}: unknown instruction `{}`",
+                            n, other
+                        )))
+                    }
+                }
+            }
+        };
+        code.push(instruction);
+    }
+    Ok(code)
+}
+
+/// A counter. Every call increments the word stored at key 0.
+#[cfg(test)]
+const COUNTER: &str = "
+    push 0
+    sload
+    push 1
+    add
+    push 0
+    sstore
+";
+
+/// An escrow between a payer and a payee.
+///
+/// * `deposit(payee)` - input `[0, payee]`. Records the caller as the payer and locks the
+///   attached value. May only be called once.
+/// * `release` - input `[1]`. The payer releases the funds to the payee.
+/// * `refund` - input `[2]`. The payee gives the funds back to the payer.
+///
+/// Storage layout: key 0 is 1 once funded, key 1 is the payer, key 2 is the payee.
+#[cfg(test)]
+const ESCROW: &str = "
+    calldata 0
+    dup
+    push 0
+    eq
+    jumpi deposit
+    dup
+    push 1
+    eq
+    jumpi release
+    push 2
+    eq
+    jumpi refund
+    revert
+
+deposit:
+    pop
+    push 0
+    sload
+    jumpi fail          ; already funded
+    push 1
+    push 0
+    sstore
+    caller
+    push 1
+    sstore
+    calldata 1
+    push 2
+    sstore
+    stop
+
+release:
+    pop
+    caller
+    push 1
+    sload
+    eq
+    not
+    jumpi fail          ; only the payer may release
+    balance
+    push 2
+    sload
+    transfer
+    stop
+
+refund:
+    caller
+    push 2
+    sload
+    eq
+    not
+    jumpi fail          ; only the payee may refund
+    balance
+    push 1
+    sload
+    transfer
+    stop
+
+fail:
+    revert
+";
+
+/// A fungible token whose balances live in contract storage, keyed by user id.
+///
+/// * `init(supply)` - input `[0, supply]`. Gives the whole supply to the caller. Only works once.
+/// * `transfer(to, amount)` - input `[1, to, amount]`.
+///
+/// Storage layout: key 100 is 1 once initialized, keys 0..=2 are user balances.
+#[cfg(test)]
+const TOKEN: &str = "
+    calldata 0
+    jumpi transfer
+
+    push 100            ; init
+    sload
+    jumpi fail
+    push 1
+    push 100
+    sstore
+    calldata 1
+    caller
+    sstore
+    stop
+
+transfer:
+    caller              ; revert if balance[caller] < amount
+    sload
+    calldata 2
+    lt
+    jumpi fail
+    caller              ; balance[caller] -= amount
+    sload
+    calldata 2
+    sub
+    caller
+    sstore
+    calldata 1          ; balance[to] += amount
+    sload
+    calldata 2
+    add
+    calldata 1
+    sstore
+    stop
+
+fail:
+    revert
+";
+
+#[cfg(test)]
+fn deploy(state: &State, deployer: User, source: &str) -> State {
+    ContractVm::next_state(
+        state,
+        &ContractTransaction::Deploy {
+            deployer,
+            code: assemble(source).unwrap(),
+            value: 0,
+        },
+    )
+}
+
+#[cfg(test)]
+fn call(state: &State, caller: User, value: u64, input: Vec<u64>) -> State {
+    ContractVm::next_state(
+        state,
+        &ContractTransaction::Call {
+            caller,
+            contract: 0,
+            value,
+            input,
+            gas_limit: 10_000,
+        },
+    )
+}
+
+#[test]
+fn sm_7_assemble_resolves_labels() {
+    let code = assemble(
+        "
+        start:
+            push 1      ; a comment
+            jumpi end
+            jump start
+        end:
+            stop
+        ",
+    )
+    .unwrap();
+
+    assert_eq!(
+        code,
+        vec![
+            Instruction::Push(1),
+            Instruction::JumpIf(3),
+            Instruction::Jump(0),
+            Instruction::Stop,
+        ]
+    );
+}
+
+#[test]
+fn sm_7_assemble_rejects_bad_source() {
+    assert!(assemble("frobnicate").is_err());
+    assert!(assemble("push").is_err());
+    assert!(assemble("push x").is_err());
+    assert!(assemble("add 3").is_err());
+    assert!(assemble("jump nowhere").is_err());
+    assert!(assemble("a:\na:\nstop").is_err());
+}
+
+#[test]
+fn sm_7_deploy_creates_contract() {
+    let start = State::with_balances([(User::Alice, 100)]);
+    let end = ContractVm::next_state(
+        &start,
+        &ContractTransaction::Deploy {
+            deployer: User::Alice,
+            code: assemble(COUNTER).unwrap(),
+            value: 30,
+        },
+    );
+
+    assert_eq!(end.next_contract_id(), 1);
+    assert_eq!(end.contract_balance(0), 30);
+    assert_eq!(end.balance_of(&User::Alice), 70);
+}
+
+#[test]
+fn sm_7_deploy_with_insufficient_balance_fails() {
+    let start = State::with_balances([(User::Alice, 10)]);
+    let end = ContractVm::next_state(
+        &start,
+        &ContractTransaction::Deploy {
+            deployer: User::Alice,
+            code: vec![],
+            value: 30,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_7_counter_increments() {
+    let mut state = deploy(&State::default(), User::Alice, COUNTER);
+    for _ in 0..3 {
+        state = call(&state, User::Bob, 0, vec![]);
+    }
+
+    assert_eq!(state.storage_at(0, 0), 3);
+}
+
+#[test]
+fn sm_7_call_unknown_contract_fails() {
+    let start = State::with_balances([(User::Alice, 10)]);
+    let end = call(&start, User::Alice, 5, vec![]);
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_7_out_of_gas_reverts() {
+    let start = deploy(&State::default(), User::Alice, COUNTER);
+    // The counter needs 1 + 50 + 1 + 3 + 1 + 100 = 156 gas
+    let end = ContractVm::next_state(
+        &start,
+        &ContractTransaction::Call {
+            caller: User::Alice,
+            contract: 0,
+            value: 0,
+            input: vec![],
+            gas_limit: 155,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_7_infinite_loop_runs_out_of_gas() {
+    let start = deploy(
+        &State::with_balances([(User::Alice, 10)]),
+        User::Alice,
+        "loop:\njump loop",
+    );
+    let end = call(&start, User::Alice, 5, vec![]);
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_7_gas_limit_is_capped() {
+    let start = deploy(
+        &State::with_balances([(User::Alice, 10)]),
+        User::Alice,
+        "loop:\njump loop",
+    );
+    let call_with_gas = |gas_limit| {
+        ContractVm::next_state(
+            &start,
+            &ContractTransaction::Call {
+                caller: User::Alice,
+                contract: 0,
+                value: 5,
+                input: vec![],
+                gas_limit,
+            },
+        )
+    };
+
+    // Rejected up front rather than looping for a very long time.
+    assert_eq!(call_with_gas(u64::MAX), start);
+    // The largest allowed limit still runs out.
+    assert_eq!(call_with_gas(MAX_GAS_PER_CALL), start);
+
+    // A call within the cap goes through.
+    let counter = deploy(&start, User::Alice, COUNTER);
+    let end = ContractVm::next_state(
+        &counter,
+        &ContractTransaction::Call {
+            caller: User::Alice,
+            contract: 1,
+            value: 0,
+            input: vec![],
+            gas_limit: MAX_GAS_PER_CALL,
+        },
+    );
+    assert_ne!(end, counter);
+}
+
+#[test]
+fn sm_7_arithmetic_errors_revert() {
+    for source in [
+        "push 1\npush 0\ndiv",
+        "push 0\npush 1\nsub",
+        "add",
+        "push 1\njump 99",
+    ] {
+        let start = deploy(&State::default(), User::Alice, source);
+        let end = call(&start, User::Alice, 0, vec![]);
+        assert_eq!(end, start);
+    }
+}
+
+#[test]
+fn sm_7_escrow_release() {
+    let mut state = State::with_balances([(User::Alice, 100)]);
+    state = deploy(&state, User::Charlie, ESCROW);
+    state = call(&state, User::Alice, 60, vec![0, user_to_word(&User::Bob)]);
+
+    assert_eq!(state.balance_of(&User::Alice), 40);
+    assert_eq!(state.contract_balance(0), 60);
+
+    // The payee cannot release the funds to themselves
+    let after_bob = call(&state, User::Bob, 0, vec![1]);
+    assert_eq!(after_bob, state);
+
+    state = call(&state, User::Alice, 0, vec![1]);
+    assert_eq!(state.balance_of(&User::Bob), 60);
+    assert_eq!(state.contract_balance(0), 0);
+}
+
+#[test]
+fn sm_7_escrow_refund() {
+    let mut state = State::with_balances([(User::Alice, 100)]);
+    state = deploy(&state, User::Charlie, ESCROW);
+    state = call(&state, User::Alice, 60, vec![0, user_to_word(&User::Bob)]);
+
+    // A second deposit is rejected and the value stays with the sender
+    let second = call(
+        &state,
+        User::Alice,
+        10,
+        vec![0, user_to_word(&User::Charlie)],
+    );
+    assert_eq!(second, state);
+
+    state = call(&state, User::Bob, 0, vec![2]);
+    assert_eq!(state.balance_of(&User::Alice), 100);
+    assert_eq!(state.balance_of(&User::Bob), 0);
+    assert_eq!(state.contract_balance(0), 0);
+}
+
+#[test]
+fn sm_7_token_transfers() {
+    let alice = user_to_word(&User::Alice);
+    let bob = user_to_word(&User::Bob);
+
+    let mut state = deploy(&State::default(), User::Alice, TOKEN);
+    state = call(&state, User::Alice, 0, vec![0, 1000]);
+    assert_eq!(state.storage_at(0, alice), 1000);
+
+    // The token can only be initialized once
+    let reinit = call(&state, User::Bob, 0, vec![0, 1000]);
+    assert_eq!(reinit, state);
+
+    state = call(&state, User::Alice, 0, vec![1, bob, 300]);
+    assert_eq!(state.storage_at(0, alice), 700);
+    assert_eq!(state.storage_at(0, bob), 300);
+
+    // Bob cannot send more than he has
+    let overdraw = call(&state, User::Bob, 0, vec![1, alice, 301]);
+    assert_eq!(overdraw, state);
+}
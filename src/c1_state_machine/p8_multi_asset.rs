@@ -0,0 +1,597 @@
+//! The accounted currency from part 4 tracks balances of a single currency. Many real-world
+//! chains host a whole zoo of tokens side by side. Stablecoins, wrapped assets, and governance
+//! tokens all live in the same state and are moved around by the same users.
+//!
+//! In this module we generalize the accounted currency to many assets. Each asset has an issuer
+//! who is allowed to mint and burn it, and who may freeze misbehaving accounts. Users can transfer
+//! any asset they hold, and can also approve another user to spend on their behalf. This
+//! approve / transfer-from pattern is the same one used by ERC-20 tokens on Ethereum.
+
+use anyhow::{Error, Result};
+use std::collections::{HashMap, HashSet};
+
+use super::{StateMachine, User};
+
+/// Assets are identified by a simple counter that is incremented each time an asset is created.
+pub type AssetId = u64;
+
+/// This state machine models a multi-asset token ledger. It tracks who issued each asset,
+/// the balances of every user in every asset, and the allowances users have granted each other.
+pub struct MultiAssetLedger;
+
+/// Information about a single asset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssetDetails {
+    /// The only user who may mint, burn, freeze, and thaw this asset.
+    issuer: User,
+    /// The total amount of this asset currently in existence.
+    supply: u64,
+}
+
+/// The complete state of the multi-asset ledger.
+///
+/// Just like the accounted currency, there is an existential deposit of 1. Any balance or
+/// allowance that falls to zero is removed from the state entirely.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct State {
+    /// All assets that have been created, indexed by their id.
+    assets: HashMap<AssetId, AssetDetails>,
+    /// The balance of each user in each asset.
+    balances: HashMap<(AssetId, User), u64>,
+    /// How much of an owner's asset a spender may still transfer. Keyed by (asset, owner, spender).
+    allowances: HashMap<(AssetId, User, User), u64>,
+    /// Accounts that have been frozen by the asset's issuer. A frozen account can not send that asset.
+    frozen: HashSet<(AssetId, User)>,
+    /// The id that will be given to the next created asset.
+    next_asset_id: AssetId,
+}
+
+impl State {
+    /// The balance of the given user in the given asset.
+    pub fn balance_of(&self, asset: AssetId, user: &User) -> u64 {
+        self.balances.get(&(asset, *user)).copied().unwrap_or(0)
+    }
+
+    /// How much of the owner's asset the spender may still transfer.
+    pub fn allowance(&self, asset: AssetId, owner: &User, spender: &User) -> u64 {
+        self.allowances
+            .get(&(asset, *owner, *spender))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The total supply of the given asset. Returns None if the asset does not exist.
+    pub fn total_supply(&self, asset: AssetId) -> Option<u64> {
+        self.assets.get(&asset).map(|details| details.supply)
+    }
+
+    /// The issuer of the given asset. Returns None if the asset does not exist.
+    pub fn issuer(&self, asset: AssetId) -> Option<User> {
+        self.assets.get(&asset).map(|details| details.issuer)
+    }
+
+    /// Whether the given account is frozen for the given asset.
+    pub fn is_frozen(&self, asset: AssetId, user: &User) -> bool {
+        self.frozen.contains(&(asset, *user))
+    }
+
+    /// The id that will be given to the next created asset.
+    pub fn next_asset_id(&self) -> AssetId {
+        self.next_asset_id
+    }
+
+    /// Look up an asset and make sure the given user is its issuer.
+    fn ensure_issuer(&mut self, asset: AssetId, who: &User) -> Result<&mut AssetDetails> {
+        let details = self
+            .assets
+            .get_mut(&asset)
+            .ok_or(Error::msg("asset does not exist"))?;
+        if details.issuer != *who {
+            return Err(Error::msg("only the issuer may do this"));
+        }
+        Ok(details)
+    }
+
+    fn set_balance(&mut self, asset: AssetId, user: &User, amount: u64) {
+        if amount == 0 {
+            self.balances.remove(&(asset, *user));
+        } else {
+            self.balances.insert((asset, *user), amount);
+        }
+    }
+
+    /// Move funds between two accounts, respecting frozen accounts.
+    fn move_funds(&mut self, asset: AssetId, from: &User, to: &User, amount: u64) -> Result<()> {
+        if !self.assets.contains_key(&asset) {
+            return Err(Error::msg("asset does not exist"));
+        }
+        if self.is_frozen(asset, from) {
+            return Err(Error::msg("account is frozen"));
+        }
+        let from_balance = self.balance_of(asset, from);
+        if from_balance < amount {
+            return Err(Error::msg("insufficient balance"));
+        }
+        if from == to {
+            return Ok(());
+        }
+        // The receiver's balance can not overflow because the total supply does not.
+        let to_balance = self.balance_of(asset, to);
+        self.set_balance(asset, from, from_balance - amount);
+        self.set_balance(asset, to, to_balance + amount);
+        Ok(())
+    }
+}
+
+/// The state transitions that users can make in the multi-asset ledger.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AssetTransaction {
+    /// Create a new asset with the given issuer and zero supply.
+    /// The new asset receives the id `State::next_asset_id`.
+    Create { issuer: User },
+    /// Create new units of an asset for the given beneficiary. Only the issuer may mint.
+    Mint {
+        issuer: User,
+        asset: AssetId,
+        beneficiary: User,
+        amount: u64,
+    },
+    /// Destroy units of an asset held by the given account. Only the issuer may burn.
+    /// If the burn amount exceeds the account balance, the entire balance is burned.
+    Burn {
+        issuer: User,
+        asset: AssetId,
+        who: User,
+        amount: u64,
+    },
+    /// Send some units of an asset from one account to another
+    Transfer {
+        asset: AssetId,
+        sender: User,
+        receiver: User,
+        amount: u64,
+    },
+    /// Allow the spender to transfer up to `amount` of the owner's asset. This replaces
+    /// any previous allowance rather than adding to it.
+    Approve {
+        asset: AssetId,
+        owner: User,
+        spender: User,
+        amount: u64,
+    },
+    /// Spend part of an allowance by sending the owner's asset to the receiver.
+    TransferFrom {
+        asset: AssetId,
+        spender: User,
+        owner: User,
+        receiver: User,
+        amount: u64,
+    },
+    /// Prevent an account from sending the given asset. Only the issuer may freeze.
+    Freeze {
+        issuer: User,
+        asset: AssetId,
+        who: User,
+    },
+    /// Allow a previously frozen account to send the given asset again. Only the issuer may thaw.
+    Thaw {
+        issuer: User,
+        asset: AssetId,
+        who: User,
+    },
+}
+
+/// We model the ledger as a state machine with eight possible transitions
+impl StateMachine for MultiAssetLedger {
+    type State = State;
+    type Transition = AssetTransaction;
+
+    fn next_state(starting_state: &State, t: &AssetTransaction) -> State {
+        let mut new_state = starting_state.clone();
+
+        let process_transaction = |new_state: &mut State| -> Result<()> {
+            match t {
+                AssetTransaction::Create { issuer } => {
+                    let id = new_state.next_asset_id;
+                    new_state.assets.insert(
+                        id,
+                        AssetDetails {
+                            issuer: *issuer,
+                            supply: 0,
+                        },
+                    );
+                    new_state.next_asset_id += 1;
+                }
+                AssetTransaction::Mint {
+                    issuer,
+                    asset,
+                    beneficiary,
+                    amount,
+                } => {
+                    let details = new_state.ensure_issuer(*asset, issuer)?;
+                    details.supply = details
+                        .supply
+                        .checked_add(*amount)
+                        .ok_or(Error::msg("supply overflow"))?;
+                    let balance = new_state.balance_of(*asset, beneficiary);
+                    new_state.set_balance(*asset, beneficiary, balance + amount);
+                }
+                AssetTransaction::Burn {
+                    issuer,
+                    asset,
+                    who,
+                    amount,
+                } => {
+                    let balance = new_state.balance_of(*asset, who);
+                    let burned = balance.min(*amount);
+                    let details = new_state.ensure_issuer(*asset, issuer)?;
+                    details.supply -= burned;
+                    new_state.set_balance(*asset, who, balance - burned);
+                }
+                AssetTransaction::Transfer {
+                    asset,
+                    sender,
+                    receiver,
+                    amount,
+                } => {
+                    new_state.move_funds(*asset, sender, receiver, *amount)?;
+                }
+                AssetTransaction::Approve {
+                    asset,
+                    owner,
+                    spender,
+                    amount,
+                } => {
+                    if !new_state.assets.contains_key(asset) {
+                        return Err(Error::msg("asset does not exist"));
+                    }
+                    if *amount == 0 {
+                        new_state.allowances.remove(&(*asset, *owner, *spender));
+                    } else {
+                        new_state
+                            .allowances
+                            .insert((*asset, *owner, *spender), *amount);
+                    }
+                }
+                AssetTransaction::TransferFrom {
+                    asset,
+                    spender,
+                    owner,
+                    receiver,
+                    amount,
+                } => {
+                    let allowance = new_state.allowance(*asset, owner, spender);
+                    if allowance < *amount {
+                        return Err(Error::msg("insufficient allowance"));
+                    }
+                    new_state.move_funds(*asset, owner, receiver, *amount)?;
+                    if allowance == *amount {
+                        new_state.allowances.remove(&(*asset, *owner, *spender));
+                    } else {
+                        new_state
+                            .allowances
+                            .insert((*asset, *owner, *spender), allowance - amount);
+                    }
+                }
+                AssetTransaction::Freeze { issuer, asset, who } => {
+                    new_state.ensure_issuer(*asset, issuer)?;
+                    new_state.frozen.insert((*asset, *who));
+                }
+                AssetTransaction::Thaw { issuer, asset, who } => {
+                    new_state.ensure_issuer(*asset, issuer)?;
+                    new_state.frozen.remove(&(*asset, *who));
+                }
+            }
+            Ok(())
+        };
+
+        match process_transaction(&mut new_state) {
+            Ok(_) => new_state,
+            Err(_) => starting_state.clone(),
+        }
+    }
+
+    fn human_name() -> String {
+        "Multi-Asset Ledger".into()
+    }
+}
+
+/// Build a state with a single asset (id 0) issued by Alice, in which the given users
+/// hold the given balances.
+#[cfg(test)]
+fn state_with_one_asset<const N: usize>(balances: [(User, u64); N]) -> State {
+    let mut state = MultiAssetLedger::next_state(
+        &State::default(),
+        &AssetTransaction::Create {
+            issuer: User::Alice,
+        },
+    );
+    for (beneficiary, amount) in balances {
+        state = MultiAssetLedger::next_state(
+            &state,
+            &AssetTransaction::Mint {
+                issuer: User::Alice,
+                asset: 0,
+                beneficiary,
+                amount,
+            },
+        );
+    }
+    state
+}
+
+#[test]
+fn sm_8_create_assets() {
+    let mut state = State::default();
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Create {
+            issuer: User::Alice,
+        },
+    );
+    state = MultiAssetLedger::next_state(&state, &AssetTransaction::Create { issuer: User::Bob });
+
+    assert_eq!(state.next_asset_id(), 2);
+    assert_eq!(state.issuer(0), Some(User::Alice));
+    assert_eq!(state.issuer(1), Some(User::Bob));
+    assert_eq!(state.total_supply(1), Some(0));
+    assert_eq!(state.total_supply(2), None);
+}
+
+#[test]
+fn sm_8_issuer_mints() {
+    let state = state_with_one_asset([(User::Bob, 50), (User::Bob, 25)]);
+
+    assert_eq!(state.balance_of(0, &User::Bob), 75);
+    assert_eq!(state.total_supply(0), Some(75));
+}
+
+#[test]
+fn sm_8_non_issuer_cannot_mint_or_burn() {
+    let start = state_with_one_asset([(User::Bob, 50)]);
+    let minted = MultiAssetLedger::next_state(
+        &start,
+        &AssetTransaction::Mint {
+            issuer: User::Bob,
+            asset: 0,
+            beneficiary: User::Bob,
+            amount: 100,
+        },
+    );
+    let burned = MultiAssetLedger::next_state(
+        &start,
+        &AssetTransaction::Burn {
+            issuer: User::Bob,
+            asset: 0,
+            who: User::Bob,
+            amount: 10,
+        },
+    );
+
+    assert_eq!(minted, start);
+    assert_eq!(burned, start);
+}
+
+#[test]
+fn sm_8_burn_more_than_balance() {
+    let start = state_with_one_asset([(User::Bob, 50), (User::Charlie, 10)]);
+    let end = MultiAssetLedger::next_state(
+        &start,
+        &AssetTransaction::Burn {
+            issuer: User::Alice,
+            asset: 0,
+            who: User::Bob,
+            amount: 80,
+        },
+    );
+
+    assert_eq!(end.balance_of(0, &User::Bob), 0);
+    assert!(!end.balances.contains_key(&(0, User::Bob)));
+    assert_eq!(end.total_supply(0), Some(10));
+}
+
+#[test]
+fn sm_8_transfer_keeps_assets_separate() {
+    let mut state = state_with_one_asset([(User::Bob, 50)]);
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Create {
+            issuer: User::Charlie,
+        },
+    );
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Mint {
+            issuer: User::Charlie,
+            asset: 1,
+            beneficiary: User::Bob,
+            amount: 7,
+        },
+    );
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Transfer {
+            asset: 0,
+            sender: User::Bob,
+            receiver: User::Alice,
+            amount: 20,
+        },
+    );
+
+    assert_eq!(state.balance_of(0, &User::Bob), 30);
+    assert_eq!(state.balance_of(0, &User::Alice), 20);
+    assert_eq!(state.balance_of(1, &User::Bob), 7);
+    assert_eq!(state.balance_of(1, &User::Alice), 0);
+}
+
+#[test]
+fn sm_8_insufficient_balance_transfer() {
+    let start = state_with_one_asset([(User::Bob, 50)]);
+    let end = MultiAssetLedger::next_state(
+        &start,
+        &AssetTransaction::Transfer {
+            asset: 0,
+            sender: User::Bob,
+            receiver: User::Alice,
+            amount: 51,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_8_transfer_unknown_asset() {
+    let start = state_with_one_asset([(User::Bob, 50)]);
+    let end = MultiAssetLedger::next_state(
+        &start,
+        &AssetTransaction::Transfer {
+            asset: 9,
+            sender: User::Bob,
+            receiver: User::Alice,
+            amount: 0,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_8_approve_and_transfer_from() {
+    let mut state = state_with_one_asset([(User::Bob, 50)]);
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Approve {
+            asset: 0,
+            owner: User::Bob,
+            spender: User::Charlie,
+            amount: 30,
+        },
+    );
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::TransferFrom {
+            asset: 0,
+            spender: User::Charlie,
+            owner: User::Bob,
+            receiver: User::Alice,
+            amount: 20,
+        },
+    );
+
+    assert_eq!(state.balance_of(0, &User::Bob), 30);
+    assert_eq!(state.balance_of(0, &User::Alice), 20);
+    assert_eq!(state.allowance(0, &User::Bob, &User::Charlie), 10);
+
+    // The remaining allowance is not enough for another 20
+    let overspend = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::TransferFrom {
+            asset: 0,
+            spender: User::Charlie,
+            owner: User::Bob,
+            receiver: User::Charlie,
+            amount: 20,
+        },
+    );
+    assert_eq!(overspend, state);
+}
+
+#[test]
+fn sm_8_spending_entire_allowance_removes_it() {
+    let mut state = state_with_one_asset([(User::Bob, 50)]);
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Approve {
+            asset: 0,
+            owner: User::Bob,
+            spender: User::Charlie,
+            amount: 30,
+        },
+    );
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::TransferFrom {
+            asset: 0,
+            spender: User::Charlie,
+            owner: User::Bob,
+            receiver: User::Charlie,
+            amount: 30,
+        },
+    );
+
+    assert_eq!(state.balance_of(0, &User::Charlie), 30);
+    assert!(state.allowances.is_empty());
+}
+
+#[test]
+fn sm_8_frozen_account_cannot_send() {
+    let mut state = state_with_one_asset([(User::Bob, 50)]);
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Freeze {
+            issuer: User::Alice,
+            asset: 0,
+            who: User::Bob,
+        },
+    );
+    assert!(state.is_frozen(0, &User::Bob));
+
+    let transfer = AssetTransaction::Transfer {
+        asset: 0,
+        sender: User::Bob,
+        receiver: User::Charlie,
+        amount: 10,
+    };
+    assert_eq!(MultiAssetLedger::next_state(&state, &transfer), state);
+
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Thaw {
+            issuer: User::Alice,
+            asset: 0,
+            who: User::Bob,
+        },
+    );
+    state = MultiAssetLedger::next_state(&state, &transfer);
+    assert_eq!(state.balance_of(0, &User::Charlie), 10);
+}
+
+#[test]
+fn sm_8_frozen_account_can_still_receive() {
+    let mut state = state_with_one_asset([(User::Bob, 50)]);
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Freeze {
+            issuer: User::Alice,
+            asset: 0,
+            who: User::Charlie,
+        },
+    );
+    state = MultiAssetLedger::next_state(
+        &state,
+        &AssetTransaction::Transfer {
+            asset: 0,
+            sender: User::Bob,
+            receiver: User::Charlie,
+            amount: 10,
+        },
+    );
+
+    assert_eq!(state.balance_of(0, &User::Charlie), 10);
+}
+
+#[test]
+fn sm_8_only_issuer_can_freeze() {
+    let start = state_with_one_asset([(User::Bob, 50)]);
+    let end = MultiAssetLedger::next_state(
+        &start,
+        &AssetTransaction::Freeze {
+            issuer: User::Charlie,
+            asset: 0,
+            who: User::Bob,
+        },
+    );
+
+    assert_eq!(end, start);
+}
@@ -0,0 +1,748 @@
+//! A prediction market lets users bet on the outcome of a future event. Each market has two
+//! outcomes, yes and no, and users hold shares in those outcomes. Once the event has happened, a
+//! designated oracle reports the result, and every share of the winning outcome can be redeemed for
+//! one unit of currency. Shares of the losing outcome become worthless.
+//!
+//! Before resolution, the price of a share reflects how likely the market thinks that outcome is.
+//! Rather than matching buyers with sellers in an order book, we price shares with an automated
+//! market maker. The market creator seeds a pool with liquidity, and users always trade against
+//! that pool. We use the fixed product market maker popularized by Gnosis, which is a close
+//! relative of the constant product formula used by Uniswap.
+//!
+//! The key bookkeeping trick is that one unit of currency can always be split into a "complete set"
+//! of one yes share and one no share, and a complete set can always be merged back into one unit of
+//! currency. So the currency held by a market always equals the number of complete sets outstanding,
+//! and no value is ever created or destroyed.
+
+use anyhow::{Error, Result};
+use std::collections::HashMap;
+
+use super::{StateMachine, User};
+
+/// Markets are identified by a simple counter that is incremented each time a market is created.
+pub type MarketId = u64;
+
+/// This state machine models a multi-user currency along with any number of binary prediction markets.
+pub struct PredictionMarket;
+
+/// The two possible outcomes of every market.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    Yes,
+    No,
+}
+
+impl Outcome {
+    fn index(&self) -> usize {
+        match self {
+            Outcome::Yes => 0,
+            Outcome::No => 1,
+        }
+    }
+}
+
+/// A single binary prediction market.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Market {
+    /// The user who created the market and provided its initial liquidity.
+    creator: User,
+    /// The only user who may report the outcome.
+    oracle: User,
+    /// The outcome shares held by the market maker, indexed by `Outcome::index`.
+    pool: [u64; 2],
+    /// The outcome shares held by each user, indexed by `Outcome::index`.
+    shares: HashMap<User, [u64; 2]>,
+    /// The currency locked in this market. Before resolution, this always equals the number of
+    /// complete sets in existence.
+    collateral: u64,
+    /// The reported outcome. None until the oracle resolves the market.
+    resolution: Option<Outcome>,
+}
+
+impl Market {
+    /// The current price of one share of the given outcome, in millionths of a currency unit.
+    ///
+    /// For the fixed product market maker, the price of an outcome is the fraction of the pool made
+    /// up by the _other_ outcome. The scarcer an outcome is in the pool, the more it costs.
+    pub fn price_millionths(&self, outcome: Outcome) -> u64 {
+        let other = self.pool[1 - outcome.index()] as u128;
+        let total = self.pool[0] as u128 + self.pool[1] as u128;
+        (other * 1_000_000 / total) as u64
+    }
+
+    /// The number of shares of the given outcome held by the given user.
+    pub fn shares_of(&self, user: &User, outcome: Outcome) -> u64 {
+        self.shares
+            .get(user)
+            .map(|s| s[outcome.index()])
+            .unwrap_or(0)
+    }
+
+    /// The reported outcome, if the market has been resolved.
+    pub fn resolution(&self) -> Option<Outcome> {
+        self.resolution
+    }
+
+    /// The total shares of the given outcome held by the pool and by users.
+    fn outstanding(&self, outcome: Outcome) -> u64 {
+        self.pool[outcome.index()]
+            + self
+                .shares
+                .values()
+                .map(|s| s[outcome.index()])
+                .sum::<u64>()
+    }
+
+    fn set_shares(&mut self, user: &User, outcome: Outcome, amount: u64) {
+        let entry = self.shares.entry(*user).or_insert([0, 0]);
+        entry[outcome.index()] = amount;
+        if *entry == [0, 0] {
+            self.shares.remove(user);
+        }
+    }
+}
+
+/// The complete state of the prediction market system.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct State {
+    /// The currency balance of each user. Zero balances are removed.
+    balances: HashMap<User, u64>,
+    /// All markets that have been created, indexed by their id.
+    markets: HashMap<MarketId, Market>,
+    /// The id that will be given to the next created market.
+    next_market_id: MarketId,
+}
+
+impl State {
+    /// Create a state in which the given users hold the given balances and no markets exist yet.
+    pub fn with_balances<const N: usize>(balances: [(User, u64); N]) -> Self {
+        State {
+            balances: balances.into_iter().filter(|(_, b)| *b > 0).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The currency balance of the given user.
+    pub fn balance_of(&self, user: &User) -> u64 {
+        self.balances.get(user).copied().unwrap_or(0)
+    }
+
+    /// Look up a market by id.
+    pub fn market(&self, id: MarketId) -> Option<&Market> {
+        self.markets.get(&id)
+    }
+
+    /// The total value in the system. This is the sum of all user balances plus all collateral
+    /// locked in markets. No transition ever changes it. It may be more than a single balance
+    /// can hold.
+    pub fn total_value(&self) -> u128 {
+        let balances = self.balances.values().map(|b| *b as u128);
+        balances
+            .chain(self.markets.values().map(|m| m.collateral as u128))
+            .sum()
+    }
+
+    fn debit(&mut self, user: &User, amount: u64) -> Result<()> {
+        let balance = self.balance_of(user);
+        if balance < amount {
+            return Err(Error::msg("insufficient balance"));
+        }
+        self.set_balance(user, balance - amount);
+        Ok(())
+    }
+
+    fn credit(&mut self, user: &User, amount: u64) -> Result<()> {
+        let balance = add(self.balance_of(user), amount)?;
+        self.set_balance(user, balance);
+        Ok(())
+    }
+
+    fn set_balance(&mut self, user: &User, amount: u64) {
+        if amount == 0 {
+            self.balances.remove(user);
+        } else {
+            self.balances.insert(*user, amount);
+        }
+    }
+
+    fn open_market(&mut self, id: MarketId) -> Result<&mut Market> {
+        let market = self
+            .markets
+            .get_mut(&id)
+            .ok_or(Error::msg("market does not exist"))?;
+        if market.resolution.is_some() {
+            return Err(Error::msg("market is already resolved"));
+        }
+        Ok(market)
+    }
+}
+
+/// Add two amounts, failing the transaction if the result doesn't fit in a u64.
+fn add(a: u64, b: u64) -> Result<u64> {
+    a.checked_add(b).ok_or(Error::msg("amount overflows"))
+}
+
+/// The state transitions that users can make in the prediction market system.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MarketTransaction {
+    /// Create a new market, seeding its pool with `liquidity` complete sets paid for by the creator.
+    /// The new market receives the id `State::next_market_id`.
+    CreateMarket {
+        creator: User,
+        oracle: User,
+        liquidity: u64,
+    },
+    /// Spend `amount` of currency to buy shares of the given outcome from the market maker.
+    /// Fails if fewer than `min_shares` shares would be received.
+    Buy {
+        buyer: User,
+        market: MarketId,
+        outcome: Outcome,
+        amount: u64,
+        min_shares: u64,
+    },
+    /// Sell `shares` shares of the given outcome back to the market maker for currency.
+    /// Fails if less than `min_amount` currency would be received.
+    Sell {
+        seller: User,
+        market: MarketId,
+        outcome: Outcome,
+        shares: u64,
+        min_amount: u64,
+    },
+    /// Report the outcome of a market. Only the market's oracle may resolve it, and only once.
+    /// The market maker's winning shares are paid out to the market creator.
+    Resolve {
+        oracle: User,
+        market: MarketId,
+        outcome: Outcome,
+    },
+    /// Exchange all of the redeemer's winning shares in a resolved market for currency.
+    Redeem { redeemer: User, market: MarketId },
+}
+
+/// How many shares of an outcome whose pool balance is `bought` the market maker gives out
+/// in exchange for `amount` currency, when the pool balance of the other outcome is `other`.
+///
+/// The currency is first split into `amount` complete sets which are added to the pool. Then
+/// shares of the bought outcome are removed until the product of the two pool balances is back
+/// where it started. Rounding always favors the pool.
+fn buy_shares(bought: u64, other: u64, amount: u64) -> u64 {
+    let invariant = bought as u128 * other as u128;
+    let other_after = other as u128 + amount as u128;
+    let bought_after = invariant.div_ceil(other_after);
+    (bought as u128 + amount as u128 - bought_after) as u64
+}
+
+/// How much currency the market maker pays for `shares` shares of an outcome whose pool balance is
+/// `sold`, when the pool balance of the other outcome is `other`.
+///
+/// The shares are added to the pool, and then the largest number of complete sets are removed and
+/// merged back into currency such that the product of the pool balances does not decrease.
+///
+/// Solving the quadratic for the number of sets directly would square the sum of the pool
+/// balances, which overflows even a u128 for large pools. Instead the answer is found by a binary
+/// search, where each step only needs the product of two pool balances.
+fn sell_amount(sold: u64, other: u64, shares: u64) -> u64 {
+    let invariant = sold as u128 * other as u128;
+    let sold = sold as u128 + shares as u128;
+    let other = other as u128;
+    // The product only shrinks as more sets are removed. A product too large for a u128 is
+    // certainly no smaller than the invariant.
+    let keeps_invariant = |x: u128| {
+        (sold - x)
+            .checked_mul(other - x)
+            .is_none_or(|product| product >= invariant)
+    };
+    let (mut low, mut high) = (0, other.min(sold));
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if keeps_invariant(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low as u64
+}
+
+/// We model the prediction market system as a state machine with five possible transitions
+impl StateMachine for PredictionMarket {
+    type State = State;
+    type Transition = MarketTransaction;
+
+    fn next_state(starting_state: &State, t: &MarketTransaction) -> State {
+        let mut new_state = starting_state.clone();
+
+        let process_transaction = |new_state: &mut State| -> Result<()> {
+            match t {
+                MarketTransaction::CreateMarket {
+                    creator,
+                    oracle,
+                    liquidity,
+                } => {
+                    if *liquidity == 0 {
+                        return Err(Error::msg("market needs liquidity"));
+                    }
+                    new_state.debit(creator, *liquidity)?;
+                    let id = new_state.next_market_id;
+                    new_state.markets.insert(
+                        id,
+                        Market {
+                            creator: *creator,
+                            oracle: *oracle,
+                            pool: [*liquidity, *liquidity],
+                            shares: HashMap::new(),
+                            collateral: *liquidity,
+                            resolution: None,
+                        },
+                    );
+                    new_state.next_market_id += 1;
+                }
+                MarketTransaction::Buy {
+                    buyer,
+                    market,
+                    outcome,
+                    amount,
+                    min_shares,
+                } => {
+                    new_state.debit(buyer, *amount)?;
+                    let m = new_state.open_market(*market)?;
+                    let (bought, other) = (outcome.index(), 1 - outcome.index());
+                    let shares = buy_shares(m.pool[bought], m.pool[other], *amount);
+                    if shares < *min_shares || shares == 0 {
+                        return Err(Error::msg("too few shares"));
+                    }
+                    m.pool[bought] = add(m.pool[bought], *amount)? - shares;
+                    m.pool[other] = add(m.pool[other], *amount)?;
+                    m.collateral = add(m.collateral, *amount)?;
+                    let held = m.shares_of(buyer, *outcome);
+                    m.set_shares(buyer, *outcome, add(held, shares)?);
+                }
+                MarketTransaction::Sell {
+                    seller,
+                    market,
+                    outcome,
+                    shares,
+                    min_amount,
+                } => {
+                    let m = new_state.open_market(*market)?;
+                    let held = m.shares_of(seller, *outcome);
+                    if held < *shares {
+                        return Err(Error::msg("insufficient shares"));
+                    }
+                    let (sold, other) = (outcome.index(), 1 - outcome.index());
+                    let amount = sell_amount(m.pool[sold], m.pool[other], *shares);
+                    if amount < *min_amount || amount == 0 {
+                        return Err(Error::msg("too little currency"));
+                    }
+                    m.pool[sold] = add(m.pool[sold], *shares)? - amount;
+                    m.pool[other] -= amount;
+                    m.collateral -= amount;
+                    m.set_shares(seller, *outcome, held - shares);
+                    new_state.credit(seller, amount)?;
+                }
+                MarketTransaction::Resolve {
+                    oracle,
+                    market,
+                    outcome,
+                } => {
+                    let m = new_state.open_market(*market)?;
+                    if m.oracle != *oracle {
+                        return Err(Error::msg("only the oracle may resolve"));
+                    }
+                    m.resolution = Some(*outcome);
+                    // The pool's winning shares belong to the liquidity provider.
+                    let payout = m.pool[outcome.index()];
+                    m.pool = [0, 0];
+                    m.collateral -= payout;
+                    let creator = m.creator;
+                    new_state.credit(&creator, payout)?;
+                }
+                MarketTransaction::Redeem { redeemer, market } => {
+                    let m = new_state
+                        .markets
+                        .get_mut(market)
+                        .ok_or(Error::msg("market does not exist"))?;
+                    let outcome = m.resolution.ok_or(Error::msg("market is not resolved"))?;
+                    let payout = m.shares_of(redeemer, outcome);
+                    m.shares.remove(redeemer);
+                    m.collateral -= payout;
+                    new_state.credit(redeemer, payout)?;
+                }
+            }
+            Ok(())
+        };
+
+        match process_transaction(&mut new_state) {
+            Ok(_) => new_state,
+            Err(_) => starting_state.clone(),
+        }
+    }
+
+    fn human_name() -> String {
+        "Prediction Market".into()
+    }
+}
+
+/// Check the invariants that must hold after every transition. Total value is conserved, and every
+/// unresolved market holds exactly one unit of collateral per complete set. Resolved markets hold
+/// exactly enough collateral to pay out every remaining winning share.
+#[cfg(test)]
+fn assert_invariants(state: &State, expected_total: u128) {
+    assert_eq!(state.total_value(), expected_total);
+    for market in state.markets.values() {
+        match market.resolution {
+            None => {
+                assert_eq!(market.outstanding(Outcome::Yes), market.collateral);
+                assert_eq!(market.outstanding(Outcome::No), market.collateral);
+            }
+            Some(outcome) => assert_eq!(market.outstanding(outcome), market.collateral),
+        }
+    }
+}
+
+#[cfg(test)]
+fn apply_all(state: &State, transactions: &[MarketTransaction]) -> State {
+    let total = state.total_value();
+    transactions.iter().fold(state.clone(), |state, t| {
+        let next = PredictionMarket::next_state(&state, t);
+        assert_invariants(&next, total);
+        next
+    })
+}
+
+#[cfg(test)]
+fn genesis() -> State {
+    let start = State::with_balances([
+        (User::Alice, 1000),
+        (User::Bob, 1000),
+        (User::Charlie, 1000),
+    ]);
+    apply_all(
+        &start,
+        &[MarketTransaction::CreateMarket {
+            creator: User::Alice,
+            oracle: User::Charlie,
+            liquidity: 100,
+        }],
+    )
+}
+
+#[test]
+fn sm_9_create_market() {
+    let state = genesis();
+    let market = state.market(0).unwrap();
+
+    assert_eq!(state.balance_of(&User::Alice), 900);
+    assert_eq!(market.collateral, 100);
+    assert_eq!(market.price_millionths(Outcome::Yes), 500_000);
+    assert_eq!(market.price_millionths(Outcome::No), 500_000);
+}
+
+#[test]
+fn sm_9_create_market_without_funds_fails() {
+    let start = State::with_balances([(User::Alice, 10)]);
+    let end = PredictionMarket::next_state(
+        &start,
+        &MarketTransaction::CreateMarket {
+            creator: User::Alice,
+            oracle: User::Bob,
+            liquidity: 100,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_9_buying_raises_price() {
+    let state = apply_all(
+        &genesis(),
+        &[MarketTransaction::Buy {
+            buyer: User::Bob,
+            market: 0,
+            outcome: Outcome::Yes,
+            amount: 100,
+            min_shares: 0,
+        }],
+    );
+    let market = state.market(0).unwrap();
+
+    // The pool goes from (100, 100) to (50, 200), so Bob receives 150 yes shares
+    assert_eq!(market.shares_of(&User::Bob, Outcome::Yes), 150);
+    assert_eq!(state.balance_of(&User::Bob), 900);
+    assert_eq!(market.price_millionths(Outcome::Yes), 800_000);
+}
+
+#[test]
+fn sm_9_slippage_limit() {
+    let start = genesis();
+    let end = PredictionMarket::next_state(
+        &start,
+        &MarketTransaction::Buy {
+            buyer: User::Bob,
+            market: 0,
+            outcome: Outcome::Yes,
+            amount: 100,
+            min_shares: 151,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_9_buy_then_sell_round_trip() {
+    let state = apply_all(
+        &genesis(),
+        &[
+            MarketTransaction::Buy {
+                buyer: User::Bob,
+                market: 0,
+                outcome: Outcome::No,
+                amount: 100,
+                min_shares: 0,
+            },
+            MarketTransaction::Sell {
+                seller: User::Bob,
+                market: 0,
+                outcome: Outcome::No,
+                shares: 150,
+                min_amount: 0,
+            },
+        ],
+    );
+
+    // Selling everything straight back restores the pool and Bob's balance
+    assert_eq!(state.balance_of(&User::Bob), 1000);
+    assert_eq!(state.market(0).unwrap().pool, [100, 100]);
+}
+
+#[test]
+fn sm_9_huge_pools_do_not_overflow() {
+    let start = State::with_balances([(User::Alice, u64::MAX)]);
+    let state = apply_all(
+        &start,
+        &[
+            MarketTransaction::CreateMarket {
+                creator: User::Alice,
+                oracle: User::Bob,
+                liquidity: 1 << 63,
+            },
+            MarketTransaction::Buy {
+                buyer: User::Alice,
+                market: 0,
+                outcome: Outcome::Yes,
+                amount: 1 << 62,
+                min_shares: 0,
+            },
+        ],
+    );
+    let market = state.market(0).unwrap();
+    assert_eq!(market.price_millionths(Outcome::Yes), 692_307);
+
+    let shares = market.shares_of(&User::Alice, Outcome::Yes);
+    let state = apply_all(
+        &state,
+        &[MarketTransaction::Sell {
+            seller: User::Alice,
+            market: 0,
+            outcome: Outcome::Yes,
+            shares,
+            min_amount: 0,
+        }],
+    );
+
+    // Selling everything straight back returns the amount, less a unit lost to rounding in the
+    // pool's favor.
+    assert_eq!(state.balance_of(&User::Alice), u64::MAX - (1 << 63) - 1);
+    assert_eq!(
+        state.market(0).unwrap().pool,
+        [(1 << 63) + 1, (1 << 63) + 1]
+    );
+}
+
+#[test]
+fn sm_9_overflowing_buy_is_rejected() {
+    let start = apply_all(
+        &State::with_balances([(User::Alice, 1 << 63), (User::Bob, 1 << 63)]),
+        &[MarketTransaction::CreateMarket {
+            creator: User::Alice,
+            oracle: User::Charlie,
+            liquidity: 1 << 63,
+        }],
+    );
+    // The pool of no shares would grow past u64::MAX.
+    let end = apply_all(
+        &start,
+        &[MarketTransaction::Buy {
+            buyer: User::Bob,
+            market: 0,
+            outcome: Outcome::Yes,
+            amount: 1 << 63,
+            min_shares: 0,
+        }],
+    );
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_9_cannot_sell_more_than_held() {
+    let start = apply_all(
+        &genesis(),
+        &[MarketTransaction::Buy {
+            buyer: User::Bob,
+            market: 0,
+            outcome: Outcome::Yes,
+            amount: 10,
+            min_shares: 0,
+        }],
+    );
+    let end = PredictionMarket::next_state(
+        &start,
+        &MarketTransaction::Sell {
+            seller: User::Bob,
+            market: 0,
+            outcome: Outcome::No,
+            shares: 1,
+            min_amount: 0,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_9_only_oracle_resolves() {
+    let start = genesis();
+    let end = PredictionMarket::next_state(
+        &start,
+        &MarketTransaction::Resolve {
+            oracle: User::Alice,
+            market: 0,
+            outcome: Outcome::Yes,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_9_resolve_and_redeem() {
+    let state = apply_all(
+        &genesis(),
+        &[
+            MarketTransaction::Buy {
+                buyer: User::Bob,
+                market: 0,
+                outcome: Outcome::Yes,
+                amount: 100,
+                min_shares: 0,
+            },
+            MarketTransaction::Buy {
+                buyer: User::Charlie,
+                market: 0,
+                outcome: Outcome::No,
+                amount: 50,
+                min_shares: 0,
+            },
+            MarketTransaction::Resolve {
+                oracle: User::Charlie,
+                market: 0,
+                outcome: Outcome::Yes,
+            },
+            MarketTransaction::Redeem {
+                redeemer: User::Bob,
+                market: 0,
+            },
+            MarketTransaction::Redeem {
+                redeemer: User::Charlie,
+                market: 0,
+            },
+        ],
+    );
+
+    // Bob's bet paid off, Charlie's did not
+    assert_eq!(state.balance_of(&User::Bob), 1050);
+    assert_eq!(state.balance_of(&User::Charlie), 950);
+    // Everything has been paid out, and the liquidity provider absorbed the rest
+    assert_eq!(state.market(0).unwrap().collateral, 0);
+    assert_eq!(state.balance_of(&User::Alice), 1000);
+}
+
+#[test]
+fn sm_9_no_trading_after_resolution() {
+    let start = apply_all(
+        &genesis(),
+        &[MarketTransaction::Resolve {
+            oracle: User::Charlie,
+            market: 0,
+            outcome: Outcome::No,
+        }],
+    );
+    let end = PredictionMarket::next_state(
+        &start,
+        &MarketTransaction::Buy {
+            buyer: User::Bob,
+            market: 0,
+            outcome: Outcome::No,
+            amount: 10,
+            min_shares: 0,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_9_value_conserved_through_random_trading() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(9);
+    let users = [User::Alice, User::Bob, User::Charlie];
+    let mut state = genesis();
+    let total = state.total_value();
+
+    for _ in 0..500 {
+        let user = users[rng.gen_range(0..3)];
+        let outcome = if rng.gen() { Outcome::Yes } else { Outcome::No };
+        let t = if rng.gen() {
+            MarketTransaction::Buy {
+                buyer: user,
+                market: 0,
+                outcome,
+                amount: rng.gen_range(1..50),
+                min_shares: 0,
+            }
+        } else {
+            let held = state.market(0).unwrap().shares_of(&user, outcome);
+            MarketTransaction::Sell {
+                seller: user,
+                market: 0,
+                outcome,
+                shares: rng.gen_range(0..=held),
+                min_amount: 0,
+            }
+        };
+        state = PredictionMarket::next_state(&state, &t);
+        assert_invariants(&state, total);
+    }
+
+    let mut finish = vec![MarketTransaction::Resolve {
+        oracle: User::Charlie,
+        market: 0,
+        outcome: Outcome::No,
+    }];
+    for redeemer in users {
+        finish.push(MarketTransaction::Redeem {
+            redeemer,
+            market: 0,
+        });
+    }
+    state = apply_all(&state, &finish);
+
+    // Once everyone has redeemed, every unit of value is back in a user's balance
+    assert_eq!(state.market(0).unwrap().collateral, 0);
+    assert_eq!(state.total_value(), total);
+}
@@ -5,6 +5,8 @@
 // We make the complete Block and Header types publicly visible so that we can continue developing
 // against them in future chapters. The prior iterations are not available outside this chapter.
 pub use p6_rich_state::{Block, Header};
+// The clocks are also used by the slot-based consensus engines in the next chapter.
+pub use p8_timestamps::{Clock, ManualClock, SystemClock};
 
 mod p1_header_chain;
 mod p2_extrinsic_state;
@@ -12,3 +14,139 @@ mod p3_consensus;
 pub mod p4_batched_extrinsics;
 mod p5_fork_choice;
 mod p6_rich_state;
+mod p7_block_store;
+mod p8_timestamps;
+mod p9_accumulators;
+mod p10_tree_route;
+mod p11_uncles;
+mod p12_chain_generator;
+mod p13_fork_simulation;
+pub mod p14_chain_explorer;
+mod p15_state_cache;
+mod p16_checkpoint_verifier;
+
+type Hash = u64;
+
+/// The parts of a header that are common to every header type from part 4 onwards, no matter
+/// what else it carries. Code that only needs to walk the chain, like timestamp validation, can
+/// be written once against this trait.
+pub trait ChainHeader {
+	/// The hash of this header.
+	fn block_hash(&self) -> Hash;
+
+	/// The hash of this header's parent.
+	fn parent(&self) -> Hash;
+
+	/// The height of this header.
+	fn height(&self) -> u64;
+
+	/// When this block was authored, in seconds.
+	fn timestamp(&self) -> u64;
+}
+
+/// The expected amount of work needed to find a block with the given hash.
+///
+/// A hash below `h` turns up about once in every `2^64 / (h + 1)` attempts, so that is how much
+/// work the block represents. This is always positive, and a hash half as large counts for twice
+/// as much work. Passing a difficulty threshold instead of a hash gives the expected work of any
+/// block meeting that threshold, which is how Bitcoin measures work.
+///
+/// Fork choice rules that weigh chains by work, like the heaviest chain rule or GHOST, should use
+/// this rather than inventing their own measure.
+pub fn block_work(block_hash: Hash) -> u128 {
+	(1u128 << 64) / (block_hash as u128 + 1)
+}
+
+/// The total work in a chain of headers. A `u128` can hold the work of 2^64 blocks of maximum
+/// difficulty, so this never overflows in practice.
+pub fn chain_work<H: ChainHeader>(chain: &[H]) -> u128 {
+	chain.iter().map(|header| block_work(header.block_hash())).sum()
+}
+
+/// The reason a block failed verification, along with the height and hash of the offending block.
+///
+/// Returning a plain `bool` is enough to decide whether to accept a chain, but when a long import
+/// fails it tells us nothing about where or why. Every verification function in this chapter
+/// returns this error instead, and stops at the first block that fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VerificationError {
+	/// The block's parent hash is not the hash of the previous header.
+	BadParentHash { height: u64, hash: Hash },
+	/// The block's height is not exactly one more than its parent's.
+	HeightGap { height: u64, hash: Hash },
+	/// The block body does not match the extrinsics root committed in the header.
+	ExtrinsicsRootMismatch { height: u64, hash: Hash },
+	/// Executing the block does not produce the state committed in the header.
+	StateRootMismatch { height: u64, hash: Hash },
+	/// The consensus digest does not satisfy the consensus rules, for example too little work.
+	SealInvalid { height: u64, hash: Hash },
+	/// The block breaks a rule introduced by a fork, such as the even or odd state rules.
+	ForkRuleViolation { height: u64, hash: Hash },
+	/// The block's timestamp is not after the median time of its recent ancestors.
+	TimestampTooOld { height: u64, hash: Hash },
+	/// The block's timestamp is too far ahead of our own clock.
+	TimestampInFuture { height: u64, hash: Hash },
+	/// The block's uncles do not match the uncles root committed in the header.
+	UnclesRootMismatch { height: u64, hash: Hash },
+	/// One of the block's uncles is not allowed, or the block has too many.
+	InvalidUncle { height: u64, hash: Hash },
+	/// The header that verification starts from is not the trusted checkpoint.
+	CheckpointMismatch { height: u64, hash: Hash },
+}
+
+impl VerificationError {
+	/// The height of the block that failed verification.
+	pub fn height(&self) -> u64 {
+		match self {
+			Self::BadParentHash { height, .. }
+			| Self::HeightGap { height, .. }
+			| Self::ExtrinsicsRootMismatch { height, .. }
+			| Self::StateRootMismatch { height, .. }
+			| Self::SealInvalid { height, .. }
+			| Self::ForkRuleViolation { height, .. }
+			| Self::TimestampTooOld { height, .. }
+			| Self::TimestampInFuture { height, .. }
+			| Self::UnclesRootMismatch { height, .. }
+			| Self::InvalidUncle { height, .. }
+			| Self::CheckpointMismatch { height, .. } => *height,
+		}
+	}
+
+	/// The hash of the block that failed verification.
+	pub fn hash(&self) -> Hash {
+		match self {
+			Self::BadParentHash { hash, .. }
+			| Self::HeightGap { hash, .. }
+			| Self::ExtrinsicsRootMismatch { hash, .. }
+			| Self::StateRootMismatch { hash, .. }
+			| Self::SealInvalid { hash, .. }
+			| Self::ForkRuleViolation { hash, .. }
+			| Self::TimestampTooOld { hash, .. }
+			| Self::TimestampInFuture { hash, .. }
+			| Self::UnclesRootMismatch { hash, .. }
+			| Self::InvalidUncle { hash, .. }
+			| Self::CheckpointMismatch { hash, .. } => *hash,
+		}
+	}
+}
+
+impl std::fmt::Display for VerificationError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let reason = match self {
+			Self::BadParentHash { .. } => "bad parent hash",
+			Self::HeightGap { .. } => "height gap",
+			Self::ExtrinsicsRootMismatch { .. } => "extrinsics root mismatch",
+			Self::StateRootMismatch { .. } => "state root mismatch",
+			Self::SealInvalid { .. } => "invalid seal",
+			Self::ForkRuleViolation { .. } => "fork rule violation",
+			Self::TimestampTooOld { .. } => "timestamp not after median time past",
+			Self::TimestampInFuture { .. } => "timestamp too far in the future",
+			Self::UnclesRootMismatch { .. } => "uncles root mismatch",
+			Self::InvalidUncle { .. } => "invalid uncle",
+			Self::CheckpointMismatch { .. } => "does not match the checkpoint",
+		};
+		write!(f, "block {} at height {} failed verification: {}", self.hash(), self.height(), reason)
+	}
+}
+
+impl std::error::Error for VerificationError {}
@@ -0,0 +1,354 @@
+//! The fork choice rules in part 5 compare chains that are handed to them as slices. A real node
+//! doesn't have its chains sitting in neat slices. It has a pile of headers indexed by hash, which
+//! together form a tree rooted at genesis. When the node's fork choice switches from one tip to
+//! another, it needs to answer two questions.
+//!
+//! 1. What is the lowest common ancestor of the old tip and the new tip? Everything up to there is
+//!    shared history and doesn't need to be touched.
+//! 2. Which blocks must be retracted, that is rolled back from the old tip down to the common
+//!    ancestor, and which must be enacted, that is applied from the common ancestor up to the new
+//!    tip? This is called the tree route, and it's exactly what a client needs to update its state,
+//!    and what a transaction pool needs to return retracted transactions to the queue.
+//!
+//! Walking back one parent at a time works, but it's linear in the chain length, even when the two
+//! tips are right next to each other. Instead each header in the tree stores a skip list of its
+//! ancestors: its parent, its grandparent, its 4th ancestor, its 8th, and so on. Any ancestor can be
+//! reached by making one jump per set bit of the height difference, so lookups are logarithmic.
+//! This technique is known as binary lifting.
+
+use super::ChainHeader;
+use std::collections::HashMap;
+
+type Hash = u64;
+
+/// A header in the tree, along with its skip list.
+struct Entry<H> {
+	header: H,
+	/// `ancestors[k]` is the hash of this header's ancestor 2^k blocks back. The list stops at the
+	/// root of the tree.
+	ancestors: Vec<Hash>,
+}
+
+/// A tree of headers indexed by hash.
+///
+/// The first header inserted becomes the root of the tree. It is usually genesis, but may be any
+/// header, for example a finalized checkpoint. Every header after that must extend a header that
+/// is already in the tree.
+pub struct HeaderTree<H: ChainHeader> {
+	entries: HashMap<Hash, Entry<H>>,
+	children: HashMap<Hash, Vec<Hash>>,
+	root: Option<Hash>,
+}
+
+/// The blocks to retract and enact to move from one tip to another.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeRoute {
+	/// The lowest common ancestor of the two tips.
+	pub common_ancestor: Hash,
+	/// The blocks to roll back, starting with the old tip and ending with the child of the common
+	/// ancestor.
+	pub retracted: Vec<Hash>,
+	/// The blocks to apply, starting with the child of the common ancestor and ending with the new
+	/// tip.
+	pub enacted: Vec<Hash>,
+}
+
+impl<H: ChainHeader> Default for HeaderTree<H> {
+	fn default() -> Self {
+		HeaderTree { entries: HashMap::new(), children: HashMap::new(), root: None }
+	}
+}
+
+impl<H: ChainHeader> HeaderTree<H> {
+	/// Create an empty tree.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add a header to the tree. Returns false, without adding it, if the tree is not empty and the
+	/// header's parent is not in the tree, or if its height doesn't follow on from its parent.
+	/// Adding a header that is already in the tree does nothing and returns true.
+	pub fn insert(&mut self, header: H) -> bool {
+		let block_hash = header.block_hash();
+		if self.entries.contains_key(&block_hash) {
+			return true;
+		}
+		let ancestors = if self.entries.is_empty() {
+			self.root = Some(block_hash);
+			Vec::new()
+		} else {
+			match self.entries.get(&header.parent()) {
+				Some(parent) if parent.header.height() + 1 == header.height() => {},
+				_ => return false,
+			}
+			self.children.entry(header.parent()).or_default().push(block_hash);
+			self.skip_list(header.parent())
+		};
+		self.entries.insert(block_hash, Entry { header, ancestors });
+		true
+	}
+
+	/// Build the skip list for a new child of the given parent.
+	fn skip_list(&self, parent: Hash) -> Vec<Hash> {
+		let mut ancestors = vec![parent];
+		// The 2^(k+1)th ancestor is the 2^kth ancestor of the 2^kth ancestor.
+		loop {
+			let k = ancestors.len() - 1;
+			match self.entries[&ancestors[k]].ancestors.get(k) {
+				Some(next) => ancestors.push(*next),
+				None => return ancestors,
+			}
+		}
+	}
+
+	/// The number of headers in the tree.
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Whether the tree has no headers yet.
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// The hash of the root of the tree, or None if the tree is empty.
+	pub fn root(&self) -> Option<Hash> {
+		self.root
+	}
+
+	/// The hashes of the known children of the given block, in the order they were inserted.
+	pub fn children(&self, block_hash: Hash) -> &[Hash] {
+		self.children.get(&block_hash).map(Vec::as_slice).unwrap_or_default()
+	}
+
+	/// The hashes of every block with no known children. Each one is the tip of a chain.
+	pub fn tips(&self) -> Vec<Hash> {
+		self.entries.keys().filter(|h| self.children(**h).is_empty()).copied().collect()
+	}
+
+	/// Look up a header by its hash.
+	pub fn get(&self, block_hash: Hash) -> Option<&H> {
+		self.entries.get(&block_hash).map(|entry| &entry.header)
+	}
+
+	/// The hash of the ancestor of the given block at the given height. A block is its own
+	/// ancestor at its own height. Returns None if the block is unknown, or if the height is above
+	/// the block or below the root of the tree.
+	pub fn ancestor_at_height(&self, block_hash: Hash, height: u64) -> Option<Hash> {
+		let mut distance = self.get(block_hash)?.height().checked_sub(height)?;
+		let mut current = block_hash;
+		let mut k = 0;
+		while distance > 0 {
+			if distance & 1 == 1 {
+				current = *self.entries[&current].ancestors.get(k)?;
+			}
+			distance >>= 1;
+			k += 1;
+		}
+		Some(current)
+	}
+
+	/// The hash of the lowest common ancestor of the two given blocks. This is one of the blocks
+	/// itself if it is an ancestor of the other. Returns None if either block is unknown.
+	pub fn lowest_common_ancestor(&self, a: Hash, b: Hash) -> Option<Hash> {
+		let height = self.get(a)?.height().min(self.get(b)?.height());
+		let mut a = self.ancestor_at_height(a, height)?;
+		let mut b = self.ancestor_at_height(b, height)?;
+		if a == b {
+			return Some(a);
+		}
+		// Jump both blocks up by the largest distance that keeps them apart. Afterwards they are
+		// siblings, so the answer is their parent.
+		for k in (0..self.entries[&a].ancestors.len()).rev() {
+			let (up_a, up_b) =
+				(self.entries[&a].ancestors.get(k), self.entries[&b].ancestors.get(k));
+			if let (Some(up_a), Some(up_b)) = (up_a, up_b) {
+				if up_a != up_b {
+					(a, b) = (*up_a, *up_b);
+				}
+			}
+		}
+		Some(self.entries[&a].header.parent())
+	}
+
+	/// The route from one block to another through their lowest common ancestor. Returns None if
+	/// either block is unknown.
+	pub fn tree_route(&self, from: Hash, to: Hash) -> Option<TreeRoute> {
+		let common_ancestor = self.lowest_common_ancestor(from, to)?;
+		let retracted = self.path_down_to(from, common_ancestor);
+		let mut enacted = self.path_down_to(to, common_ancestor);
+		enacted.reverse();
+		Some(TreeRoute { common_ancestor, retracted, enacted })
+	}
+
+	/// The blocks from `block_hash` back to, but not including, its ancestor `ancestor`.
+	fn path_down_to(&self, mut block_hash: Hash, ancestor: Hash) -> Vec<Hash> {
+		let mut path = Vec::new();
+		while block_hash != ancestor {
+			path.push(block_hash);
+			block_hash = self.entries[&block_hash].header.parent();
+		}
+		path
+	}
+}
+
+#[cfg(test)]
+use super::p4_batched_extrinsics::Header;
+
+/// Extend the given header with `length` children. The `fork` value goes into every extrinsics root
+/// so different forks from the same parent get different hashes.
+#[cfg(test)]
+fn extend(tree: &mut HeaderTree<Header>, parent: &Header, length: u64, fork: u64) -> Vec<Header> {
+	let mut headers = vec![parent.clone()];
+	for _ in 0..length {
+		let child = headers.last().unwrap().child(fork, 0);
+		assert!(tree.insert(child.clone()));
+		headers.push(child);
+	}
+	headers.remove(0);
+	headers
+}
+
+#[cfg(test)]
+fn hashes(headers: &[Header]) -> Vec<Hash> {
+	headers.iter().map(|h| h.block_hash()).collect()
+}
+
+/// A tree with a genesis, a trunk of 5 blocks, and two forks of 3 and 4 blocks from the trunk's tip.
+#[cfg(test)]
+fn forked_tree() -> (HeaderTree<Header>, Vec<Header>, Vec<Header>, Vec<Header>) {
+	let mut tree = HeaderTree::new();
+	let genesis = Header::genesis();
+	assert!(tree.insert(genesis.clone()));
+	let trunk = extend(&mut tree, &genesis, 5, 0);
+	let fork_a = extend(&mut tree, &trunk[4], 3, 1);
+	let fork_b = extend(&mut tree, &trunk[4], 4, 2);
+	(tree, trunk, fork_a, fork_b)
+}
+
+#[test]
+fn bc_10_ancestor_at_height() {
+	let (tree, trunk, fork_a, _) = forked_tree();
+	let tip = fork_a[2].block_hash();
+
+	assert_eq!(tree.ancestor_at_height(tip, 8), Some(tip));
+	assert_eq!(tree.ancestor_at_height(tip, 6), Some(fork_a[0].block_hash()));
+	assert_eq!(tree.ancestor_at_height(tip, 3), Some(trunk[2].block_hash()));
+	assert_eq!(tree.ancestor_at_height(tip, 0), Some(Header::genesis().block_hash()));
+	assert_eq!(tree.ancestor_at_height(tip, 9), None);
+	assert_eq!(tree.ancestor_at_height(0, 0), None);
+}
+
+#[test]
+fn bc_10_lowest_common_ancestor_of_forks() {
+	let (tree, trunk, fork_a, fork_b) = forked_tree();
+	let fork_point = trunk[4].block_hash();
+
+	assert_eq!(
+		tree.lowest_common_ancestor(fork_a[2].block_hash(), fork_b[3].block_hash()),
+		Some(fork_point)
+	);
+	assert_eq!(
+		tree.lowest_common_ancestor(fork_a[0].block_hash(), fork_b[0].block_hash()),
+		Some(fork_point)
+	);
+	// An ancestor is its own lowest common ancestor with any descendant.
+	assert_eq!(
+		tree.lowest_common_ancestor(trunk[1].block_hash(), fork_b[3].block_hash()),
+		Some(trunk[1].block_hash())
+	);
+	assert_eq!(tree.lowest_common_ancestor(fork_a[1].block_hash(), 0), None);
+}
+
+#[test]
+fn bc_10_tree_route_between_forks() {
+	let (tree, trunk, fork_a, fork_b) = forked_tree();
+	let route = tree.tree_route(fork_a[2].block_hash(), fork_b[3].block_hash()).unwrap();
+
+	let mut retracted = hashes(&fork_a);
+	retracted.reverse();
+	assert_eq!(
+		route,
+		TreeRoute { common_ancestor: trunk[4].block_hash(), retracted, enacted: hashes(&fork_b) }
+	);
+}
+
+#[test]
+fn bc_10_tree_route_along_one_chain() {
+	let (tree, trunk, _, fork_b) = forked_tree();
+
+	// Moving forward only enacts blocks.
+	let forward = tree.tree_route(trunk[2].block_hash(), fork_b[1].block_hash()).unwrap();
+	assert!(forward.retracted.is_empty());
+	assert_eq!(forward.enacted, [&hashes(&trunk)[3..], &hashes(&fork_b)[..2]].concat());
+
+	// Moving backward only retracts them.
+	let backward = tree.tree_route(fork_b[1].block_hash(), trunk[2].block_hash()).unwrap();
+	assert!(backward.enacted.is_empty());
+	assert_eq!(backward.retracted.len(), 4);
+
+	// Staying put does nothing.
+	let tip = fork_b[3].block_hash();
+	assert_eq!(
+		tree.tree_route(tip, tip),
+		Some(TreeRoute { common_ancestor: tip, retracted: vec![], enacted: vec![] })
+	);
+}
+
+#[test]
+fn bc_10_insert_requires_known_parent() {
+	let mut tree = HeaderTree::new();
+	let genesis = Header::genesis();
+	let child = genesis.child(0, 0);
+	let grandchild = child.child(0, 0);
+
+	// The first header becomes the root, whatever it is.
+	assert!(tree.insert(child.clone()));
+	assert!(tree.insert(child.clone()));
+	assert!(!tree.insert(genesis.child(1, 0).child(0, 0)));
+	assert!(tree.insert(grandchild.clone()));
+	assert_eq!(tree.len(), 2);
+
+	// Nothing below the root can be found.
+	assert_eq!(tree.ancestor_at_height(grandchild.block_hash(), 0), None);
+	assert_eq!(tree.ancestor_at_height(grandchild.block_hash(), 1), Some(child.block_hash()));
+}
+
+#[test]
+fn bc_10_long_chain_skip_lists_are_logarithmic() {
+	let mut tree = HeaderTree::new();
+	let genesis = Header::genesis();
+	tree.insert(genesis.clone());
+	let chain = extend(&mut tree, &genesis, 10_000, 0);
+	let side = extend(&mut tree, &chain[4_999], 10, 1);
+	let tip = chain.last().unwrap().block_hash();
+
+	// 10,000 blocks need 14 levels of skip list, since 2^13 < 10,000 < 2^14.
+	assert_eq!(tree.entries[&tip].ancestors.len(), 14);
+	assert_eq!(tree.ancestor_at_height(tip, 1), Some(chain[0].block_hash()));
+
+	let route = tree.tree_route(tip, side[9].block_hash()).unwrap();
+	assert_eq!(route.common_ancestor, chain[4_999].block_hash());
+	assert_eq!(route.retracted.len(), 5_000);
+	assert_eq!(route.enacted.len(), 10);
+}
+
+#[test]
+fn bc_10_root_children_and_tips() {
+	let (tree, trunk, fork_a, fork_b) = forked_tree();
+	let genesis = Header::genesis().block_hash();
+
+	assert_eq!(tree.root(), Some(genesis));
+	assert_eq!(tree.children(genesis), &[trunk[0].block_hash()]);
+	assert_eq!(
+		tree.children(trunk[4].block_hash()),
+		&[fork_a[0].block_hash(), fork_b[0].block_hash()]
+	);
+	assert!(tree.children(fork_a[2].block_hash()).is_empty());
+
+	let mut tips = tree.tips();
+	tips.sort_unstable();
+	let mut expected = vec![fork_a[2].block_hash(), fork_b[3].block_hash()];
+	expected.sort_unstable();
+	assert_eq!(tips, expected);
+}
@@ -0,0 +1,391 @@
+//! When two miners find a block at nearly the same time, only one of them ends up in the best
+//! chain. The other becomes stale, and all the work that went into it is wasted. In part 5,
+//! `create_fork_one_side_longer_other_side_heavier` threw away a whole side of the fork this way.
+//!
+//! Wasted work is bad for two reasons. It makes the chain less secure, because the honest work
+//! is split across forks while an attacker's work is not. And it is unfair to small miners, who
+//! are more likely to produce stale blocks because they hear about new blocks later.
+//!
+//! Ethereum's proof of work chain addressed this by letting a block reference a few recent stale
+//! headers, called uncles (or ommers). An uncle is a sibling of one of the block's recent ancestors.
+//! Its author is paid a reduced reward, the author of the block that includes it gets a small
+//! bonus, and fork choice can count the uncle's work towards the chain that included it.
+//!
+//! The uncle rules in this lesson are:
+//! 1. A block may include at most `MAX_UNCLES` uncles.
+//! 2. Each uncle must have a valid proof of work seal of its own.
+//! 3. Each uncle's parent must be an ancestor of the block between two and `MAX_UNCLE_DEPTH + 1`
+//!    generations back. So the uncle is at most `MAX_UNCLE_DEPTH` blocks older than the block.
+//! 4. An uncle must not itself be an ancestor, and must not already have been included as an
+//!    uncle by an earlier block or earlier in the same block.
+
+use super::{block_work, VerificationError};
+use crate::{
+	c1_state_machine::{
+		p4_accounted_currency::{AccountedCurrency, AccountingTransaction},
+		StateMachine, User,
+	},
+	hash,
+	merkle::merkle_root,
+	miner::Miner,
+};
+use std::collections::VecDeque;
+
+type Hash = u64;
+
+/// The proof of work threshold, for both blocks and uncles.
+const THRESHOLD: u64 = u64::MAX / 100;
+
+/// The most uncles a single block may include.
+pub const MAX_UNCLES: usize = 2;
+
+/// The greatest difference in height between a block and one of its uncles.
+pub const MAX_UNCLE_DEPTH: u64 = 6;
+
+/// The reward for authoring a block in the best chain.
+pub const BLOCK_REWARD: u64 = 800;
+
+/// The balances of every user, as tracked by the accounted currency from chapter 1.
+pub type Balances = <AccountedCurrency as StateMachine>::State;
+
+/// A header now records who authored it, so that its author can be rewarded even if it ends up
+/// as an uncle, and commits to the uncles included in the block.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Header {
+	parent: Hash,
+	height: u64,
+	author: User,
+	uncles_root: Hash,
+	consensus_digest: u64,
+}
+
+impl Header {
+	/// Returns a new valid genesis header.
+	pub fn genesis() -> Self {
+		Header {
+			parent: Hash::default(),
+			height: 0,
+			author: User::Alice,
+			uncles_root: Hash::default(),
+			consensus_digest: 0,
+		}
+	}
+
+	/// Create and mine a valid child header.
+	pub fn child(&self, author: User, uncles_root: Hash) -> Self {
+		let mut header = Header {
+			parent: hash(self),
+			height: self.height + 1,
+			author,
+			uncles_root,
+			consensus_digest: 0,
+		};
+		let outcome = Miner::default()
+			.mine(|nonce| {
+				let mut candidate = header.clone();
+				candidate.consensus_digest = nonce;
+				hash(&candidate) < THRESHOLD
+			})
+			.expect("some nonce is below the threshold");
+		header.consensus_digest = outcome.nonce;
+		header
+	}
+
+	/// Verify a single child header, including its proof of work.
+	fn verify_child(&self, child: &Header) -> Result<(), VerificationError> {
+		let (height, block_hash) = (child.height, hash(child));
+		if height.saturating_sub(self.height) != 1 {
+			return Err(VerificationError::HeightGap { height, hash: block_hash });
+		}
+		if child.parent != hash(self) {
+			return Err(VerificationError::BadParentHash { height, hash: block_hash });
+		}
+		if block_hash >= THRESHOLD {
+			return Err(VerificationError::SealInvalid { height, hash: block_hash });
+		}
+		Ok(())
+	}
+}
+
+/// A block is a header and the uncles it includes. There are no extrinsics in this lesson, so
+/// the only thing that changes the state is the block rewards.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Block {
+	pub(crate) header: Header,
+	pub(crate) uncles: Vec<Header>,
+}
+
+impl Block {
+	/// Returns a new valid genesis block.
+	pub fn genesis() -> Self {
+		Block { header: Header::genesis(), uncles: vec![] }
+	}
+
+	/// Create and mine a child block that includes the given uncles. The uncles are not checked,
+	/// so the resulting block may be invalid.
+	pub fn child(&self, author: User, uncles: Vec<Header>) -> Self {
+		Block { header: self.header.child(author, merkle_root(&uncles)), uncles }
+	}
+
+	/// Verify that the given blocks form a valid chain extending the given ancestors.
+	///
+	/// The ancestors are ordered from oldest to newest and are assumed to be valid already. The
+	/// last one is the parent of the first block in the chain. Only the last
+	/// `MAX_UNCLE_DEPTH + 1` ancestors are needed, because nothing older can be an uncle's parent.
+	pub fn verify_sub_chain(ancestors: &[Block], chain: &[Block]) -> Result<(), VerificationError> {
+		let start = ancestors.len().saturating_sub(MAX_UNCLE_DEPTH as usize + 1);
+		let mut window: VecDeque<&Block> = ancestors[start..].iter().collect();
+		for block in chain {
+			let (height, block_hash) = (block.header.height, hash(&block.header));
+			let Some(parent) = window.back() else {
+				return Err(VerificationError::BadParentHash { height, hash: block_hash });
+			};
+			parent.header.verify_child(&block.header)?;
+			if merkle_root(&block.uncles) != block.header.uncles_root {
+				return Err(VerificationError::UnclesRootMismatch { height, hash: block_hash });
+			}
+			if !block.uncles_are_valid(&window) {
+				return Err(VerificationError::InvalidUncle { height, hash: block_hash });
+			}
+			window.push_back(block);
+			if window.len() > MAX_UNCLE_DEPTH as usize + 1 {
+				window.pop_front();
+			}
+		}
+		Ok(())
+	}
+
+	/// Check this block's uncles against its recent ancestors, ordered from oldest to newest.
+	fn uncles_are_valid(&self, ancestors: &VecDeque<&Block>) -> bool {
+		if self.uncles.len() > MAX_UNCLES {
+			return false;
+		}
+		let min_uncle_height = self.header.height.saturating_sub(MAX_UNCLE_DEPTH);
+		self.uncles.iter().enumerate().all(|(i, uncle)| {
+			let uncle_hash = hash(uncle);
+			let has_valid_parent = ancestors.iter().any(|ancestor| {
+				hash(&ancestor.header) == uncle.parent
+					&& ancestor.header.height + 1 == uncle.height
+					&& uncle.height >= min_uncle_height
+			});
+			let is_ancestor = ancestors.iter().any(|ancestor| hash(&ancestor.header) == uncle_hash);
+			let already_included = ancestors.iter().any(|ancestor| ancestor.uncles.contains(uncle))
+				|| self.uncles[..i].contains(uncle);
+			uncle_hash < THRESHOLD
+				&& uncle.parent != self.header.parent
+				&& has_valid_parent
+				&& !is_ancestor
+				&& !already_included
+		})
+	}
+}
+
+/// The rewards paid out for a block, as minting transactions in the accounted currency.
+///
+/// The block's author gets the full block reward plus a bonus of 1/32 of it for each uncle, so
+/// that authors have a reason to include uncles at all. Each uncle's author gets a share of the
+/// block reward that shrinks the older the uncle is. A sibling of the block's parent is one block
+/// older and earns 7/8, down to 2/8 for an uncle `MAX_UNCLE_DEPTH` blocks older.
+///
+/// The block doesn't need to be valid. An uncle whose height makes no sense earns nothing.
+pub fn block_rewards(block: &Block) -> Vec<AccountingTransaction> {
+	let nephew_bonus = (BLOCK_REWARD / 32).saturating_mul(block.uncles.len() as u64);
+	let mut rewards = vec![AccountingTransaction::Mint {
+		minter: block.header.author,
+		amount: BLOCK_REWARD + nephew_bonus,
+	}];
+	for uncle in &block.uncles {
+		let depth = block.header.height.checked_sub(uncle.height).filter(|depth| *depth > 0);
+		let amount = depth.map_or(0, |depth| BLOCK_REWARD * 8u64.saturating_sub(depth) / 8);
+		rewards.push(AccountingTransaction::Mint { minter: uncle.author, amount });
+	}
+	rewards
+}
+
+/// Apply the rewards for a block to the balances before it. Blocks are assumed to be valid.
+pub fn apply_rewards(balances: &Balances, block: &Block) -> Balances {
+	block_rewards(block)
+		.iter()
+		.fold(balances.clone(), |balances, reward| AccountedCurrency::next_state(&balances, reward))
+}
+
+/// The total work in a chain, counting the work in each block's uncles as well as the block
+/// itself. A fork choice rule that uses this still rewards the side of a fork that wasted the
+/// least work, but stale blocks that were later included as uncles are no longer wasted.
+pub fn chain_work_with_uncles(chain: &[Block]) -> u128 {
+	chain
+		.iter()
+		.flat_map(|block| std::iter::once(&block.header).chain(&block.uncles))
+		.map(|header| block_work(hash(header)))
+		.sum()
+}
+
+/// A chain of `length` blocks after genesis, all authored by Alice with no uncles. Returns the
+/// blocks including genesis.
+#[cfg(test)]
+fn alice_chain(length: usize) -> Vec<Block> {
+	let mut chain = vec![Block::genesis()];
+	for _ in 0..length {
+		chain.push(chain.last().unwrap().child(User::Alice, vec![]));
+	}
+	chain
+}
+
+#[test]
+fn bc_11_stale_sibling_can_be_included() {
+	let mut chain = alice_chain(2);
+	// Bob mined a competitor to block 1 that lost the race.
+	let stale = chain[0].header.child(User::Bob, 0);
+	let b3 = chain[2].child(User::Alice, vec![stale.clone()]);
+	chain.push(b3);
+
+	assert!(Block::verify_sub_chain(&chain[..1], &chain[1..]).is_ok());
+}
+
+#[test]
+fn bc_11_uncle_authors_are_rewarded() {
+	let chain = alice_chain(2);
+	let stale = chain[0].header.child(User::Bob, 0);
+	let b3 = chain[2].child(User::Charlie, vec![stale]);
+
+	let balances = apply_rewards(&Balances::new(), &b3);
+	// The uncle is two blocks older than the block that included it, so Bob gets 6/8.
+	assert_eq!(balances.get(&User::Bob), Some(&600));
+	assert_eq!(balances.get(&User::Charlie), Some(&(BLOCK_REWARD + BLOCK_REWARD / 32)));
+	assert_eq!(balances.get(&User::Alice), None);
+
+	// A sibling of the parent is only one block older, so its author gets 7/8.
+	let parents_sibling = chain[0].header.child(User::Bob, 0);
+	let b2 = chain[1].child(User::Charlie, vec![parents_sibling]);
+	assert!(Block::verify_sub_chain(&chain[..2], std::slice::from_ref(&b2)).is_ok());
+	assert_eq!(apply_rewards(&Balances::new(), &b2).get(&User::Bob), Some(&700));
+}
+
+#[test]
+fn bc_11_rewards_for_invalid_uncles_do_not_panic() {
+	let chain = alice_chain(1);
+	let uncle = chain[0].header.child(User::Bob, 0);
+	// Neither of these could be included in a valid block at height 10.
+	let newer = Header { height: 11, ..uncle.clone() };
+	let ancient = Header { height: 1, ..uncle };
+	let mut block = chain[1].child(User::Charlie, vec![newer, ancient]);
+	block.header.height = 10;
+
+	let balances = apply_rewards(&Balances::new(), &block);
+	assert_eq!(balances.get(&User::Bob), None);
+	assert_eq!(balances.get(&User::Charlie), Some(&(BLOCK_REWARD + BLOCK_REWARD / 16)));
+}
+
+#[test]
+fn bc_11_uncle_must_not_be_own_sibling() {
+	// A sibling of the parent is fine, but a sibling of the block itself is just a competitor.
+	let chain = alice_chain(1);
+	let competitor = chain[0].header.child(User::Bob, 0);
+	let b2 = chain[1].child(User::Alice, vec![competitor.clone()]);
+	assert!(Block::verify_sub_chain(&chain, &[b2]).is_ok());
+
+	let own_sibling = chain[1].header.child(User::Bob, 0);
+	let b2 = chain[1].child(User::Alice, vec![own_sibling]);
+	assert!(matches!(
+		Block::verify_sub_chain(&chain, &[b2]),
+		Err(VerificationError::InvalidUncle { height: 2, .. })
+	));
+}
+
+#[test]
+fn bc_11_uncle_must_not_be_ancestor() {
+	let chain = alice_chain(2);
+	let b3 = chain[2].child(User::Alice, vec![chain[1].header.clone()]);
+
+	assert!(matches!(
+		Block::verify_sub_chain(&chain, &[b3]),
+		Err(VerificationError::InvalidUncle { height: 3, .. })
+	));
+}
+
+#[test]
+fn bc_11_uncle_can_only_be_included_once() {
+	let mut chain = alice_chain(2);
+	let stale = chain[0].header.child(User::Bob, 0);
+
+	let twice = chain[2].child(User::Alice, vec![stale.clone(), stale.clone()]);
+	assert!(Block::verify_sub_chain(&chain, &[twice]).is_err());
+
+	let b3 = chain[2].child(User::Alice, vec![stale.clone()]);
+	chain.push(b3);
+	let b4 = chain[3].child(User::Alice, vec![stale]);
+	assert!(matches!(
+		Block::verify_sub_chain(&chain, &[b4]),
+		Err(VerificationError::InvalidUncle { height: 4, .. })
+	));
+}
+
+#[test]
+fn bc_11_uncle_must_be_recent() {
+	let chain = alice_chain(MAX_UNCLE_DEPTH as usize + 1);
+	let stale = chain[0].header.child(User::Bob, 0);
+
+	// At height 7 the uncle, at height 1, is exactly `MAX_UNCLE_DEPTH` old.
+	let recent = chain[MAX_UNCLE_DEPTH as usize].child(User::Alice, vec![stale.clone()]);
+	assert!(Block::verify_sub_chain(&chain[..7], &[recent]).is_ok());
+
+	let too_old = chain[MAX_UNCLE_DEPTH as usize + 1].child(User::Alice, vec![stale]);
+	assert!(matches!(
+		Block::verify_sub_chain(&chain, &[too_old]),
+		Err(VerificationError::InvalidUncle { height: 8, .. })
+	));
+}
+
+#[test]
+fn bc_11_too_many_uncles() {
+	let chain = alice_chain(2);
+	let uncles = vec![
+		chain[0].header.child(User::Bob, 0),
+		chain[0].header.child(User::Charlie, 0),
+		chain[1].header.child(User::Bob, 1),
+	];
+	assert_eq!(uncles.len(), MAX_UNCLES + 1);
+	let b3 = chain[2].child(User::Alice, uncles.clone());
+	assert!(Block::verify_sub_chain(&chain, &[b3]).is_err());
+
+	let b3 = chain[2].child(User::Alice, uncles[..MAX_UNCLES].to_vec());
+	assert!(Block::verify_sub_chain(&chain, &[b3]).is_ok());
+}
+
+#[test]
+fn bc_11_uncle_needs_valid_seal() {
+	let chain = alice_chain(2);
+	let mut stale = chain[0].header.child(User::Bob, 0);
+	while hash(&stale) < THRESHOLD {
+		stale.consensus_digest += 1;
+	}
+	let b3 = chain[2].child(User::Alice, vec![stale]);
+
+	assert!(matches!(
+		Block::verify_sub_chain(&chain, &[b3]),
+		Err(VerificationError::InvalidUncle { height: 3, .. })
+	));
+}
+
+#[test]
+fn bc_11_uncles_root_must_match() {
+	let chain = alice_chain(2);
+	let mut b3 = chain[2].child(User::Alice, vec![]);
+	b3.uncles.push(chain[0].header.child(User::Bob, 0));
+
+	assert!(matches!(
+		Block::verify_sub_chain(&chain, &[b3]),
+		Err(VerificationError::UnclesRootMismatch { height: 3, .. })
+	));
+}
+
+#[test]
+fn bc_11_fork_choice_counts_uncle_work() {
+	let mut chain = alice_chain(2);
+	let stale = chain[0].header.child(User::Bob, 0);
+	let b3 = chain[2].child(User::Alice, vec![stale.clone()]);
+	chain.push(b3);
+
+	let headers_only: u128 = chain.iter().map(|block| block_work(hash(&block.header))).sum();
+	assert_eq!(chain_work_with_uncles(&chain), headers_only + block_work(hash(&stale)));
+}
@@ -0,0 +1,531 @@
+//! Most of the tests in this chapter build their chains by hand with a few `child()` calls, like
+//! `build_forked_chain` in part 2 and `build_contentious_forked_chain` in part 3. That's great for
+//! explaining one idea at a time, but hand-built chains only ever exercise the cases we thought of.
+//!
+//! This module generates random block trees of the rich-state blocks from part 6 instead. The
+//! generator is seeded, so any failure can be reproduced exactly from its seed. It can also break
+//! chosen blocks on purpose, and it records whether each block should pass verification and, if
+//! not, which error should be reported. That makes it possible to check a verification function
+//! or fork choice rule against many shapes of tree at once.
+//!
+//! The rich-state headers carry no seal, so the generator can also grow trees of the proof of
+//! work headers from part 3. Those have no body to tamper with, but their seal can be broken
+//! instead. Between the two, every rule that either verification function checks can be broken.
+
+use super::{
+	p10_tree_route::HeaderTree,
+	p3_consensus::{Header as SealedHeader, THRESHOLD},
+	p6_rich_state::{Accumulator, Block, Header},
+	VerificationError,
+};
+use crate::{hash, merkle::merkle_root};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::VecDeque, ops::RangeInclusive};
+
+type Hash = u64;
+
+/// A way to break a block so that it fails verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Corruption {
+	/// The header points at a parent that doesn't exist.
+	UnknownParent,
+	/// The header skips a height.
+	SkippedHeight,
+	/// The header commits to the wrong post state.
+	WrongStateRoot,
+	/// The body doesn't match the extrinsics root in the header. Only rich-state blocks have a
+	/// body.
+	TamperedBody,
+	/// The header's proof of work is not below the threshold. Only sealed headers have a seal.
+	BadSeal,
+}
+
+impl Corruption {
+	/// The error that verifying a block with this corruption should report, given the height and
+	/// hash of the broken header.
+	fn expected_error(&self, height: u64, hash: Hash) -> VerificationError {
+		match self {
+			Corruption::UnknownParent => VerificationError::BadParentHash { height, hash },
+			Corruption::SkippedHeight => VerificationError::HeightGap { height, hash },
+			Corruption::WrongStateRoot => VerificationError::StateRootMismatch { height, hash },
+			Corruption::TamperedBody => VerificationError::ExtrinsicsRootMismatch { height, hash },
+			Corruption::BadSeal => VerificationError::SealInvalid { height, hash },
+		}
+	}
+
+	/// The result that verifying a new block should give, given its parent's expected result. A
+	/// block is only valid if it and all of its ancestors are.
+	fn expected_child(
+		corruption: Option<Corruption>,
+		parent: &Result<(), VerificationError>,
+		height: u64,
+		hash: Hash,
+	) -> Result<(), VerificationError> {
+		match (parent, corruption) {
+			(Err(e), _) => Err(*e),
+			(Ok(()), Some(corruption)) => Err(corruption.expected_error(height, hash)),
+			(Ok(()), None) => Ok(()),
+		}
+	}
+}
+
+/// The shape of the trees to generate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratorConfig {
+	/// The seed for the random number generator.
+	pub seed: u64,
+	/// How many blocks deep the tree grows below genesis.
+	pub depth: u64,
+	/// Each block gets between one and this many children, so keep it small. The number of blocks
+	/// grows exponentially with the depth.
+	pub max_children: usize,
+	/// How many extrinsics each block contains. Sealed headers always carry exactly one.
+	pub extrinsics_per_block: RangeInclusive<usize>,
+	/// The range each extrinsic is drawn from.
+	pub extrinsic_values: RangeInclusive<u64>,
+	/// Blocks to corrupt, by their index in the generated tree.
+	pub corruptions: Vec<(usize, Corruption)>,
+}
+
+impl Default for GeneratorConfig {
+	fn default() -> Self {
+		GeneratorConfig {
+			seed: 0,
+			depth: 5,
+			max_children: 2,
+			extrinsics_per_block: 0..=4,
+			extrinsic_values: 0..=100,
+			corruptions: vec![],
+		}
+	}
+}
+
+/// A single block in a generated tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedBlock<S> {
+	pub block: Block,
+	/// The index of this block's parent in the tree, or None for genesis.
+	pub parent: Option<usize>,
+	/// The state before this block, which is what `verify_sub_chain` needs.
+	pub pre_state: S,
+	/// The result that verifying the chain from genesis to this block should give. A block is
+	/// only valid if it and all of its ancestors are.
+	pub expected: Result<(), VerificationError>,
+}
+
+/// A randomly generated tree of blocks. Blocks are stored in breadth first order, so every
+/// block comes after its parent and genesis is at index zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedTree<S> {
+	pub blocks: Vec<GeneratedBlock<S>>,
+}
+
+/// A tree that the generator knows how to grow, with genesis at index zero.
+trait Grow {
+	/// How many blocks the tree holds.
+	fn len(&self) -> usize;
+
+	/// The height of the block at the given index.
+	fn height(&self, index: usize) -> u64;
+
+	/// Add a random child to the block at index `parent`, breaking it in the given way if there is
+	/// one.
+	fn grow_child(
+		&mut self,
+		parent: usize,
+		corruption: Option<Corruption>,
+		config: &GeneratorConfig,
+		rng: &mut StdRng,
+	);
+}
+
+/// Grow the given tree, which holds only genesis, breadth first into the shape from the config.
+///
+/// Panics if genesis is chosen for corruption, because nothing checks genesis against a parent.
+fn grow<T: Grow>(mut tree: T, config: &GeneratorConfig) -> T {
+	assert!(config.corruptions.iter().all(|(i, _)| *i != 0), "genesis cannot be corrupted");
+	let mut rng = StdRng::seed_from_u64(config.seed);
+	// Each entry is a block still to be given children.
+	let mut queue = VecDeque::from([0]);
+	while let Some(parent) = queue.pop_front() {
+		if tree.height(parent) >= config.depth {
+			continue;
+		}
+		for _ in 0..rng.gen_range(1..=config.max_children.max(1)) {
+			let index = tree.len();
+			let corruption = config.corruptions.iter().find(|(i, _)| *i == index);
+			tree.grow_child(parent, corruption.map(|(_, c)| *c), config, &mut rng);
+			queue.push_back(index);
+		}
+	}
+	tree
+}
+
+impl<S: Accumulator> Grow for GeneratedTree<S> {
+	fn len(&self) -> usize {
+		self.blocks.len()
+	}
+
+	fn height(&self, index: usize) -> u64 {
+		self.blocks[index].block.header.height
+	}
+
+	fn grow_child(
+		&mut self,
+		parent: usize,
+		corruption: Option<Corruption>,
+		config: &GeneratorConfig,
+		rng: &mut StdRng,
+	) {
+		let count = rng.gen_range(config.extrinsics_per_block.clone());
+		let extrinsics: Vec<u64> =
+			(0..count).map(|_| rng.gen_range(config.extrinsic_values.clone())).collect();
+		self.push_child(parent, extrinsics, corruption, rng);
+	}
+}
+
+impl<S: Accumulator> GeneratedTree<S> {
+	/// Generate a tree of blocks from the given config, starting at the accumulator's initial
+	/// state.
+	///
+	/// Panics if genesis is chosen for corruption, or if any block is chosen for a bad seal.
+	pub fn generate(config: &GeneratorConfig) -> Self {
+		grow(Self::with_genesis(), config)
+	}
+
+	/// A tree containing only a genesis block at the accumulator's initial state.
+	pub fn with_genesis() -> Self {
+		let genesis_state = S::initial();
+		let genesis = GeneratedBlock {
+			block: Block::genesis(&genesis_state),
+			parent: None,
+			pre_state: genesis_state,
+			expected: Ok(()),
+		};
+		GeneratedTree { blocks: vec![genesis] }
+	}
+
+	/// Add a child with the given extrinsics to the block at index `parent`, breaking it in the
+	/// given way if there is one. Returns the index of the new block.
+	pub fn push_child(
+		&mut self,
+		parent: usize,
+		extrinsics: Vec<u64>,
+		corruption: Option<Corruption>,
+		rng: &mut StdRng,
+	) -> usize {
+		let pre_state = self.post_state(parent);
+		let mut block = self.blocks[parent].block.child(&pre_state, extrinsics);
+		if let Some(corruption) = corruption {
+			corrupt(&mut block, corruption, rng);
+		}
+		let (height, hash) = (block.header.height, hash(&block.header));
+		let expected =
+			Corruption::expected_child(corruption, &self.blocks[parent].expected, height, hash);
+		self.blocks.push(GeneratedBlock { block, parent: Some(parent), pre_state, expected });
+		self.blocks.len() - 1
+	}
+
+	/// The state after executing the body of the block at the given index, whether or not the
+	/// block is valid.
+	pub fn post_state(&self, index: usize) -> S {
+		let generated = &self.blocks[index];
+		let mut state = generated.pre_state.clone();
+		generated.block.body.iter().for_each(|extrinsic| state.apply(*extrinsic));
+		state
+	}
+
+	/// The blocks from genesis to the block at the given index, inclusive.
+	pub fn chain_to(&self, index: usize) -> Vec<Block> {
+		let mut chain = vec![];
+		let mut current = Some(index);
+		while let Some(i) = current {
+			chain.push(self.blocks[i].block.clone());
+			current = self.blocks[i].parent;
+		}
+		chain.reverse();
+		chain
+	}
+
+	/// A header tree, as used for fork choice, containing every block expected to be valid.
+	pub fn valid_header_tree(&self) -> HeaderTree<Header> {
+		let mut tree = HeaderTree::new();
+		for generated in self.blocks.iter().filter(|b| b.expected.is_ok()) {
+			tree.insert(generated.block.header.clone());
+		}
+		tree
+	}
+}
+
+/// Break a freshly built block in the given way.
+fn corrupt(block: &mut Block, corruption: Corruption, rng: &mut StdRng) {
+	let header = &mut block.header;
+	match corruption {
+		Corruption::UnknownParent => header.parent = rng.gen(),
+		Corruption::SkippedHeight => header.height += 1,
+		Corruption::WrongStateRoot => header.state_root = header.state_root.wrapping_add(1),
+		Corruption::TamperedBody => {
+			block.body.push(rng.gen());
+			debug_assert_ne!(merkle_root(&block.body), header.extrinsics_root);
+		},
+		Corruption::BadSeal => panic!("rich-state headers carry no seal to break"),
+	}
+}
+
+/// A single header in a generated tree of sealed headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedHeader {
+	pub header: SealedHeader,
+	/// The index of this header's parent in the tree, or None for genesis.
+	pub parent: Option<usize>,
+	/// The result that verifying the chain from genesis to this header should give.
+	pub expected: Result<(), VerificationError>,
+}
+
+/// A randomly generated tree of the proof of work headers from part 3, stored in breadth first
+/// order like `GeneratedTree`. Every header is mined, so this is slower to generate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SealedTree {
+	pub headers: Vec<GeneratedHeader>,
+}
+
+impl Grow for SealedTree {
+	fn len(&self) -> usize {
+		self.headers.len()
+	}
+
+	fn height(&self, index: usize) -> u64 {
+		self.headers[index].header.height
+	}
+
+	fn grow_child(
+		&mut self,
+		parent: usize,
+		corruption: Option<Corruption>,
+		config: &GeneratorConfig,
+		rng: &mut StdRng,
+	) {
+		let extrinsic = rng.gen_range(config.extrinsic_values.clone());
+		self.push_child(parent, extrinsic, corruption, rng);
+	}
+}
+
+impl SealedTree {
+	/// Generate a tree of sealed headers from the given config.
+	///
+	/// Panics if genesis is chosen for corruption, or if any header is chosen for a tampered
+	/// body.
+	pub fn generate(config: &GeneratorConfig) -> Self {
+		grow(Self::with_genesis(), config)
+	}
+
+	/// A tree containing only a genesis header.
+	pub fn with_genesis() -> Self {
+		let genesis =
+			GeneratedHeader { header: SealedHeader::genesis(), parent: None, expected: Ok(()) };
+		SealedTree { headers: vec![genesis] }
+	}
+
+	/// Mine a child with the given extrinsic onto the header at index `parent`, breaking it in the
+	/// given way if there is one. Returns the index of the new header.
+	pub fn push_child(
+		&mut self,
+		parent: usize,
+		extrinsic: u64,
+		corruption: Option<Corruption>,
+		rng: &mut StdRng,
+	) -> usize {
+		let mut header = self.headers[parent].header.child(extrinsic);
+		if let Some(corruption) = corruption {
+			corrupt_sealed(&mut header, corruption, rng);
+		}
+		let (height, hash) = (header.height, hash(&header));
+		let expected =
+			Corruption::expected_child(corruption, &self.headers[parent].expected, height, hash);
+		self.headers.push(GeneratedHeader { header, parent: Some(parent), expected });
+		self.headers.len() - 1
+	}
+
+	/// The headers from genesis to the header at the given index, inclusive.
+	pub fn chain_to(&self, index: usize) -> Vec<SealedHeader> {
+		let mut chain = vec![];
+		let mut current = Some(index);
+		while let Some(i) = current {
+			chain.push(self.headers[i].header.clone());
+			current = self.headers[i].parent;
+		}
+		chain.reverse();
+		chain
+	}
+}
+
+/// Break a freshly mined header in the given way. Breaking anything other than the seal almost
+/// always breaks the seal too, but the other rules are checked first.
+fn corrupt_sealed(header: &mut SealedHeader, corruption: Corruption, rng: &mut StdRng) {
+	match corruption {
+		Corruption::UnknownParent => header.parent = rng.gen(),
+		Corruption::SkippedHeight => header.height += 1,
+		Corruption::WrongStateRoot => header.state = header.state.wrapping_add(1),
+		Corruption::TamperedBody => panic!("sealed headers have no body to tamper with"),
+		Corruption::BadSeal => {
+			while hash(&*header) < THRESHOLD {
+				header.consensus_digest = rng.gen();
+			}
+		},
+	}
+}
+
+#[cfg(test)]
+use super::{
+	p5_fork_choice::{LongestChainRule, TreeForkChoice},
+	p9_accumulators::Sum,
+};
+
+/// Verify the chain to every block in the tree and check the result matches the expectation.
+#[cfg(test)]
+fn assert_verification_matches<S: Accumulator + std::fmt::Debug>(tree: &GeneratedTree<S>) {
+	let genesis = &tree.blocks[0];
+	for (i, generated) in tree.blocks.iter().enumerate() {
+		let chain = tree.chain_to(i);
+		assert_eq!(
+			chain[0].verify_sub_chain(&genesis.pre_state, &chain[1..]),
+			generated.expected,
+			"block {}",
+			i
+		);
+	}
+}
+
+/// Verify the chain to every header in a sealed tree and check the result matches the
+/// expectation.
+#[cfg(test)]
+fn assert_sealed_verification_matches(tree: &SealedTree) {
+	for (i, generated) in tree.headers.iter().enumerate() {
+		let chain = tree.chain_to(i);
+		assert_eq!(chain[0].verify_sub_chain(&chain[1..]), generated.expected, "header {}", i);
+	}
+}
+
+#[test]
+fn bc_12_same_seed_same_tree() {
+	let config = GeneratorConfig { seed: 42, ..Default::default() };
+	let a = GeneratedTree::<Sum>::generate(&config);
+	let b = GeneratedTree::<Sum>::generate(&config);
+	assert_eq!(a, b);
+
+	let c = GeneratedTree::<Sum>::generate(&GeneratorConfig { seed: 43, ..config });
+	assert_ne!(a, c);
+}
+
+#[test]
+fn bc_12_shape_follows_config() {
+	let config = GeneratorConfig { depth: 4, max_children: 3, ..Default::default() };
+	let tree = GeneratedTree::<Sum>::generate(&config);
+
+	assert!(tree.blocks.iter().all(|b| b.block.header.height <= 4));
+	assert!(tree.blocks.iter().any(|b| b.block.header.height == 4));
+	for (i, _) in tree.blocks.iter().enumerate() {
+		let children = tree.blocks.iter().filter(|b| b.parent == Some(i)).count();
+		assert!(children <= 3);
+	}
+	for generated in &tree.blocks {
+		assert!(generated.block.body.len() <= 4);
+		assert!(generated.block.body.iter().all(|e| *e <= 100));
+	}
+}
+
+#[test]
+fn bc_12_uncorrupted_tree_is_valid() {
+	let tree = GeneratedTree::<Sum>::generate(&GeneratorConfig::default());
+	assert!(tree.blocks.iter().all(|b| b.expected.is_ok()));
+	assert_verification_matches(&tree);
+}
+
+#[test]
+fn bc_12_each_corruption_is_reported() {
+	for corruption in [
+		Corruption::UnknownParent,
+		Corruption::SkippedHeight,
+		Corruption::WrongStateRoot,
+		Corruption::TamperedBody,
+	] {
+		let config = GeneratorConfig { corruptions: vec![(3, corruption)], ..Default::default() };
+		let tree = GeneratedTree::<Sum>::generate(&config);
+
+		let broken = &tree.blocks[3];
+		let (height, hash) = (broken.block.header.height, hash(&broken.block.header));
+		assert_eq!(broken.expected, Err(corruption.expected_error(height, hash)));
+		// Descendants inherit the error, and everything else is still valid.
+		let invalid = tree.blocks.iter().filter(|b| b.expected.is_err()).count();
+		assert!(invalid >= 1 && invalid < tree.blocks.len());
+		assert_verification_matches(&tree);
+	}
+}
+
+#[test]
+fn bc_12_fuzz_verification_and_fork_choice() {
+	let corruptions = [
+		Corruption::UnknownParent,
+		Corruption::SkippedHeight,
+		Corruption::WrongStateRoot,
+		Corruption::TamperedBody,
+	];
+	for seed in 0..20 {
+		let mut rng = StdRng::seed_from_u64(seed);
+		let corruptions = (0..3).map(|_| (rng.gen_range(1..20), corruptions[rng.gen_range(0..4)]));
+		let config = GeneratorConfig {
+			seed,
+			max_children: 3,
+			corruptions: corruptions.collect(),
+			..Default::default()
+		};
+		let tree = GeneratedTree::<Sum>::generate(&config);
+		assert_verification_matches(&tree);
+
+		// The longest valid chain ends at the tallest valid block.
+		let header_tree = tree.valid_header_tree();
+		let best = LongestChainRule::best_tip(&header_tree).unwrap();
+		let tallest =
+			tree.blocks.iter().filter(|b| b.expected.is_ok()).map(|b| b.block.header.height);
+		assert_eq!(header_tree.get(best).unwrap().height, tallest.max().unwrap());
+	}
+}
+
+#[test]
+fn bc_12_uncorrupted_sealed_tree_is_valid() {
+	let tree = SealedTree::generate(&GeneratorConfig::default());
+	assert!(tree.headers.len() > 1);
+	assert!(tree.headers.iter().all(|h| h.expected.is_ok()));
+	assert_sealed_verification_matches(&tree);
+}
+
+#[test]
+fn bc_12_each_sealed_corruption_is_reported() {
+	for corruption in [
+		Corruption::UnknownParent,
+		Corruption::SkippedHeight,
+		Corruption::WrongStateRoot,
+		Corruption::BadSeal,
+	] {
+		let config = GeneratorConfig { corruptions: vec![(3, corruption)], ..Default::default() };
+		let tree = SealedTree::generate(&config);
+
+		let broken = &tree.headers[3];
+		let (height, hash) = (broken.header.height, hash(&broken.header));
+		assert_eq!(broken.expected, Err(corruption.expected_error(height, hash)));
+		let invalid = tree.headers.iter().filter(|h| h.expected.is_err()).count();
+		assert!(invalid >= 1 && invalid < tree.headers.len());
+		assert_sealed_verification_matches(&tree);
+	}
+}
+
+#[test]
+fn bc_12_bad_seal_is_only_seal_broken() {
+	let config =
+		GeneratorConfig { corruptions: vec![(1, Corruption::BadSeal)], ..Default::default() };
+	let tree = SealedTree::generate(&config);
+	let (genesis, broken) = (&tree.headers[0].header, &tree.headers[1].header);
+	assert_eq!(broken.parent, hash(genesis));
+	assert_eq!(broken.height, 1);
+	assert!(hash(broken) >= THRESHOLD);
+}
@@ -0,0 +1,382 @@
+//! In part 3 we wrote the even and odd validity rules of a contentious hard fork, and checked them
+//! against a couple of hand-built chains. That shows which blocks each side accepts, but not what
+//! actually happens to a network when its miners disagree.
+//!
+//! This module simulates that. A number of miners, each following one of the two rule sets, mine
+//! on their own best chain and gossip every block they find to everyone else. Mining is modelled
+//! as a random process in which each miner finds blocks at a rate given by its hashrate, and every
+//! block takes a fixed latency to reach the other miners. Blocks are still real part 3 headers,
+//! and every miner validates what it receives with `verify_sub_chain_even` or
+//! `verify_sub_chain_odd`.
+//!
+//! The simulation is a discrete-event simulation. Rather than running in real time, it keeps a
+//! queue of future events ordered by the time they happen and processes them one at a time. With
+//! a seeded random number generator the whole run is deterministic, so a run can be reproduced
+//! exactly from its config.
+//!
+//! Before the fork height everyone builds one chain. After it, each side rejects the other side's
+//! blocks, so the network splits into two chains that never join again. Even on one side, two
+//! miners can find blocks at about the same time. Only one of them ends up in the chain and the
+//! other is orphaned. The longer blocks take to propagate, the more often that happens.
+
+use super::p3_consensus::{Header, FORK_HEIGHT};
+use crate::hash;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+	cmp::Reverse,
+	collections::{BinaryHeap, HashMap},
+};
+
+type Hash = u64;
+
+/// Which side of the fork a miner is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RuleSet {
+	/// Only blocks after the fork height with an even state are valid.
+	Even,
+	/// Only blocks after the fork height with an odd state are valid.
+	Odd,
+}
+
+impl RuleSet {
+	/// Check that `child` is a valid child of `parent` under these rules.
+	fn accepts(&self, parent: &Header, child: &Header) -> bool {
+		let chain = std::slice::from_ref(child);
+		match self {
+			RuleSet::Even => parent.verify_sub_chain_even(chain).is_ok(),
+			RuleSet::Odd => parent.verify_sub_chain_odd(chain).is_ok(),
+		}
+	}
+
+	/// The remainder that states after the fork height must have when divided by two.
+	fn parity(&self) -> u64 {
+		match self {
+			RuleSet::Even => 0,
+			RuleSet::Odd => 1,
+		}
+	}
+}
+
+/// A single miner in the simulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MinerConfig {
+	/// The rules this miner follows.
+	pub rules: RuleSet,
+	/// How many blocks this miner finds per second on average.
+	pub hashrate: f64,
+}
+
+/// The network to simulate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationConfig {
+	/// The seed for the random number generator.
+	pub seed: u64,
+	/// The miners taking part.
+	pub miners: Vec<MinerConfig>,
+	/// How long, in milliseconds, a block takes to reach every other miner.
+	pub latency_ms: u64,
+	/// How long, in milliseconds, to run the simulation for.
+	pub duration_ms: u64,
+}
+
+/// One side of the network at the end of a simulation. Miners are on the same side when their best
+/// chains agree on the first block after the fork height, or when neither has reached it yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SideReport {
+	/// The indices of the miners on this side, in the order they were configured.
+	pub miners: Vec<usize>,
+	/// The height of the best chain on this side.
+	pub chain_length: u64,
+	/// How many blocks the miners on this side found.
+	pub blocks_mined: usize,
+	/// How many of those blocks are not in this side's best chain.
+	pub orphaned: usize,
+}
+
+impl SideReport {
+	/// The fraction of blocks found on this side that were orphaned.
+	pub fn orphan_rate(&self) -> f64 {
+		if self.blocks_mined == 0 {
+			return 0.0;
+		}
+		self.orphaned as f64 / self.blocks_mined as f64
+	}
+}
+
+/// The outcome of a simulation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulationReport {
+	/// The sides the network ended up split into, ordered by their lowest miner index.
+	pub sides: Vec<SideReport>,
+	/// The height of each miner's best chain, in the order the miners were configured.
+	pub best_heights: Vec<u64>,
+}
+
+#[derive(Clone, Debug)]
+enum Event {
+	/// The given miner finds a block on top of its current best chain.
+	BlockFound { miner: usize },
+	/// A block reaches the given miner.
+	BlockArrives { miner: usize, header: Header },
+}
+
+/// The blocks one miner has accepted and the best chain among them.
+struct Node {
+	rules: RuleSet,
+	known: HashMap<Hash, Header>,
+	best: Header,
+}
+
+impl Node {
+	fn new(rules: RuleSet, genesis: &Header) -> Self {
+		Node {
+			rules,
+			known: HashMap::from([(hash(genesis), genesis.clone())]),
+			best: genesis.clone(),
+		}
+	}
+
+	/// Import a block if this node considers it valid. A block whose parent this node hasn't
+	/// accepted is dropped too. With a fixed latency the parent always arrives first, so such a
+	/// block must build on a chain this node has rejected.
+	///
+	/// The longest chain is followed, and among equally long chains the one seen first is kept.
+	fn import(&mut self, header: Header) {
+		let valid = match self.known.get(&header.parent) {
+			Some(parent) => self.rules.accepts(parent, &header),
+			None => false,
+		};
+		if !valid {
+			return;
+		}
+		if header.height > self.best.height {
+			self.best = header.clone();
+		}
+		self.known.insert(hash(&header), header);
+	}
+
+	/// The hashes of the blocks in this node's best chain.
+	fn best_chain(&self) -> Vec<Hash> {
+		let mut chain = vec![];
+		let mut current = Some(&self.best);
+		while let Some(header) = current {
+			chain.push(hash(header));
+			current = (header.height > 0).then(|| &self.known[&header.parent]);
+		}
+		chain
+	}
+
+	/// The first block after the fork height in this node's best chain, if it has one.
+	fn fork_block(&self) -> Option<Hash> {
+		let mut header = &self.best;
+		if header.height <= FORK_HEIGHT {
+			return None;
+		}
+		while header.height > FORK_HEIGHT + 1 {
+			header = &self.known[&header.parent];
+		}
+		Some(hash(header))
+	}
+}
+
+/// A queue of events ordered by the time they happen. Events at the same time are handled in the
+/// order they were scheduled, which keeps the simulation deterministic.
+struct EventQueue {
+	order: BinaryHeap<Reverse<(u64, usize)>>,
+	events: Vec<Option<Event>>,
+}
+
+impl EventQueue {
+	fn new() -> Self {
+		EventQueue { order: BinaryHeap::new(), events: vec![] }
+	}
+
+	fn schedule(&mut self, time: u64, event: Event) {
+		self.order.push(Reverse((time, self.events.len())));
+		self.events.push(Some(event));
+	}
+
+	fn next(&mut self) -> Option<(u64, Event)> {
+		let Reverse((time, index)) = self.order.pop()?;
+		Some((time, self.events[index].take().expect("each event is handled once")))
+	}
+}
+
+/// How long until a miner with the given hashrate finds its next block. Block discovery is a
+/// Poisson process, so the waiting times are exponentially distributed.
+fn time_to_next_block(hashrate: f64, rng: &mut StdRng) -> u64 {
+	let uniform: f64 = rng.gen();
+	(-(1.0 - uniform).ln() / hashrate * 1000.0).round() as u64
+}
+
+/// Run the simulation described by the config.
+///
+/// Panics if any miner has a hashrate that is not positive.
+pub fn simulate(config: &SimulationConfig) -> SimulationReport {
+	assert!(config.miners.iter().all(|m| m.hashrate > 0.0), "hashrates must be positive");
+	let mut rng = StdRng::seed_from_u64(config.seed);
+	let genesis = Header::genesis();
+	let mut nodes: Vec<Node> = config.miners.iter().map(|m| Node::new(m.rules, &genesis)).collect();
+	// Who mined each block, so orphans can be counted per side at the end.
+	let mut authors: Vec<(Hash, usize)> = vec![];
+
+	let mut queue = EventQueue::new();
+	for (miner, miner_config) in config.miners.iter().enumerate() {
+		let delay = time_to_next_block(miner_config.hashrate, &mut rng);
+		queue.schedule(delay, Event::BlockFound { miner });
+	}
+
+	while let Some((now, event)) = queue.next() {
+		if now > config.duration_ms {
+			break;
+		}
+		match event {
+			Event::BlockFound { miner } => {
+				let node = &mut nodes[miner];
+				// Before the fork any extrinsic will do. After it, a miner only builds blocks its
+				// own rules accept.
+				let mut extrinsic = rng.gen_range(0..10);
+				let parent = &node.best;
+				if parent.height + 1 > FORK_HEIGHT
+					&& (parent.state + extrinsic) % 2 != node.rules.parity()
+				{
+					extrinsic += 1;
+				}
+				let header = parent.child(extrinsic);
+				authors.push((hash(&header), miner));
+				node.import(header.clone());
+
+				for other in (0..nodes.len()).filter(|other| *other != miner) {
+					let event = Event::BlockArrives { miner: other, header: header.clone() };
+					queue.schedule(now + config.latency_ms, event);
+				}
+				let delay = time_to_next_block(config.miners[miner].hashrate, &mut rng);
+				queue.schedule(now + delay, Event::BlockFound { miner });
+			},
+			Event::BlockArrives { miner, header } => nodes[miner].import(header),
+		}
+	}
+
+	report(&nodes, &authors)
+}
+
+/// Group the miners into sides and count the blocks each side mined and orphaned.
+fn report(nodes: &[Node], authors: &[(Hash, usize)]) -> SimulationReport {
+	let mut sides: Vec<(Option<Hash>, SideReport)> = vec![];
+	for (miner, node) in nodes.iter().enumerate() {
+		let key = node.fork_block();
+		match sides.iter_mut().find(|(k, _)| *k == key) {
+			Some((_, side)) => side.miners.push(miner),
+			None => {
+				let side = SideReport {
+					miners: vec![miner],
+					chain_length: 0,
+					blocks_mined: 0,
+					orphaned: 0,
+				};
+				sides.push((key, side));
+			},
+		}
+	}
+
+	for (_, side) in sides.iter_mut() {
+		// The best chain on a side is the longest among its miners, or the first of those.
+		let leader = side.miners.iter().map(|m| &nodes[*m]).rev().max_by_key(|n| n.best.height);
+		let leader = leader.expect("every side has a miner");
+		let best_chain = leader.best_chain();
+		side.chain_length = leader.best.height;
+
+		let mined = authors.iter().filter(|(_, author)| side.miners.contains(author));
+		side.blocks_mined = mined.clone().count();
+		side.orphaned = mined.filter(|(block, _)| !best_chain.contains(block)).count();
+	}
+
+	SimulationReport {
+		sides: sides.into_iter().map(|(_, side)| side).collect(),
+		best_heights: nodes.iter().map(|n| n.best.height).collect(),
+	}
+}
+
+#[cfg(test)]
+fn miners(rules: &[(RuleSet, f64)]) -> Vec<MinerConfig> {
+	rules
+		.iter()
+		.map(|(rules, hashrate)| MinerConfig { rules: *rules, hashrate: *hashrate })
+		.collect()
+}
+
+#[cfg(test)]
+fn split_network(seed: u64, latency_ms: u64) -> SimulationConfig {
+	SimulationConfig {
+		seed,
+		// Three even miners with 75% of the hashrate and two odd miners with 25%. Together they
+		// find a block every twelve and a half seconds on average.
+		miners: miners(&[
+			(RuleSet::Even, 0.02),
+			(RuleSet::Even, 0.02),
+			(RuleSet::Even, 0.02),
+			(RuleSet::Odd, 0.01),
+			(RuleSet::Odd, 0.01),
+		]),
+		latency_ms,
+		duration_ms: 1_000_000,
+	}
+}
+
+#[test]
+fn bc_13_same_seed_same_report() {
+	let a = simulate(&split_network(7, 1_000));
+	let b = simulate(&split_network(7, 1_000));
+	assert_eq!(a, b);
+
+	let c = simulate(&split_network(8, 1_000));
+	assert_ne!(a, c);
+}
+
+#[test]
+fn bc_13_network_splits_by_rule_set() {
+	let config = split_network(1, 1_000);
+	let report = simulate(&config);
+
+	assert_eq!(report.sides.len(), 2);
+	assert_eq!(report.sides[0].miners, vec![0, 1, 2]);
+	assert_eq!(report.sides[1].miners, vec![3, 4]);
+	assert!(report.sides.iter().all(|side| side.chain_length > FORK_HEIGHT));
+	// The side with more hashrate builds the longer chain.
+	assert!(report.sides[0].chain_length > report.sides[1].chain_length);
+	for side in &report.sides {
+		assert!(side.miners.iter().all(|m| report.best_heights[*m] <= side.chain_length));
+	}
+}
+
+#[test]
+fn bc_13_agreeing_miners_stay_together() {
+	let config = SimulationConfig {
+		miners: miners(&[(RuleSet::Odd, 0.05), (RuleSet::Odd, 0.03), (RuleSet::Odd, 0.02)]),
+		..split_network(3, 1_000)
+	};
+	let report = simulate(&config);
+
+	assert_eq!(report.sides.len(), 1);
+	let side = &report.sides[0];
+	assert_eq!(side.miners, vec![0, 1, 2]);
+	assert_eq!(side.blocks_mined as u64, side.chain_length + side.orphaned as u64);
+}
+
+#[test]
+fn bc_13_fork_needs_blocks_past_fork_height() {
+	// Nobody finds enough blocks to reach the fork, so there is nothing to disagree about.
+	let config = SimulationConfig { duration_ms: 1, ..split_network(5, 0) };
+	let report = simulate(&config);
+
+	assert_eq!(report.sides.len(), 1);
+	assert_eq!(report.sides[0].miners.len(), 5);
+}
+
+#[test]
+fn bc_13_latency_increases_orphan_rate() {
+	let orphan_rate = |latency_ms| simulate(&split_network(11, latency_ms)).sides[0].orphan_rate();
+	// A block arrives every twelve and a half seconds on average. When blocks take twenty seconds
+	// to propagate, miners keep racing each other without knowing it.
+	assert!(orphan_rate(20_000) > orphan_rate(100));
+}
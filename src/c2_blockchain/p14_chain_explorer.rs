@@ -0,0 +1,286 @@
+//! Everything in this chapter so far has been checked by tests, which tell us whether a chain is
+//! valid but don't make it easy to _look_ at one. This module is the library side of the
+//! `chain_explorer` binary, which prints a tree of rich-state blocks from part 6 so you can see what
+//! a chain actually contains.
+//!
+//! For every block it prints the height, hash, parent, extrinsics and state root, along with the
+//! state that the state root commits to. The state isn't stored anywhere in the block, so the
+//! explorer reconstructs it by executing every block from genesis. Forks are drawn as an ASCII
+//! tree. When a chain becomes invalid, the first bad block is marked with the reason, and
+//! everything built on top of it is marked invalid too. Finally, the fork choice rules from part 5
+//! are run over the valid blocks so you can compare the tips they pick side by side.
+//!
+//! Trees can be generated at random with the generator from part 12, or loaded from a text file.
+//! Each line of the file describes one block, numbered from one because genesis is block zero:
+//!
+//! ```text
+//! # parent: extrinsics [!corruption]
+//! 0: 5 3
+//! 1: 8
+//! 1: 2 2 !wrong-state-root
+//! ```
+//!
+//! Try `cargo run --bin chain_explorer -- generate --seed 3 --corrupt 4:tampered-body`.
+
+use super::{
+	p12_chain_generator::{Corruption, GeneratedTree, GeneratorConfig},
+	p5_fork_choice::{HeaviestChainRule, LongestChainRule, MostBlocksWithEvenHash, TreeForkChoice},
+	p6_rich_state::{Accumulator, State},
+};
+use crate::hash;
+use anyhow::{anyhow, bail, Context, Result};
+use rand::{rngs::StdRng, SeedableRng};
+use std::fmt::{Debug, Write};
+
+type Hash = u64;
+
+const USAGE: &str = "\
+usage: chain_explorer generate [--seed N] [--depth N] [--max-children N] [--corrupt INDEX:KIND]...
+       chain_explorer load PATH
+
+KIND is one of unknown-parent, skipped-height, wrong-state-root or tampered-body.
+";
+
+/// Parse the name of a corruption, as used on the command line and in tree files.
+fn parse_corruption(name: &str) -> Result<Corruption> {
+	match name {
+		"unknown-parent" => Ok(Corruption::UnknownParent),
+		"skipped-height" => Ok(Corruption::SkippedHeight),
+		"wrong-state-root" => Ok(Corruption::WrongStateRoot),
+		"tampered-body" => Ok(Corruption::TamperedBody),
+		_ => bail!("unknown corruption `{}`", name),
+	}
+}
+
+/// Build a tree from the text format described in the module docs. Blank lines and lines starting
+/// with `#` are ignored.
+pub fn parse_tree<S: Accumulator>(text: &str) -> Result<GeneratedTree<S>> {
+	// Only corrupting a block with an unknown parent needs randomness.
+	let mut rng = StdRng::seed_from_u64(0);
+	let mut tree = GeneratedTree::with_genesis();
+	let lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.trim()));
+	for (line_number, line) in lines.filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+	{
+		let parse_line = || -> Result<_> {
+			let (parent, rest) = line.split_once(':').ok_or_else(|| anyhow!("missing `:`"))?;
+			let parent: usize = parent.trim().parse().context("bad parent index")?;
+			if parent >= tree.blocks.len() {
+				bail!("parent {} must come before block {}", parent, tree.blocks.len());
+			}
+			let mut extrinsics = vec![];
+			let mut corruption = None;
+			for word in rest.split_whitespace() {
+				match word.strip_prefix('!') {
+					Some(name) => corruption = Some(parse_corruption(name)?),
+					None => extrinsics.push(word.parse().context("bad extrinsic")?),
+				}
+			}
+			Ok((parent, extrinsics, corruption))
+		};
+		let (parent, extrinsics, corruption) =
+			parse_line().with_context(|| format!("line {}", line_number))?;
+		tree.push_child(parent, extrinsics, corruption, &mut rng);
+	}
+	Ok(tree)
+}
+
+/// The tip picked by each of the fork choice rules from part 5, considering only valid blocks.
+pub fn fork_choices<S: Accumulator>(tree: &GeneratedTree<S>) -> Vec<(&'static str, Hash)> {
+	let header_tree = tree.valid_header_tree();
+	let tip = |best_tip: Option<Hash>| best_tip.expect("genesis is always valid");
+	vec![
+		("LongestChainRule", tip(LongestChainRule::best_tip(&header_tree))),
+		("HeaviestChainRule", tip(HeaviestChainRule::best_tip(&header_tree))),
+		("MostBlocksWithEvenHash", tip(MostBlocksWithEvenHash::best_tip(&header_tree))),
+	]
+}
+
+/// Describe a single block on one line.
+fn describe_block<S: Accumulator + Debug>(tree: &GeneratedTree<S>, index: usize) -> String {
+	let block = &tree.blocks[index].block;
+	format!(
+		"#{} {:016x} parent {:016x} extrinsics {:?} state_root {:016x} state {:?}",
+		block.header.height,
+		hash(&block.header),
+		block.header.parent,
+		block.body,
+		block.header.state_root,
+		tree.post_state(index),
+	)
+}
+
+/// Draw the tree of blocks, one block per line, with the tips chosen by each fork choice rule
+/// marked on the right.
+pub fn render_tree<S: Accumulator + Debug>(tree: &GeneratedTree<S>) -> String {
+	let mut children = vec![vec![]; tree.blocks.len()];
+	for (index, generated) in tree.blocks.iter().enumerate() {
+		if let Some(parent) = generated.parent {
+			children[parent].push(index);
+		}
+	}
+	let choices = fork_choices(tree);
+
+	let mut out = String::new();
+	// Each entry is a block to draw, the prefix for its line and the prefix for its children.
+	let mut stack = vec![(0, String::new(), String::new())];
+	while let Some((index, prefix, child_prefix)) = stack.pop() {
+		let generated = &tree.blocks[index];
+		write!(out, "{}{}", prefix, describe_block(tree, index)).unwrap();
+
+		let parent_is_valid = generated.parent.is_none_or(|p| tree.blocks[p].expected.is_ok());
+		match &generated.expected {
+			Err(e) if parent_is_valid => write!(out, "  <-- INVALID: {}", e).unwrap(),
+			Err(_) => out.push_str("  (invalid)"),
+			Ok(()) => {
+				let block_hash = hash(&generated.block.header);
+				let rules: Vec<_> =
+					choices.iter().filter(|(_, tip)| *tip == block_hash).map(|(r, _)| *r).collect();
+				if !rules.is_empty() {
+					write!(out, "  <-- {}", rules.join(", ")).unwrap();
+				}
+			},
+		}
+		out.push('\n');
+
+		// Push the children in reverse so they are drawn in order.
+		let last = children[index].len().saturating_sub(1);
+		for (i, child) in children[index].iter().enumerate().rev() {
+			let (branch, continuation) =
+				if i == last { ("└── ", "    ") } else { ("├── ", "│   ") };
+			let prefix = format!("{}{}", child_prefix, branch);
+			stack.push((*child, prefix, format!("{}{}", child_prefix, continuation)));
+		}
+	}
+	out
+}
+
+/// Tabulate the tip picked by each fork choice rule.
+pub fn render_fork_choices<S: Accumulator>(tree: &GeneratedTree<S>) -> String {
+	let mut out = format!("{:<24} {:>6}  {}\n", "rule", "height", "tip");
+	for (rule, tip) in fork_choices(tree) {
+		let index = tree.blocks.iter().position(|b| hash(&b.block.header) == tip).unwrap();
+		let height = tree.blocks[index].block.header.height;
+		writeln!(out, "{:<24} {:>6}  {:016x}", rule, height, tip).unwrap();
+	}
+	out
+}
+
+/// Run the explorer with the given command line arguments, not including the program name, and
+/// return what it should print.
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<String> {
+	let mut args = args.into_iter();
+	let tree: GeneratedTree<State> = match args.next().as_deref() {
+		Some("generate") => {
+			let mut config = GeneratorConfig::default();
+			while let Some(flag) = args.next() {
+				let value = args.next().ok_or_else(|| anyhow!("missing value for {}", flag))?;
+				match flag.as_str() {
+					"--seed" => config.seed = value.parse().context("bad seed")?,
+					"--depth" => config.depth = value.parse().context("bad depth")?,
+					"--max-children" => {
+						config.max_children = value.parse().context("bad child count")?
+					},
+					"--corrupt" => {
+						let (index, kind) =
+							value.split_once(':').ok_or_else(|| anyhow!("expected INDEX:KIND"))?;
+						let index: usize = index.parse().context("bad block index")?;
+						if index == 0 {
+							bail!("genesis cannot be corrupted");
+						}
+						config.corruptions.push((index, parse_corruption(kind)?));
+					},
+					_ => bail!("unknown flag {}\n\n{}", flag, USAGE),
+				}
+			}
+			GeneratedTree::generate(&config)
+		},
+		Some("load") => {
+			let path = args.next().ok_or_else(|| anyhow!("missing path\n\n{}", USAGE))?;
+			let text =
+				std::fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+			parse_tree(&text)?
+		},
+		Some("help") | None => return Ok(USAGE.to_string()),
+		Some(command) => bail!("unknown command {}\n\n{}", command, USAGE),
+	};
+	Ok(format!("{}\n{}", render_tree(&tree), render_fork_choices(&tree)))
+}
+
+#[cfg(test)]
+const TREE_FILE: &str = "
+# A fork at block one, where the second branch goes bad.
+0: 5 3
+1: 8
+1: 2 2 !wrong-state-root
+3: 7
+";
+
+#[test]
+fn bc_14_parse_tree() {
+	let tree = parse_tree::<State>(TREE_FILE).unwrap();
+	assert_eq!(tree.blocks.len(), 5);
+	assert_eq!(tree.blocks[3].parent, Some(1));
+	assert_eq!(tree.blocks[2].block.body, vec![8]);
+	assert_eq!(tree.post_state(2), State::new(16, 120));
+	assert!(tree.blocks[2].expected.is_ok());
+	assert!(tree.blocks[3].expected.is_err());
+	assert_eq!(tree.blocks[4].expected, tree.blocks[3].expected);
+}
+
+#[test]
+fn bc_14_parse_errors_name_the_line() {
+	let error = parse_tree::<State>("0: 1\n5: 2").unwrap_err();
+	assert_eq!(format!("{:#}", error), "line 2: parent 5 must come before block 2");
+
+	assert!(parse_tree::<State>("0 1").is_err());
+	assert!(parse_tree::<State>("0: x").is_err());
+	assert!(parse_tree::<State>("0: 1 !bogus").is_err());
+}
+
+#[test]
+fn bc_14_render_tree_marks_forks_and_invalid_blocks() {
+	let tree = parse_tree::<State>(TREE_FILE).unwrap();
+	let lines: Vec<String> = render_tree(&tree).lines().map(String::from).collect();
+
+	assert_eq!(lines.len(), 5);
+	assert!(lines[0].starts_with("#0 "));
+	assert!(lines[1].starts_with("└── #1 "));
+	assert!(lines[2].starts_with("    ├── #2 "));
+	assert!(lines[3].starts_with("    └── #2 "));
+	assert!(lines[4].starts_with("        └── #3 "));
+
+	assert!(lines[1].contains("extrinsics [5, 3] "));
+	assert!(lines[1].contains("state State { sum: 8, product: 15 }"));
+	let error = tree.blocks[3].expected.unwrap_err();
+	assert!(lines[3].ends_with(&format!("<-- INVALID: {}", error)));
+	assert!(lines[4].ends_with("(invalid)"));
+	// Only the valid branch is left for fork choice.
+	assert!(lines[2].contains("LongestChainRule"));
+}
+
+#[test]
+fn bc_14_fork_choices_side_by_side() {
+	let tree = parse_tree::<State>(TREE_FILE).unwrap();
+	let table = render_fork_choices(&tree);
+	let rows: Vec<&str> = table.lines().collect();
+
+	assert_eq!(rows.len(), 4);
+	for (row, (rule, tip)) in rows[1..].iter().zip(fork_choices(&tree)) {
+		assert!(row.starts_with(rule));
+		assert!(row.ends_with(&format!("{:016x}", tip)));
+	}
+	assert!(rows[1].contains("     2  "));
+}
+
+#[test]
+fn bc_14_run_generate() {
+	let args = ["generate", "--seed", "3", "--depth", "3", "--corrupt", "2:tampered-body"];
+	let out = run(args.map(String::from)).unwrap();
+	assert!(out.contains("<-- INVALID: "));
+	assert!(out.contains("MostBlocksWithEvenHash"));
+
+	assert!(run(["generate", "--corrupt", "0:tampered-body"].map(String::from)).is_err());
+	assert!(run(["generate", "--seed"].map(String::from)).is_err());
+	assert!(run(["frobnicate".to_string()]).is_err());
+	assert!(run(Vec::<String>::new()).unwrap().starts_with("usage"));
+}
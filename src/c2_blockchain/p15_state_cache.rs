@@ -0,0 +1,245 @@
+//! In part 6 the state moved out of the blocks, and `Block::verify_sub_chain` started asking the
+//! caller for the state before the first block. Until now the tests have always had that state at
+//! hand because they built the chain themselves. A real node has to keep it somewhere.
+//!
+//! The simplest thing would be to keep the state after every block. But states can be large, and
+//! a long chain has a great many of them, so that would use an unbounded amount of memory. Instead
+//! this module keeps a full snapshot of the state only every few blocks, plus the states of the
+//! most recent blocks, because that is where new blocks get built and most questions get asked.
+//! Any other state is rebuilt on demand by starting from the nearest earlier snapshot and replaying
+//! the extrinsics of the blocks since then.
+//!
+//! Even one snapshot every few blocks grows without bound on a long enough chain, as do the
+//! bodies needed for replaying. So the pruning policy can also cap the number of snapshots. When
+//! the oldest one is discarded, so are the bodies that could only be replayed from it, and states
+//! that old can no longer be rebuilt at all. Real nodes make the same trade-off. An archive node
+//! keeps everything, while a pruned node only answers questions about recent history.
+
+use super::{
+	p6_rich_state::{Accumulator, Block},
+	VerificationError,
+};
+use std::collections::BTreeMap;
+
+/// Which states a `StateCache` keeps in full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PruningPolicy {
+	/// Keep a snapshot of the state after every block whose height is a multiple of this.
+	pub snapshot_interval: u64,
+	/// Keep the states after this many of the most recent blocks. At least the tip's state is
+	/// always kept, because the next block is built on it.
+	pub keep_recent: u64,
+	/// Keep at most this many interval snapshots, discarding the oldest ones along with the
+	/// bodies that were only needed to replay from them. None keeps every snapshot.
+	pub max_snapshots: Option<usize>,
+}
+
+impl Default for PruningPolicy {
+	fn default() -> Self {
+		PruningPolicy { snapshot_interval: 100, keep_recent: 10, max_snapshots: None }
+	}
+}
+
+/// The states of a single chain of rich-state blocks, stored according to a pruning policy.
+pub struct StateCache<S> {
+	policy: PruningPolicy,
+	/// The full states that are kept, by the height of the block they come after.
+	states: BTreeMap<u64, S>,
+	/// The bodies of the blocks after the oldest kept state, by height.
+	bodies: BTreeMap<u64, Vec<u64>>,
+	/// The latest block, which the next block must build on.
+	tip: Block,
+	/// The state before the latest block, needed to verify its children.
+	tip_pre_state: S,
+}
+
+impl<S: Accumulator> StateCache<S> {
+	/// Start a cache for a chain with the given genesis block and state.
+	///
+	/// Panics if the snapshot interval is zero.
+	pub fn new(genesis: Block, genesis_state: S, policy: PruningPolicy) -> Self {
+		assert!(policy.snapshot_interval > 0, "snapshot interval must be positive");
+		let height = genesis.header.height;
+		StateCache {
+			policy,
+			states: BTreeMap::from([(height, genesis_state.clone())]),
+			bodies: BTreeMap::new(),
+			tip: genesis,
+			tip_pre_state: genesis_state,
+		}
+	}
+
+	/// The height of the latest block.
+	pub fn tip_height(&self) -> u64 {
+		self.tip.header.height
+	}
+
+	/// The number of full states currently kept.
+	pub fn stored_states(&self) -> usize {
+		self.states.len()
+	}
+
+	/// The number of block bodies currently kept for replaying.
+	pub fn stored_bodies(&self) -> usize {
+		self.bodies.len()
+	}
+
+	/// The lowest height whose state can still be rebuilt.
+	pub fn oldest_height(&self) -> u64 {
+		*self.states.keys().next().expect("the tip's state is always kept")
+	}
+
+	/// Verify a block that builds on the tip and, if it is valid, make it the new tip.
+	pub fn push(&mut self, block: Block) -> Result<(), VerificationError> {
+		self.tip.verify_sub_chain(&self.tip_pre_state, std::slice::from_ref(&block))?;
+		let height = block.header.height;
+		let pre_state = self.states[&self.tip_height()].clone();
+		let mut post_state = pre_state.clone();
+		block.body.iter().for_each(|extrinsic| post_state.apply(*extrinsic));
+
+		self.states.insert(height, post_state);
+		self.bodies.insert(height, block.body.clone());
+		self.tip = block;
+		self.tip_pre_state = pre_state;
+		self.prune();
+		Ok(())
+	}
+
+	/// The state after the block at the given height, or None if that block is above the tip or
+	/// has been pruned away.
+	pub fn state_at(&self, height: u64) -> Option<S> {
+		if height > self.tip_height() {
+			return None;
+		}
+		let (&from, snapshot) = self.states.range(..=height).next_back()?;
+		let mut state = snapshot.clone();
+		for (_, body) in self.bodies.range(from + 1..height + 1) {
+			body.iter().for_each(|extrinsic| state.apply(*extrinsic));
+		}
+		Some(state)
+	}
+
+	/// The state before the block at the given height, which is what `Block::verify_sub_chain`
+	/// needs to verify a chain starting at that block.
+	pub fn pre_state(&self, height: u64) -> Option<S> {
+		self.state_at(height.checked_sub(1)?)
+	}
+
+	/// Drop every state and body the policy doesn't require.
+	fn prune(&mut self) {
+		let tip = self.tip_height();
+		let recent = tip.saturating_sub(self.policy.keep_recent.max(1)) + 1;
+		let interval = self.policy.snapshot_interval;
+		self.states.retain(|height, _| *height >= recent || height.is_multiple_of(interval));
+
+		if let Some(max) = self.policy.max_snapshots {
+			let snapshots: Vec<u64> =
+				self.states.keys().copied().filter(|height| *height < recent).collect();
+			for height in &snapshots[..snapshots.len().saturating_sub(max)] {
+				self.states.remove(height);
+			}
+			// Bodies at or below the oldest state are never replayed.
+			let oldest = self.oldest_height();
+			self.bodies = self.bodies.split_off(&(oldest + 1));
+		}
+	}
+}
+
+#[cfg(test)]
+use super::p9_accumulators::Sum;
+
+/// Build a chain of the given length after genesis, returning the blocks and the state after each.
+#[cfg(test)]
+fn build_chain(length: u64) -> (Vec<Block>, Vec<Sum>) {
+	let mut states = vec![Sum::initial()];
+	let mut blocks = vec![Block::genesis(&states[0])];
+	for height in 1..=length {
+		let extrinsics = vec![height, height * 2];
+		let parent = blocks.last().unwrap();
+		let block = parent.child(states.last().unwrap(), extrinsics.clone());
+		let mut state = states.last().unwrap().clone();
+		extrinsics.iter().for_each(|extrinsic| state.apply(*extrinsic));
+		blocks.push(block);
+		states.push(state);
+	}
+	(blocks, states)
+}
+
+#[cfg(test)]
+fn fill_cache(blocks: &[Block], policy: PruningPolicy) -> StateCache<Sum> {
+	let mut cache = StateCache::new(blocks[0].clone(), Sum::initial(), policy);
+	for block in &blocks[1..] {
+		cache.push(block.clone()).unwrap();
+	}
+	cache
+}
+
+#[test]
+fn bc_15_every_state_can_be_rebuilt() {
+	let (blocks, states) = build_chain(50);
+	let policy = PruningPolicy { snapshot_interval: 10, keep_recent: 3, max_snapshots: None };
+	let cache = fill_cache(&blocks, policy);
+
+	assert_eq!(cache.tip_height(), 50);
+	for (height, state) in states.iter().enumerate() {
+		assert_eq!(cache.state_at(height as u64).as_ref(), Some(state));
+	}
+	assert_eq!(cache.state_at(51), None);
+}
+
+#[test]
+fn bc_15_only_snapshots_and_recent_states_are_kept() {
+	let (blocks, _) = build_chain(50);
+	let policy = PruningPolicy { snapshot_interval: 10, keep_recent: 3, max_snapshots: None };
+	let cache = fill_cache(&blocks, policy);
+
+	let kept: Vec<u64> = cache.states.keys().copied().collect();
+	assert_eq!(kept, vec![0, 10, 20, 30, 40, 48, 49, 50]);
+	assert_eq!(cache.stored_bodies(), 50);
+}
+
+#[test]
+fn bc_15_capped_snapshots_bound_memory() {
+	let (blocks, states) = build_chain(1_000);
+	let policy = PruningPolicy { snapshot_interval: 10, keep_recent: 5, max_snapshots: Some(3) };
+	let mut cache = StateCache::new(blocks[0].clone(), Sum::initial(), policy);
+	for block in &blocks[1..] {
+		cache.push(block.clone()).unwrap();
+		assert!(cache.stored_states() <= 3 + 5);
+		assert!(cache.stored_bodies() <= 3 * 10 + 5);
+	}
+
+	assert_eq!(cache.oldest_height(), 970);
+	assert_eq!(cache.state_at(969), None);
+	for height in 970..=1_000 {
+		assert_eq!(cache.state_at(height).as_ref(), Some(&states[height as usize]));
+	}
+}
+
+#[test]
+fn bc_15_supplies_pre_state_for_verification() {
+	let (blocks, _) = build_chain(30);
+	let policy = PruningPolicy { snapshot_interval: 8, keep_recent: 2, max_snapshots: None };
+	let cache = fill_cache(&blocks, policy);
+
+	// Verify the chain from the middle, without building it again from genesis.
+	let pre_state = cache.pre_state(13).unwrap();
+	assert!(blocks[13].verify_sub_chain(&pre_state, &blocks[14..]).is_ok());
+	assert_eq!(cache.pre_state(0), None);
+}
+
+#[test]
+fn bc_15_invalid_block_is_not_pushed() {
+	let (blocks, states) = build_chain(5);
+	let mut cache = fill_cache(&blocks[..4], PruningPolicy::default());
+
+	// Built on the right parent, but from the wrong pre-state.
+	let bad = blocks[3].child(&states[2], vec![7]);
+	assert!(matches!(cache.push(bad), Err(VerificationError::StateRootMismatch { .. })));
+	// Not built on the tip at all.
+	assert!(cache.push(blocks[3].clone()).is_err());
+
+	assert_eq!(cache.tip_height(), 3);
+	cache.push(blocks[4].clone()).unwrap();
+	assert_eq!(cache.state_at(4).as_ref(), Some(&states[4]));
+}
@@ -0,0 +1,271 @@
+//! The `verify_sub_chain` functions in parts 4 and 6 check the first header and then call
+//! themselves on the rest of the chain. That reads nicely, but every header adds a stack frame, so
+//! a long enough chain overflows the stack. They also hash every header twice, once as a child and
+//! again as the next parent, and they always start from genesis.
+//!
+//! This module verifies header chains in a loop instead, with three improvements.
+//!
+//! 1. It starts from a trusted checkpoint, a height and hash that the node already knows to be
+//!    part of the right chain. Real clients ship with checkpoints so that new nodes don't have to
+//!    check the whole history before they can do anything useful.
+//! 2. Each link in a header chain can be checked knowing only the parent and the child, so the
+//!    links don't have to be checked in order. The chain is cut into batches that are verified in
+//!    parallel on several threads, and each header is only hashed once within its batch.
+//! 3. It reports its progress as batches complete, because verifying a long chain takes a while.
+//!
+//! When several headers are invalid, the one nearest the checkpoint is reported, just like the
+//! sequential verifiers would.
+
+use super::{p4_batched_extrinsics, p6_rich_state, ChainHeader, VerificationError};
+use std::{
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		mpsc, Mutex,
+	},
+	thread,
+};
+
+type Hash = u64;
+
+/// A header that can be verified by the `CheckpointVerifier`.
+pub trait VerifiableHeader: ChainHeader + Sync {
+	/// Check everything about a child header except its height and parent hash, which the
+	/// verifier checks itself.
+	fn verify_child_contents(&self, _child: &Self) -> Result<(), VerificationError> {
+		Ok(())
+	}
+}
+
+/// The headers from parts 4 and 6 have nothing to check beyond the hash linkage. Their states can
+/// only be checked by executing the block bodies.
+impl VerifiableHeader for p4_batched_extrinsics::Header {}
+
+impl VerifiableHeader for p6_rich_state::Header {}
+
+/// A header that is trusted to be part of the chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+	pub height: u64,
+	pub hash: Hash,
+}
+
+/// How far a verification has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+	/// The number of headers verified so far.
+	pub verified: usize,
+	/// The number of headers to verify, not counting the checkpoint.
+	pub total: usize,
+}
+
+/// Verifies header chains iteratively, in parallel batches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointVerifier {
+	threads: usize,
+	batch_size: usize,
+}
+
+impl Default for CheckpointVerifier {
+	/// A verifier with one thread per available core and batches of ten thousand headers.
+	fn default() -> Self {
+		let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+		CheckpointVerifier::new(threads, 10_000)
+	}
+}
+
+impl CheckpointVerifier {
+	/// Create a verifier that uses the given number of threads and headers per batch. At least
+	/// one of each is always used.
+	pub fn new(threads: usize, batch_size: usize) -> Self {
+		CheckpointVerifier { threads: threads.max(1), batch_size: batch_size.max(1) }
+	}
+
+	/// Verify that the given headers form a valid chain starting at the checkpoint. The first
+	/// header must be the checkpoint itself. It is trusted, so only its hash and height are
+	/// checked. An empty list of headers is trivially valid.
+	///
+	/// The progress callback is called on the calling thread each time a batch is finished.
+	/// Batches can finish in any order, so only the number of verified headers is reported.
+	pub fn verify<H: VerifiableHeader>(
+		&self,
+		checkpoint: Checkpoint,
+		headers: &[H],
+		mut progress: impl FnMut(Progress),
+	) -> Result<(), VerificationError> {
+		let Some(first) = headers.first() else {
+			return Ok(());
+		};
+		let (height, hash) = (first.height(), first.block_hash());
+		if checkpoint != (Checkpoint { height, hash }) {
+			return Err(VerificationError::CheckpointMismatch { height, hash });
+		}
+
+		let total = headers.len() - 1;
+		let batches = total.div_ceil(self.batch_size);
+		let next_batch = AtomicUsize::new(0);
+		let failed = AtomicBool::new(false);
+		// The lowest index of an invalid header found so far, and its error.
+		let first_error: Mutex<Option<(usize, VerificationError)>> = Mutex::new(None);
+		let (sender, receiver) = mpsc::channel();
+
+		thread::scope(|scope| {
+			for _ in 0..self.threads.min(batches) {
+				let (next_batch, failed, first_error) = (&next_batch, &failed, &first_error);
+				let sender = sender.clone();
+				scope.spawn(move || {
+					// Batches are claimed in order, so once a header has failed every batch that
+					// could contain an earlier failure has already been claimed.
+					while !failed.load(Ordering::Relaxed) {
+						let batch = next_batch.fetch_add(1, Ordering::Relaxed);
+						if batch >= batches {
+							break;
+						}
+						// The children in this batch, plus the parent of the first one.
+						let start = batch * self.batch_size;
+						let end = (start + self.batch_size).min(total);
+						if let Err((offset, e)) = verify_batch(&headers[start..=end]) {
+							failed.store(true, Ordering::Relaxed);
+							let index = start + offset;
+							let mut first_error = first_error.lock().unwrap();
+							if first_error.is_none_or(|(i, _)| index < i) {
+								*first_error = Some((index, e));
+							}
+						}
+						sender.send(end - start).unwrap();
+					}
+				});
+			}
+			// Only the workers hold senders now, so this loop ends when they are all done.
+			drop(sender);
+			let mut verified = 0;
+			for count in receiver {
+				verified += count;
+				progress(Progress { verified, total });
+			}
+		});
+
+		match first_error.into_inner().unwrap() {
+			Some((_, e)) => Err(e),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Verify that every header after the first is a valid child of the one before. On failure,
+/// returns the offset of the invalid header along with the error.
+fn verify_batch<H: VerifiableHeader>(headers: &[H]) -> Result<(), (usize, VerificationError)> {
+	let mut parent_hash = headers[0].block_hash();
+	for (offset, pair) in headers.windows(2).enumerate() {
+		let (parent, child) = (&pair[0], &pair[1]);
+		let (height, hash) = (child.height(), child.block_hash());
+		if height.saturating_sub(parent.height()) != 1 {
+			return Err((offset, VerificationError::HeightGap { height, hash }));
+		}
+		if child.parent() != parent_hash {
+			return Err((offset, VerificationError::BadParentHash { height, hash }));
+		}
+		parent.verify_child_contents(child).map_err(|e| (offset, e))?;
+		parent_hash = hash;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+use p4_batched_extrinsics::Header;
+
+/// A chain of part 4 headers with the given number of headers after genesis.
+#[cfg(test)]
+fn build_chain(length: usize) -> Vec<Header> {
+	let mut chain = Vec::with_capacity(length + 1);
+	chain.push(Header::genesis());
+	for i in 0..length {
+		let child = chain[i].child(i as u64, 0);
+		chain.push(child);
+	}
+	chain
+}
+
+#[cfg(test)]
+fn checkpoint_of<H: ChainHeader>(header: &H) -> Checkpoint {
+	Checkpoint { height: header.height(), hash: header.block_hash() }
+}
+
+#[test]
+fn bc_16_million_headers() {
+	let chain = build_chain(1_000_000);
+	let verifier = CheckpointVerifier::new(4, 50_000);
+	let mut reports = vec![];
+	let result = verifier.verify(checkpoint_of(&chain[0]), &chain, |p| reports.push(p));
+
+	assert_eq!(result, Ok(()));
+	assert_eq!(reports.len(), 20);
+	assert!(reports.windows(2).all(|w| w[0].verified < w[1].verified));
+	assert_eq!(reports.last(), Some(&Progress { verified: 1_000_000, total: 1_000_000 }));
+}
+
+#[test]
+fn bc_16_start_from_checkpoint() {
+	let mut chain = build_chain(100);
+	// History before the checkpoint is trusted and not checked at all.
+	chain[10] = Header::genesis();
+	let checkpoint = checkpoint_of(&chain[50]);
+	let verifier = CheckpointVerifier::new(2, 7);
+	assert_eq!(verifier.verify(checkpoint, &chain[50..], |_| ()), Ok(()));
+
+	// But the first header must really be the checkpoint.
+	let error = verifier.verify(checkpoint, &chain[49..], |_| ()).unwrap_err();
+	assert_eq!(
+		error,
+		VerificationError::CheckpointMismatch { height: 49, hash: chain[49].block_hash() }
+	);
+	assert_eq!(verifier.verify(checkpoint, &chain[..0], |_| ()), Ok(()));
+}
+
+#[test]
+fn bc_16_reports_failure_nearest_checkpoint() {
+	let chain = build_chain(1_000);
+	let mut broken = chain.clone();
+	broken[300] = chain[300].child(0, 0);
+	broken[800] = chain[800].child(0, 0);
+
+	for threads in [1, 3, 8] {
+		let verifier = CheckpointVerifier::new(threads, 64);
+		let error = verifier.verify(checkpoint_of(&chain[0]), &broken, |_| ()).unwrap_err();
+		assert_eq!(
+			error,
+			VerificationError::HeightGap { height: 301, hash: broken[300].block_hash() }
+		);
+	}
+}
+
+#[test]
+fn bc_16_same_errors_as_sequential_verifier() {
+	let chain = build_chain(20);
+	let verifier = CheckpointVerifier::new(2, 3);
+	let checkpoint = checkpoint_of(&chain[0]);
+
+	// A valid replacement for block four leaves block five pointing at the wrong parent.
+	let mut bad_parent = chain.clone();
+	bad_parent[4] = chain[3].child(99, 0);
+	let mut skipped = chain.clone();
+	skipped.remove(12);
+
+	for broken in [bad_parent, skipped] {
+		let sequential = broken[0].verify_sub_chain(&broken[1..]);
+		assert!(sequential.is_err());
+		assert_eq!(verifier.verify(checkpoint, &broken, |_| ()), sequential);
+	}
+}
+
+#[test]
+fn bc_16_rich_state_headers() {
+	let state = p6_rich_state::State::new(0, 1);
+	let mut blocks = vec![p6_rich_state::Block::genesis(&state)];
+	for _ in 0..10 {
+		let child = blocks.last().unwrap().child(&state, vec![]);
+		blocks.push(child);
+	}
+	let headers: Vec<p6_rich_state::Header> = blocks.into_iter().map(|b| b.header).collect();
+	let verifier = CheckpointVerifier::default();
+	assert_eq!(verifier.verify(checkpoint_of(&headers[3]), &headers[3..], |_| ()), Ok(()));
+}
@@ -2,6 +2,7 @@
 //! structure. We learned from the lecture that it is actually the headers that are hash linked, so
 //! let's start with that.
 
+use super::VerificationError;
 use crate::hash;
 
 // We will use Rust's built-in hashing where the output type is u64. I'll make an alias
@@ -48,20 +49,19 @@ impl Header {
 	/// An "entire" chain can be verified by calling this method on a genesis header.
 	/// This method may assume that the block on which it is called is valid, but it
 	/// must verify all of the blocks in the slice;
-	fn verify_sub_chain(&self, chain: &[Header]) -> bool {
-		let mut curr_hash = hash(self);
-		let mut is_verified: bool = true;
-		let mut chain_iter = chain.iter();
-		let mut curr_height = self.height;
-		while let Some(header) = chain_iter.next() {
-			if header.height - curr_height != 1 {
-				return false;
+	fn verify_sub_chain(&self, chain: &[Header]) -> Result<(), VerificationError> {
+		let mut prev_header = self;
+		for header in chain {
+			let (height, block_hash) = (header.height, hash(header));
+			if height != prev_header.height + 1 {
+				return Err(VerificationError::HeightGap { height, hash: block_hash });
 			}
-			is_verified &= header.parent == curr_hash;
-			curr_hash = hash(header);
-			curr_height = header.height;
+			if header.parent != hash(prev_header) {
+				return Err(VerificationError::BadParentHash { height, hash: block_hash });
+			}
+			prev_header = header;
 		}
-		is_verified
+		Ok(())
 	}
 }
 
@@ -123,7 +123,7 @@ fn bc_1_child_block_parent() {
 fn bc_1_verify_genesis_only() {
 	let g = Header::genesis();
 
-	assert!(g.verify_sub_chain(&[]));
+	assert!(g.verify_sub_chain(&[]).is_ok());
 }
 
 #[test]
@@ -132,7 +132,7 @@ fn bc_1_verify_three_blocks() {
 	let b1 = g.child();
 	let b2 = b1.child();
 
-	assert!(g.verify_sub_chain(&[b1, b2]));
+	assert!(g.verify_sub_chain(&[b1, b2]).is_ok());
 }
 
 #[test]
@@ -143,7 +143,10 @@ fn bc_1_cant_verify_invalid_height() {
 	let mut b1 = g.child();
 	b1.height = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]))
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::HeightGap { height: 10, .. })
+	))
 }
 
 #[test]
@@ -154,7 +157,10 @@ fn bc_1_cant_verify_invalid_parent() {
 	let mut b1 = g.child();
 	b1.parent = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]))
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::BadParentHash { height: 1, .. })
+	))
 }
 
 #[test]
@@ -162,7 +168,7 @@ fn bc_1_verify_chain_length_five() {
 	// This test chooses to use the student's own verify function.
 	// This should be relatively safe given that we have already tested that function.
 	let chain = build_valid_chain_length_5();
-	assert!(chain[0].verify_sub_chain(&chain[1..]))
+	assert!(chain[0].verify_sub_chain(&chain[1..]).is_ok())
 }
 
 #[test]
@@ -170,5 +176,5 @@ fn bc_1_invalid_chain_is_really_invalid() {
 	// This test chooses to use the student's own verify function.
 	// This should be relatively safe given that we have already tested that function.
 	let invalid_chain = build_an_invalid_chain();
-	assert!(!invalid_chain[0].verify_sub_chain(&invalid_chain[1..]))
+	assert!(invalid_chain[0].verify_sub_chain(&invalid_chain[1..]).is_err())
 }
@@ -7,6 +7,7 @@
 //! In the coming parts of this tutorial, we will expand this to be more real-world like and
 //! use some real batching.
 
+use super::VerificationError;
 use crate::hash;
 
 // We will use Rust's built-in hashing where the output type is u64. I'll make an alias
@@ -60,21 +61,22 @@ impl Header {
 	///
 	/// So in order for a block to verify, we must have the above explained relationship between the
 	/// extrinsic, the previous state, and the current state.
-	fn verify_sub_chain(&self, chain: &[Header]) -> bool {
-		let mut curr_header = self;
-		let mut is_verified: bool = true;
-		let mut chain_iter = chain.iter();
-		let mut curr_height = self.height;
-		while let Some(header) = chain_iter.next() {
-			if header.height - curr_height != 1 {
-				return false;
+	fn verify_sub_chain(&self, chain: &[Header]) -> Result<(), VerificationError> {
+		let mut prev_header = self;
+		for header in chain {
+			let (height, block_hash) = (header.height, hash(header));
+			if height != prev_header.height + 1 {
+				return Err(VerificationError::HeightGap { height, hash: block_hash });
 			}
-			is_verified &= header.parent == hash(curr_header)
-				&& header.state == curr_header.state + header.extrinsic;
-			curr_header = header;
-			curr_height = header.height;
+			if header.parent != hash(prev_header) {
+				return Err(VerificationError::BadParentHash { height, hash: block_hash });
+			}
+			if header.state != prev_header.state + header.extrinsic {
+				return Err(VerificationError::StateRootMismatch { height, hash: block_hash });
+			}
+			prev_header = header;
 		}
-		is_verified
+		Ok(())
 	}
 }
 
@@ -200,7 +202,7 @@ fn bc_2_child_block_state() {
 fn bc_2_verify_genesis_only() {
 	let g = Header::genesis();
 
-	assert!(g.verify_sub_chain(&[]));
+	assert!(g.verify_sub_chain(&[]).is_ok());
 }
 
 #[test]
@@ -210,7 +212,7 @@ fn bc_2_verify_three_blocks() {
 	let b2 = b1.child(6);
 
 	assert_eq!(b2.state, 11);
-	assert!(g.verify_sub_chain(&[b1, b2]));
+	assert!(g.verify_sub_chain(&[b1, b2]).is_ok());
 }
 
 #[test]
@@ -219,7 +221,10 @@ fn bc_2_cant_verify_invalid_parent() {
 	let mut b1 = g.child(5);
 	b1.parent = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]));
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::BadParentHash { height: 1, .. })
+	));
 }
 
 #[test]
@@ -228,7 +233,10 @@ fn bc_2_cant_verify_invalid_number() {
 	let mut b1 = g.child(5);
 	b1.height = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]));
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::HeightGap { height: 10, .. })
+	));
 }
 
 #[test]
@@ -237,7 +245,10 @@ fn bc_2_cant_verify_invalid_state() {
 	let mut b1 = g.child(5);
 	b1.state = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]));
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::StateRootMismatch { height: 1, .. })
+	));
 }
 
 #[test]
@@ -250,8 +261,8 @@ fn bc_2_verify_forked_chain() {
 	assert_eq!(g, c2[0]);
 
 	// Both chains are individually valid
-	assert!(g.verify_sub_chain(&c1[1..]));
-	assert!(g.verify_sub_chain(&c2[1..]));
+	assert!(g.verify_sub_chain(&c1[1..]).is_ok());
+	assert!(g.verify_sub_chain(&c2[1..]).is_ok());
 
 	// The two chains are not identical
 	// Question for students: I've only compared the last blocks here.
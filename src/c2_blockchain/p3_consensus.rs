@@ -5,9 +5,8 @@
 //! 1. Rules to throttle authoring. In this case we will use a simple PoW.
 //! 2. Arbitrary / Political rules. Here we will implement two alternate validity rules
 
-use crate::hash;
-
-use rand::Rng;
+use super::VerificationError;
+use crate::{hash, miner::Miner};
 
 // We will use Rust's built-in hashing where the output type is u64. I'll make an alias
 // so the code is slightly more readable.
@@ -16,11 +15,11 @@ type Hash = u64;
 /// In this lesson we are introducing proof of work onto our blocks. We need a hash threshold.
 /// You may change this as you see fit, and I encourage you to experiment. Probably best to start
 /// high so we aren't wasting time mining. I'll start with 1 in 100 blocks being valid.
-const THRESHOLD: u64 = u64::max_value() / 100;
+pub(super) const THRESHOLD: u64 = u64::max_value() / 100;
 
 /// In this lesson we introduce the concept of a contentious hard fork. The fork will happen at
 /// this block height.
-const FORK_HEIGHT: u64 = 2;
+pub(super) const FORK_HEIGHT: u64 = 2;
 
 /// The header is now expanded to contain a consensus digest.
 /// For Proof of Work, the consensus digest is basically just a nonce which gets the block
@@ -28,23 +27,18 @@ const FORK_HEIGHT: u64 = 2;
 /// the more general `digest` term. For PoA we would have a cryptographic signature in this field.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Header {
-	parent: Hash,
-	height: u64,
+	pub(super) parent: Hash,
+	pub(super) height: u64,
 	extrinsic: u64,
-	state: u64,
-	consensus_digest: u64,
+	pub(super) state: u64,
+	pub(super) consensus_digest: u64,
 }
 
 // Here are the methods for creating new header and verifying headers.
 // It is your job to write them.
 impl Header {
-	fn generate_nonce(&self) -> u64 {
-		let mut rng = rand::thread_rng();
-		return rng.gen::<u32>() as u64;
-	}
-
 	/// Returns a new valid genesis header.
-	fn genesis() -> Self {
+	pub(super) fn genesis() -> Self {
 		return Self {
 			height: 0,
 			parent: Hash::default(),
@@ -55,7 +49,7 @@ impl Header {
 	}
 
 	/// Create and return a valid child header.
-	fn child(&self, extrinsic: u64) -> Self {
+	pub(super) fn child(&self, extrinsic: u64) -> Self {
 		let mut valid_header: Header = Self {
 			height: self.height + 1,
 			parent: hash(self),
@@ -63,40 +57,46 @@ impl Header {
 			extrinsic,
 			consensus_digest: Hash::default(),
 		};
-		loop {
-			let nonce = self.generate_nonce();
-			valid_header.consensus_digest = nonce;
-			if hash(&valid_header) < THRESHOLD {
-				return valid_header;
-			}
-		}
+		let outcome = Miner::default()
+			.mine(|nonce| {
+				let mut candidate = valid_header.clone();
+				candidate.consensus_digest = nonce;
+				hash(&candidate) < THRESHOLD
+			})
+			.expect("some nonce is below the threshold");
+		valid_header.consensus_digest = outcome.nonce;
+		valid_header
 	}
 
-	fn is_header_verified(prev_header: &Header, header: &Header) -> bool {
-		if header.height.saturating_sub(prev_header.height) != 1 {
-			return false;
+	/// Verify a single child header against all the rules we had before, plus the proof of work.
+	fn verify_child(&self, child: &Header) -> Result<(), VerificationError> {
+		let (height, block_hash) = (child.height, hash(child));
+		if height.saturating_sub(self.height) != 1 {
+			return Err(VerificationError::HeightGap { height, hash: block_hash });
 		}
-
-		let valid_hash = header.parent == hash(prev_header);
-		let valid_extrinsic = header.state == prev_header.state + header.extrinsic;
-		let valid_consensus_digest = hash(header) < THRESHOLD;
-		return valid_hash && valid_extrinsic && valid_consensus_digest;
+		if child.parent != hash(self) {
+			return Err(VerificationError::BadParentHash { height, hash: block_hash });
+		}
+		if child.state != self.state + child.extrinsic {
+			return Err(VerificationError::StateRootMismatch { height, hash: block_hash });
+		}
+		if block_hash >= THRESHOLD {
+			return Err(VerificationError::SealInvalid { height, hash: block_hash });
+		}
+		Ok(())
 	}
 
 	/// Verify that all the given headers form a valid chain from this header to the tip.
 	///
 	/// In addition to all the rules we had before, we now need to check that the block hash
 	/// is below a specific threshold.
-	fn verify_sub_chain(&self, chain: &[Header]) -> bool {
+	pub(super) fn verify_sub_chain(&self, chain: &[Header]) -> Result<(), VerificationError> {
 		let mut prev_header = self;
-		let mut chain_iter = chain.iter();
-		while let Some(header) = chain_iter.next() {
-			if !Header::is_header_verified(prev_header, header) {
-				return false;
-			}
+		for header in chain {
+			prev_header.verify_child(header)?;
 			prev_header = header;
 		}
-		return true;
+		Ok(())
 	}
 
 	// After the blockchain ran for a while, a political rift formed in the community.
@@ -105,38 +105,37 @@ impl Header {
 	// On the one side, people believe that only blocks with even states should be valid.
 	// On the other side, people believe in only blocks with odd states.
 
-	/// verify that the given headers form a valid chain.
-	/// In this case "valid" means that the STATE MUST BE EVEN.
-	fn verify_sub_chain_even(&self, chain: &[Header]) -> bool {
+	/// Verify that the given headers form a valid chain in which every block after the fork
+	/// height has a state with the given remainder when divided by two.
+	fn verify_sub_chain_with_parity(
+		&self,
+		chain: &[Header],
+		parity: u64,
+	) -> Result<(), VerificationError> {
 		let mut prev_header = self;
-		let mut chain_iter = chain.iter();
-		while let Some(header) = chain_iter.next() {
-			if header.height > FORK_HEIGHT && header.state % 2 != 0 {
-				return false;
-			}
-			if !Header::is_header_verified(prev_header, header) {
-				return false;
+		for header in chain {
+			prev_header.verify_child(header)?;
+			if header.height > FORK_HEIGHT && header.state % 2 != parity {
+				return Err(VerificationError::ForkRuleViolation {
+					height: header.height,
+					hash: hash(header),
+				});
 			}
 			prev_header = header;
 		}
-		return true;
+		Ok(())
+	}
+
+	/// verify that the given headers form a valid chain.
+	/// In this case "valid" means that the STATE MUST BE EVEN.
+	pub(super) fn verify_sub_chain_even(&self, chain: &[Header]) -> Result<(), VerificationError> {
+		self.verify_sub_chain_with_parity(chain, 0)
 	}
 
 	/// verify that the given headers form a valid chain.
 	/// In this case "valid" means that the STATE MUST BE ODD.
-	fn verify_sub_chain_odd(&self, chain: &[Header]) -> bool {
-		let mut prev_header = self;
-		let mut chain_iter = chain.iter();
-		while let Some(header) = chain_iter.next() {
-			if header.height > FORK_HEIGHT && header.state % 2 != 1 {
-				return false;
-			}
-			if !Header::is_header_verified(prev_header, header) {
-				return false;
-			}
-			prev_header = header;
-		}
-		return true;
+	pub(super) fn verify_sub_chain_odd(&self, chain: &[Header]) -> Result<(), VerificationError> {
+		self.verify_sub_chain_with_parity(chain, 1)
 	}
 }
 
@@ -250,7 +249,7 @@ fn bc_3_child_block_consensus_digest() {
 fn bc_3_verify_genesis_only() {
 	let g = Header::genesis();
 
-	assert!(g.verify_sub_chain(&[]));
+	assert!(g.verify_sub_chain(&[]).is_ok());
 }
 
 #[test]
@@ -260,7 +259,7 @@ fn bc_3_verify_three_blocks() {
 	let b2 = b1.child(6);
 
 	assert_eq!(b2.state, 11);
-	assert!(g.verify_sub_chain(&[b1, b2]));
+	assert!(g.verify_sub_chain(&[b1, b2]).is_ok());
 }
 
 #[test]
@@ -269,7 +268,10 @@ fn bc_3_cant_verify_invalid_parent() {
 	let mut b1 = g.child(5);
 	b1.parent = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]));
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::BadParentHash { height: 1, .. })
+	));
 }
 
 #[test]
@@ -278,7 +280,10 @@ fn bc_3_cant_verify_invalid_number() {
 	let mut b1 = g.child(5);
 	b1.height = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]));
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::HeightGap { height: 10, .. })
+	));
 }
 
 #[test]
@@ -287,7 +292,10 @@ fn bc_3_cant_verify_invalid_state() {
 	let mut b1 = g.child(5);
 	b1.state = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]));
+	assert!(matches!(
+		g.verify_sub_chain(&[b1]),
+		Err(VerificationError::StateRootMismatch { height: 1, .. })
+	));
 }
 
 #[test]
@@ -298,7 +306,7 @@ fn bc_3_cant_verify_invalid_pow() {
 	// the PoW difficulty is relatively low.
 	b1.consensus_digest = 10;
 
-	assert!(!g.verify_sub_chain(&[b1]));
+	assert!(g.verify_sub_chain(&[b1]).is_err());
 }
 
 #[test]
@@ -311,7 +319,7 @@ fn bc_3_even_chain_valid() {
 	let b3 = b2.child(1); // 4
 	let b4 = b3.child(2); // 6
 
-	assert!(g.verify_sub_chain_even(&[b1, b2, b3, b4]));
+	assert!(g.verify_sub_chain_even(&[b1, b2, b3, b4]).is_ok());
 }
 
 #[test]
@@ -322,7 +330,10 @@ fn bc_3_even_chain_invalid_first_block_after_fork() {
 	let b3 = b2.child(2); // 5 - invalid
 	let b4 = b3.child(1); // 6
 
-	assert!(!g.verify_sub_chain_even(&[b1, b2, b3, b4]));
+	assert!(matches!(
+		g.verify_sub_chain_even(&[b1, b2, b3, b4]),
+		Err(VerificationError::ForkRuleViolation { height: 3, .. })
+	));
 }
 
 #[test]
@@ -333,7 +344,7 @@ fn bc_3_even_chain_invalid_second_block_after_fork() {
 	let b3 = b2.child(1); // 4
 	let b4 = b3.child(1); // 5 - invalid
 
-	assert!(!g.verify_sub_chain_even(&[b1, b2, b3, b4]));
+	assert!(g.verify_sub_chain_even(&[b1, b2, b3, b4]).is_err());
 }
 
 #[test]
@@ -346,7 +357,7 @@ fn bc_3_odd_chain_valid() {
 	let b3 = b2.child(2); // 5
 	let b4 = b3.child(2); // 7
 
-	assert!(g.verify_sub_chain_odd(&[b1, b2, b3, b4]));
+	assert!(g.verify_sub_chain_odd(&[b1, b2, b3, b4]).is_ok());
 }
 
 #[test]
@@ -357,7 +368,7 @@ fn bc_3_odd_chain_invalid_first_block_after_fork() {
 	let b3 = b2.child(1); // 4 - invalid
 	let b4 = b3.child(1); // 5
 
-	assert!(!g.verify_sub_chain_odd(&[b1, b2, b3, b4]));
+	assert!(g.verify_sub_chain_odd(&[b1, b2, b3, b4]).is_err());
 }
 
 #[test]
@@ -368,7 +379,10 @@ fn bc_3_odd_chain_invalid_second_block_after_fork() {
 	let b3 = b2.child(2); // 5
 	let b4 = b3.child(1); // 6 - invalid
 
-	assert!(!g.verify_sub_chain_odd(&[b1, b2, b3, b4]));
+	assert!(matches!(
+		g.verify_sub_chain_odd(&[b1, b2, b3, b4]),
+		Err(VerificationError::ForkRuleViolation { height: 4, .. })
+	));
 }
 
 #[test]
@@ -380,14 +394,14 @@ fn bc_3_verify_forked_chain() {
 	let full_odd_chain = [&prefix[1..], &odd].concat();
 
 	// Both chains are individually valid according to the original rules.
-	assert!(g.verify_sub_chain(&full_even_chain[..]));
-	assert!(g.verify_sub_chain(&full_odd_chain[..]));
+	assert!(g.verify_sub_chain(&full_even_chain[..]).is_ok());
+	assert!(g.verify_sub_chain(&full_odd_chain[..]).is_ok());
 
 	// Only the even chain is valid according to the even rules
-	assert!(g.verify_sub_chain_even(&full_even_chain[..]));
-	assert!(!g.verify_sub_chain_even(&full_odd_chain[..]));
+	assert!(g.verify_sub_chain_even(&full_even_chain[..]).is_ok());
+	assert!(g.verify_sub_chain_even(&full_odd_chain[..]).is_err());
 
 	// Only the odd chain is valid according to the odd rules
-	assert!(!g.verify_sub_chain_odd(&full_even_chain[..]));
-	assert!(g.verify_sub_chain_odd(&full_odd_chain[..]));
+	assert!(g.verify_sub_chain_odd(&full_even_chain[..]).is_err());
+	assert!(g.verify_sub_chain_odd(&full_odd_chain[..]).is_ok());
 }
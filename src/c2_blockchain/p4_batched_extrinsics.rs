@@ -1,7 +1,15 @@
 //! Until now, each block has contained just a single extrinsic. Really we would prefer to batch
 //! them. Now, we stop relying solely on headers, and instead, create complete blocks.
 
-use crate::hash;
+use super::{
+	p7_block_store::{encode_u64, encode_u64s, Decoder, StoredBlock},
+	p8_timestamps::{verify_with_timestamps, Clock},
+	ChainHeader, VerificationError,
+};
+use crate::{
+	hash,
+	merkle::{merkle_proof, merkle_root, MerkleProof},
+};
 type Hash = u64;
 
 /// The header no longer contains an extrinsic directly. Rather a vector of extrinsics will be
@@ -11,11 +19,13 @@ type Hash = u64;
 pub struct Header {
 	parent: Hash,
 	height: u64,
+	// When the block was authored, in seconds. See part 8 for how this is validated.
+	timestamp: u64,
 	// We now switch from storing an extrinsic directly, to storing an extrinsic root.
 	// This is basically a concise cryptographic commitment to the complete list of extrinsics.
-	// For example, a hash or a Merkle root.
+	// We use a Merkle root so that a single extrinsic can be proven without the whole body.
 	extrinsics_root: Hash,
-	state: u64,
+	pub(super) state: u64,
 	pub consensus_digest: u64,
 }
 
@@ -31,6 +41,7 @@ impl Header {
 		return Self {
 			height: 0,
 			parent: Hash::default(),
+			timestamp: 0,
 			state: 0,
 			consensus_digest: 0,
 			extrinsics_root: Hash::default(),
@@ -41,11 +52,17 @@ impl Header {
 	/// Without the extrinsics themselves, we cannot calculate the final state
 	/// so that information is passed in.
 	pub fn child(&self, extrinsics_root: Hash, state: u64) -> Self {
+		self.child_at(extrinsics_root, state, self.timestamp + 1)
+	}
+
+	/// Create and return a valid child header with the given timestamp.
+	pub fn child_at(&self, extrinsics_root: Hash, state: u64, timestamp: u64) -> Self {
 		return Self {
 			state,
 			extrinsics_root,
 			height: self.height + 1,
 			parent: hash(self),
+			timestamp,
 			consensus_digest: 0,
 		};
 	}
@@ -57,14 +74,24 @@ impl Header {
 	/// This is useful because checking the header can now be thought of as a
 	/// subtask of checking an entire block. So it doesn't make sense to check
 	/// the entire header chain at once if the chain may be invalid at the second block.
-	fn verify_child(&self, child: &Header) -> bool {
-		if child.height.saturating_sub(self.height) != 1 {
-			return false;
+	///
+	/// The state can't be checked here, because that requires executing the extrinsics in the
+	/// block body. `Block::verify_sub_chain` does that.
+	fn verify_child(&self, child: &Header) -> Result<(), VerificationError> {
+		let (height, block_hash) = (child.height, hash(child));
+		if height.saturating_sub(self.height) != 1 {
+			return Err(VerificationError::HeightGap { height, hash: block_hash });
+		}
+		if child.parent != hash(self) {
+			return Err(VerificationError::BadParentHash { height, hash: block_hash });
 		}
+		Ok(())
+	}
 
-		let valid_hash = child.parent == hash(self);
-		let valid_extrinsic = child.state == self.state;
-		return valid_hash && valid_extrinsic;
+	/// Verify that the given extrinsic is included in this header's block, using only the header
+	/// and a Merkle proof rather than the whole block body.
+	pub fn verify_extrinsic(&self, extrinsic: &u64, proof: &MerkleProof) -> bool {
+		proof.verify(self.extrinsics_root, extrinsic)
 	}
 
 	/// Verify that all the given headers form a valid chain from this header to the tip.
@@ -74,15 +101,32 @@ impl Header {
 	///  - with a loop
 	///  - with head recursion
 	///  - with tail recursion
-	fn verify_sub_chain(&self, chain: &[Header]) -> bool {
-		let mut chain_iter = chain.iter();
-		let get_child_header = chain_iter.next();
-		return match get_child_header {
-			Some(header) => {
-				self.verify_child(header) && header.verify_sub_chain(chain_iter.as_slice())
+	pub(super) fn verify_sub_chain(&self, chain: &[Header]) -> Result<(), VerificationError> {
+		match chain.split_first() {
+			Some((header, rest)) => {
+				self.verify_child(header)?;
+				header.verify_sub_chain(rest)
 			},
-			None => true,
-		};
+			None => Ok(()),
+		}
+	}
+}
+
+impl ChainHeader for Header {
+	fn block_hash(&self) -> Hash {
+		hash(self)
+	}
+
+	fn parent(&self) -> Hash {
+		self.parent
+	}
+
+	fn height(&self) -> u64 {
+		self.height
+	}
+
+	fn timestamp(&self) -> u64 {
+		self.timestamp
 	}
 }
 
@@ -100,9 +144,9 @@ pub struct Block {
 // the transactions are no longer available at the Header level.
 impl Block {
 	fn execute_extrinsics(extrinsics: &Vec<u64>) -> u64 {
-		let mut state = 0;
+		let mut state: u64 = 0;
 		for extrinsic in extrinsics.iter() {
-			state += extrinsic;
+			state = state.wrapping_add(*extrinsic);
 		}
 		return state;
 	}
@@ -114,33 +158,112 @@ impl Block {
 	/// Create and return a valid child block.
 	/// The extrinsics are batched now, so we need to execute each of them.
 	pub fn child(&self, extrinsics: Vec<u64>) -> Self {
+		self.child_at(extrinsics, self.header.timestamp + 1)
+	}
+
+	/// Create and return a valid child block with the given timestamp.
+	pub fn child_at(&self, extrinsics: Vec<u64>, timestamp: u64) -> Self {
 		return Self {
-			header: self.header.child(
-				hash(&extrinsics),
-				self.header.state + Block::execute_extrinsics(&extrinsics),
+			header: self.header.child_at(
+				merkle_root(&extrinsics),
+				self.header.state.wrapping_add(Block::execute_extrinsics(&extrinsics)),
+				timestamp,
 			),
 			body: extrinsics,
 		};
 	}
 
+	/// Build a proof that the extrinsic at the given index is included in this block.
+	/// Anyone holding just the header can check it with `Header::verify_extrinsic`.
+	pub fn extrinsic_proof(&self, index: usize) -> Option<MerkleProof> {
+		merkle_proof(&self.body, index)
+	}
+
 	/// Verify that all the given blocks form a valid chain from this block to the tip.
 	///
 	/// We need to verify the headers as well as execute all transactions and check the final state.
-	pub fn verify_sub_chain(&self, chain: &[Block]) -> bool {
-		let mut chain_iter = chain.iter();
-		let get_child_block = chain_iter.next();
-
-		return match get_child_block {
-			Some(block) => {
-				// state value of the current block + state value of the child block = final state in block header
-				Block::execute_extrinsics(&self.body) + Block::execute_extrinsics(&block.body)
-					== block.header.state
-					&& hash(&block.body) == block.header.extrinsics_root
-					&& block.verify_sub_chain(chain_iter.as_slice())
+	pub fn verify_sub_chain(&self, chain: &[Block]) -> Result<(), VerificationError> {
+		match chain.split_first() {
+			Some((block, rest)) => {
+				self.header.verify_child(&block.header)?;
+				let (height, block_hash) = (block.header.height, hash(&block.header));
+				if merkle_root(&block.body) != block.header.extrinsics_root {
+					return Err(VerificationError::ExtrinsicsRootMismatch {
+						height,
+						hash: block_hash,
+					});
+				}
+				// state value of the parent block + the child's extrinsics = final state in block header
+				if self.header.state.wrapping_add(Block::execute_extrinsics(&block.body))
+					!= block.header.state
+				{
+					return Err(VerificationError::StateRootMismatch { height, hash: block_hash });
+				}
+				block.verify_sub_chain(rest)
 			},
-			None => true,
+			None => Ok(()),
+		}
+	}
+
+	/// Verify the chain like `verify_sub_chain`, and also check every block's timestamp against
+	/// the median time past and the given clock, as described in part 8. The ancestors are the
+	/// headers before this block, oldest first. Only the last few are actually needed.
+	pub fn verify_sub_chain_with_clock<C: Clock>(
+		&self,
+		ancestors: &[Header],
+		chain: &[Block],
+		clock: &C,
+	) -> Result<(), VerificationError> {
+		let headers: Vec<Header> = chain.iter().map(|b| b.header.clone()).collect();
+		let structure = self.verify_sub_chain(chain);
+		verify_with_timestamps(structure, ancestors, &self.header, &headers, clock)
+	}
+}
+
+/// Blocks from this section can be persisted in the block store from part 7.
+/// Their state lives in the header, so no extra state is needed for verification.
+impl StoredBlock for Block {
+	type PreState = ();
+
+	fn encode(&self) -> Vec<u8> {
+		let mut out = Vec::new();
+		let h = &self.header;
+		for value in [h.parent, h.height, h.timestamp, h.extrinsics_root, h.state, h.consensus_digest]
+		{
+			encode_u64(&mut out, value);
+		}
+		encode_u64s(&mut out, &self.body);
+		out
+	}
+
+	fn decode(bytes: &[u8]) -> Option<Self> {
+		let mut d = Decoder::new(bytes);
+		let header = Header {
+			parent: d.u64()?,
+			height: d.u64()?,
+			timestamp: d.u64()?,
+			extrinsics_root: d.u64()?,
+			state: d.u64()?,
+			consensus_digest: d.u64()?,
 		};
+		let body = d.u64s()?;
+		d.finish()?;
+		Some(Block { header, body })
+	}
+
+	fn block_hash(&self) -> Hash {
+		hash(&self.header)
+	}
+
+	fn height(&self) -> u64 {
+		self.header.height
+	}
+
+	fn verify_children(&self, _: &(), chain: &[Self]) -> Result<(), VerificationError> {
+		self.verify_sub_chain(chain)
 	}
+
+	fn post_state(&self, _: &()) {}
 }
 
 /// Create an invalid child block of the given block. Although the child block is invalid,
@@ -219,15 +342,65 @@ fn bc_4_verify_three_blocks() {
 	let b1 = g.child(vec![1]);
 	let b2 = b1.child(vec![2]);
 	let chain = vec![g.clone(), b1, b2];
-	assert!(g.verify_sub_chain(&chain[1..]));
+	assert!(g.verify_sub_chain(&chain[1..]).is_ok());
+}
+
+#[test]
+fn bc_4_verify_long_chain_with_extrinsics() {
+	let g = Block::genesis();
+	let b1 = g.child(vec![1, 2, 3]);
+	let b2 = b1.child(vec![4]);
+	let b3 = b2.child(vec![5, 6]);
+	let b4 = b3.child(vec![]);
+	assert_eq!(b4.header.state, 21);
+	assert_eq!(g.verify_sub_chain(&[b1, b2, b3, b4]), Ok(()));
+}
+
+#[test]
+fn bc_4_wrong_state_deep_in_chain_does_not_check() {
+	let g = Block::genesis();
+	let b1 = g.child(vec![1, 2]);
+	let b2 = b1.child(vec![3]);
+	// Built as if its parent's state were only the parent's own extrinsics.
+	let mut b3 = b2.child(vec![4]);
+	b3.header.state = 3 + 4;
+	let b3_hash = hash(&b3.header);
+
+	assert_eq!(
+		g.verify_sub_chain(&[b1, b2, b3]),
+		Err(VerificationError::StateRootMismatch { height: 3, hash: b3_hash })
+	);
+}
+
+#[test]
+fn bc_4_block_with_bad_parent_does_not_check() {
+	let g = Block::genesis();
+	let b1 = g.child(vec![1]);
+	let b2 = b1.child(vec![2]);
+	let mut b3 = b2.child(vec![3]);
+	b3.header.parent = hash(&b1.header);
+	let b3_hash = hash(&b3.header);
+	let b4 = b3.child(vec![4]);
+
+	assert_eq!(
+		g.verify_sub_chain(&[b1, b2, b3, b4]),
+		Err(VerificationError::BadParentHash { height: 3, hash: b3_hash })
+	);
 }
 
 #[test]
 fn bc_4_invalid_header_does_not_check() {
 	let g = Header::genesis();
-	let h1 = Header { parent: 0, height: 100, extrinsics_root: 0, state: 100, consensus_digest: 0 };
-
-	assert!(!g.verify_child(&h1));
+	let h1 = Header {
+		parent: 0,
+		height: 100,
+		timestamp: 0,
+		extrinsics_root: 0,
+		state: 100,
+		consensus_digest: 0,
+	};
+
+	assert!(matches!(g.verify_child(&h1), Err(VerificationError::HeightGap { height: 100, .. })));
 }
 
 #[test]
@@ -236,7 +409,10 @@ fn bc_4_invalid_block_state_does_not_check() {
 	let mut b1 = b0.child(vec![1, 2, 3]);
 	b1.body = vec![];
 
-	assert!(!b0.verify_sub_chain(&[b1]));
+	assert!(matches!(
+		b0.verify_sub_chain(&[b1]),
+		Err(VerificationError::ExtrinsicsRootMismatch { height: 1, .. })
+	));
 }
 
 #[test]
@@ -245,7 +421,7 @@ fn bc_4_block_with_invalid_header_does_not_check() {
 	let mut b1 = b0.child(vec![1, 2, 3]);
 	b1.header = Header::genesis();
 
-	assert!(!b0.verify_sub_chain(&[b1]));
+	assert!(b0.verify_sub_chain(&[b1]).is_err());
 }
 
 #[test]
@@ -257,8 +433,20 @@ fn bc_4_student_invalid_block_really_is_invalid() {
 	let h1 = &b1.header;
 
 	// Make sure that the header is valid according to header rules.
-	assert!(gh.verify_child(h1));
+	assert!(gh.verify_child(h1).is_ok());
 
 	// Make sure that the block is not valid when executed.
-	assert!(!gb.verify_sub_chain(&[b1]));
+	assert!(gb.verify_sub_chain(&[b1]).is_err());
+}
+
+#[test]
+fn bc_4_extrinsic_inclusion_proof() {
+	let b0 = Block::genesis();
+	let b1 = b0.child(vec![5, 6, 7]);
+
+	let proof = b1.extrinsic_proof(1).unwrap();
+	assert!(b1.header.verify_extrinsic(&6, &proof));
+	assert!(!b1.header.verify_extrinsic(&5, &proof));
+	assert!(!b0.header.verify_extrinsic(&6, &proof));
+	assert_eq!(b1.extrinsic_proof(3), None);
 }
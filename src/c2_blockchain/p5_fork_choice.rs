@@ -6,14 +6,24 @@
 //!
 //! Since we have nothing to add to the Block or Header data structures in this lesson,
 //! we will import them from the previous lesson.
-
-use rand::Rng;
-
-use super::p4_batched_extrinsics::{Block, Header};
-use crate::hash;
+//!
+//! Comparing chains two at a time is not the whole story though. A node actually knows about a tree
+//! of headers, and some fork choice rules need to see all of it. At the end of this lesson we
+//! revisit each rule as a `TreeForkChoice` over the header tree from part 10, and add GHOST.
+
+use super::{
+	p10_tree_route::HeaderTree,
+	block_work,
+	p4_batched_extrinsics::{Block, Header},
+	chain_work, ChainHeader,
+};
+use crate::{hash, miner::Miner};
+use std::collections::HashMap;
 
 const THRESHOLD: u64 = u64::max_value() / 100;
 
+type Hash = u64;
+
 /// Judge which blockchain is "best" when there are multiple candidates. There are several
 /// meaningful notions of "best" which is why this is a trait instead of just a
 /// method.
@@ -67,27 +77,23 @@ impl ForkChoice for LongestChainRule {
 /// In Proof of Work chains, each block contains a certain amount of "work".
 /// Roughly speaking, the lower a block's hash is, the more work it contains,
 /// because finding a block with a low hash requires, on average, trying more
-/// nonces. Modeling the amount of work required to achieve a particular hash
-/// is out of scope for this exercise, so we will use the not-really-right-but
-/// conceptually-good-enough formula `work = THRESHOLD - block_hash`
+/// nonces. A hash below `h` turns up about once in every `2^64 / (h + 1)`
+/// attempts, so that is the work we credit the block with. See `block_work`
+/// in the chapter module.
 pub struct HeaviestChainRule;
 
-fn generate_nonce() -> u64 {
-	let mut rng = rand::thread_rng();
-	return rng.gen::<u32>() as u64;
-}
-
+/// Search for a nonce that brings the header's hash below the threshold, using every core.
 fn mine_consensus_digest(header: &mut Header, threshold: u64) {
-	let mut valid_header = header.clone();
-	loop {
-		let nonce = generate_nonce();
-		valid_header.consensus_digest = nonce;
-		if hash(&valid_header) < threshold {
-			header.consensus_digest = nonce;
-			break;
-		}
-	}
+	let outcome = Miner::default()
+		.mine(|nonce| {
+			let mut candidate = header.clone();
+			candidate.consensus_digest = nonce;
+			hash(&candidate) < threshold
+		})
+		.expect("some nonce is below the threshold");
+	header.consensus_digest = outcome.nonce;
 }
+
 /// Mutates a block (and its embedded header) to contain more PoW difficulty.
 /// This will be useful for exploring the heaviest chain rule. The expected
 /// usage is that you create a block using the normal `Block.child()` method
@@ -97,12 +103,8 @@ fn mine_extra_hard(block: &mut Block, threshold: u64) {
 }
 
 impl HeaviestChainRule {
-	fn get_total_work(chain: &[Header]) -> i64 {
-		let mut total_work: i64 = 0;
-		chain.iter().for_each(|header| {
-			total_work = total_work.saturating_add(THRESHOLD as i64 - hash(header) as i64);
-		});
-		return total_work;
+	fn get_total_work(chain: &[Header]) -> u128 {
+		chain_work(chain)
 	}
 }
 
@@ -176,18 +178,108 @@ impl ForkChoice for MostBlocksWithEvenHash {
 	}
 }
 
-// This lesson has omitted one popular fork choice rule:
-// GHOST - Greedy Heaviest Observed SubTree
-//
-// I've omitted GHOST from here because it requires information about blocks that
-// are _not_ in the chain to decide which chain is best. Therefore it does't work
-// well with this relatively simple trait definition. We will return to the GHOST
-// rule later when we have written a full blockchain client
-//
-// The GHOST rule was first published in 2013 by Yonatan Sompolinsky and Aviv Zohar.
-// Learn more at https://eprint.iacr.org/2013/881.pdf
+/// Judge which chain is "best" given every header we know about, arranged as a tree.
+///
+/// Each of the rules above only ever looks at the blocks in the chains being compared, so it can
+/// also be applied to a tree by scoring the chain ending at each tip. But some rules need
+/// information about blocks that are _not_ in the chain, and those can only be written this way.
+pub trait TreeForkChoice {
+	/// Return the hash of the tip of the best chain in the tree, or None if the tree is empty.
+	///
+	/// Ties are broken in favour of the lower hash, so that every node picks the same tip no
+	/// matter what order it learned about the blocks in.
+	fn best_tip<H: ChainHeader>(tree: &HeaderTree<H>) -> Option<Hash>;
+}
+
+/// Score the chain from the root to every block in the tree by adding up the score of each
+/// block, and return the tip with the highest total.
+fn best_tip_by_chain_score<H: ChainHeader>(
+	tree: &HeaderTree<H>,
+	block_score: impl Fn(Hash) -> u128,
+) -> Option<Hash> {
+	let root = tree.root()?;
+	let mut best = None;
+	let mut stack = vec![(block_score(root), root)];
+	while let Some((score, block_hash)) = stack.pop() {
+		let children = tree.children(block_hash);
+		for child in children {
+			stack.push((score + block_score(*child), *child));
+		}
+		// Only tips are candidates. Comparing the reversed hash means the lower hash wins a tie.
+		let candidate = (score, std::cmp::Reverse(block_hash));
+		if children.is_empty() && best.is_none_or(|best| candidate > best) {
+			best = Some(candidate);
+		}
+	}
+	best.map(|(_, std::cmp::Reverse(block_hash))| block_hash)
+}
+
+impl TreeForkChoice for LongestChainRule {
+	fn best_tip<H: ChainHeader>(tree: &HeaderTree<H>) -> Option<Hash> {
+		best_tip_by_chain_score(tree, |_| 1)
+	}
+}
+
+impl TreeForkChoice for HeaviestChainRule {
+	fn best_tip<H: ChainHeader>(tree: &HeaderTree<H>) -> Option<Hash> {
+		best_tip_by_chain_score(tree, block_work)
+	}
+}
+
+impl TreeForkChoice for MostBlocksWithEvenHash {
+	fn best_tip<H: ChainHeader>(tree: &HeaderTree<H>) -> Option<Hash> {
+		best_tip_by_chain_score(tree, |block_hash| block_hash.is_multiple_of(2) as u128)
+	}
+}
+
+/// GHOST - Greedy Heaviest Observed SubTree
+///
+/// Start at the root and repeatedly step to the child whose entire subtree contains the most work,
+/// until reaching a tip. Blocks that are not in the resulting chain still count, as long as they
+/// are descendants of a block that is. The idea is that a miner who builds on a side block was
+/// still voting for its parent, so that work shouldn't be wasted just because it was orphaned.
+/// This makes the chain safer when blocks are fast and forks are common.
+///
+/// Notice that the winning chain need not be the heaviest chain, or even the longest.
+///
+/// The GHOST rule was first published in 2013 by Yonatan Sompolinsky and Aviv Zohar.
+/// Learn more at https://eprint.iacr.org/2013/881.pdf
+pub struct Ghost;
+
+impl Ghost {
+	/// The total work in the subtree below every block in the tree, including the block itself.
+	fn subtree_work<H: ChainHeader>(tree: &HeaderTree<H>, root: Hash) -> HashMap<Hash, u128> {
+		// Visit the blocks in depth first order, then total them up in reverse so that every
+		// block's children are finished before the block itself.
+		let mut order = vec![];
+		let mut stack = vec![root];
+		while let Some(block_hash) = stack.pop() {
+			order.push(block_hash);
+			stack.extend_from_slice(tree.children(block_hash));
+		}
+		let mut work = HashMap::new();
+		for block_hash in order.into_iter().rev() {
+			let below: u128 = tree.children(block_hash).iter().map(|child| work[child]).sum();
+			work.insert(block_hash, block_work(block_hash) + below);
+		}
+		work
+	}
+}
 
-//
+impl TreeForkChoice for Ghost {
+	fn best_tip<H: ChainHeader>(tree: &HeaderTree<H>) -> Option<Hash> {
+		let mut current = tree.root()?;
+		let work = Ghost::subtree_work(tree, current);
+		while let Some(heaviest) = tree
+			.children(current)
+			.iter()
+			.max_by_key(|child| (work[*child], std::cmp::Reverse(**child)))
+		{
+			current = *heaviest;
+		}
+		Some(current)
+	}
+}
 
 /// Build and return two different chains with a common prefix.
 /// They should have the same genesis header. Both chains should be valid.
@@ -218,9 +310,11 @@ fn create_fork_one_side_longer_other_side_heavier() -> (Vec<Header>, Vec<Header>
 	mine_consensus_digest(&mut longest_chain_hd, u64::max_value() / 4);
 
 	let mut more_work_chain_ha = forked_header.child(hash(&vec![5, 6]), 11);
-	mine_consensus_digest(&mut more_work_chain_ha, u64::max_value() / 200); // 1 valid block / 200 blocks
+	// Work is heavy tailed, so a lucky block on the longer chain could outweigh a few hundred units.
+	// Mining this side much harder makes that vanishingly unlikely.
+	mine_consensus_digest(&mut more_work_chain_ha, u64::max_value() / 200_000);
 	let mut more_work_chain_hb = more_work_chain_ha.child(hash(&vec![1, 2]), 14);
-	mine_consensus_digest(&mut more_work_chain_hb, u64::max_value() / 250); // 1 valid block / 250 blocks
+	mine_consensus_digest(&mut more_work_chain_hb, u64::max_value() / 250_000);
 
 	return (
 		prefix_chain.clone(),
@@ -344,3 +438,113 @@ fn bc_5_longest_vs_heaviest() {
 
 	assert_eq!(HeaviestChainRule::best_chain(&[&longest_chain, &pow_chain]), &pow_chain);
 }
+
+#[test]
+fn bc_5_block_work() {
+	assert_eq!(block_work(u64::MAX), 1);
+	assert_eq!(block_work(0), 1 << 64);
+	// One hash in a hundred is below the threshold. The division rounds down, so just under 100.
+	assert_eq!(block_work(THRESHOLD), 99);
+	// Halving the hash doubles the work.
+	assert_eq!(block_work(u64::MAX / 2), 2);
+	assert_eq!(block_work(u64::MAX / 4), 4);
+
+	// Every hash, including those above i64::MAX, carries positive work, so a longer chain of
+	// equally difficult blocks is always heavier.
+	let g = Header::genesis();
+	let chain = [g.child(1, 0), g.child(2, 0), g.child(3, 0)];
+	assert!(chain.iter().all(|header| block_work(hash(header)) > 0));
+	assert!(chain_work(&chain) > chain_work(&chain[..2]));
+	assert_eq!(HeaviestChainRule::get_total_work(&chain), chain_work(&chain));
+}
+
+/// A child of the given header whose block work lies in the given range. The `fork` value goes
+/// into the extrinsics root so that different branches get different blocks.
+#[cfg(test)]
+fn child_with_work(parent: &Header, fork: u64, work: std::ops::Range<u128>) -> Header {
+	(0..)
+		.map(|state| parent.child(fork, state))
+		.find(|header| work.contains(&block_work(hash(header))))
+		.unwrap()
+}
+
+/// A child of the given header whose hash is even or odd as requested.
+#[cfg(test)]
+fn child_with_parity(parent: &Header, fork: u64, even: bool) -> Header {
+	(0..)
+		.map(|state| parent.child(fork, state))
+		.find(|header| (hash(header) % 2 == 0) == even)
+		.unwrap()
+}
+
+#[test]
+fn bc_5_tree_rules_agree_with_slice_rules() {
+	// Branch A is longer, but only branch B has a block with an even hash.
+	let g = Header::genesis();
+	let a1 = child_with_parity(&g, 1, false);
+	let a2 = child_with_parity(&a1, 1, false);
+	let b1 = child_with_parity(&g, 2, true);
+
+	let mut tree = HeaderTree::new();
+	for header in [&g, &a1, &a2, &b1] {
+		assert!(tree.insert(header.clone()));
+	}
+	let chain_a = [g.clone(), a1, a2.clone()];
+	let chain_b = [g, b1.clone()];
+	let expected = |first_is_better| if first_is_better { hash(&a2) } else { hash(&b1) };
+
+	assert_eq!(
+		LongestChainRule::best_tip(&tree),
+		Some(expected(LongestChainRule::first_chain_is_better(&chain_a, &chain_b)))
+	);
+	assert_eq!(
+		HeaviestChainRule::best_tip(&tree),
+		Some(expected(HeaviestChainRule::first_chain_is_better(&chain_a, &chain_b)))
+	);
+	assert_eq!(
+		MostBlocksWithEvenHash::best_tip(&tree),
+		Some(expected(MostBlocksWithEvenHash::first_chain_is_better(&chain_a, &chain_b)))
+	);
+	assert_eq!(MostBlocksWithEvenHash::best_tip(&tree), Some(hash(&b1)));
+	assert_eq!(Ghost::best_tip(&HeaderTree::<Header>::new()), None);
+}
+
+#[test]
+fn bc_5_ghost_flips_on_side_block() {
+	// Blocks on branch A have slightly less work than blocks on branch B. A block right at the
+	// threshold has about 100 units of work.
+	let light = 100..105;
+	let heavy = 110..115;
+
+	let g = Header::genesis();
+	let mut tree = HeaderTree::new();
+	tree.insert(g.clone());
+	let mut a = vec![g.clone()];
+	for _ in 0..4 {
+		a.push(child_with_work(a.last().unwrap(), 1, light.clone()));
+		tree.insert(a.last().unwrap().clone());
+	}
+	let mut b = vec![g];
+	for _ in 0..3 {
+		b.push(child_with_work(b.last().unwrap(), 2, heavy.clone()));
+		tree.insert(b.last().unwrap().clone());
+	}
+	let a_tip = hash(a.last().unwrap());
+	let b_tip = hash(b.last().unwrap());
+
+	// Branch A has four blocks of work against B's three, so every rule picks A.
+	assert_eq!(LongestChainRule::best_tip(&tree), Some(a_tip));
+	assert_eq!(HeaviestChainRule::best_tip(&tree), Some(a_tip));
+	assert_eq!(Ghost::best_tip(&tree), Some(a_tip));
+
+	// Now a side block arrives, forking off B's first block. It makes B's subtree heavier than
+	// A's, so GHOST switches to B. But GHOST follows B's own chain, not the side block.
+	let side = child_with_work(&b[1], 3, heavy);
+	tree.insert(side.clone());
+	assert_eq!(Ghost::best_tip(&tree), Some(b_tip));
+	assert_ne!(Ghost::best_tip(&tree), Some(hash(&side)));
+
+	// The chain-based rules don't see the side block's work at all.
+	assert_eq!(LongestChainRule::best_tip(&tree), Some(a_tip));
+	assert_eq!(HeaviestChainRule::best_tip(&tree), Some(a_tip));
+}
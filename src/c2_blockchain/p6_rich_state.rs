@@ -10,11 +10,23 @@
 //! previously performed? We use a state root to cryptographically link our heder to a complete
 //! state.
 //!
+//! The sum and product are just one choice. The block logic only needs to start from some state,
+//! apply extrinsics to it one at a time, and commit to the result, so it is written against the
+//! `Accumulator` trait. Part 9 provides several more accumulators to plug in.
+//!
 //! This notion of state may sound familiar from our previous work on state machines. Indeed this
 //! naming coincidence foreshadows a key abstraction that we will make in a coming chapter.
 
 type Hash = u64;
-use crate::hash;
+use super::{
+	p7_block_store::{encode_u64, encode_u64s, Decoder, StoredBlock},
+	p8_timestamps::{verify_with_timestamps, Clock},
+	ChainHeader, VerificationError,
+};
+use crate::{
+	hash,
+	merkle::{merkle_proof, merkle_root, MerkleProof},
+};
 
 /// In this section we will use sum and product together to be our state. While this is only a
 /// doubling of state size remember that in real world blockchains, the state is often really really
@@ -25,18 +37,54 @@ pub struct State {
 	product: u64,
 }
 
+impl State {
+	/// Create a state with the given sum and product.
+	pub fn new(sum: u64, product: u64) -> Self {
+		State { sum, product }
+	}
+}
+
+/// Something the chain can track by folding over every extrinsic in order.
+pub trait Accumulator: Clone {
+	/// The state before any extrinsics have been applied.
+	fn initial() -> Self;
+
+	/// Update the state with a single extrinsic.
+	fn apply(&mut self, extrinsic: u64);
+
+	/// A commitment to the complete state, suitable for storing in a header as the state root.
+	fn commitment(&self) -> Hash;
+}
+
+impl Accumulator for State {
+	fn initial() -> Self {
+		State { sum: 0, product: 1 }
+	}
+
+	fn apply(&mut self, extrinsic: u64) {
+		self.sum = self.sum.wrapping_add(extrinsic);
+		self.product = self.product.wrapping_mul(extrinsic);
+	}
+
+	fn commitment(&self) -> Hash {
+		hash(self)
+	}
+}
+
 /// The header no longer contains the state directly, but rather, it contains a hash of
 /// the complete state. This hash will allow block verifiers to cryptographically confirm
 /// that they got the same state as the author without having a complete copy of the
 /// author's state
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Header {
-	parent: Hash,
-	height: u64,
-	extrinsics_root: Hash,
+	pub(super) parent: Hash,
+	pub(super) height: u64,
+	/// When the block was authored, in seconds. See part 8 for how this is validated.
+	pub(super) timestamp: u64,
+	pub(super) extrinsics_root: Hash,
 	/// Stores a cryptographic commitment, like a Merkle root or a hash to the complete
 	/// post state.
-	state_root: Hash,
+	pub(super) state_root: Hash,
 	consensus_digest: u64,
 }
 
@@ -52,6 +100,7 @@ impl Header {
 		return Self {
 			height: 0,
 			parent: Hash::default(),
+			timestamp: 0,
 			state_root: genesis_state_root,
 			consensus_digest: 0,
 			extrinsics_root: Hash::default(),
@@ -63,9 +112,15 @@ impl Header {
 	/// The state root is passed in similarly to how the complete state
 	/// was in the previous section.
 	fn child(&self, extrinsics_root: Hash, state_root: Hash) -> Self {
+		self.child_at(extrinsics_root, state_root, self.timestamp + 1)
+	}
+
+	/// Create and return a valid child header with the given timestamp.
+	fn child_at(&self, extrinsics_root: Hash, state_root: Hash, timestamp: u64) -> Self {
 		return Self {
 			height: self.height + 1,
 			parent: hash(self),
+			timestamp,
 			state_root,
 			consensus_digest: 0,
 			extrinsics_root,
@@ -73,16 +128,49 @@ impl Header {
 	}
 
 	/// Verify a single child header.
-	fn verify_child(&self, child: &Header) -> bool {
-		child.height.saturating_sub(self.height) == 1 && child.parent == hash(self)
+	fn verify_child(&self, child: &Header) -> Result<(), VerificationError> {
+		let (height, block_hash) = (child.height, hash(child));
+		if height.saturating_sub(self.height) != 1 {
+			return Err(VerificationError::HeightGap { height, hash: block_hash });
+		}
+		if child.parent != hash(self) {
+			return Err(VerificationError::BadParentHash { height, hash: block_hash });
+		}
+		Ok(())
+	}
+
+	/// Verify that the given extrinsic is included in this header's block using a Merkle proof.
+	pub fn verify_extrinsic(&self, extrinsic: &u64, proof: &MerkleProof) -> bool {
+		proof.verify(self.extrinsics_root, extrinsic)
 	}
 
 	/// Verify that all the given headers form a valid chain from this header to the tip.
-	fn verify_sub_chain(&self, chain: &[Header]) -> bool {
-		let get_next_header = chain.iter().next();
-		return get_next_header.is_some()
-			&& self.verify_child(get_next_header.unwrap())
-			&& get_next_header.unwrap().verify_sub_chain(chain);
+	fn verify_sub_chain(&self, chain: &[Header]) -> Result<(), VerificationError> {
+		match chain.split_first() {
+			Some((header, rest)) => {
+				self.verify_child(header)?;
+				header.verify_sub_chain(rest)
+			},
+			None => Ok(()),
+		}
+	}
+}
+
+impl ChainHeader for Header {
+	fn block_hash(&self) -> Hash {
+		hash(self)
+	}
+
+	fn parent(&self) -> Hash {
+		self.parent
+	}
+
+	fn height(&self) -> u64 {
+		self.height
+	}
+
+	fn timestamp(&self) -> u64 {
+		self.timestamp
 	}
 }
 
@@ -105,20 +193,37 @@ pub struct Block {
 /// calculate state roots to pass to the header-level methods.
 impl Block {
 	/// Returns a new valid genesis block. By convention this block has no extrinsics.
-	pub fn genesis(genesis_state: &State) -> Self {
-		Block { header: Header::genesis(hash(genesis_state)), body: vec![] }
+	pub fn genesis<S: Accumulator>(genesis_state: &S) -> Self {
+		Block { header: Header::genesis(genesis_state.commitment()), body: vec![] }
 	}
 
 	/// Create and return a valid child block.
-	pub fn child(&self, pre_state: &State, extrinsics: Vec<u64>) -> Self {
+	pub fn child<S: Accumulator>(&self, pre_state: &S, extrinsics: Vec<u64>) -> Self {
+		self.child_at(pre_state, extrinsics, self.header.timestamp + 1)
+	}
+
+	/// Create and return a valid child block with the given timestamp.
+	pub fn child_at<S: Accumulator>(
+		&self,
+		pre_state: &S,
+		extrinsics: Vec<u64>,
+		timestamp: u64,
+	) -> Self {
 		let state = Block::execute_extrinsics(&mut pre_state.clone(), &extrinsics);
-		Block { header: self.header.child(hash(&extrinsics), hash(&state)), body: extrinsics }
+		Block {
+			header: self.header.child_at(merkle_root(&extrinsics), state.commitment(), timestamp),
+			body: extrinsics,
+		}
 	}
 
-	fn execute_extrinsics(pre_state: &mut State, extrinsics: &Vec<u64>) -> State {
+	/// Build a proof that the extrinsic at the given index is included in this block.
+	pub fn extrinsic_proof(&self, index: usize) -> Option<MerkleProof> {
+		merkle_proof(&self.body, index)
+	}
+
+	fn execute_extrinsics<S: Accumulator>(pre_state: &mut S, extrinsics: &Vec<u64>) -> S {
 		for extrinsic in extrinsics.iter() {
-			pre_state.sum += extrinsic;
-			pre_state.product *= extrinsic;
+			pre_state.apply(*extrinsic);
 		}
 		return pre_state.clone();
 	}
@@ -128,21 +233,97 @@ impl Block {
 	/// This time we need to validate the initial block itself by confirming that we
 	/// have been given a valid pre-state. And we still need to verify the headers,
 	/// execute all transactions, and check the final state.
-	pub fn verify_sub_chain(&self, pre_state: &State, chain: &[Block]) -> bool {
-		let mut chain_iter = chain.iter();
-		let get_child_block = chain_iter.next();
+	pub fn verify_sub_chain<S: Accumulator>(
+		&self,
+		pre_state: &S,
+		chain: &[Block],
+	) -> Result<(), VerificationError> {
 		let state = Block::execute_extrinsics(&mut pre_state.clone(), &self.body);
 		// validate the initial block itself by confirming that we have been give a valid pre-state
-		return hash(&state) == self.header.state_root
-			&& match get_child_block {
-				Some(block) => {
-					self.header.verify_child(&block.header)
-					// validate that the block extrinsics stored in body match with the header extrinsic root
-					&& hash(&block.body) == block.header.extrinsics_root
-					&& block.verify_sub_chain(&state, chain_iter.as_slice())
-				},
-				None => true,
-			};
+		if state.commitment() != self.header.state_root {
+			return Err(VerificationError::StateRootMismatch {
+				height: self.header.height,
+				hash: hash(&self.header),
+			});
+		}
+		match chain.split_first() {
+			Some((block, rest)) => {
+				self.header.verify_child(&block.header)?;
+				// validate that the block extrinsics stored in body match with the header extrinsic root
+				if merkle_root(&block.body) != block.header.extrinsics_root {
+					return Err(VerificationError::ExtrinsicsRootMismatch {
+						height: block.header.height,
+						hash: hash(&block.header),
+					});
+				}
+				block.verify_sub_chain(&state, rest)
+			},
+			None => Ok(()),
+		}
+	}
+
+	/// Verify the chain like `verify_sub_chain`, and also check every block's timestamp against
+	/// the median time past and the given clock, as described in part 8. The ancestors are the
+	/// headers before this block, oldest first. Only the last few are actually needed.
+	pub fn verify_sub_chain_with_clock<S: Accumulator, C: Clock>(
+		&self,
+		pre_state: &S,
+		ancestors: &[Header],
+		chain: &[Block],
+		clock: &C,
+	) -> Result<(), VerificationError> {
+		let headers: Vec<Header> = chain.iter().map(|b| b.header.clone()).collect();
+		let structure = self.verify_sub_chain(pre_state, chain);
+		verify_with_timestamps(structure, ancestors, &self.header, &headers, clock)
+	}
+}
+
+/// Blocks from this section can be persisted in the block store from part 7.
+/// Verifying them requires the state before the block, just like `verify_sub_chain`.
+impl StoredBlock for Block {
+	type PreState = State;
+
+	fn encode(&self) -> Vec<u8> {
+		let mut out = Vec::new();
+		let h = &self.header;
+		for value in
+			[h.parent, h.height, h.timestamp, h.extrinsics_root, h.state_root, h.consensus_digest]
+		{
+			encode_u64(&mut out, value);
+		}
+		encode_u64s(&mut out, &self.body);
+		out
+	}
+
+	fn decode(bytes: &[u8]) -> Option<Self> {
+		let mut d = Decoder::new(bytes);
+		let header = Header {
+			parent: d.u64()?,
+			height: d.u64()?,
+			timestamp: d.u64()?,
+			extrinsics_root: d.u64()?,
+			state_root: d.u64()?,
+			consensus_digest: d.u64()?,
+		};
+		let body = d.u64s()?;
+		d.finish()?;
+		Some(Block { header, body })
+	}
+
+	fn block_hash(&self) -> Hash {
+		hash(&self.header)
+	}
+
+	fn height(&self) -> u64 {
+		self.header.height
+	}
+
+	fn verify_children(&self, pre_state: &State, chain: &[Self]) -> Result<(), VerificationError> {
+		self.verify_sub_chain(pre_state, chain)
+	}
+
+	fn post_state(&self, pre_state: &State) -> State {
+		Block::execute_extrinsics(&mut pre_state.clone(), &self.body)
 	}
 }
 
@@ -161,8 +342,8 @@ fn build_invalid_child_block_with_valid_header(parent: &Header, pre_state: &Stat
 	let extrinsics = vec![1, 2, 3];
 	Block {
 		header: parent.child(
-			hash(&extrinsics),
-			hash(&Block::execute_extrinsics(&mut pre_state.clone(), &vec![1])),
+			merkle_root(&extrinsics),
+			Block::execute_extrinsics(&mut pre_state.clone(), &vec![1]).commitment(),
 		),
 		body: extrinsics,
 	}
@@ -250,7 +431,7 @@ fn bc_6_verify_three_blocks() {
 	let state_2 = State { sum: 7, product: 9 };
 	let b2 = b1.child(&state_2, vec![2]);
 	let chain = vec![g.clone(), b1, b2];
-	assert!(g.verify_sub_chain(&state_1, &chain[1..]));
+	assert!(g.verify_sub_chain(&state_1, &chain[1..]).is_ok());
 }
 
 #[test]
@@ -260,12 +441,13 @@ fn bc_6_invalid_header_doesnt_check() {
 	let h1 = Header {
 		parent: 0,
 		height: 100,
+		timestamp: 0,
 		extrinsics_root: 0,
 		state_root: hash(&(State { sum: 0, product: 0 })),
 		consensus_digest: 0,
 	};
 
-	assert!(!g.verify_child(&h1));
+	assert!(matches!(g.verify_child(&h1), Err(VerificationError::HeightGap { height: 100, .. })));
 }
 
 #[test]
@@ -275,7 +457,7 @@ fn bc_6_invalid_block_state_doesnt_check() {
 	let mut b1 = b0.child(&state, vec![1, 2, 3]);
 	b1.body = vec![];
 
-	assert!(!b0.verify_sub_chain(&state, &[b1]));
+	assert!(b0.verify_sub_chain(&state, &[b1]).is_err());
 }
 
 #[test]
@@ -285,7 +467,7 @@ fn bc_6_block_with_invalid_header_doesnt_check() {
 	let mut b1 = b0.child(&state, vec![1, 2, 3]);
 	b1.header = Header::genesis(hash(&state));
 
-	assert!(!b0.verify_sub_chain(&state, &[b1]));
+	assert!(b0.verify_sub_chain(&state, &[b1]).is_err());
 }
 
 #[test]
@@ -298,8 +480,59 @@ fn bc_6_student_invalid_block_really_is_invalid() {
 	let h1 = &b1.header;
 
 	// Make sure that the header is valid according to header rules.
-	assert!(gh.verify_child(h1));
+	assert!(gh.verify_child(h1).is_ok());
 
 	// Make sure that the block is not valid when executed.
-	assert!(!gb.verify_sub_chain(&state, &[b1]));
+	assert!(matches!(
+		gb.verify_sub_chain(&state, &[b1]),
+		Err(VerificationError::StateRootMismatch { height: 1, .. })
+	));
+}
+
+#[test]
+fn bc_6_error_reports_failing_block() {
+	let state = State { sum: 0, product: 1 };
+	let g = Block::genesis(&state);
+	let b1 = g.child(&state, vec![1]);
+	let state_1 = State { sum: 1, product: 1 };
+	let b2 = b1.child(&state_1, vec![2]);
+	let mut b3 = b2.child(&State { sum: 3, product: 2 }, vec![3]);
+	b3.header.parent = 0;
+
+	let headers = [b1.header.clone(), b2.header.clone(), b3.header.clone()];
+	assert!(g.header.verify_sub_chain(&headers[..2]).is_ok());
+	assert!(g.header.verify_sub_chain(&[]).is_ok());
+
+	let expected = VerificationError::BadParentHash { height: 3, hash: hash(&b3.header) };
+	assert_eq!(g.header.verify_sub_chain(&headers), Err(expected));
+	assert_eq!(g.verify_sub_chain(&state, &[b1, b2, b3]), Err(expected));
+	assert_eq!(
+		expected.to_string(),
+		format!("block {} at height 3 failed verification: bad parent hash", expected.hash())
+	);
+}
+
+#[test]
+fn bc_6_extrinsic_inclusion_proof() {
+	let state = State { sum: 0, product: 1 };
+	let b0 = Block::genesis(&state);
+	let b1 = b0.child(&state, vec![2, 3, 4, 5, 6]);
+
+	for (i, extrinsic) in b1.body.iter().enumerate() {
+		let proof = b1.extrinsic_proof(i).unwrap();
+		assert!(b1.header.verify_extrinsic(extrinsic, &proof));
+	}
+	let proof = b1.extrinsic_proof(4).unwrap();
+	assert!(!b1.header.verify_extrinsic(&7, &proof));
+}
+
+#[test]
+fn bc_6_state_is_an_accumulator() {
+	let mut state = State::initial();
+	assert_eq!(state, State::new(0, 1));
+	for extrinsic in [2, 3, 4] {
+		state.apply(extrinsic);
+	}
+	assert_eq!(state, State::new(9, 24));
+	assert_eq!(state.commitment(), hash(&state));
 }
@@ -0,0 +1,536 @@
+//! So far every chain we have built lives in a `Vec` in memory and disappears when the program
+//! exits. Real nodes persist their blocks to disk so that they can restart without re-downloading
+//! the whole chain. In this section we build a very simple block store.
+//!
+//! Blocks are written one after another to an append-only data file. Each record is framed with
+//! its length and a checksum:
+//!
+//! ```text
+//! | length: u32 | checksum: u64 | payload: length bytes |
+//! ```
+//!
+//! The checksum is 64 bit FNV-1a rather than the crate's `hash` function. `hash` uses the standard
+//! library's `DefaultHasher`, whose algorithm may change between Rust releases, and a store written
+//! by one build of the node must still open with the next.
+//!
+//! An index from height and from block hash to the record's position in the file is kept in
+//! memory and rebuilt whenever the store is opened. Because we never trust data just because it
+//! came from our own disk, the whole chain is re-verified on startup. The chain may be very long,
+//! so it is checked one block at a time in a loop rather than with a single recursive call to
+//! `verify_sub_chain`.
+//!
+//! Appending is not atomic. If the node crashes half way through writing a record, the file ends
+//! with a partial record. We detect this on startup and truncate the file back to the last
+//! complete record, losing only the block that was being written. A bad record anywhere else
+//! in the file is real corruption, and we refuse to open the store.
+
+use super::VerificationError;
+use anyhow::{Error, Result};
+use std::{
+	collections::HashMap,
+	fs::{File, OpenOptions},
+	io::{Read, Seek, SeekFrom, Write},
+	path::Path,
+};
+
+type Hash = u64;
+
+/// The size of the length and checksum that come before every record's payload.
+const RECORD_HEADER_SIZE: u64 = 4 + 8;
+
+/// A block that knows how to encode itself for storage and verify itself against its parent.
+///
+/// We hand-roll a simple binary encoding rather than pulling in a serialization library. Every
+/// integer is written as eight little-endian bytes, and lists are prefixed by their length.
+pub trait StoredBlock: Clone + Sized {
+	/// Whatever state is needed to verify a block in addition to the block itself. For blocks
+	/// that carry their whole state in the header this is just `()`.
+	type PreState: Clone;
+
+	/// Encode this block into bytes.
+	fn encode(&self) -> Vec<u8>;
+
+	/// Decode a block from bytes produced by `encode`. Returns None if the bytes are malformed.
+	fn decode(bytes: &[u8]) -> Option<Self>;
+
+	/// The hash of this block's header.
+	fn block_hash(&self) -> Hash;
+
+	/// The height of this block.
+	fn height(&self) -> u64;
+
+	/// Verify that the given blocks form a valid chain on top of this one, given the state before
+	/// this block. This should simply call the block's own `verify_sub_chain`.
+	fn verify_children(
+		&self,
+		pre_state: &Self::PreState,
+		chain: &[Self],
+	) -> Result<(), VerificationError>;
+
+	/// The state after executing this block on top of the given pre-state.
+	fn post_state(&self, pre_state: &Self::PreState) -> Self::PreState;
+}
+
+/// Append a u64 to an encoding buffer.
+pub(super) fn encode_u64(out: &mut Vec<u8>, value: u64) {
+	out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Append a length-prefixed list of u64s to an encoding buffer.
+pub(super) fn encode_u64s(out: &mut Vec<u8>, values: &[u64]) {
+	encode_u64(out, values.len() as u64);
+	for value in values {
+		encode_u64(out, *value);
+	}
+}
+
+/// Reads values back out of an encoded block, in the same order they were written.
+pub(super) struct Decoder<'a> {
+	bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+	pub(super) fn new(bytes: &'a [u8]) -> Self {
+		Decoder { bytes }
+	}
+
+	pub(super) fn u64(&mut self) -> Option<u64> {
+		let (value, rest) = self.bytes.split_first_chunk::<8>()?;
+		self.bytes = rest;
+		Some(u64::from_le_bytes(*value))
+	}
+
+	pub(super) fn u64s(&mut self) -> Option<Vec<u64>> {
+		let len = self.u64()?;
+		// Don't trust a corrupt length to allocate huge amounts of memory
+		if len > (self.bytes.len() / 8) as u64 {
+			return None;
+		}
+		(0..len).map(|_| self.u64()).collect()
+	}
+
+	/// Succeeds only if every byte has been consumed.
+	pub(super) fn finish(self) -> Option<()> {
+		self.bytes.is_empty().then_some(())
+	}
+}
+
+/// A file-backed, append-only store for a single chain of blocks.
+pub struct BlockStore<B: StoredBlock> {
+	/// The data file, opened for reading and appending.
+	file: File,
+	/// The offset and payload length of each block's record, indexed by height.
+	records: Vec<(u64, u32)>,
+	/// The height of each block, indexed by block hash.
+	heights: HashMap<Hash, u64>,
+	/// The most recent block, kept in memory so new blocks can be verified against it.
+	tip: B,
+	/// The state before the tip block was executed.
+	tip_pre_state: B::PreState,
+}
+
+impl<B: StoredBlock> BlockStore<B> {
+	/// Create a brand new store at the given path containing only the genesis block.
+	/// Fails if a file already exists there.
+	pub fn create(path: &Path, genesis: B, genesis_state: B::PreState) -> Result<Self> {
+		genesis.verify_children(&genesis_state, &[])?;
+		let file = OpenOptions::new().read(true).append(true).create_new(true).open(path)?;
+		let mut store = BlockStore {
+			file,
+			records: Vec::new(),
+			heights: HashMap::new(),
+			tip: genesis.clone(),
+			tip_pre_state: genesis_state,
+		};
+		store.write_record(&genesis)?;
+		Ok(store)
+	}
+
+	/// Open an existing store, rebuilding the index and re-verifying the entire chain.
+	///
+	/// The genesis state is needed to verify the chain. If the final record was only partially
+	/// written, it is discarded and, once the rest of the chain has been verified, the file is
+	/// truncated.
+	pub fn open(path: &Path, genesis_state: B::PreState) -> Result<Self> {
+		let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+		let mut bytes = Vec::new();
+		file.read_to_end(&mut bytes)?;
+
+		let mut blocks = Vec::new();
+		let mut records = Vec::new();
+		let mut offset = 0;
+		let mut torn_tail = false;
+		while offset < bytes.len() {
+			match read_record(&bytes[offset..]) {
+				Some(payload) => {
+					let block = B::decode(payload).ok_or(Error::msg(format!(
+						"undecodable block record at offset {}",
+						offset
+					)))?;
+					records.push((offset as u64, payload.len() as u32));
+					blocks.push(block);
+					offset += RECORD_HEADER_SIZE as usize + payload.len();
+				},
+				None if is_torn_tail(&bytes[offset..]) => {
+					// The node crashed while writing this record. Drop it.
+					torn_tail = true;
+					break;
+				},
+				None => {
+					return Err(Error::msg(format!("corrupt block record at offset {}", offset)));
+				},
+			}
+		}
+
+		let genesis = blocks.first().ok_or(Error::msg("block store is empty"))?;
+		genesis.verify_children(&genesis_state, &[])?;
+
+		// Verify each block against its parent, carrying the state along as we go.
+		let mut tip_pre_state = genesis_state;
+		for pair in blocks.windows(2) {
+			pair[0].verify_children(&tip_pre_state, &pair[1..])?;
+			tip_pre_state = pair[0].post_state(&tip_pre_state);
+		}
+
+		if torn_tail {
+			file.set_len(offset as u64)?;
+		}
+		let heights = blocks.iter().map(|b| (b.block_hash(), b.height())).collect();
+		let tip = blocks.pop().expect("there is at least a genesis block");
+
+		Ok(BlockStore { file, records, heights, tip, tip_pre_state })
+	}
+
+	/// Verify the given block on top of the current tip and append it to the store.
+	pub fn append(&mut self, block: B) -> Result<()> {
+		self.tip.verify_children(&self.tip_pre_state, std::slice::from_ref(&block))?;
+		self.write_record(&block)?;
+		self.tip_pre_state = self.tip.post_state(&self.tip_pre_state);
+		self.tip = block;
+		Ok(())
+	}
+
+	/// The most recently appended block.
+	pub fn tip(&self) -> &B {
+		&self.tip
+	}
+
+	/// The number of blocks in the store, including genesis.
+	pub fn len(&self) -> usize {
+		self.records.len()
+	}
+
+	/// Read the block at the given height from disk.
+	pub fn get_by_height(&self, height: u64) -> Result<Option<B>> {
+		let Some(&(offset, len)) = self.records.get(height as usize) else {
+			return Ok(None);
+		};
+		let mut payload = vec![0; len as usize];
+		let mut file = &self.file;
+		file.seek(SeekFrom::Start(offset + RECORD_HEADER_SIZE))?;
+		file.read_exact(&mut payload)?;
+		Ok(Some(B::decode(&payload).ok_or(Error::msg("undecodable block record"))?))
+	}
+
+	/// Read the block with the given hash from disk.
+	pub fn get_by_hash(&self, block_hash: Hash) -> Result<Option<B>> {
+		match self.heights.get(&block_hash) {
+			Some(height) => self.get_by_height(*height),
+			None => Ok(None),
+		}
+	}
+
+	/// Frame the block as a record, write it to the end of the file, and index it.
+	fn write_record(&mut self, block: &B) -> Result<()> {
+		let record = frame_record(block);
+		let offset = self.file.seek(SeekFrom::End(0))?;
+		self.file.write_all(&record)?;
+		self.file.sync_data()?;
+
+		let payload_len = record.len() - RECORD_HEADER_SIZE as usize;
+		self.records.push((offset, payload_len as u32));
+		self.heights.insert(block.block_hash(), block.height());
+		Ok(())
+	}
+}
+
+/// Encode the block and frame it with its length and checksum.
+fn frame_record<B: StoredBlock>(block: &B) -> Vec<u8> {
+	let payload = block.encode();
+	let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+	record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+	record.extend_from_slice(&checksum(&payload).to_le_bytes());
+	record.extend_from_slice(&payload);
+	record
+}
+
+/// Parse the record at the start of the given bytes, returning its payload if the record is
+/// complete and its checksum matches.
+fn read_record(bytes: &[u8]) -> Option<&[u8]> {
+	let (len, rest) = bytes.split_first_chunk::<4>()?;
+	let (expected, rest) = rest.split_first_chunk::<8>()?;
+	let payload = rest.get(..u32::from_le_bytes(*len) as usize)?;
+	(checksum(payload) == u64::from_le_bytes(*expected)).then_some(payload)
+}
+
+/// The 64 bit FNV-1a hash of the given bytes, used as the checksum of each record.
+fn checksum(bytes: &[u8]) -> u64 {
+	bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+		(hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+	})
+}
+
+/// Whether a bad record looks like the final record was only partially written. That is the case
+/// when the record header is incomplete, or it claims more bytes than remain, or the payload runs
+/// exactly to the end of the file but doesn't match its checksum.
+///
+/// A record with a corrupt length can look just like that, even in the middle of the file. So it
+/// only counts as torn if no valid record starts anywhere after it either.
+fn is_torn_tail(bytes: &[u8]) -> bool {
+	let runs_to_end = match bytes.get(..4) {
+		Some(len) => {
+			let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+			RECORD_HEADER_SIZE as usize + len >= bytes.len()
+		},
+		None => true,
+	};
+	runs_to_end && (1..bytes.len()).all(|start| read_record(&bytes[start..]).is_none())
+}
+
+#[cfg(test)]
+use super::{p4_batched_extrinsics, p6_rich_state};
+
+/// A fresh path in the system temp directory. Any leftover file from an earlier run is removed.
+#[cfg(test)]
+fn temp_path(name: &str) -> std::path::PathBuf {
+	let path = std::env::temp_dir().join(format!("bc7-{}-{}", std::process::id(), name));
+	let _ = std::fs::remove_file(&path);
+	path
+}
+
+#[cfg(test)]
+fn p6_store_with_three_children(path: &Path) -> BlockStore<p6_rich_state::Block> {
+	let state = p6_rich_state::State::new(0, 1);
+	let g = p6_rich_state::Block::genesis(&state);
+	let mut store = BlockStore::create(path, g.clone(), state.clone()).unwrap();
+	let mut parent = g;
+	let mut pre_state = state;
+	for extrinsics in [vec![1, 2], vec![3], vec![4, 5, 6]] {
+		let child = parent.child(&pre_state, extrinsics);
+		pre_state = child.post_state(&pre_state);
+		store.append(child.clone()).unwrap();
+		parent = child;
+	}
+	store
+}
+
+#[test]
+fn bc_7_p4_round_trip() {
+	let path = temp_path("p4_round_trip");
+	let g = p4_batched_extrinsics::Block::genesis();
+	let b1 = g.child(vec![1, 2, 3]);
+	let b2 = b1.child(vec![4]);
+	let b3 = b2.child(vec![5, 6]);
+
+	let mut store = BlockStore::create(&path, g.clone(), ()).unwrap();
+	store.append(b1.clone()).unwrap();
+	store.append(b2.clone()).unwrap();
+	store.append(b3.clone()).unwrap();
+	drop(store);
+
+	let store = BlockStore::<p4_batched_extrinsics::Block>::open(&path, ()).unwrap();
+	assert_eq!(store.len(), 4);
+	assert_eq!(store.tip(), &b3);
+	assert_eq!(store.get_by_height(0).unwrap(), Some(g));
+	assert_eq!(store.get_by_hash(b1.block_hash()).unwrap(), Some(b1));
+	assert_eq!(store.get_by_height(4).unwrap(), None);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_open_rejects_broken_parent_link() {
+	let path = temp_path("broken_link");
+	let g = p4_batched_extrinsics::Block::genesis();
+	let b1 = g.child(vec![1, 2, 3]);
+	// A valid block at height two, but on a different fork.
+	let fork = g.child(vec![9]).child(vec![4]);
+
+	let mut store = BlockStore::create(&path, g, ()).unwrap();
+	store.append(b1).unwrap();
+	assert!(store.append(fork.clone()).is_err());
+	// Write it anyway, as a buggy or malicious writer might.
+	store.write_record(&fork).unwrap();
+	drop(store);
+
+	let err = BlockStore::<p4_batched_extrinsics::Block>::open(&path, ()).err().unwrap();
+	assert_eq!(
+		err.downcast_ref::<VerificationError>(),
+		Some(&VerificationError::BadParentHash { height: 2, hash: fork.block_hash() })
+	);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_p6_round_trip() {
+	let path = temp_path("p6_round_trip");
+	let store = p6_store_with_three_children(&path);
+	let tip = store.tip().clone();
+	drop(store);
+
+	let mut store =
+		BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(0, 1)).unwrap();
+	assert_eq!(store.len(), 4);
+	assert_eq!(store.tip(), &tip);
+	assert_eq!(store.get_by_hash(tip.block_hash()).unwrap(), Some(tip.clone()));
+
+	// The reopened store knows the tip's state, so it can keep extending the chain
+	let tip_state = p6_rich_state::State::new(21, 720);
+	store.append(tip.child(&tip_state, vec![7])).unwrap();
+	assert_eq!(store.len(), 5);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_open_with_wrong_genesis_state_fails() {
+	let path = temp_path("wrong_genesis");
+	drop(p6_store_with_three_children(&path));
+
+	let result = BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(5, 5));
+	let err = result.err().unwrap();
+	assert!(matches!(
+		err.downcast_ref::<VerificationError>(),
+		Some(VerificationError::StateRootMismatch { height: 0, .. })
+	));
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_invalid_block_is_not_appended() {
+	let path = temp_path("invalid_append");
+	let mut store = p6_store_with_three_children(&path);
+	let tip = store.tip().clone();
+
+	// Built from the wrong pre-state, so its state root is wrong
+	let bad = tip.child(&p6_rich_state::State::new(0, 0), vec![1]);
+	assert!(store.append(bad).is_err());
+	assert_eq!(store.len(), 4);
+	drop(store);
+
+	let store =
+		BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(0, 1)).unwrap();
+	assert_eq!(store.tip(), &tip);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_recovers_from_truncated_tail() {
+	let path = temp_path("truncated_tail");
+	let store = p6_store_with_three_children(&path);
+	let second_last = store.get_by_height(2).unwrap().unwrap();
+	drop(store);
+
+	// Simulate a crash part way through writing the final record
+	let len = std::fs::metadata(&path).unwrap().len();
+	OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 5).unwrap();
+
+	let store =
+		BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(0, 1)).unwrap();
+	assert_eq!(store.len(), 3);
+	assert_eq!(store.tip(), &second_last);
+	drop(store);
+
+	// The partial record was truncated away, so the file reopens cleanly
+	let store =
+		BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(0, 1)).unwrap();
+	assert_eq!(store.len(), 3);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_corruption_before_tail_is_an_error() {
+	let path = temp_path("corrupt_middle");
+	let store = p6_store_with_three_children(&path);
+	let (offset, _) = store.records[1];
+	drop(store);
+
+	// Flip a byte inside the payload of the block at height one
+	let mut bytes = std::fs::read(&path).unwrap();
+	bytes[(offset + RECORD_HEADER_SIZE) as usize] ^= 0xff;
+	std::fs::write(&path, bytes).unwrap();
+
+	assert!(
+		BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(0, 1)).is_err()
+	);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_corrupt_length_before_tail_is_an_error() {
+	let path = temp_path("corrupt_length");
+	let store = p6_store_with_three_children(&path);
+	let (offset, _) = store.records[1];
+	drop(store);
+
+	// Make the record at height one claim to run past the end of the file. That looks like a torn
+	// write, but there are valid records after it.
+	let mut bytes = std::fs::read(&path).unwrap();
+	bytes[offset as usize + 3] = 0xff;
+	std::fs::write(&path, &bytes).unwrap();
+
+	let err = BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(0, 1))
+		.err()
+		.unwrap();
+	assert_eq!(err.to_string(), format!("corrupt block record at offset {}", offset));
+	assert_eq!(std::fs::read(&path).unwrap(), bytes);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_failed_open_keeps_torn_tail() {
+	let path = temp_path("failed_open_tail");
+	drop(p6_store_with_three_children(&path));
+	let len = std::fs::metadata(&path).unwrap().len();
+	OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 5).unwrap();
+
+	// The chain doesn't verify, so nothing is truncated.
+	let result = BlockStore::<p6_rich_state::Block>::open(&path, p6_rich_state::State::new(5, 5));
+	assert!(result.is_err());
+	assert_eq!(std::fs::metadata(&path).unwrap().len(), len - 5);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_open_long_chain() {
+	let path = temp_path("long_chain");
+	let mut block = p4_batched_extrinsics::Block::genesis();
+	let mut bytes = frame_record(&block);
+	for _ in 0..200_000 {
+		block = block.child(vec![]);
+		bytes.extend(frame_record(&block));
+	}
+	std::fs::write(&path, bytes).unwrap();
+
+	// Far too deep to verify recursively on a test thread's stack.
+	let store = BlockStore::<p4_batched_extrinsics::Block>::open(&path, ()).unwrap();
+	assert_eq!(store.len(), 200_001);
+	assert_eq!(store.tip(), &block);
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bc_7_checksum_is_fnv() {
+	// The checksum is part of the file format, so it must never change.
+	assert_eq!(checksum(b""), 0xcbf2_9ce4_8422_2325);
+	assert_eq!(checksum(b"a"), 0xaf63_dc4c_8601_ec8c);
+	assert_eq!(checksum(b"foobar"), 0x8594_4171_f739_67e8);
+}
+
+#[test]
+fn bc_7_create_refuses_to_overwrite() {
+	let path = temp_path("no_overwrite");
+	let g = p4_batched_extrinsics::Block::genesis();
+	drop(BlockStore::create(&path, g.clone(), ()).unwrap());
+
+	assert!(BlockStore::create(&path, g, ()).is_err());
+	std::fs::remove_file(&path).unwrap();
+}
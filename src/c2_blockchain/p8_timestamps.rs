@@ -0,0 +1,307 @@
+//! Headers now carry a timestamp recording when the block was authored. Time is needed for all
+//! sorts of things: retargeting proof of work difficulty, checking slots in proof of authority,
+//! and time-based locks on funds.
+//!
+//! But there is no global clock in a distributed network. Every node has its own clock and they
+//! all disagree a little. So we can't require that a block's timestamp is exactly right. Instead we
+//! use two loose rules, the same ones Bitcoin uses.
+//!
+//! 1. The timestamp must be strictly greater than the median timestamp of the previous eleven
+//!    blocks. This is called the median time past. Using the median rather than the parent's
+//!    timestamp means a single block with a wild timestamp can't drag the chain's time around,
+//!    and means timestamps don't have to be strictly increasing.
+//! 2. The timestamp must not be too far ahead of the verifying node's own clock. Otherwise a miner
+//!    could claim a time far in the future. Notice that this rule is subjective. A block that is
+//!    rejected now may be accepted later once the node's clock catches up.
+//!
+//! To keep tests deterministic, the clock is passed in rather than read directly from the system.
+
+use super::{ChainHeader, VerificationError};
+use std::{
+	collections::VecDeque,
+	sync::atomic::{AtomicU64, Ordering},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How many ancestors are considered when computing the median time past.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of our own clock a block's timestamp may be, in seconds. Two hours, as in Bitcoin.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+/// A source of the current time, in seconds.
+pub trait Clock {
+	fn now(&self) -> u64;
+}
+
+/// The real clock. Returns the number of seconds since the unix epoch.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+	}
+}
+
+/// A clock that only moves when it is told to. Useful in tests and simulations.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+	now: AtomicU64,
+}
+
+impl ManualClock {
+	/// Create a clock showing the given time.
+	pub fn new(now: u64) -> Self {
+		ManualClock { now: AtomicU64::new(now) }
+	}
+
+	/// Set the clock to the given time.
+	pub fn set(&self, now: u64) {
+		self.now.store(now, Ordering::SeqCst);
+	}
+
+	/// Move the clock forward by the given number of seconds.
+	pub fn advance(&self, seconds: u64) {
+		self.now.fetch_add(seconds, Ordering::SeqCst);
+	}
+
+	/// Move the clock forward to the given time. Does nothing if it already shows a later time.
+	pub fn advance_to(&self, now: u64) {
+		self.now.fetch_max(now, Ordering::SeqCst);
+	}
+}
+
+impl Clock for ManualClock {
+	fn now(&self) -> u64 {
+		self.now.load(Ordering::SeqCst)
+	}
+}
+
+/// The median of the given timestamps. Zero if there are none.
+fn median(timestamps: impl Iterator<Item = u64>) -> u64 {
+	let mut timestamps: Vec<u64> = timestamps.collect();
+	timestamps.sort_unstable();
+	timestamps.get(timestamps.len() / 2).copied().unwrap_or_default()
+}
+
+/// The median timestamp of the last `MEDIAN_TIME_SPAN` of the given ancestors, which should be
+/// ordered from oldest to newest. A new child of the last ancestor must have a later timestamp.
+pub fn median_time_past<H: ChainHeader>(ancestors: &[H]) -> u64 {
+	let start = ancestors.len().saturating_sub(MEDIAN_TIME_SPAN);
+	median(ancestors[start..].iter().map(|h| h.timestamp()))
+}
+
+/// Check a single header's timestamp, given the median time past of its ancestors.
+fn verify_timestamp<H: ChainHeader, C: Clock>(
+	median_time_past: u64,
+	header: &H,
+	clock: &C,
+) -> Result<(), VerificationError> {
+	let (height, hash) = (header.height(), header.block_hash());
+	if header.timestamp() <= median_time_past {
+		return Err(VerificationError::TimestampTooOld { height, hash });
+	}
+	if header.timestamp() > clock.now().saturating_add(MAX_FUTURE_DRIFT) {
+		return Err(VerificationError::TimestampInFuture { height, hash });
+	}
+	Ok(())
+}
+
+/// Verify the timestamps of the headers in `chain`, which extends `ancestors`.
+///
+/// Both slices are ordered from oldest to newest. The ancestors are assumed to be valid already,
+/// and only the last `MEDIAN_TIME_SPAN` of them are needed. This checks only timestamps. The
+/// blocks from parts 4 and 6 have a `verify_sub_chain_with_clock` that checks both.
+pub fn verify_timestamps<H: ChainHeader, C: Clock>(
+	ancestors: &[H],
+	chain: &[H],
+	clock: &C,
+) -> Result<(), VerificationError> {
+	let start = ancestors.len().saturating_sub(MEDIAN_TIME_SPAN);
+	let mut window: VecDeque<u64> = ancestors[start..].iter().map(|h| h.timestamp()).collect();
+	for header in chain {
+		verify_timestamp(median(window.iter().copied()), header, clock)?;
+		window.push_back(header.timestamp());
+		if window.len() > MEDIAN_TIME_SPAN {
+			window.pop_front();
+		}
+	}
+	Ok(())
+}
+
+/// Check the timestamps of a chain whose structure has been verified separately, and combine the
+/// two results. `ancestors` are the headers before `parent`, oldest first, and `chain` is the
+/// headers of the blocks built on `parent`. If both checks fail, the failure nearest the start of
+/// the chain is reported, just as if every block had been checked completely before the next.
+pub(super) fn verify_with_timestamps<H: ChainHeader + Clone, C: Clock>(
+	structure: Result<(), VerificationError>,
+	ancestors: &[H],
+	parent: &H,
+	chain: &[H],
+	clock: &C,
+) -> Result<(), VerificationError> {
+	let start = ancestors.len().saturating_sub(MEDIAN_TIME_SPAN - 1);
+	let mut window = ancestors[start..].to_vec();
+	window.push(parent.clone());
+	let timestamps = verify_timestamps(&window, chain, clock);
+	match (structure, timestamps) {
+		(Err(structure), Err(timestamps)) if timestamps.height() < structure.height() => {
+			Err(timestamps)
+		},
+		(structure, timestamps) => structure.and(timestamps),
+	}
+}
+
+#[cfg(test)]
+use super::{
+	p4_batched_extrinsics::{Block, Header},
+	p6_rich_state,
+};
+
+/// A chain of headers from genesis with the given timestamps after genesis.
+#[cfg(test)]
+fn chain_with_timestamps(timestamps: &[u64]) -> Vec<Header> {
+	let mut blocks = vec![Block::genesis()];
+	for timestamp in timestamps {
+		let child = blocks.last().unwrap().child_at(vec![], *timestamp);
+		blocks.push(child);
+	}
+	blocks.into_iter().map(|b| b.header).collect()
+}
+
+#[test]
+fn bc_8_default_children_are_valid() {
+	let mut blocks = vec![Block::genesis()];
+	for _ in 0..20 {
+		blocks.push(blocks.last().unwrap().child(vec![1]));
+	}
+	let headers: Vec<Header> = blocks.into_iter().map(|b| b.header).collect();
+
+	assert_eq!(verify_timestamps(&headers[..1], &headers[1..], &ManualClock::new(0)), Ok(()));
+}
+
+#[test]
+fn bc_8_median_uses_last_eleven_ancestors() {
+	// The first ancestors have huge timestamps, but they fall out of the window.
+	let mut timestamps = vec![1000, 1000, 1000];
+	timestamps.extend(1..=11);
+	let headers = chain_with_timestamps(&timestamps);
+
+	assert_eq!(median_time_past(&headers), 6);
+}
+
+#[test]
+fn bc_8_timestamp_must_exceed_median() {
+	let headers = chain_with_timestamps(&[10, 20, 30, 40, 50]);
+	// Genesis is at time zero, so the median of the six ancestors is 30.
+	assert_eq!(median_time_past(&headers), 30);
+	let clock = ManualClock::new(100);
+
+	let at_median = chain_with_timestamps(&[10, 20, 30, 40, 50, 30]);
+	assert_eq!(
+		verify_timestamps(&headers, &at_median[6..], &clock),
+		Err(VerificationError::TimestampTooOld { height: 6, hash: at_median[6].block_hash() })
+	);
+
+	// Timestamps need not increase, they just need to beat the median.
+	let before_parent = chain_with_timestamps(&[10, 20, 30, 40, 50, 31]);
+	assert_eq!(verify_timestamps(&headers, &before_parent[6..], &clock), Ok(()));
+}
+
+#[test]
+fn bc_8_timestamp_must_not_be_too_far_in_future() {
+	let headers = chain_with_timestamps(&[10 + MAX_FUTURE_DRIFT]);
+	let clock = ManualClock::new(9);
+
+	assert!(matches!(
+		verify_timestamps(&headers[..1], &headers[1..], &clock),
+		Err(VerificationError::TimestampInFuture { height: 1, .. })
+	));
+
+	// Once our clock catches up, the same block becomes acceptable.
+	clock.advance(1);
+	assert_eq!(verify_timestamps(&headers[..1], &headers[1..], &clock), Ok(()));
+}
+
+#[test]
+fn bc_8_window_slides_through_chain() {
+	// Each header is checked against the ones before it in the chain being verified, not just
+	// the ancestors that were passed in.
+	let headers = chain_with_timestamps(&[5, 6, 7, 8, 9, 3]);
+	let clock = ManualClock::new(100);
+
+	assert!(matches!(
+		verify_timestamps(&headers[..1], &headers[1..], &clock),
+		Err(VerificationError::TimestampTooOld { height: 6, .. })
+	));
+}
+
+#[test]
+fn bc_8_system_clock_is_after_2020() {
+	assert!(SystemClock.now() > 1_577_836_800);
+}
+
+#[test]
+fn bc_8_p4_chain_rejects_backdated_block() {
+	let mut blocks = vec![Block::genesis()];
+	for timestamp in [10, 20, 30, 40, 50, 25] {
+		let child = blocks.last().unwrap().child_at(vec![timestamp], timestamp);
+		blocks.push(child);
+	}
+	let clock = ManualClock::new(100);
+
+	// The structure is fine, so the plain verification accepts the backdated block.
+	assert_eq!(blocks[0].verify_sub_chain(&blocks[1..]), Ok(()));
+	assert_eq!(
+		blocks[0].verify_sub_chain_with_clock(&[], &blocks[1..], &clock),
+		Err(VerificationError::TimestampTooOld { height: 6, hash: blocks[6].header.block_hash() })
+	);
+
+	// Starting part way along, the earlier headers are passed in as ancestors.
+	let ancestors: Vec<Header> = blocks[..3].iter().map(|b| b.header.clone()).collect();
+	assert_eq!(blocks[3].verify_sub_chain_with_clock(&ancestors, &blocks[4..6], &clock), Ok(()));
+	assert!(matches!(
+		blocks[3].verify_sub_chain_with_clock(&ancestors, &blocks[4..], &clock),
+		Err(VerificationError::TimestampTooOld { height: 6, .. })
+	));
+}
+
+#[test]
+fn bc_8_p6_chain_rejects_future_block() {
+	let state = p6_rich_state::State::new(0, 1);
+	let g = p6_rich_state::Block::genesis(&state);
+	let b1 = g.child(&state, vec![2]);
+	let b2 = b1.child_at(&p6_rich_state::State::new(2, 2), vec![3], 1_000 + MAX_FUTURE_DRIFT);
+	let chain = [b1, b2];
+	let clock = ManualClock::new(999);
+
+	assert_eq!(g.verify_sub_chain(&state, &chain), Ok(()));
+	assert!(matches!(
+		g.verify_sub_chain_with_clock(&state, &[], &chain, &clock),
+		Err(VerificationError::TimestampInFuture { height: 2, .. })
+	));
+	clock.advance(1);
+	assert_eq!(g.verify_sub_chain_with_clock(&state, &[], &chain, &clock), Ok(()));
+}
+
+#[test]
+fn bc_8_earliest_failure_is_reported() {
+	let g = Block::genesis();
+	let b1 = g.child_at(vec![1], 0);
+	let mut b2 = b1.child(vec![2]);
+	b2.body = vec![3];
+	let clock = ManualClock::new(100);
+
+	// Block one is backdated and block two has the wrong body. Block one fails first.
+	assert!(matches!(
+		g.verify_sub_chain_with_clock(&[], &[b1.clone(), b2.clone()], &clock),
+		Err(VerificationError::TimestampTooOld { height: 1, .. })
+	));
+	let b1 = g.child(vec![1]);
+	let mut b2 = b1.child_at(vec![2], 0);
+	b2.body = vec![3];
+	assert!(matches!(
+		g.verify_sub_chain_with_clock(&[], &[b1, b2], &clock),
+		Err(VerificationError::ExtrinsicsRootMismatch { height: 2, .. })
+	));
+}
@@ -0,0 +1,247 @@
+//! In part 6 the chain tracked the sum and product of its extrinsics, and the module docs mentioned
+//! that we could track many other things instead. Because the rich-state blocks are written against
+//! the `Accumulator` trait, any of the states in this module can be plugged into the same chain
+//! without touching the block logic.
+//!
+//! Notice that the states here are not all the same size. A sum is a single number no matter how
+//! long the chain gets. A mean needs two. An exact median would need every extrinsic ever applied,
+//! which is exactly the kind of unbounded state growth real chains try to avoid. So our median only
+//! looks at a bounded window of the most recent extrinsics.
+//!
+//! The arithmetic wraps on overflow rather than panicking. A block that would overflow is still a
+//! valid block, and every node must agree on what the resulting state is.
+
+use super::p6_rich_state::Accumulator;
+use crate::hash;
+use std::collections::VecDeque;
+
+type Hash = u64;
+
+/// The sum of all extrinsics.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Sum(pub u64);
+
+impl Accumulator for Sum {
+	fn initial() -> Self {
+		Sum(0)
+	}
+
+	fn apply(&mut self, extrinsic: u64) {
+		self.0 = self.0.wrapping_add(extrinsic);
+	}
+
+	fn commitment(&self) -> Hash {
+		hash(self)
+	}
+}
+
+/// The product of all extrinsics.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Product(pub u64);
+
+impl Accumulator for Product {
+	fn initial() -> Self {
+		Product(1)
+	}
+
+	fn apply(&mut self, extrinsic: u64) {
+		self.0 = self.0.wrapping_mul(extrinsic);
+	}
+
+	fn commitment(&self) -> Hash {
+		hash(self)
+	}
+}
+
+/// The smallest extrinsic seen so far, or None if there have been none.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Min(pub Option<u64>);
+
+impl Accumulator for Min {
+	fn initial() -> Self {
+		Min(None)
+	}
+
+	fn apply(&mut self, extrinsic: u64) {
+		self.0 = Some(self.0.map_or(extrinsic, |min| min.min(extrinsic)));
+	}
+
+	fn commitment(&self) -> Hash {
+		hash(self)
+	}
+}
+
+/// The largest extrinsic seen so far, or None if there have been none.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Max(pub Option<u64>);
+
+impl Accumulator for Max {
+	fn initial() -> Self {
+		Max(None)
+	}
+
+	fn apply(&mut self, extrinsic: u64) {
+		self.0 = Some(self.0.map_or(extrinsic, |max| max.max(extrinsic)));
+	}
+
+	fn commitment(&self) -> Hash {
+		hash(self)
+	}
+}
+
+/// The mean of all extrinsics. Only the count and the total are stored, so the state stays small
+/// however long the chain grows.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RunningMean {
+	count: u64,
+	total: u128,
+}
+
+impl RunningMean {
+	/// The mean of the extrinsics so far, rounded down, or None if there have been none.
+	pub fn mean(&self) -> Option<u64> {
+		(self.count > 0).then(|| (self.total / self.count as u128) as u64)
+	}
+}
+
+impl Accumulator for RunningMean {
+	fn initial() -> Self {
+		RunningMean { count: 0, total: 0 }
+	}
+
+	fn apply(&mut self, extrinsic: u64) {
+		self.count = self.count.wrapping_add(1);
+		self.total = self.total.wrapping_add(extrinsic as u128);
+	}
+
+	fn commitment(&self) -> Hash {
+		hash(self)
+	}
+}
+
+/// The median of the most recent `N` extrinsics.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Median<const N: usize> {
+	window: VecDeque<u64>,
+}
+
+impl<const N: usize> Median<N> {
+	/// The median of the extrinsics in the window, or None if the window is empty. When the window
+	/// holds an even number of extrinsics the upper of the two middle values is used, just like
+	/// the median time past in part 8.
+	pub fn median(&self) -> Option<u64> {
+		let mut sorted: Vec<u64> = self.window.iter().copied().collect();
+		sorted.sort_unstable();
+		sorted.get(sorted.len() / 2).copied()
+	}
+}
+
+impl<const N: usize> Accumulator for Median<N> {
+	fn initial() -> Self {
+		Median { window: VecDeque::with_capacity(N) }
+	}
+
+	fn apply(&mut self, extrinsic: u64) {
+		self.window.push_back(extrinsic);
+		if self.window.len() > N {
+			self.window.pop_front();
+		}
+	}
+
+	fn commitment(&self) -> Hash {
+		hash(self)
+	}
+}
+
+#[cfg(test)]
+use super::p6_rich_state::Block;
+
+/// Build a chain from the initial state with one block per batch of extrinsics. Returns the
+/// blocks, including genesis, and the state after the last one.
+#[cfg(test)]
+fn build_chain<S: Accumulator>(batches: &[Vec<u64>]) -> (Vec<Block>, S) {
+	let mut state = S::initial();
+	let mut blocks = vec![Block::genesis(&state)];
+	for batch in batches {
+		let child = blocks.last().unwrap().child(&state, batch.clone());
+		for extrinsic in batch {
+			state.apply(*extrinsic);
+		}
+		blocks.push(child);
+	}
+	(blocks, state)
+}
+
+#[cfg(test)]
+fn batches() -> Vec<Vec<u64>> {
+	vec![vec![5, 3], vec![], vec![8, 1, 9], vec![4]]
+}
+
+#[test]
+fn bc_9_sum_and_product() {
+	let (blocks, sum) = build_chain::<Sum>(&batches());
+	assert_eq!(sum, Sum(30));
+	assert!(blocks[0].verify_sub_chain(&Sum::initial(), &blocks[1..]).is_ok());
+
+	let (blocks, product) = build_chain::<Product>(&batches());
+	assert_eq!(product, Product(4320));
+	assert!(blocks[0].verify_sub_chain(&Product::initial(), &blocks[1..]).is_ok());
+}
+
+#[test]
+fn bc_9_min_and_max() {
+	let (blocks, min) = build_chain::<Min>(&batches());
+	assert_eq!(min, Min(Some(1)));
+	assert!(blocks[0].verify_sub_chain(&Min::initial(), &blocks[1..]).is_ok());
+
+	let (blocks, max) = build_chain::<Max>(&batches());
+	assert_eq!(max, Max(Some(9)));
+	assert!(blocks[0].verify_sub_chain(&Max::initial(), &blocks[1..]).is_ok());
+
+	assert_eq!(Min::initial(), Min(None));
+	assert_eq!(Max::initial(), Max(None));
+}
+
+#[test]
+fn bc_9_running_mean() {
+	let (blocks, mean) = build_chain::<RunningMean>(&batches());
+	assert_eq!(mean.mean(), Some(5));
+	assert_eq!(RunningMean::initial().mean(), None);
+	assert!(blocks[0].verify_sub_chain(&RunningMean::initial(), &blocks[1..]).is_ok());
+}
+
+#[test]
+fn bc_9_median_window_is_bounded() {
+	let (blocks, median) = build_chain::<Median<3>>(&batches());
+	// Only the last three extrinsics, 1, 9 and 4, are still in the window.
+	assert_eq!(median.window.len(), 3);
+	assert_eq!(median.median(), Some(4));
+	assert!(blocks[0].verify_sub_chain(&Median::<3>::initial(), &blocks[1..]).is_ok());
+
+	// With a wider window the early extrinsics still count.
+	let (_, median) = build_chain::<Median<100>>(&batches());
+	assert_eq!(median.median(), Some(5));
+}
+
+#[test]
+fn bc_9_arithmetic_wraps() {
+	let mut sum = Sum(u64::MAX);
+	sum.apply(2);
+	assert_eq!(sum, Sum(1));
+
+	let mut product = Product(u64::MAX);
+	product.apply(2);
+	assert_eq!(product, Product(u64::MAX - 1));
+}
+
+#[test]
+fn bc_9_wrong_accumulator_does_not_verify() {
+	// The same blocks checked against a different kind of state fail as soon as the two states
+	// diverge, even though both start out empty.
+	let (blocks, _) = build_chain::<Max>(&batches());
+	assert!(blocks[0].verify_sub_chain(&Min::initial(), &blocks[1..]).is_err());
+
+	// And a chain built with a bounded median depends on the window size.
+	let (blocks, _) = build_chain::<Median<2>>(&batches());
+	assert!(blocks[0].verify_sub_chain(&Median::<3>::initial(), &blocks[1..]).is_err());
+}
@@ -8,11 +8,27 @@ mod p3_poa; // exercise: dictator is a special case of poa. Create dictator in t
 mod p4_even_only;
 mod p5_interleave;
 mod p6_forking;
+mod p7_staking;
+mod p8_governance;
+mod p9_difficulty_adjustment;
+mod p10_signatures;
+mod p11_vrf_slots;
+mod p12_multisig;
 
 // Re-export some individual consensus engines so they can be be re-used in the Client chapter.
 pub use p1_pow::Pow;
 pub use p3_poa::SimplePoa;
 
+use crate::{
+    c2_blockchain::{ChainHeader, Clock, ManualClock, SystemClock, VerificationError},
+    hash,
+    merkle::MerkleProof,
+};
+use std::{
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 type Hash = u64;
 
 /// A Block Header similar to prior chapters of this tutorial.
@@ -27,10 +43,77 @@ type Hash = u64;
 pub struct Header<Digest> {
     parent: Hash,
     height: u64,
+    timestamp: u64,
     state_root: Hash,
     extrinsics_root: Hash,
     consensus_digest: Digest,
 }
+
+impl<Digest> Header<Digest> {
+    /// Verify that the given extrinsic is included in this header's block using a Merkle proof.
+    /// The extrinsics root is expected to be built with `crate::merkle::merkle_root`.
+    pub fn verify_extrinsic<T: std::hash::Hash>(&self, extrinsic: &T, proof: &MerkleProof) -> bool {
+        proof.verify(self.extrinsics_root, extrinsic)
+    }
+}
+
+/// Attach a consensus digest to a partial header.
+fn with_digest<Digest>(partial_header: Header<()>, digest: Digest) -> Header<Digest> {
+    Header {
+        parent: partial_header.parent,
+        height: partial_header.height,
+        timestamp: partial_header.timestamp,
+        state_root: partial_header.state_root,
+        extrinsics_root: partial_header.extrinsics_root,
+        consensus_digest: digest,
+    }
+}
+
+/// A partial header at the given height, ready to be sealed.
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+    Header {
+        parent: height.wrapping_sub(1),
+        height,
+        timestamp: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+/// The keypairs of `n` authorities, derived from the seeds `0..n`.
+#[cfg(test)]
+fn authority_keys<S: p10_signatures::SignatureScheme>(
+    n: u64,
+) -> Vec<(S::SecretKey, S::PublicKey)> {
+    (0..n).map(S::keypair).collect()
+}
+
+/// A parent digest to pass to engines that don't look at it, such as the signature-based Proof of
+/// Authority engines.
+#[cfg(test)]
+fn unchecked_parent_digest<Digest: Default>() -> Digest {
+    Digest::default()
+}
+
+impl<Digest: std::hash::Hash> ChainHeader for Header<Digest> {
+    fn block_hash(&self) -> Hash {
+        hash(self)
+    }
+
+    fn parent(&self) -> Hash {
+        self.parent
+    }
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
 /// A Consensus Engine. Responsible for Sealing blocks and verifying their seals
 ///
 /// Consensus exists independently of execution logic, and therefore operates
@@ -64,18 +147,22 @@ pub trait Consensus {
         parent_digest: &Self::Digest,
         partial_header: Header<()>,
     ) -> Option<Header<Self::Digest>>;
-    // NOTE TO SELF. For slot-based PoA etc, just look at the system time. It's what real-world aura does
+    // Slot-based engines need the time to seal. Rather than looking at the system time directly,
+    // as real-world Aura does, they are given a `SlotClock` so that they can be tested.
 
     /// Verify that all the given headers are valid according to the consensus rules.
     ///
     /// This method assumes that the parent_digest is valid, and verifies all the
     /// following headers relative to the given parent digest. This is a provided method
     /// on the trait, so it must be general enough to work for any specific consensus engine.
+    ///
+    /// Consensus only looks at the seals, so the first header that fails `validate` should be
+    /// reported as `VerificationError::SealInvalid`.
     fn verify_sub_chain(
         &self,
         parent_digest: &Self::Digest,
         chain: &[Header<Self::Digest>],
-    ) -> bool {
+    ) -> Result<(), VerificationError> {
         todo!("Exercise 1")
     }
 
@@ -111,3 +198,72 @@ pub enum ConsensusAuthority {
     Bob,
     Charlie,
 }
+
+/// A clock that slot-based consensus engines can wait on.
+///
+/// Slot-based engines need the time both to decide when they may author and to reject blocks
+/// claiming slots that haven't started yet. They read it from the same `Clock` that checks block
+/// timestamps in chapter 2, so times are in seconds and tests can use its `ManualClock`. The only
+/// addition is waiting for a slot to start before sealing.
+pub trait SlotClock: Clock {
+    /// Wait until the clock shows at least the given time.
+    fn wait_until(&self, time: u64);
+}
+
+impl SlotClock for SystemClock {
+    fn wait_until(&self, time: u64) {
+        let target = UNIX_EPOCH + Duration::from_secs(time);
+        if let Ok(remaining) = target.duration_since(SystemTime::now()) {
+            thread::sleep(remaining);
+        }
+    }
+}
+
+/// Waiting on a manual clock moves it forward immediately, as if the time had passed.
+impl SlotClock for ManualClock {
+    fn wait_until(&self, time: u64) {
+        self.advance_to(time);
+    }
+}
+
+/// How time is divided into slots. All times are in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotConfig {
+    /// How long each slot lasts. Never zero.
+    slot_duration: u64,
+    /// When slot zero starts.
+    genesis_time: u64,
+    /// How far ahead of our own clock a slot may start and still be accepted, to allow for clocks
+    /// that disagree a little.
+    max_drift: u64,
+}
+
+impl SlotConfig {
+    /// Slots of the given duration, starting at the genesis time.
+    ///
+    /// Panics if the slot duration is zero.
+    pub fn new(slot_duration: u64, genesis_time: u64, max_drift: u64) -> Self {
+        assert!(slot_duration > 0, "slot duration must be positive");
+        SlotConfig {
+            slot_duration,
+            genesis_time,
+            max_drift,
+        }
+    }
+
+    /// The slot in progress at the given time. Times before genesis are in slot zero.
+    pub fn slot_at(&self, time: u64) -> u64 {
+        time.saturating_sub(self.genesis_time) / self.slot_duration
+    }
+
+    /// The time at which the given slot starts.
+    pub fn slot_start(&self, slot: u64) -> u64 {
+        self.genesis_time
+            .saturating_add(slot.saturating_mul(self.slot_duration))
+    }
+
+    /// Whether the given slot has started, allowing for the maximum drift.
+    pub fn has_started<C: SlotClock>(&self, slot: u64, clock: &C) -> bool {
+        self.slot_start(slot) <= clock.now().saturating_add(self.max_drift)
+    }
+}
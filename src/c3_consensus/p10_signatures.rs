@@ -0,0 +1,417 @@
+//! The identity-based engines earlier in this chapter "sign" a header by attaching a
+//! `ConsensusAuthority` value to it. That keeps the focus on consensus logic, but it means anyone
+//! can put any authority's name on any header. Real identity-based consensus relies on digital
+//! signatures, which only the holder of a secret key can produce, but anyone can check against the
+//! matching public key.
+//!
+//! This module puts signatures behind a `SignatureScheme` trait, so engines can be written once and
+//! used with any scheme. Three schemes are provided.
+//! * `Schnorr` is a toy Schnorr signature scheme for teaching. It works in a group of integers
+//!   modulo a prime that fits in 64 bits, so the arithmetic only needs `u128` and is easy to
+//!   follow. It is NOT secure. Pollard's rho finds a discrete logarithm in a group this size in
+//!   about 2^32 steps, which recovers any authority's secret key on a laptop. The hashes come from
+//!   the standard library's SipHash, which is not a cryptographic hash function either.
+//! * `InsecureTestScheme` "signs" by hashing the message with the key. It is fast and deterministic,
+//!   which is handy in tests, but the public key is the secret key so anyone can forge anything.
+//! * `Ed25519`, available with the `ed25519` cargo feature, wraps the `ed25519-dalek` crate. It is
+//!   the same idea as `Schnorr`, but over an elliptic curve group large enough to be secure, and it
+//!   is the one to use for anything that matters.
+//!
+//! A signature must commit to everything about the header except the signature itself, so
+//! authorities sign the hash of the header before the seal is attached.
+
+use super::{with_digest, Consensus, Header};
+use crate::hash;
+use std::fmt::Debug;
+
+#[cfg(test)]
+use super::{authority_keys, partial_header, unchecked_parent_digest};
+
+type Hash = u64;
+
+/// A digital signature scheme.
+pub trait SignatureScheme {
+    type SecretKey: Clone + Debug;
+    type PublicKey: Clone + Debug + Eq + std::hash::Hash;
+    type Signature: Clone + Debug + Eq + std::hash::Hash;
+
+    /// Deterministically derive a keypair from a seed. In practice the seed must be random and
+    /// kept secret. Fixed seeds make tests reproducible.
+    fn keypair(seed: u64) -> (Self::SecretKey, Self::PublicKey);
+
+    /// The public key that matches the given secret key.
+    fn public_key(secret: &Self::SecretKey) -> Self::PublicKey;
+
+    /// Sign a message hash.
+    fn sign(secret: &Self::SecretKey, message: Hash) -> Self::Signature;
+
+    /// Check that the signature was made over the message by the holder of the public key.
+    fn verify(public: &Self::PublicKey, message: Hash, signature: &Self::Signature) -> bool;
+}
+
+/// The hash of a header with its seal removed. This is what authorities sign.
+pub fn pre_seal_hash<Digest>(header: &Header<Digest>) -> Hash {
+    hash(&Header {
+        parent: header.parent,
+        height: header.height,
+        timestamp: header.timestamp,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        consensus_digest: (),
+    })
+}
+
+/// The prime modulus of the group. It is a safe prime, `P = 2Q + 1` with `Q` also prime.
+pub(super) const P: u64 = 0x7fff_ffff_ffff_ee27;
+/// The prime order of the subgroup we work in.
+pub(super) const Q: u64 = (P - 1) / 2;
+/// A generator of the subgroup of order `Q`. Any square other than one would do.
+pub(super) const G: u64 = 4;
+
+pub(super) fn mul_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    (a as u128 * b as u128 % modulus as u128) as u64
+}
+
+pub(super) fn add_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 + b as u128) % modulus as u128) as u64
+}
+
+/// Raise `base` to the power `exponent` modulo `P` by repeated squaring.
+pub(super) fn pow_mod(mut base: u64, mut exponent: u64) -> u64 {
+    let mut result = 1;
+    base %= P;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul_mod(result, base, P);
+        }
+        base = mul_mod(base, base, P);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Hash some values to a nonzero scalar, that is, a nonzero number modulo `Q`.
+pub(super) fn hash_to_scalar<T: std::hash::Hash>(t: &T) -> u64 {
+    hash(t) % (Q - 1) + 1
+}
+
+/// Toy Schnorr signatures over a prime-order subgroup of the integers modulo a 63-bit prime. They
+/// show how the scheme works, but can be forged. See the module docs.
+///
+/// The secret key is a scalar `x` and the public key is `G^x`. To sign, pick a nonce `k` and
+/// compute the commitment `R = G^k`, the challenge `e = H(R, public key, message)` and the
+/// response `s = k + e * x`. The signature is `(R, s)`, and it verifies if `G^s = R * public^e`.
+///
+/// The nonce must never be reused for two different messages, because the secret key can be
+/// solved for from the two signatures. Rather than trusting a random number generator, the nonce is
+/// derived by hashing the secret key with the message, as Ed25519 does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Schnorr;
+
+/// A Schnorr signature, made of the commitment `R` and the response `s`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SchnorrSignature {
+    commitment: u64,
+    response: u64,
+}
+
+impl SignatureScheme for Schnorr {
+    type SecretKey = u64;
+    type PublicKey = u64;
+    type Signature = SchnorrSignature;
+
+    fn keypair(seed: u64) -> (u64, u64) {
+        let secret = hash_to_scalar(&("schnorr secret key", seed));
+        (secret, Self::public_key(&secret))
+    }
+
+    fn public_key(secret: &u64) -> u64 {
+        pow_mod(G, *secret)
+    }
+
+    fn sign(secret: &u64, message: Hash) -> SchnorrSignature {
+        let public = Self::public_key(secret);
+        let nonce = hash_to_scalar(&("schnorr nonce", secret, message));
+        let commitment = pow_mod(G, nonce);
+        let challenge = hash_to_scalar(&(commitment, public, message));
+        let response = add_mod(nonce, mul_mod(challenge, *secret, Q), Q);
+        SchnorrSignature {
+            commitment,
+            response,
+        }
+    }
+
+    fn verify(public: &u64, message: Hash, signature: &SchnorrSignature) -> bool {
+        let SchnorrSignature {
+            commitment,
+            response,
+        } = *signature;
+        // Both values must be in range, and the commitment must be in the subgroup.
+        if commitment == 0 || commitment >= P || response >= Q || pow_mod(commitment, Q) != 1 {
+            return false;
+        }
+        let challenge = hash_to_scalar(&(commitment, *public, message));
+        pow_mod(G, response) == mul_mod(commitment, pow_mod(*public, challenge), P)
+    }
+}
+
+/// A deterministic scheme for tests. It is NOT secure. The public key is the same as the secret key,
+/// so anyone who can check a signature can also forge one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InsecureTestScheme;
+
+impl SignatureScheme for InsecureTestScheme {
+    type SecretKey = u64;
+    type PublicKey = u64;
+    type Signature = u64;
+
+    fn keypair(seed: u64) -> (u64, u64) {
+        (seed, seed)
+    }
+
+    fn public_key(secret: &u64) -> u64 {
+        *secret
+    }
+
+    fn sign(secret: &u64, message: Hash) -> u64 {
+        hash(&(*secret, message))
+    }
+
+    fn verify(public: &u64, message: Hash, signature: &u64) -> bool {
+        hash(&(*public, message)) == *signature
+    }
+}
+
+/// Ed25519 signatures from the `ed25519-dalek` crate.
+///
+/// Keys are derived from the seed directly, so `keypair` is only fit for tests. Real keys must come
+/// from a secure random number generator.
+#[cfg(feature = "ed25519")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ed25519;
+
+/// An Ed25519 signature as raw bytes, so that it can be hashed as part of a header.
+#[cfg(feature = "ed25519")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ed25519Signature(pub [u8; 64]);
+
+#[cfg(feature = "ed25519")]
+impl SignatureScheme for Ed25519 {
+    type SecretKey = ed25519_dalek::SigningKey;
+    type PublicKey = ed25519_dalek::VerifyingKey;
+    type Signature = Ed25519Signature;
+
+    fn keypair(seed: u64) -> (Self::SecretKey, Self::PublicKey) {
+        let mut bytes = [0; 32];
+        bytes[..8].copy_from_slice(&seed.to_le_bytes());
+        let secret = ed25519_dalek::SigningKey::from_bytes(&bytes);
+        let public = secret.verifying_key();
+        (secret, public)
+    }
+
+    fn public_key(secret: &Self::SecretKey) -> Self::PublicKey {
+        secret.verifying_key()
+    }
+
+    fn sign(secret: &Self::SecretKey, message: Hash) -> Ed25519Signature {
+        use ed25519_dalek::Signer;
+        Ed25519Signature(secret.sign(&message.to_le_bytes()).to_bytes())
+    }
+
+    fn verify(public: &Self::PublicKey, message: Hash, signature: &Ed25519Signature) -> bool {
+        let signature = ed25519_dalek::Signature::from_bytes(&signature.0);
+        public
+            .verify_strict(&message.to_le_bytes(), &signature)
+            .is_ok()
+    }
+}
+
+/// The digest of a signed header. It names the authority, by their index in the authority set, and
+/// carries their signature over the pre-seal header hash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SignedDigest<Signature> {
+    pub authority: usize,
+    pub signature: Signature,
+}
+
+/// Proof of Authority with signatures. Any one of the authorities may sign a block, like
+/// `SimplePoa`, but now nobody else can. With a single authority this is the dictator engine.
+pub struct SignedPoa<S: SignatureScheme> {
+    /// The public keys of the authorities.
+    pub authorities: Vec<S::PublicKey>,
+    /// The secret key this node signs with, if it is an authority. Nodes that only follow the
+    /// chain don't have one, and can't seal blocks.
+    pub local_key: Option<S::SecretKey>,
+}
+
+impl<S: SignatureScheme> Consensus for SignedPoa<S> {
+    type Digest = SignedDigest<S::Signature>;
+
+    /// Check that the named authority exists and signed this header.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        self.authorities
+            .get(digest.authority)
+            .is_some_and(|public| S::verify(public, pre_seal_hash(header), &digest.signature))
+    }
+
+    /// Sign with the local key. Returns None if there is no local key or it doesn't belong to an
+    /// authority.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        let secret = self.local_key.as_ref()?;
+        let public = S::public_key(secret);
+        let authority = self.authorities.iter().position(|a| *a == public)?;
+        let signature = S::sign(secret, hash(&partial_header));
+        Some(with_digest(
+            partial_header,
+            SignedDigest {
+                authority,
+                signature,
+            },
+        ))
+    }
+
+    fn human_name() -> String {
+        "Signed Proof of Authority".into()
+    }
+}
+
+/// A signed PoA engine with three authorities, whose local key is the given authority's.
+#[cfg(test)]
+fn signed_poa<S: SignatureScheme>(local: Option<usize>) -> SignedPoa<S> {
+    let keys = authority_keys::<S>(3);
+    SignedPoa {
+        authorities: keys.iter().map(|(_, public)| public.clone()).collect(),
+        local_key: local.map(|i| keys[i].0.clone()),
+    }
+}
+
+#[test]
+fn cs_10_group_parameters() {
+    // G generates the subgroup of prime order Q.
+    assert_eq!(P, 2 * Q + 1);
+    assert_eq!(pow_mod(G, Q), 1);
+    assert_ne!(pow_mod(G, 2), 1);
+}
+
+#[test]
+fn cs_10_schnorr_sign_and_verify() {
+    let (secret, public) = Schnorr::keypair(1);
+    assert_eq!(Schnorr::public_key(&secret), public);
+    let signature = Schnorr::sign(&secret, 42);
+
+    assert!(Schnorr::verify(&public, 42, &signature));
+    assert!(!Schnorr::verify(&public, 43, &signature));
+    let (_, other) = Schnorr::keypair(2);
+    assert!(!Schnorr::verify(&other, 42, &signature));
+
+    // Signing is deterministic, and tampering with either half breaks the signature.
+    assert_eq!(Schnorr::sign(&secret, 42), signature);
+    let mut tampered = signature;
+    tampered.response = add_mod(tampered.response, 1, Q);
+    assert!(!Schnorr::verify(&public, 42, &tampered));
+    let mut tampered = signature;
+    tampered.commitment = mul_mod(tampered.commitment, G, P);
+    assert!(!Schnorr::verify(&public, 42, &tampered));
+}
+
+#[test]
+fn cs_10_insecure_test_scheme() {
+    let (secret, public) = InsecureTestScheme::keypair(7);
+    let signature = InsecureTestScheme::sign(&secret, 42);
+    assert!(InsecureTestScheme::verify(&public, 42, &signature));
+    assert!(!InsecureTestScheme::verify(&public, 43, &signature));
+    // As promised, the public key is all you need to forge a signature.
+    assert_eq!(InsecureTestScheme::sign(&public, 42), signature);
+}
+
+#[test]
+fn cs_10_signed_poa_seals_and_validates() {
+    let author = signed_poa::<Schnorr>(Some(1));
+    let follower = signed_poa::<Schnorr>(None);
+    let header = author
+        .seal(&unchecked_parent_digest(), partial_header(1))
+        .unwrap();
+
+    assert_eq!(header.consensus_digest.authority, 1);
+    assert!(follower.validate(&unchecked_parent_digest(), &header));
+    assert_eq!(
+        follower.seal(&unchecked_parent_digest(), partial_header(1)),
+        None
+    );
+}
+
+#[test]
+fn cs_10_signed_poa_rejects_forgeries() {
+    let poa = signed_poa::<Schnorr>(Some(0));
+    let header = poa
+        .seal(&unchecked_parent_digest(), partial_header(1))
+        .unwrap();
+
+    // Claiming to be a different authority doesn't work.
+    let mut impostor = header.clone();
+    impostor.consensus_digest.authority = 2;
+    assert!(!poa.validate(&unchecked_parent_digest(), &impostor));
+    impostor.consensus_digest.authority = 3;
+    assert!(!poa.validate(&unchecked_parent_digest(), &impostor));
+
+    // Nor does changing the header after it is signed.
+    let mut altered = header.clone();
+    altered.state_root = 99;
+    assert!(!poa.validate(&unchecked_parent_digest(), &altered));
+
+    // An outsider's key can't seal at all, and their signature doesn't verify.
+    let (outsider, _) = Schnorr::keypair(100);
+    let outsider_poa = SignedPoa::<Schnorr> {
+        authorities: poa.authorities.clone(),
+        local_key: Some(outsider),
+    };
+    assert_eq!(
+        outsider_poa.seal(&unchecked_parent_digest(), partial_header(1)),
+        None
+    );
+    let mut forged = header;
+    forged.consensus_digest.signature = Schnorr::sign(&outsider, pre_seal_hash(&forged));
+    assert!(!poa.validate(&unchecked_parent_digest(), &forged));
+}
+
+#[test]
+fn cs_10_signed_poa_with_test_scheme() {
+    let poa = signed_poa::<InsecureTestScheme>(Some(2));
+    let parent = unchecked_parent_digest();
+    let header = poa.seal(&parent, partial_header(5)).unwrap();
+    assert!(poa.validate(&parent, &header));
+    assert_eq!(pre_seal_hash(&header), hash(&partial_header(5)));
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn cs_10_ed25519_sign_and_verify() {
+    let (secret, public) = Ed25519::keypair(1);
+    assert_eq!(Ed25519::public_key(&secret), public);
+    let signature = Ed25519::sign(&secret, 42);
+
+    assert!(Ed25519::verify(&public, 42, &signature));
+    assert!(!Ed25519::verify(&public, 43, &signature));
+    assert!(!Ed25519::verify(&Ed25519::keypair(2).1, 42, &signature));
+    let mut tampered = signature;
+    tampered.0[0] ^= 1;
+    assert!(!Ed25519::verify(&public, 42, &tampered));
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn cs_10_signed_poa_with_ed25519() {
+    let author = signed_poa::<Ed25519>(Some(1));
+    let follower = signed_poa::<Ed25519>(None);
+    let parent = SignedDigest {
+        authority: 0,
+        signature: Ed25519Signature([0; 64]),
+    };
+    let header = author.seal(&parent, partial_header(1)).unwrap();
+    assert!(follower.validate(&parent, &header));
+
+    let mut impostor = header;
+    impostor.consensus_digest.authority = 2;
+    assert!(!follower.validate(&parent, &impostor));
+}
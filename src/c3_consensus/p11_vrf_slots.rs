@@ -0,0 +1,526 @@
+//! Round robin by slot fixes the worst problems of the simpler PoA engines, but it makes the whole
+//! future schedule public. Everyone knows exactly who will author the block in any slot, which
+//! makes the next author easy to target with a denial of service attack right before their turn.
+//!
+//! BABE, the engine used by Polkadot, hides the schedule with a lottery. In every slot each
+//! authority evaluates a verifiable random function, or VRF, on the slot number and some shared
+//! randomness. A VRF is like a hash function that can only be evaluated with a secret key, but
+//! comes with a proof that anyone can check with the matching public key. If the output is below a
+//! threshold the authority has won a primary slot and may author a block. Nobody else can tell who
+//! won until the block turns up. Authorities with more stake get a higher threshold, so they win
+//! more often.
+//!
+//! Some slots have no winner at all, and the chain would stall for a while if nothing else happened.
+//! So every slot also has a secondary author, picked from the authorities by hashing the slot with
+//! the randomness. Secondary authors are predictable, like round robin, but only act as a fallback.
+//!
+//! The randomness can't come from the authorities directly, or they could choose it to win the
+//! lottery. Instead it is built up from the VRF outputs in the blocks themselves. Each block's
+//! digest carries the randomness its lottery used and a running hash of the VRF outputs so far in
+//! the epoch. When a new epoch starts, the running hash of the previous epoch becomes the new
+//! randomness. Because `validate` only sees the parent's digest, these values are carried along in
+//! every digest, just like the threshold in the difficulty adjusting proof of work.
+//!
+//! The VRF here is built on the same toy group as the Schnorr signatures from part 10, so it is
+//! equally insecure. It is a real construction though. The output is `H^x` where `H` is the input
+//! hashed into the group and `x` is the secret key, and the proof shows that `H^x` and the public
+//! key `G^x` use the same exponent, without revealing it.
+
+use super::{
+    p10_signatures::{
+        add_mod, hash_to_scalar, mul_mod, pow_mod, pre_seal_hash, Schnorr, SchnorrSignature,
+        SignatureScheme, G, P, Q,
+    },
+    with_digest, Consensus, Header, SlotClock, SlotConfig,
+};
+use crate::hash;
+
+type Hash = u64;
+
+/// A VRF output along with the proof that it was computed correctly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VrfProof {
+    /// The input raised to the power of the secret key.
+    gamma: u64,
+    challenge: u64,
+    response: u64,
+}
+
+impl VrfProof {
+    /// The random output. Only someone with the secret key could have computed it, and it is
+    /// uniquely determined by the key and the input.
+    pub fn output(&self) -> Hash {
+        hash(&("vrf output", self.gamma))
+    }
+}
+
+/// Hash a VRF input into the group. Squaring lands in the subgroup of order `Q`, and nobody knows
+/// the discrete logarithm of the result.
+fn hash_to_group(input: Hash) -> u64 {
+    let x = hash(&("vrf input", input)) % (P - 3) + 2;
+    mul_mod(x, x, P)
+}
+
+/// The challenge for the proof that `G^x = public` and `h^x = gamma` use the same `x`.
+fn vrf_challenge(h: u64, public: u64, gamma: u64, u: u64, v: u64) -> u64 {
+    hash_to_scalar(&(h, public, gamma, u, v))
+}
+
+/// Evaluate the VRF on the given input with a secret key from the Schnorr scheme.
+pub fn vrf_prove(secret: u64, input: Hash) -> VrfProof {
+    let h = hash_to_group(input);
+    let gamma = pow_mod(h, secret);
+    let nonce = hash_to_scalar(&("vrf nonce", secret, input));
+    let (u, v) = (pow_mod(G, nonce), pow_mod(h, nonce));
+    let challenge = vrf_challenge(h, Schnorr::public_key(&secret), gamma, u, v);
+    let response = add_mod(nonce, mul_mod(challenge, secret, Q), Q);
+    VrfProof {
+        gamma,
+        challenge,
+        response,
+    }
+}
+
+/// Check a VRF proof against the public key and input.
+pub fn vrf_verify(public: u64, input: Hash, proof: &VrfProof) -> bool {
+    let VrfProof {
+        gamma,
+        challenge,
+        response,
+    } = *proof;
+    if gamma == 0 || gamma >= P || pow_mod(gamma, Q) != 1 || response >= Q || challenge >= Q {
+        return false;
+    }
+    let h = hash_to_group(input);
+    // Raising to `Q - c` divides by the `c`th power, because everything is in the subgroup.
+    let u = mul_mod(pow_mod(G, response), pow_mod(public, Q - challenge), P);
+    let v = mul_mod(pow_mod(h, response), pow_mod(gamma, Q - challenge), P);
+    challenge == vrf_challenge(h, public, gamma, u, v)
+}
+
+/// An authority and how much stake backs it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StakedAuthority {
+    pub public: u64,
+    pub stake: u64,
+}
+
+/// How an author claimed a slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SlotClaim {
+    /// The author's VRF output was below its threshold.
+    Primary,
+    /// The author is the slot's fallback author.
+    Secondary,
+}
+
+/// The consensus digest for the VRF slot lottery.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VrfDigest {
+    pub slot: u64,
+    /// The index of the author in the authority set.
+    pub authority: usize,
+    pub claim: SlotClaim,
+    /// The VRF evaluated on the randomness and the slot. It is included in secondary blocks too,
+    /// so that every block contributes to the next epoch's randomness.
+    pub vrf: VrfProof,
+    /// The randomness of this block's epoch.
+    pub randomness: Hash,
+    /// A running hash of the VRF outputs in this epoch up to and including this block.
+    pub accumulator: Hash,
+    /// The author's signature over the header and the rest of the digest.
+    pub signature: SchnorrSignature,
+}
+
+/// A BABE-style engine. Authorities win slots in a private lottery, with a public round robin
+/// fallback.
+pub struct VrfSlotLottery<C: SlotClock> {
+    pub authorities: Vec<StakedAuthority>,
+    /// The Schnorr secret key this node authors with, if it is an authority.
+    pub local_key: Option<u64>,
+    pub slots: SlotConfig,
+    /// The number of slots in each epoch. Zero is treated as one.
+    pub epoch_length: u64,
+    /// The chance that an authority with all the stake wins any given slot. BABE calls this `c`.
+    /// The chance for any single authority is `1 - (1 - c)^share`, where `share` is its fraction
+    /// of the total stake. That keeps the chance of a slot having some winner the same no matter
+    /// how the stake is split.
+    pub primary_probability: f64,
+    pub clock: C,
+}
+
+impl<C: SlotClock> VrfSlotLottery<C> {
+    /// The digest to put in the genesis header. Genesis is not authored, so only the slot and
+    /// randomness matter. The slot is zero.
+    pub fn genesis_digest(randomness: Hash) -> VrfDigest {
+        VrfDigest {
+            slot: 0,
+            authority: 0,
+            claim: SlotClaim::Secondary,
+            vrf: VrfProof {
+                gamma: 1,
+                challenge: 0,
+                response: 0,
+            },
+            randomness,
+            accumulator: randomness,
+            signature: Schnorr::sign(&1, 0),
+        }
+    }
+
+    fn epoch_length(&self) -> u64 {
+        self.epoch_length.max(1)
+    }
+
+    fn epoch(&self, slot: u64) -> u64 {
+        slot / self.epoch_length()
+    }
+
+    /// The randomness for the given slot, and the accumulator to fold its VRF output into, given
+    /// the parent's digest.
+    fn randomness_for(&self, parent: &VrfDigest, slot: u64) -> (Hash, Hash) {
+        let epoch = self.epoch(slot);
+        if epoch == self.epoch(parent.slot) {
+            (parent.randomness, parent.accumulator)
+        } else {
+            let randomness = hash(&(parent.accumulator, epoch));
+            (randomness, randomness)
+        }
+    }
+
+    /// The VRF output an authority must beat to win a primary slot.
+    pub fn primary_threshold(&self, authority: usize) -> u64 {
+        let total: u128 = self.authorities.iter().map(|a| a.stake as u128).sum();
+        if total == 0 {
+            return 0;
+        }
+        let share = self.authorities[authority].stake as f64 / total as f64;
+        let chance = 1.0 - (1.0 - self.primary_probability).powf(share);
+        (chance * u64::MAX as f64) as u64
+    }
+
+    /// The fallback author of a slot.
+    pub fn secondary_author(&self, randomness: Hash, slot: u64) -> usize {
+        (hash(&("secondary", randomness, slot)) % self.authorities.len() as u64) as usize
+    }
+
+    /// Whether the given authority won the slot in the way it claims. An authority that is both
+    /// a primary winner and the secondary author may claim either.
+    fn may_claim(
+        &self,
+        authority: usize,
+        claim: SlotClaim,
+        randomness: Hash,
+        slot: u64,
+        output: Hash,
+    ) -> bool {
+        match claim {
+            SlotClaim::Primary => output < self.primary_threshold(authority),
+            SlotClaim::Secondary => self.secondary_author(randomness, slot) == authority,
+        }
+    }
+
+    /// The message an author signs. It covers the header and every other part of the digest.
+    fn signing_message(header: &Header<VrfDigest>) -> Hash {
+        let d = &header.consensus_digest;
+        hash(&(
+            pre_seal_hash(header),
+            d.slot,
+            d.authority,
+            d.claim,
+            d.vrf,
+            d.randomness,
+            d.accumulator,
+        ))
+    }
+}
+
+impl<C: SlotClock> Consensus for VrfSlotLottery<C> {
+    type Digest = VrfDigest;
+
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let d = &header.consensus_digest;
+        let Some(author) = self.authorities.get(d.authority) else {
+            return false;
+        };
+        if d.slot <= parent_digest.slot || !self.slots.has_started(d.slot, &self.clock) {
+            return false;
+        }
+        let (randomness, accumulator) = self.randomness_for(parent_digest, d.slot);
+        let output = d.vrf.output();
+        d.randomness == randomness
+            && d.accumulator == hash(&(accumulator, output))
+            && vrf_verify(author.public, hash(&(randomness, d.slot)), &d.vrf)
+            && self.may_claim(d.authority, d.claim, randomness, d.slot, output)
+            && Schnorr::verify(&author.public, Self::signing_message(header), &d.signature)
+    }
+
+    /// Author in the first slot the local authority can claim, waiting for it to start if
+    /// necessary. Slots are searched for up to a few epochs ahead.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let secret = self.local_key?;
+        let public = Schnorr::public_key(&secret);
+        let authority = self.authorities.iter().position(|a| a.public == public)?;
+
+        let earliest = (parent_digest.slot + 1).max(self.slots.slot_at(self.clock.now()));
+        let search = earliest..earliest.saturating_add(self.epoch_length().saturating_mul(4));
+        let (slot, claim, vrf, randomness, accumulator) = search.into_iter().find_map(|slot| {
+            let (randomness, accumulator) = self.randomness_for(parent_digest, slot);
+            let vrf = vrf_prove(secret, hash(&(randomness, slot)));
+            // Primary claims are preferred, because they are the ones that stay private.
+            let claim = [SlotClaim::Primary, SlotClaim::Secondary]
+                .into_iter()
+                .find(|claim| self.may_claim(authority, *claim, randomness, slot, vrf.output()))?;
+            Some((slot, claim, vrf, randomness, accumulator))
+        })?;
+
+        self.clock.wait_until(self.slots.slot_start(slot));
+        let mut header = with_digest(
+            partial_header,
+            VrfDigest {
+                slot,
+                authority,
+                claim,
+                vrf,
+                randomness,
+                accumulator: hash(&(accumulator, vrf.output())),
+                signature: Schnorr::sign(&secret, 0),
+            },
+        );
+        header.consensus_digest.signature = Schnorr::sign(&secret, Self::signing_message(&header));
+        Some(header)
+    }
+
+    fn human_name() -> String {
+        "VRF Slot Lottery".into()
+    }
+}
+
+#[cfg(test)]
+use super::{authority_keys, partial_header};
+#[cfg(test)]
+use crate::c2_blockchain::{Clock, ManualClock};
+
+/// An engine with three authorities, the first with half the stake, in epochs of ten one second
+/// slots. The clock starts at zero.
+#[cfg(test)]
+fn lottery(local: Option<usize>) -> VrfSlotLottery<ManualClock> {
+    let keys = authority_keys::<Schnorr>(3);
+    let stakes = [50, 30, 20];
+    VrfSlotLottery {
+        authorities: keys
+            .iter()
+            .zip(stakes)
+            .map(|((_, public), stake)| StakedAuthority {
+                public: *public,
+                stake,
+            })
+            .collect(),
+        local_key: local.map(|i| keys[i].0),
+        slots: SlotConfig::new(1, 0, 0),
+        epoch_length: 10,
+        primary_probability: 0.5,
+        clock: ManualClock::new(0),
+    }
+}
+
+/// Build a chain in which every authority tries to seal each block and the earliest slot wins.
+/// Returns the digests from genesis onwards.
+#[cfg(test)]
+fn build_chain(blocks: u64) -> Vec<Header<VrfDigest>> {
+    let authors: Vec<_> = (0..3).map(|i| lottery(Some(i))).collect();
+    let genesis = with_digest(
+        partial_header(0),
+        VrfSlotLottery::<ManualClock>::genesis_digest(7),
+    );
+    let mut chain = vec![genesis];
+    for height in 1..=blocks {
+        let parent = chain.last().unwrap().consensus_digest;
+        let header = authors
+            .iter()
+            .filter_map(|author| author.seal(&parent, partial_header(height)))
+            .min_by_key(|header| header.consensus_digest.slot)
+            .unwrap();
+        for author in &authors {
+            author
+                .clock
+                .set(lottery(None).slots.slot_start(header.consensus_digest.slot));
+        }
+        chain.push(header);
+    }
+    chain
+}
+
+#[test]
+fn cs_11_vrf_prove_and_verify() {
+    let (secret, public) = Schnorr::keypair(1);
+    let proof = vrf_prove(secret, 42);
+
+    assert!(vrf_verify(public, 42, &proof));
+    assert!(!vrf_verify(public, 43, &proof));
+    assert!(!vrf_verify(Schnorr::keypair(2).1, 42, &proof));
+    // The output is unique to the key and input.
+    assert_eq!(vrf_prove(secret, 42).output(), proof.output());
+    assert_ne!(vrf_prove(secret, 43).output(), proof.output());
+
+    // A different output can't be passed off with the same proof.
+    let mut forged = proof;
+    forged.gamma = mul_mod(forged.gamma, G, P);
+    assert!(!vrf_verify(public, 42, &forged));
+}
+
+#[test]
+fn cs_11_threshold_follows_stake() {
+    let mut engine = lottery(None);
+    assert!(engine.primary_threshold(0) > engine.primary_threshold(1));
+    assert!(engine.primary_threshold(1) > engine.primary_threshold(2));
+
+    // With all the stake, the chance of winning is exactly `c`.
+    engine.authorities[1].stake = 0;
+    engine.authorities[2].stake = 0;
+    assert_eq!(engine.primary_threshold(1), 0);
+    assert_eq!(engine.primary_threshold(0), (0.5 * u64::MAX as f64) as u64);
+}
+
+#[test]
+fn cs_11_chain_validates() {
+    let chain = build_chain(40);
+    let follower = lottery(None);
+    follower.clock.set(u64::MAX / 2);
+    for pair in chain.windows(2) {
+        assert!(follower.validate(&pair[0].consensus_digest, &pair[1]));
+    }
+
+    let digests: Vec<_> = chain.iter().map(|h| h.consensus_digest).collect();
+    assert!(digests.iter().any(|d| d.claim == SlotClaim::Primary));
+    assert!(digests[1..].iter().any(|d| d.claim == SlotClaim::Secondary));
+    assert!(digests.windows(2).all(|w| w[0].slot < w[1].slot));
+}
+
+#[test]
+fn cs_11_randomness_changes_each_epoch() {
+    let chain = build_chain(40);
+    let digests: Vec<_> = chain.iter().map(|h| h.consensus_digest).collect();
+    for pair in digests.windows(2) {
+        let (parent, child) = (pair[0], pair[1]);
+        if parent.slot / 10 == child.slot / 10 {
+            assert_eq!(child.randomness, parent.randomness);
+        } else {
+            // The new randomness comes from the VRF outputs of the previous epoch.
+            assert_eq!(
+                child.randomness,
+                hash(&(parent.accumulator, child.slot / 10))
+            );
+            assert_ne!(child.randomness, parent.randomness);
+        }
+    }
+}
+
+#[test]
+fn cs_11_validate_rejects_bad_claims() {
+    // Authority 0 holds all the stake and is certain to win every primary slot. The others can
+    // never win one.
+    let mut follower = lottery(None);
+    follower.authorities[0].stake = 100;
+    follower.authorities[1].stake = 0;
+    follower.authorities[2].stake = 0;
+    follower.primary_probability = 1.0;
+    follower.clock.set(u64::MAX / 2);
+
+    // A slot in which authority 1 is the secondary author.
+    let genesis = VrfSlotLottery::<ManualClock>::genesis_digest(7);
+    let (randomness, accumulator) = follower.randomness_for(&genesis, 1);
+    let slot = (1..10)
+        .find(|slot| follower.secondary_author(randomness, *slot) == 1)
+        .unwrap();
+
+    // Every authority can evaluate the VRF and sign, but only winners may claim the slot.
+    let claim_slot = |authority: usize, claim| {
+        let secret = Schnorr::keypair(authority as u64).0;
+        let vrf = vrf_prove(secret, hash(&(randomness, slot)));
+        let mut header = with_digest(
+            partial_header(1),
+            VrfDigest {
+                slot,
+                authority,
+                claim,
+                vrf,
+                randomness,
+                accumulator: hash(&(accumulator, vrf.output())),
+                signature: Schnorr::sign(&secret, 0),
+            },
+        );
+        let message = VrfSlotLottery::<ManualClock>::signing_message(&header);
+        header.consensus_digest.signature = Schnorr::sign(&secret, message);
+        header
+    };
+    let expected = [
+        (0, SlotClaim::Primary, true),
+        (1, SlotClaim::Primary, false),
+        (2, SlotClaim::Primary, false),
+        (0, SlotClaim::Secondary, false),
+        (1, SlotClaim::Secondary, true),
+        (2, SlotClaim::Secondary, false),
+    ];
+    for (authority, claim, accepted) in expected {
+        assert_eq!(
+            follower.validate(&genesis, &claim_slot(authority, claim)),
+            accepted,
+            "authority {authority} claiming {claim:?}"
+        );
+    }
+
+    // Choosing different randomness.
+    let header = claim_slot(0, SlotClaim::Primary);
+    let mut wrong_randomness = header.clone();
+    wrong_randomness.consensus_digest.randomness ^= 1;
+    assert!(!follower.validate(&genesis, &wrong_randomness));
+
+    // Claiming someone else's block.
+    let mut impostor = header.clone();
+    impostor.consensus_digest.authority = 1;
+    assert!(!follower.validate(&genesis, &impostor));
+
+    // A slot that hasn't started yet.
+    follower.clock.set(follower.slots.slot_start(slot) - 1);
+    assert!(!follower.validate(&genesis, &header));
+}
+
+#[test]
+fn cs_11_huge_stakes_do_not_overflow() {
+    let mut engine = lottery(None);
+    engine.authorities[0].stake = u64::MAX;
+    engine.authorities[1].stake = u64::MAX;
+    engine.authorities[2].stake = 0;
+    assert_eq!(engine.primary_threshold(0), engine.primary_threshold(1));
+    assert!(engine.primary_threshold(0) > 0);
+    assert_eq!(engine.primary_threshold(2), 0);
+}
+
+#[test]
+fn cs_11_zero_epoch_length_is_one_slot() {
+    let mut author = lottery(Some(0));
+    author.epoch_length = 0;
+    let genesis = VrfSlotLottery::<ManualClock>::genesis_digest(7);
+    let header = author.seal(&genesis, partial_header(1)).unwrap();
+    let d = header.consensus_digest;
+    // Every slot is its own epoch, so the randomness is renewed straight away.
+    assert_eq!(d.randomness, hash(&(genesis.accumulator, d.slot)));
+    assert!(author.validate(&genesis, &header));
+}
+
+#[test]
+fn cs_11_seal_waits_for_slot() {
+    let author = lottery(Some(0));
+    let genesis = VrfSlotLottery::<ManualClock>::genesis_digest(7);
+    let header = author.seal(&genesis, partial_header(1)).unwrap();
+    assert_eq!(
+        author.clock.now(),
+        author.slots.slot_start(header.consensus_digest.slot)
+    );
+    assert!(lottery(None).seal(&genesis, partial_header(1)).is_none());
+}
@@ -0,0 +1,200 @@
+//! `SimplePoa` and its signed version from part 10 accept a block signed by any one authority, so
+//! a single corrupt authority can sign whatever it likes. Requiring several authorities to agree
+//! on every block limits the damage. With a threshold of `k` out of `n` authorities, at least `k`
+//! of them have to collude to get a bad block accepted, and the chain keeps going as long as `k`
+//! of them are online and honest.
+//!
+//! The digest simply carries a signature from each signer. Without some care, one authority could
+//! sign twice and be counted twice, so each signer may only appear once. The signers must also be
+//! listed in a canonical order, in this case ascending by their index in the authority set. That
+//! makes duplicates easy to spot, and means a header has exactly one valid digest for any set of
+//! signers, rather than one for every ordering of them. The same key could also appear more than
+//! once in the authority set, so a key may only sign under the first index it appears at.
+//!
+//! Real chains usually aggregate the signatures into a single one, with schemes like BLS or
+//! MuSig, so the digest doesn't grow with the number of signers. The security argument is the
+//! same as here.
+
+use super::{
+    p10_signatures::{pre_seal_hash, SignatureScheme},
+    with_digest, Consensus, Header,
+};
+use crate::hash;
+
+/// The digest of a multisigned header. Each entry names an authority by its index in the
+/// authority set, and carries its signature over the pre-seal header hash. Entries are sorted by
+/// authority, with no repeats.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MultiSigDigest<Signature> {
+    pub signatures: Vec<(usize, Signature)>,
+}
+
+/// Proof of Authority in which at least `threshold` distinct authorities must sign every block.
+pub struct MultiSigPoa<S: SignatureScheme> {
+    /// The public keys of the authorities.
+    pub authorities: Vec<S::PublicKey>,
+    /// How many distinct authorities must sign each block. A threshold of zero would accept
+    /// unsigned blocks, so at least one signature is always required.
+    pub threshold: usize,
+    /// The secret keys this node can sign with. A single node holding several keys stands in for
+    /// several authorities passing the header around to be signed.
+    pub local_keys: Vec<S::SecretKey>,
+}
+
+impl<S: SignatureScheme> MultiSigPoa<S> {
+    /// The index a key signs under. If the key is listed more than once, only the first counts.
+    fn authority_index(&self, public: &S::PublicKey) -> Option<usize> {
+        self.authorities.iter().position(|a| a == public)
+    }
+}
+
+impl<S: SignatureScheme> Consensus for MultiSigPoa<S> {
+    type Digest = MultiSigDigest<S::Signature>;
+
+    /// Check that enough distinct authorities, listed in ascending order, all signed this header.
+    /// Each key counts once, under the first index it has in the authority set.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let signatures = &header.consensus_digest.signatures;
+        let message = pre_seal_hash(header);
+        signatures.len() >= self.threshold.max(1)
+            && signatures.windows(2).all(|pair| pair[0].0 < pair[1].0)
+            && signatures.iter().all(|(authority, signature)| {
+                self.authorities.get(*authority).is_some_and(|public| {
+                    self.authority_index(public) == Some(*authority)
+                        && S::verify(public, message, signature)
+                })
+            })
+    }
+
+    /// Sign with every local key that belongs to an authority. Returns None if that isn't enough
+    /// authorities to meet the threshold.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        let message = hash(&partial_header);
+        let mut signatures: Vec<_> = self
+            .local_keys
+            .iter()
+            .filter_map(|secret| {
+                let public = S::public_key(secret);
+                let authority = self.authority_index(&public)?;
+                Some((authority, S::sign(secret, message)))
+            })
+            .collect();
+        signatures.sort_by_key(|(authority, _)| *authority);
+        signatures.dedup_by_key(|(authority, _)| *authority);
+
+        if signatures.len() < self.threshold.max(1) {
+            return None;
+        }
+        Some(with_digest(partial_header, MultiSigDigest { signatures }))
+    }
+
+    fn human_name() -> String {
+        "Multisig Proof of Authority".into()
+    }
+}
+
+#[cfg(test)]
+use super::{authority_keys, p10_signatures::Schnorr, partial_header, unchecked_parent_digest};
+
+/// A 3 of 5 multisig engine holding the keys of the given authorities.
+#[cfg(test)]
+fn multisig<S: SignatureScheme>(local: &[usize]) -> MultiSigPoa<S> {
+    let keys = authority_keys::<S>(5);
+    MultiSigPoa {
+        authorities: keys.iter().map(|(_, public)| public.clone()).collect(),
+        threshold: 3,
+        local_keys: local.iter().map(|i| keys[*i].0.clone()).collect(),
+    }
+}
+
+#[test]
+fn cs_12_seals_with_enough_signers() {
+    let sealer = multisig::<Schnorr>(&[4, 1, 2]);
+    let follower = multisig::<Schnorr>(&[]);
+    let header = sealer
+        .seal(&unchecked_parent_digest(), partial_header(1))
+        .unwrap();
+
+    let signers: Vec<_> = header
+        .consensus_digest
+        .signatures
+        .iter()
+        .map(|(a, _)| *a)
+        .collect();
+    assert_eq!(signers, vec![1, 2, 4]);
+    assert!(follower.validate(&unchecked_parent_digest(), &header));
+
+    // Extra signers beyond the threshold are fine.
+    let everyone = multisig::<Schnorr>(&[0, 1, 2, 3, 4]);
+    let header = everyone
+        .seal(&unchecked_parent_digest(), partial_header(1))
+        .unwrap();
+    assert_eq!(header.consensus_digest.signatures.len(), 5);
+    assert!(follower.validate(&unchecked_parent_digest(), &header));
+}
+
+#[test]
+fn cs_12_too_few_signers() {
+    // A key held twice still only counts once, and outsiders don't count at all.
+    let mut sealer = multisig::<Schnorr>(&[0, 3, 3]);
+    sealer.local_keys.push(Schnorr::keypair(100).0);
+    assert_eq!(
+        sealer.seal(&unchecked_parent_digest(), partial_header(1)),
+        None
+    );
+
+    let header = multisig::<Schnorr>(&[0, 1, 3])
+        .seal(&unchecked_parent_digest(), partial_header(1))
+        .unwrap();
+    let mut short = header.clone();
+    short.consensus_digest.signatures.pop();
+    assert!(!sealer.validate(&unchecked_parent_digest(), &short));
+
+    // Even with a threshold of zero, an unsigned block is not accepted.
+    sealer.threshold = 0;
+    short.consensus_digest.signatures.clear();
+    assert!(!sealer.validate(&unchecked_parent_digest(), &short));
+}
+
+#[test]
+fn cs_12_rejects_duplicates_and_unordered_signers() {
+    let poa = multisig::<Schnorr>(&[0, 1, 2]);
+    let header = poa
+        .seal(&unchecked_parent_digest(), partial_header(1))
+        .unwrap();
+    let signatures = &header.consensus_digest.signatures;
+
+    // Authority 0 signing twice doesn't make up for authority 2.
+    let mut duplicate = header.clone();
+    duplicate.consensus_digest.signatures = vec![signatures[0], signatures[0], signatures[1]];
+    assert!(!poa.validate(&unchecked_parent_digest(), &duplicate));
+
+    // The same valid signatures in a different order are not canonical.
+    let mut reordered = header.clone();
+    reordered.consensus_digest.signatures.swap(0, 2);
+    assert!(!poa.validate(&unchecked_parent_digest(), &reordered));
+}
+
+#[test]
+fn cs_12_repeated_key_counts_once() {
+    // Authority 1's key is listed again as authority 3, so holding it only makes two signers.
+    let mut poa = multisig::<Schnorr>(&[0, 1]);
+    poa.authorities[3] = poa.authorities[1];
+    assert_eq!(
+        poa.seal(&unchecked_parent_digest(), partial_header(1)),
+        None
+    );
+
+    // It signs under its first index.
+    poa.threshold = 2;
+    let mut header = poa
+        .seal(&unchecked_parent_digest(), partial_header(1))
+        .unwrap();
+    assert!(poa.validate(&unchecked_parent_digest(), &header));
+
+    // Signing under both indices doesn't make up a third signer.
+    let (_, signature) = header.consensus_digest.signatures[1];
+    header.consensus_digest.signatures.push((3, signature));
+    poa.threshold = 3;
+    assert!(!poa.validate(&unchecked_parent_digest(), &header));
+}
@@ -7,6 +7,7 @@
 //! Throughout this chapter we will avoid performing _Actual_ cryptographic calculations because they
 //! require a crypto library which and overcoming its own learning curve, plus they distract from the
 //! underlying consensus-related logic. Instead, we just use the `ConsensusAuthority` enum from the module root.
+//! Part 10 shows how to swap in real signatures once the consensus logic is clear.
 
 use super::{Consensus, ConsensusAuthority, Header};
 /// Dictator consensus is an identity-based consensus algorithm. It specifies a single dictator
@@ -9,7 +9,11 @@
 //! Even when using the Proof of Stake configuration, the underlying consensus logic is identical to
 //! the proof of authority we are writing here.
 
-use super::{Consensus, ConsensusAuthority, Header};
+#[cfg(test)]
+use super::partial_header;
+use super::{with_digest, Consensus, ConsensusAuthority, Header, SlotClock, SlotConfig};
+#[cfg(test)]
+use crate::c2_blockchain::{Clock, ManualClock};
 
 /// A Proof of Authority consensus engine. If any of the authorities have signed the block, it is valid.
 pub struct SimplePoa {
@@ -63,8 +67,23 @@ impl Consensus for PoaRoundRobinByHeight {
 ///
 /// A common PoA scheme that works around these weaknesses is to divide time into slots, and then do a round robin
 /// by slot instead of by height
-struct PoaRoundRobinBySlot {
+///
+/// Slots are measured by the given clock. An authority that doesn't sign in its slot simply
+/// misses its turn, and the next authority signs in the next slot.
+struct PoaRoundRobinBySlot<C: SlotClock> {
     authorities: Vec<ConsensusAuthority>,
+    /// The authority this node seals as, if it is one.
+    local_authority: Option<ConsensusAuthority>,
+    slots: SlotConfig,
+    clock: C,
+}
+
+impl<C: SlotClock> PoaRoundRobinBySlot<C> {
+    /// The authority allowed to sign in the given slot.
+    fn author_of(&self, slot: u64) -> Option<ConsensusAuthority> {
+        let index = slot.checked_rem(self.authorities.len() as u64)?;
+        Some(self.authorities[index as usize])
+    }
 }
 
 /// A digest used for PoaRoundRobinBySlot. The digest contains the slot number as well as the signature.
@@ -76,18 +95,127 @@ struct SlotDigest {
     signature: ConsensusAuthority,
 }
 
-impl Consensus for PoaRoundRobinBySlot {
+impl<C: SlotClock> Consensus for PoaRoundRobinBySlot<C> {
     type Digest = SlotDigest;
 
+    /// Check that the slot is after the parent's, has already started by our clock, give or take
+    /// the allowed drift, and was signed by the authority whose turn it is.
     fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
-        todo!("Exercise 5")
+        let digest = &header.consensus_digest;
+        digest.slot > parent_digest.slot
+            && self.slots.has_started(digest.slot, &self.clock)
+            && self.author_of(digest.slot) == Some(digest.signature)
     }
 
+    /// Sign in the local authority's next slot. That is the first of its slots that is after the
+    /// parent's slot and not already over. If the slot hasn't started yet, wait for it.
     fn seal(
         &self,
         parent_digest: &Self::Digest,
         partial_header: Header<()>,
     ) -> Option<Header<Self::Digest>> {
-        todo!("Exercise 6")
+        let local = self.local_authority?;
+        let earliest = (parent_digest.slot + 1).max(self.slots.slot_at(self.clock.now()));
+        let turns = self.authorities.len() as u64;
+        let slot = (earliest..earliest + turns).find(|s| self.author_of(*s) == Some(local))?;
+        self.clock.wait_until(self.slots.slot_start(slot));
+        Some(with_digest(
+            partial_header,
+            SlotDigest {
+                slot,
+                signature: local,
+            },
+        ))
+    }
+}
+
+/// Alice, Bob and Charlie take turns in six second slots starting at time 1000.
+#[cfg(test)]
+fn slot_poa(
+    local_authority: Option<ConsensusAuthority>,
+    now: u64,
+) -> PoaRoundRobinBySlot<ManualClock> {
+    PoaRoundRobinBySlot {
+        authorities: vec![
+            ConsensusAuthority::Alice,
+            ConsensusAuthority::Bob,
+            ConsensusAuthority::Charlie,
+        ],
+        local_authority,
+        slots: SlotConfig::new(6, 1_000, 2),
+        clock: ManualClock::new(now),
     }
 }
+
+#[cfg(test)]
+fn slot_digest(slot: u64, signature: ConsensusAuthority) -> SlotDigest {
+    SlotDigest { slot, signature }
+}
+
+#[test]
+fn cs_3_slot_arithmetic() {
+    let slots = slot_poa(None, 0).slots;
+    assert_eq!(slots.slot_at(0), 0);
+    assert_eq!(slots.slot_at(1_005), 0);
+    assert_eq!(slots.slot_at(1_006), 1);
+    assert_eq!(slots.slot_start(2), 1_012);
+}
+
+#[test]
+fn cs_3_slot_seal_picks_next_own_slot() {
+    // It is slot 4, which is Bob's. Alice's next slot is 6.
+    let poa = slot_poa(Some(ConsensusAuthority::Alice), 1_026);
+    let parent = slot_digest(2, ConsensusAuthority::Charlie);
+    let header = poa.seal(&parent, partial_header(3)).unwrap();
+
+    assert_eq!(
+        header.consensus_digest,
+        slot_digest(6, ConsensusAuthority::Alice)
+    );
+    // Sealing waited until the slot started.
+    assert_eq!(poa.clock.now(), 1_036);
+    assert!(poa.validate(&parent, &header));
+}
+
+#[test]
+fn cs_3_slot_seal_uses_current_slot() {
+    // It is already Bob's slot, so there is no need to wait.
+    let poa = slot_poa(Some(ConsensusAuthority::Bob), 1_029);
+    let header = poa
+        .seal(
+            &slot_digest(0, ConsensusAuthority::Alice),
+            partial_header(1),
+        )
+        .unwrap();
+    assert_eq!(
+        header.consensus_digest,
+        slot_digest(4, ConsensusAuthority::Bob)
+    );
+    assert_eq!(poa.clock.now(), 1_029);
+
+    // Without a local authority there is nothing to seal with.
+    assert!(slot_poa(None, 1_029)
+        .seal(
+            &slot_digest(0, ConsensusAuthority::Alice),
+            partial_header(1)
+        )
+        .is_none());
+}
+
+#[test]
+fn cs_3_slot_validate_rules() {
+    let poa = slot_poa(None, 1_019);
+    let parent = slot_digest(1, ConsensusAuthority::Bob);
+    let header = |slot, signature| with_digest(partial_header(2), slot_digest(slot, signature));
+
+    assert!(poa.validate(&parent, &header(3, ConsensusAuthority::Alice)));
+    // Wrong author for the slot.
+    assert!(!poa.validate(&parent, &header(3, ConsensusAuthority::Bob)));
+    // Slots must increase.
+    assert!(!poa.validate(&parent, &header(1, ConsensusAuthority::Bob)));
+    // Slot 4 starts at 1024. Our clock says 1019, which is too far off.
+    assert!(!poa.validate(&parent, &header(4, ConsensusAuthority::Bob)));
+    // Within the allowed drift it is accepted, and later on it is accepted anyway.
+    poa.clock.set(1_022);
+    assert!(poa.validate(&parent, &header(4, ConsensusAuthority::Bob)));
+}
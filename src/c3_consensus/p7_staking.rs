@@ -0,0 +1,611 @@
+//! In the Proof of Authority section we mentioned that public chains often elect their authorities
+//! on-chain through an economic game in which users stake tokens. Here we build that game.
+//!
+//! Staking is just another multi-user state machine like the ones from the first chapter. Users
+//! bond some of their funds, and may then either declare their intention to validate, or nominate
+//! validators that they trust. At the end of each era, the validators with the most stake backing
+//! them become the authority set for the next era. This set can be handed directly to any of the
+//! PoA engines in this chapter.
+//!
+//! Bonded funds are at risk. A misbehaving validator can be slashed, and the nominators who backed
+//! them lose the same fraction of their stake. To make sure that offenders can't simply run away
+//! with their stake before being punished, unbonded funds remain locked for a few eras before they
+//! can be withdrawn.
+
+use std::collections::{HashMap, HashSet};
+
+use super::ConsensusAuthority;
+use crate::c1_state_machine::{StateMachine, User};
+
+/// How many eras unbonded funds stay locked before they can be withdrawn.
+pub const BONDING_DURATION: u64 = 2;
+
+/// Each user is able to act as a consensus authority using the matching identity.
+impl From<User> for ConsensusAuthority {
+    fn from(user: User) -> Self {
+        match user {
+            User::Alice => ConsensusAuthority::Alice,
+            User::Bob => ConsensusAuthority::Bob,
+            User::Charlie => ConsensusAuthority::Charlie,
+        }
+    }
+}
+
+/// A fixed ordering of users used to break ties deterministically when electing validators.
+fn user_index(user: &User) -> u8 {
+    match user {
+        User::Alice => 0,
+        User::Bob => 1,
+        User::Charlie => 2,
+    }
+}
+
+/// This state machine models a nominated proof of stake system. It tracks free and bonded
+/// balances, who wants to validate, who nominates whom, and the currently elected authority set.
+pub struct Staking;
+
+/// Some funds that have been unbonded but are not yet withdrawable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnlockChunk {
+    /// The amount being unbonded
+    amount: u64,
+    /// The first era in which the amount may be withdrawn
+    unlock_era: u64,
+}
+
+/// The complete state of the staking system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    /// Balances that are freely transferable and not at stake.
+    free: HashMap<User, u64>,
+    /// Balances that are bonded and count as stake.
+    bonded: HashMap<User, u64>,
+    /// Funds that have been unbonded and are waiting out the bonding duration.
+    unlocking: HashMap<User, Vec<UnlockChunk>>,
+    /// Users who have declared their intention to validate.
+    validators: HashSet<User>,
+    /// Users who back validators, and the validators they back.
+    nominations: HashMap<User, Vec<User>>,
+    /// How many validators are elected each era.
+    validator_count: usize,
+    /// The current era.
+    era: u64,
+    /// The validators elected at the start of the current era, and the stake backing each of them.
+    /// Sorted from most to least backed. Backing can add up to more than a u64 holds.
+    elected: Vec<(User, u128)>,
+}
+
+impl State {
+    /// Create a genesis state in which the given users hold the given free balances and
+    /// `validator_count` validators will be elected each era.
+    pub fn new<const N: usize>(validator_count: usize, balances: [(User, u64); N]) -> Self {
+        State {
+            free: balances.into_iter().filter(|(_, b)| *b > 0).collect(),
+            bonded: HashMap::new(),
+            unlocking: HashMap::new(),
+            validators: HashSet::new(),
+            nominations: HashMap::new(),
+            validator_count,
+            era: 0,
+            elected: Vec::new(),
+        }
+    }
+
+    /// The free balance of the given user.
+    pub fn free_balance(&self, user: &User) -> u64 {
+        self.free.get(user).copied().unwrap_or(0)
+    }
+
+    /// The bonded balance of the given user.
+    pub fn bonded_balance(&self, user: &User) -> u64 {
+        self.bonded.get(user).copied().unwrap_or(0)
+    }
+
+    /// The total amount the given user is currently unbonding.
+    pub fn unlocking_balance(&self, user: &User) -> u64 {
+        self.unlocking
+            .get(user)
+            .map(|chunks| chunks.iter().map(|c| c.amount).sum())
+            .unwrap_or(0)
+    }
+
+    /// The current era.
+    pub fn era(&self) -> u64 {
+        self.era
+    }
+
+    /// The validators elected for the current era along with their total backing stake.
+    pub fn elected(&self) -> &[(User, u128)] {
+        &self.elected
+    }
+
+    /// The authority set for the current era, ready to be consumed by a PoA engine.
+    pub fn authorities(&self) -> Vec<ConsensusAuthority> {
+        self.elected
+            .iter()
+            .map(|(user, _)| ConsensusAuthority::from(*user))
+            .collect()
+    }
+
+    /// Calculate how much stake backs each validator candidate.
+    ///
+    /// A validator backs itself with its entire bond. Each nominator splits its bond evenly among
+    /// the validator candidates it nominates. Nominations of users who are not currently
+    /// validator candidates are ignored. Any remainder from the split is not counted.
+    pub fn backing(&self) -> HashMap<User, u128> {
+        let mut backing: HashMap<User, u128> = self
+            .validators
+            .iter()
+            .map(|v| (*v, self.bonded_balance(v) as u128))
+            .collect();
+
+        for (nominator, targets) in self.nominations.iter() {
+            let candidates: Vec<&User> = targets
+                .iter()
+                .filter(|t| self.validators.contains(t))
+                .collect();
+            if candidates.is_empty() {
+                continue;
+            }
+            let share = self.bonded_balance(nominator) / candidates.len() as u64;
+            for candidate in candidates {
+                *backing.get_mut(candidate).unwrap() += share as u128;
+            }
+        }
+        backing
+    }
+
+    /// Elect the top `validator_count` validators by backing stake. Candidates without any
+    /// backing are never elected.
+    fn elect(&self) -> Vec<(User, u128)> {
+        let mut candidates: Vec<(User, u128)> = self
+            .backing()
+            .into_iter()
+            .filter(|(_, stake)| *stake > 0)
+            .collect();
+        candidates.sort_by_key(|(user, stake)| (std::cmp::Reverse(*stake), user_index(user)));
+        candidates.truncate(self.validator_count);
+        candidates
+    }
+
+    fn set(map: &mut HashMap<User, u64>, user: &User, amount: u64) {
+        if amount == 0 {
+            map.remove(user);
+        } else {
+            map.insert(*user, amount);
+        }
+    }
+}
+
+/// The state transitions that users can make in the staking system.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StakingTransaction {
+    /// Move some free balance into the bond. Bonding more than the free balance bonds everything.
+    Bond { who: User, amount: u64 },
+    /// Start unbonding part of the bond. The funds stop counting as stake right away, but can only
+    /// be withdrawn after `BONDING_DURATION` eras. Unbonding more than the bond unbonds everything.
+    Unbond { who: User, amount: u64 },
+    /// Move all unlocking funds whose bonding duration has passed back into the free balance.
+    WithdrawUnbonded { who: User },
+    /// Declare the intention to validate. Replaces any nominations.
+    Validate { who: User },
+    /// Nominate the given validators. Replaces any intention to validate.
+    Nominate { who: User, targets: Vec<User> },
+    /// Stop both validating and nominating. The funds stay bonded.
+    Chill { who: User },
+    /// Punish a validator by destroying the given percentage of its stake, including funds that are
+    /// still unlocking. Everyone who currently nominates the validator loses the same percentage.
+    /// The offender is also removed from the validator candidates.
+    Slash { offender: User, percent: u8 },
+    /// End the current era. The era counter advances and a new set of validators is elected.
+    NewEra,
+}
+
+/// The given percentage of an amount, rounded down. Computed in u128 so large stakes don't overflow.
+fn percent_of(amount: u64, percent: u64) -> u64 {
+    (amount as u128 * percent as u128 / 100) as u64
+}
+
+/// Destroy the given percentage of a user's bonded and unlocking funds.
+fn slash_stake(state: &mut State, who: &User, percent: u64) {
+    let bonded = state.bonded_balance(who);
+    State::set(&mut state.bonded, who, bonded - percent_of(bonded, percent));
+    if let Some(chunks) = state.unlocking.get_mut(who) {
+        for chunk in chunks.iter_mut() {
+            chunk.amount -= percent_of(chunk.amount, percent);
+        }
+        chunks.retain(|chunk| chunk.amount > 0);
+        if chunks.is_empty() {
+            state.unlocking.remove(who);
+        }
+    }
+}
+
+/// We model the staking system as a state machine with eight possible transitions
+impl StateMachine for Staking {
+    type State = State;
+    type Transition = StakingTransaction;
+
+    fn next_state(starting_state: &State, t: &StakingTransaction) -> State {
+        let mut new_state = starting_state.clone();
+        match t {
+            StakingTransaction::Bond { who, amount } => {
+                let free = new_state.free_balance(who);
+                let amount = free.min(*amount);
+                let bonded = new_state.bonded_balance(who);
+                State::set(&mut new_state.free, who, free - amount);
+                State::set(&mut new_state.bonded, who, bonded + amount);
+            }
+            StakingTransaction::Unbond { who, amount } => {
+                let bonded = new_state.bonded_balance(who);
+                let amount = bonded.min(*amount);
+                if amount > 0 {
+                    State::set(&mut new_state.bonded, who, bonded - amount);
+                    let unlock_era = new_state.era + BONDING_DURATION;
+                    new_state
+                        .unlocking
+                        .entry(*who)
+                        .or_default()
+                        .push(UnlockChunk { amount, unlock_era });
+                }
+            }
+            StakingTransaction::WithdrawUnbonded { who } => {
+                let era = new_state.era;
+                if let Some(chunks) = new_state.unlocking.get_mut(who) {
+                    let withdrawn: u64 = chunks
+                        .iter()
+                        .filter(|c| c.unlock_era <= era)
+                        .map(|c| c.amount)
+                        .sum();
+                    chunks.retain(|c| c.unlock_era > era);
+                    if chunks.is_empty() {
+                        new_state.unlocking.remove(who);
+                    }
+                    let free = new_state.free_balance(who);
+                    State::set(&mut new_state.free, who, free + withdrawn);
+                }
+            }
+            StakingTransaction::Validate { who } => {
+                // Only bonded users may take part in staking.
+                if new_state.bonded_balance(who) > 0 {
+                    new_state.nominations.remove(who);
+                    new_state.validators.insert(*who);
+                }
+            }
+            StakingTransaction::Nominate { who, targets } => {
+                if new_state.bonded_balance(who) > 0 && !targets.is_empty() {
+                    new_state.validators.remove(who);
+                    new_state.nominations.insert(*who, targets.clone());
+                }
+            }
+            StakingTransaction::Chill { who } => {
+                new_state.validators.remove(who);
+                new_state.nominations.remove(who);
+            }
+            StakingTransaction::Slash { offender, percent } => {
+                let percent = (*percent).min(100) as u64;
+                let nominators: Vec<User> = new_state
+                    .nominations
+                    .iter()
+                    .filter(|(_, targets)| targets.contains(offender))
+                    .map(|(nominator, _)| *nominator)
+                    .collect();
+                slash_stake(&mut new_state, offender, percent);
+                for nominator in nominators.iter() {
+                    slash_stake(&mut new_state, nominator, percent);
+                }
+                new_state.validators.remove(offender);
+            }
+            StakingTransaction::NewEra => {
+                new_state.era += 1;
+                new_state.elected = new_state.elect();
+            }
+        }
+        new_state
+    }
+
+    fn human_name() -> String {
+        "Nominated Proof of Stake".into()
+    }
+}
+
+/// Run a sequence of transactions starting from the given state.
+#[cfg(test)]
+fn apply_all(state: &State, transactions: Vec<StakingTransaction>) -> State {
+    transactions
+        .iter()
+        .fold(state.clone(), |state, t| Staking::next_state(&state, t))
+}
+
+#[test]
+fn cs_7_bond_moves_free_to_bonded() {
+    let start = State::new(2, [(User::Alice, 100)]);
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 60,
+            },
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 1000,
+            },
+        ],
+    );
+
+    assert_eq!(end.free_balance(&User::Alice), 0);
+    assert_eq!(end.bonded_balance(&User::Alice), 100);
+}
+
+#[test]
+fn cs_7_unbonded_funds_locked_for_bonding_duration() {
+    let start = State::new(2, [(User::Alice, 100)]);
+    let mut state = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 100,
+            },
+            StakingTransaction::Unbond {
+                who: User::Alice,
+                amount: 40,
+            },
+        ],
+    );
+    assert_eq!(state.bonded_balance(&User::Alice), 60);
+    assert_eq!(state.unlocking_balance(&User::Alice), 40);
+
+    // Not yet withdrawable after one era
+    state = apply_all(
+        &state,
+        vec![
+            StakingTransaction::NewEra,
+            StakingTransaction::WithdrawUnbonded { who: User::Alice },
+        ],
+    );
+    assert_eq!(state.free_balance(&User::Alice), 0);
+    assert_eq!(state.unlocking_balance(&User::Alice), 40);
+
+    state = apply_all(
+        &state,
+        vec![
+            StakingTransaction::NewEra,
+            StakingTransaction::WithdrawUnbonded { who: User::Alice },
+        ],
+    );
+    assert_eq!(state.free_balance(&User::Alice), 40);
+    assert_eq!(state.unlocking_balance(&User::Alice), 0);
+}
+
+#[test]
+fn cs_7_unbonded_validator_cannot_validate() {
+    let start = State::new(2, [(User::Alice, 100)]);
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Validate { who: User::Alice },
+            StakingTransaction::NewEra,
+        ],
+    );
+
+    assert!(end.authorities().is_empty());
+}
+
+#[test]
+fn cs_7_election_picks_top_backed_validators() {
+    let start = State::new(
+        2,
+        [(User::Alice, 100), (User::Bob, 50), (User::Charlie, 70)],
+    );
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 10,
+            },
+            StakingTransaction::Bond {
+                who: User::Bob,
+                amount: 50,
+            },
+            StakingTransaction::Bond {
+                who: User::Charlie,
+                amount: 30,
+            },
+            StakingTransaction::Validate { who: User::Alice },
+            StakingTransaction::Validate { who: User::Bob },
+            StakingTransaction::Validate { who: User::Charlie },
+            StakingTransaction::NewEra,
+        ],
+    );
+
+    assert_eq!(end.era(), 1);
+    assert_eq!(end.elected(), &[(User::Bob, 50), (User::Charlie, 30)]);
+    assert_eq!(
+        end.authorities(),
+        vec![ConsensusAuthority::Bob, ConsensusAuthority::Charlie]
+    );
+}
+
+#[test]
+fn cs_7_nominations_change_election() {
+    let start = State::new(
+        1,
+        [(User::Alice, 100), (User::Bob, 50), (User::Charlie, 70)],
+    );
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 10,
+            },
+            StakingTransaction::Bond {
+                who: User::Bob,
+                amount: 50,
+            },
+            StakingTransaction::Bond {
+                who: User::Charlie,
+                amount: 70,
+            },
+            StakingTransaction::Validate { who: User::Alice },
+            StakingTransaction::Validate { who: User::Bob },
+            StakingTransaction::Nominate {
+                who: User::Charlie,
+                targets: vec![User::Alice],
+            },
+            StakingTransaction::NewEra,
+        ],
+    );
+
+    assert_eq!(end.elected(), &[(User::Alice, 80)]);
+}
+
+#[test]
+fn cs_7_nominator_splits_stake_among_targets() {
+    let start = State::new(3, [(User::Alice, 10), (User::Bob, 10), (User::Charlie, 70)]);
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 10,
+            },
+            StakingTransaction::Bond {
+                who: User::Bob,
+                amount: 10,
+            },
+            StakingTransaction::Bond {
+                who: User::Charlie,
+                amount: 70,
+            },
+            StakingTransaction::Validate { who: User::Alice },
+            StakingTransaction::Validate { who: User::Bob },
+            StakingTransaction::Nominate {
+                who: User::Charlie,
+                targets: vec![User::Alice, User::Bob],
+            },
+        ],
+    );
+
+    assert_eq!(
+        end.backing(),
+        HashMap::from([(User::Alice, 45), (User::Bob, 45)])
+    );
+}
+
+#[test]
+fn cs_7_ties_are_broken_deterministically() {
+    let start = State::new(1, [(User::Bob, 10), (User::Charlie, 10)]);
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Charlie,
+                amount: 10,
+            },
+            StakingTransaction::Bond {
+                who: User::Bob,
+                amount: 10,
+            },
+            StakingTransaction::Validate { who: User::Charlie },
+            StakingTransaction::Validate { who: User::Bob },
+            StakingTransaction::NewEra,
+        ],
+    );
+
+    assert_eq!(end.authorities(), vec![ConsensusAuthority::Bob]);
+}
+
+#[test]
+fn cs_7_slash_hits_validator_and_nominators() {
+    let start = State::new(2, [(User::Alice, 100), (User::Bob, 100)]);
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 100,
+            },
+            StakingTransaction::Bond {
+                who: User::Bob,
+                amount: 100,
+            },
+            StakingTransaction::Unbond {
+                who: User::Bob,
+                amount: 20,
+            },
+            StakingTransaction::Validate { who: User::Alice },
+            StakingTransaction::Nominate {
+                who: User::Bob,
+                targets: vec![User::Alice],
+            },
+            StakingTransaction::Slash {
+                offender: User::Alice,
+                percent: 10,
+            },
+            StakingTransaction::NewEra,
+        ],
+    );
+
+    assert_eq!(end.bonded_balance(&User::Alice), 90);
+    assert_eq!(end.bonded_balance(&User::Bob), 72);
+    // Unbonding does not protect from slashes
+    assert_eq!(end.unlocking_balance(&User::Bob), 18);
+    // The offender is no longer a candidate
+    assert!(end.authorities().is_empty());
+}
+
+#[test]
+fn cs_7_slash_large_stake() {
+    let start = State::new(1, [(User::Alice, u64::MAX)]);
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: u64::MAX,
+            },
+            StakingTransaction::Unbond {
+                who: User::Alice,
+                amount: u64::MAX / 2,
+            },
+            StakingTransaction::Slash {
+                offender: User::Alice,
+                percent: 50,
+            },
+        ],
+    );
+
+    assert_eq!(end.bonded_balance(&User::Alice), u64::MAX / 4 + 1);
+    assert_eq!(end.unlocking_balance(&User::Alice), u64::MAX / 4 + 1);
+}
+
+#[test]
+fn cs_7_huge_backing_does_not_overflow() {
+    let start = State::new(1, [(User::Alice, u64::MAX), (User::Bob, u64::MAX)]);
+    let end = apply_all(
+        &start,
+        vec![
+            StakingTransaction::Bond {
+                who: User::Alice,
+                amount: u64::MAX,
+            },
+            StakingTransaction::Bond {
+                who: User::Bob,
+                amount: u64::MAX,
+            },
+            StakingTransaction::Validate { who: User::Alice },
+            StakingTransaction::Nominate {
+                who: User::Bob,
+                targets: vec![User::Alice],
+            },
+            StakingTransaction::NewEra,
+        ],
+    );
+
+    assert_eq!(end.elected(), &[(User::Alice, 2 * u64::MAX as u128)]);
+    assert_eq!(end.authorities(), vec![ConsensusAuthority::Alice]);
+}
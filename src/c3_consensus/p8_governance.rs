@@ -0,0 +1,580 @@
+//! In the blockchain chapter, the even/odd contentious fork was settled entirely off-chain. Someone
+//! picked a `FORK_HEIGHT`, hardcoded it, and every node operator had to decide which software to
+//! run. The forking engines from earlier in this chapter have the same property. The fork height and
+//! the new rules are baked into the engine when it is constructed.
+//!
+//! An alternative is to make the rules themselves part of the on-chain state. Token holders submit
+//! proposals, vote on them with their balance, and proposals that pass are scheduled to take effect
+//! at some future height. Every node reads the schedule from the state, so the whole network switches
+//! rules at the same block without anyone shipping new software.
+//!
+//! This module models such a governance system as a state machine. Like the staking machine, it does
+//! not know about blocks on its own, so the client applies a `NewBlock` transition at every block.
+
+use std::collections::{HashMap, HashSet};
+
+use super::ConsensusAuthority;
+use crate::c1_state_machine::{StateMachine, User};
+
+/// Proposals are identified by a simple counter that is incremented on every submission.
+pub type ProposalId = u64;
+
+/// This state machine models on-chain governance of the consensus parameters.
+pub struct Governance;
+
+/// A restriction on the parity of the state root, as in the contentious fork from the previous chapter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Parity {
+    /// Any state root is valid
+    Any,
+    /// Only even state roots are valid
+    Even,
+    /// Only odd state roots are valid
+    Odd,
+}
+
+/// A single consensus parameter change that a proposal may schedule.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ParameterChange {
+    /// Change the PoW threshold
+    PowThreshold(u64),
+    /// Replace the PoA authority set
+    Authorities(Vec<ConsensusAuthority>),
+    /// Change the state root parity rule
+    StateParity(Parity),
+}
+
+/// The complete set of consensus parameters in effect at some height.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Parameters {
+    pub pow_threshold: u64,
+    pub authorities: Vec<ConsensusAuthority>,
+    pub state_parity: Parity,
+}
+
+impl Parameters {
+    fn apply(&mut self, change: &ParameterChange) {
+        match change {
+            ParameterChange::PowThreshold(threshold) => self.pow_threshold = *threshold,
+            ParameterChange::Authorities(authorities) => self.authorities = authorities.clone(),
+            ParameterChange::StateParity(parity) => self.state_parity = *parity,
+        }
+    }
+}
+
+/// The lifecycle of a proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposalStatus {
+    /// Voting is still open
+    Voting,
+    /// The proposal passed and its change has been scheduled
+    Passed,
+    /// The proposal did not reach quorum or had more nays than ayes
+    Rejected,
+}
+
+/// A proposal to change a consensus parameter at a future height.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proposal {
+    /// The change to make if the proposal passes
+    change: ParameterChange,
+    /// The height at which the change takes effect
+    enactment_height: u64,
+    /// The last height at which votes are accepted
+    voting_ends: u64,
+    /// Total balance voting in favor. Wider than a balance, since the votes of several holders
+    /// may not fit in a u64.
+    ayes: u128,
+    /// Total balance voting against
+    nays: u128,
+    /// Users who have already voted. Each user may vote only once.
+    voters: HashSet<User>,
+    status: ProposalStatus,
+}
+
+impl Proposal {
+    /// The current status of the proposal.
+    pub fn status(&self) -> ProposalStatus {
+        self.status
+    }
+}
+
+/// The complete state of the governance system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    /// The voting power of each token holder.
+    balances: HashMap<User, u64>,
+    /// The current block height.
+    height: u64,
+    /// How many blocks proposals stay open for voting.
+    voting_period: u64,
+    /// The percentage of the total issuance that must vote for a proposal to be decided. At most 100.
+    quorum_percent: u64,
+    /// The parameters in effect at genesis.
+    genesis_parameters: Parameters,
+    /// All proposals ever submitted, indexed by id.
+    proposals: HashMap<ProposalId, Proposal>,
+    /// Changes from passed proposals, in the order they passed, along with their enactment height.
+    scheduled: Vec<(u64, ParameterChange)>,
+    /// The id that will be given to the next submitted proposal.
+    next_proposal_id: ProposalId,
+}
+
+impl State {
+    /// Create a genesis state with the given token holders and governance configuration.
+    ///
+    /// Panics if the quorum is more than 100 percent.
+    pub fn new<const N: usize>(
+        genesis_parameters: Parameters,
+        voting_period: u64,
+        quorum_percent: u64,
+        balances: [(User, u64); N],
+    ) -> Self {
+        assert!(quorum_percent <= 100, "quorum must be at most 100 percent");
+        State {
+            balances: balances.into_iter().filter(|(_, b)| *b > 0).collect(),
+            height: 0,
+            voting_period,
+            quorum_percent,
+            genesis_parameters,
+            proposals: HashMap::new(),
+            scheduled: Vec::new(),
+            next_proposal_id: 0,
+        }
+    }
+
+    /// The current block height as seen by the governance system.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Look up a proposal by id.
+    pub fn proposal(&self, id: ProposalId) -> Option<&Proposal> {
+        self.proposals.get(&id)
+    }
+
+    /// The id that will be given to the next submitted proposal.
+    pub fn next_proposal_id(&self) -> ProposalId {
+        self.next_proposal_id
+    }
+
+    /// All changes that passed, along with the height at which they take effect.
+    pub fn scheduled_changes(&self) -> &[(u64, ParameterChange)] {
+        &self.scheduled
+    }
+
+    /// The parameters that are in effect at the given height.
+    ///
+    /// This is the method that the client and consensus engines use to follow governance. It may be
+    /// asked about future heights, in which case it only reflects the changes scheduled so far.
+    pub fn parameters_at(&self, height: u64) -> Parameters {
+        let mut parameters = self.genesis_parameters.clone();
+        let mut changes: Vec<&(u64, ParameterChange)> = self
+            .scheduled
+            .iter()
+            .filter(|(enactment_height, _)| *enactment_height <= height)
+            .collect();
+        // When several changes touch the same parameter, the one enacted latest wins. The sort is
+        // stable, so changes enacted at the same height apply in the order they passed.
+        changes.sort_by_key(|(enactment_height, _)| *enactment_height);
+        for (_, change) in changes {
+            parameters.apply(change);
+        }
+        parameters
+    }
+
+    fn total_issuance(&self) -> u128 {
+        self.balances.values().map(|b| *b as u128).sum()
+    }
+
+    /// Decide every proposal whose voting period has ended.
+    fn close_finished_votes(&mut self) {
+        let quorum = self.total_issuance() * self.quorum_percent as u128 / 100;
+        let mut ids: Vec<ProposalId> = self.proposals.keys().copied().collect();
+        // Decide proposals in submission order so the schedule is deterministic.
+        ids.sort();
+        for id in ids {
+            let proposal = &self.proposals[&id];
+            if proposal.status != ProposalStatus::Voting || proposal.voting_ends >= self.height {
+                continue;
+            }
+            let turnout = proposal.ayes + proposal.nays;
+            let status = if turnout >= quorum && proposal.ayes > proposal.nays {
+                self.scheduled
+                    .push((proposal.enactment_height, proposal.change.clone()));
+                ProposalStatus::Passed
+            } else {
+                ProposalStatus::Rejected
+            };
+            self.proposals.get_mut(&id).unwrap().status = status;
+        }
+    }
+}
+
+/// The state transitions that users can make in the governance system.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GovernanceTransaction {
+    /// Submit a proposal. Only token holders may submit. The enactment height must be after the end of
+    /// the voting period. The new proposal receives the id `State::next_proposal_id`.
+    Submit {
+        proposer: User,
+        change: ParameterChange,
+        enactment_height: u64,
+    },
+    /// Vote on an open proposal with the voter's entire balance.
+    Vote {
+        voter: User,
+        proposal: ProposalId,
+        aye: bool,
+    },
+    /// Advance to the next block height. Any proposal whose voting period has ended is decided.
+    NewBlock,
+}
+
+/// We model governance as a state machine with three possible transitions
+impl StateMachine for Governance {
+    type State = State;
+    type Transition = GovernanceTransaction;
+
+    fn next_state(starting_state: &State, t: &GovernanceTransaction) -> State {
+        let mut new_state = starting_state.clone();
+        match t {
+            GovernanceTransaction::Submit {
+                proposer,
+                change,
+                enactment_height,
+            } => {
+                let voting_ends = new_state.height + new_state.voting_period;
+                if new_state.balances.contains_key(proposer) && *enactment_height > voting_ends {
+                    let id = new_state.next_proposal_id;
+                    new_state.proposals.insert(
+                        id,
+                        Proposal {
+                            change: change.clone(),
+                            enactment_height: *enactment_height,
+                            voting_ends,
+                            ayes: 0,
+                            nays: 0,
+                            voters: HashSet::new(),
+                            status: ProposalStatus::Voting,
+                        },
+                    );
+                    new_state.next_proposal_id += 1;
+                }
+            }
+            GovernanceTransaction::Vote {
+                voter,
+                proposal,
+                aye,
+            } => {
+                let weight = new_state.balances.get(voter).copied().unwrap_or(0);
+                let height = new_state.height;
+                if let Some(p) = new_state.proposals.get_mut(proposal) {
+                    if weight > 0
+                        && p.status == ProposalStatus::Voting
+                        && height <= p.voting_ends
+                        && p.voters.insert(*voter)
+                    {
+                        if *aye {
+                            p.ayes += weight as u128;
+                        } else {
+                            p.nays += weight as u128;
+                        }
+                    }
+                }
+            }
+            GovernanceTransaction::NewBlock => {
+                new_state.height += 1;
+                new_state.close_finished_votes();
+            }
+        }
+        new_state
+    }
+
+    fn human_name() -> String {
+        "On-chain Governance".into()
+    }
+}
+
+#[cfg(test)]
+fn genesis() -> State {
+    State::new(
+        Parameters {
+            pow_threshold: u64::MAX / 100,
+            authorities: vec![ConsensusAuthority::Alice],
+            state_parity: Parity::Any,
+        },
+        3,
+        50,
+        [(User::Alice, 50), (User::Bob, 30), (User::Charlie, 20)],
+    )
+}
+
+#[cfg(test)]
+fn apply_all(state: &State, transactions: Vec<GovernanceTransaction>) -> State {
+    transactions
+        .iter()
+        .fold(state.clone(), |state, t| Governance::next_state(&state, t))
+}
+
+#[cfg(test)]
+fn new_blocks(n: usize) -> Vec<GovernanceTransaction> {
+    vec![GovernanceTransaction::NewBlock; n]
+}
+
+#[test]
+fn cs_8_submit_proposal() {
+    let state = apply_all(
+        &genesis(),
+        vec![GovernanceTransaction::Submit {
+            proposer: User::Bob,
+            change: ParameterChange::PowThreshold(7),
+            enactment_height: 10,
+        }],
+    );
+
+    assert_eq!(state.next_proposal_id(), 1);
+    assert_eq!(
+        state.proposal(0).map(|p| p.status()),
+        Some(ProposalStatus::Voting)
+    );
+}
+
+#[test]
+fn cs_8_cannot_enact_during_voting_period() {
+    let start = genesis();
+    let end = Governance::next_state(
+        &start,
+        &GovernanceTransaction::Submit {
+            proposer: User::Bob,
+            change: ParameterChange::PowThreshold(7),
+            enactment_height: 3,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn cs_8_proposal_passes_and_schedules_change() {
+    let mut transactions = vec![
+        GovernanceTransaction::Submit {
+            proposer: User::Bob,
+            change: ParameterChange::StateParity(Parity::Even),
+            enactment_height: 10,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Alice,
+            proposal: 0,
+            aye: true,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Bob,
+            proposal: 0,
+            aye: false,
+        },
+    ];
+    transactions.extend(new_blocks(4));
+    let state = apply_all(&genesis(), transactions);
+
+    assert_eq!(state.proposal(0).unwrap().status(), ProposalStatus::Passed);
+    assert_eq!(
+        state.scheduled_changes(),
+        &[(10, ParameterChange::StateParity(Parity::Even))]
+    );
+    assert_eq!(state.parameters_at(9).state_parity, Parity::Any);
+    assert_eq!(state.parameters_at(10).state_parity, Parity::Even);
+    assert_eq!(state.parameters_at(10).pow_threshold, u64::MAX / 100);
+}
+
+#[test]
+fn cs_8_proposal_stays_open_until_period_ends() {
+    let mut transactions = vec![
+        GovernanceTransaction::Submit {
+            proposer: User::Bob,
+            change: ParameterChange::PowThreshold(7),
+            enactment_height: 10,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Alice,
+            proposal: 0,
+            aye: true,
+        },
+    ];
+    transactions.extend(new_blocks(3));
+    let state = apply_all(&genesis(), transactions);
+
+    assert_eq!(state.proposal(0).unwrap().status(), ProposalStatus::Voting);
+}
+
+#[test]
+fn cs_8_proposal_without_quorum_is_rejected() {
+    let mut transactions = vec![
+        GovernanceTransaction::Submit {
+            proposer: User::Bob,
+            change: ParameterChange::PowThreshold(7),
+            enactment_height: 10,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Bob,
+            proposal: 0,
+            aye: true,
+        },
+    ];
+    transactions.extend(new_blocks(4));
+    let state = apply_all(&genesis(), transactions);
+
+    assert_eq!(
+        state.proposal(0).unwrap().status(),
+        ProposalStatus::Rejected
+    );
+    assert!(state.scheduled_changes().is_empty());
+}
+
+#[test]
+fn cs_8_majority_nays_reject() {
+    let mut transactions = vec![
+        GovernanceTransaction::Submit {
+            proposer: User::Bob,
+            change: ParameterChange::PowThreshold(7),
+            enactment_height: 10,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Alice,
+            proposal: 0,
+            aye: false,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Bob,
+            proposal: 0,
+            aye: true,
+        },
+    ];
+    transactions.extend(new_blocks(4));
+    let state = apply_all(&genesis(), transactions);
+
+    assert_eq!(
+        state.proposal(0).unwrap().status(),
+        ProposalStatus::Rejected
+    );
+}
+
+#[test]
+fn cs_8_users_vote_only_once() {
+    let mut transactions = vec![
+        GovernanceTransaction::Submit {
+            proposer: User::Bob,
+            change: ParameterChange::PowThreshold(7),
+            enactment_height: 10,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Charlie,
+            proposal: 0,
+            aye: true,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Charlie,
+            proposal: 0,
+            aye: true,
+        },
+    ];
+    transactions.extend(new_blocks(4));
+    let state = apply_all(&genesis(), transactions);
+
+    // Charlie's 20 counted twice would have reached the quorum of 50
+    assert_eq!(
+        state.proposal(0).unwrap().status(),
+        ProposalStatus::Rejected
+    );
+}
+
+#[test]
+fn cs_8_late_votes_are_ignored() {
+    let mut transactions = vec![GovernanceTransaction::Submit {
+        proposer: User::Bob,
+        change: ParameterChange::PowThreshold(7),
+        enactment_height: 10,
+    }];
+    transactions.extend(new_blocks(4));
+    transactions.push(GovernanceTransaction::Vote {
+        voter: User::Alice,
+        proposal: 0,
+        aye: true,
+    });
+    let state = apply_all(&genesis(), transactions);
+
+    assert_eq!(
+        state.proposal(0).unwrap().status(),
+        ProposalStatus::Rejected
+    );
+}
+
+#[test]
+fn cs_8_later_changes_override_earlier_ones() {
+    let mut transactions = vec![
+        GovernanceTransaction::Submit {
+            proposer: User::Alice,
+            change: ParameterChange::Authorities(vec![ConsensusAuthority::Bob]),
+            enactment_height: 20,
+        },
+        GovernanceTransaction::Submit {
+            proposer: User::Alice,
+            change: ParameterChange::Authorities(vec![ConsensusAuthority::Charlie]),
+            enactment_height: 10,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Alice,
+            proposal: 0,
+            aye: true,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Alice,
+            proposal: 1,
+            aye: true,
+        },
+    ];
+    transactions.extend(new_blocks(4));
+    let state = apply_all(&genesis(), transactions);
+
+    assert_eq!(
+        state.parameters_at(5).authorities,
+        vec![ConsensusAuthority::Alice]
+    );
+    assert_eq!(
+        state.parameters_at(15).authorities,
+        vec![ConsensusAuthority::Charlie]
+    );
+    assert_eq!(
+        state.parameters_at(25).authorities,
+        vec![ConsensusAuthority::Bob]
+    );
+}
+
+#[test]
+fn cs_8_huge_balances_do_not_overflow() {
+    let mut state = genesis();
+    state.balances = HashMap::from([(User::Alice, u64::MAX), (User::Bob, u64::MAX)]);
+    let mut transactions = vec![
+        GovernanceTransaction::Submit {
+            proposer: User::Alice,
+            change: ParameterChange::PowThreshold(7),
+            enactment_height: 10,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Alice,
+            proposal: 0,
+            aye: true,
+        },
+        GovernanceTransaction::Vote {
+            voter: User::Bob,
+            proposal: 0,
+            aye: true,
+        },
+    ];
+    transactions.extend(new_blocks(4));
+    let state = apply_all(&state, transactions);
+
+    assert_eq!(state.proposal(0).unwrap().ayes, 2 * u64::MAX as u128);
+    assert_eq!(state.proposal(0).unwrap().status(), ProposalStatus::Passed);
+    assert_eq!(state.parameters_at(10).pow_threshold, 7);
+}
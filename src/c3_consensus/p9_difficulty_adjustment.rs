@@ -0,0 +1,399 @@
+//! Our Proof of Work engines so far use a fixed threshold. But the amount of hashing power on a
+//! real network changes constantly as miners join and leave. With a fixed threshold, doubling the
+//! hashrate halves the block time. Real chains instead retarget: they periodically recompute the
+//! threshold from how long recent blocks actually took, so that block times stay near a target.
+//!
+//! The threshold each block was mined against is stored in its consensus digest. That way
+//! `validate` can recompute what the threshold should have been from the parent digest alone, and
+//! check that the author didn't give themselves an easier puzzle. For the same reason the digest
+//! also carries a copy of the block's timestamp, since `validate` only ever sees the parent's digest
+//! and not the parent's header.
+//!
+//! We implement two adjustment algorithms.
+//! * Epoch retargeting, as in Bitcoin. The threshold stays fixed for an epoch of blocks. At the
+//!   start of each new epoch it is scaled by how long the previous epoch actually took compared to
+//!   how long it should have taken. The change is limited to a factor of four in either direction.
+//! * A per-block moving average. Every block nudges the threshold a little towards the value that
+//!   would have produced the target block time. This reacts to hashrate changes much faster and
+//!   more smoothly, which is why many newer chains prefer it.
+
+use super::{Consensus, Header};
+use crate::hash;
+
+#[cfg(test)]
+use super::partial_header;
+
+/// The consensus digest used by difficulty-adjusting Proof of Work.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PowDigest {
+    /// The nonce found by the miner.
+    pub nonce: u64,
+    /// The threshold this block's hash had to be below.
+    pub threshold: u64,
+    /// A copy of this block's timestamp, so that children can see how long it took.
+    pub timestamp: u64,
+    /// A reference time chosen by the adjustment algorithm. Epoch retargeting stores the time the
+    /// current epoch started here. Algorithms that don't need it just carry it forward.
+    pub anchor: u64,
+}
+
+/// A rule for deciding a block's threshold (and anchor) from its parent's digest.
+pub trait DifficultyAdjustment {
+    /// Returns the threshold and anchor that a block at the given height with the given
+    /// timestamp must use, given its parent's digest.
+    fn next(&self, parent: &PowDigest, height: u64, timestamp: u64) -> (u64, u64);
+}
+
+/// Scale a threshold by `numerator / denominator` without overflowing. The result never drops to
+/// zero, because no hash is below a threshold of zero.
+fn scale(threshold: u64, numerator: u64, denominator: u64) -> u64 {
+    let scaled = threshold as u128 * numerator as u128 / denominator.max(1) as u128;
+    scaled.clamp(1, u64::MAX as u128) as u64
+}
+
+/// Bitcoin-style retargeting once per epoch.
+pub struct EpochRetarget {
+    /// The number of seconds we would like each block to take.
+    pub target_block_time: u64,
+    /// The number of blocks in each epoch. Bitcoin uses 2016. Zero is treated as one.
+    pub epoch_length: u64,
+}
+
+impl DifficultyAdjustment for EpochRetarget {
+    fn next(&self, parent: &PowDigest, height: u64, _: u64) -> (u64, u64) {
+        let epoch_length = self.epoch_length.max(1);
+        if !height.is_multiple_of(epoch_length) {
+            return (parent.threshold, parent.anchor);
+        }
+        // The parent is the last block of the epoch that just finished. The anchor is the last
+        // block of the epoch before that, so the gap between them spans a whole epoch.
+        let actual = parent.timestamp.saturating_sub(parent.anchor);
+        let expected = self.target_block_time.saturating_mul(epoch_length);
+        let actual = actual.clamp(expected / 4, expected.saturating_mul(4));
+        (scale(parent.threshold, actual, expected), parent.timestamp)
+    }
+}
+
+/// Adjust every block using an exponential moving average over roughly `window` blocks.
+pub struct MovingAverage {
+    /// The number of seconds we would like each block to take.
+    pub target_block_time: u64,
+    /// How many blocks it takes for the average to mostly forget old block times. Larger windows
+    /// react more slowly but are less sensitive to the natural randomness of block times. Zero is
+    /// treated as one.
+    pub window: u64,
+}
+
+impl DifficultyAdjustment for MovingAverage {
+    fn next(&self, parent: &PowDigest, _: u64, timestamp: u64) -> (u64, u64) {
+        // Timestamps need not increase, and a single wild timestamp should not move the
+        // threshold too far, so the observed block time is bounded.
+        let solve_time = timestamp
+            .saturating_sub(parent.timestamp)
+            .min(self.target_block_time.saturating_mul(10));
+        // Move 1 / window of the way towards `threshold * solve_time / target`.
+        let window = self.window.max(1);
+        let numerator = (window - 1)
+            .saturating_mul(self.target_block_time)
+            .saturating_add(solve_time);
+        let denominator = window.saturating_mul(self.target_block_time);
+        (
+            scale(parent.threshold, numerator, denominator),
+            parent.anchor,
+        )
+    }
+}
+
+/// A Proof of Work consensus engine whose threshold is retargeted by the given algorithm.
+pub struct AdjustingPow<D: DifficultyAdjustment> {
+    pub adjustment: D,
+}
+
+impl<D: DifficultyAdjustment> AdjustingPow<D> {
+    /// The digest to put in a genesis header with the given starting threshold and timestamp.
+    /// Genesis is not mined, so the nonce is unimportant.
+    pub fn genesis_digest(initial_threshold: u64, genesis_timestamp: u64) -> PowDigest {
+        PowDigest {
+            nonce: 0,
+            threshold: initial_threshold,
+            timestamp: genesis_timestamp,
+            anchor: genesis_timestamp,
+        }
+    }
+}
+
+impl<D: DifficultyAdjustment> Consensus for AdjustingPow<D> {
+    type Digest = PowDigest;
+
+    /// Check that the digest uses the retargeted threshold and anchor, carries the header's
+    /// timestamp, and that the header's hash is below that threshold.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        let expected = self
+            .adjustment
+            .next(parent_digest, header.height, header.timestamp);
+        digest.timestamp == header.timestamp
+            && (digest.threshold, digest.anchor) == expected
+            && hash(header) < digest.threshold
+    }
+
+    /// Mine a seal at the retargeted threshold. Nonces are tried in order from zero.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let (threshold, anchor) = self.adjustment.next(
+            parent_digest,
+            partial_header.height,
+            partial_header.timestamp,
+        );
+        let mut header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            timestamp: partial_header.timestamp,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: PowDigest {
+                nonce: 0,
+                threshold,
+                timestamp: partial_header.timestamp,
+                anchor,
+            },
+        };
+        for nonce in 0..=u64::MAX {
+            header.consensus_digest.nonce = nonce;
+            if hash(&header) < threshold {
+                return Some(header);
+            }
+        }
+        None
+    }
+
+    fn human_name() -> String {
+        "Difficulty Adjusting Proof of Work".into()
+    }
+}
+
+/// Simulate mining `blocks` blocks with the given hashrate, in hashes per second, returning the
+/// final digest and the time each block took.
+///
+/// Instead of actually mining, which would be far too slow, we draw each block time from the
+/// exponential distribution that real mining follows. With a threshold `t`, each hash succeeds
+/// with probability `t / 2^64`, so the mean block time is `2^64 / (t * hashrate)`.
+#[cfg(test)]
+fn simulate<D: DifficultyAdjustment>(
+    adjustment: &D,
+    rng: &mut rand::rngs::StdRng,
+    mut parent: PowDigest,
+    first_height: u64,
+    blocks: u64,
+    hashrate: f64,
+) -> (PowDigest, Vec<u64>) {
+    use rand::Rng;
+
+    let mut times = Vec::new();
+    for height in first_height..first_height + blocks {
+        let mean = 2f64.powi(64) / (parent.threshold as f64 * hashrate);
+        // Block times are drawn as if the threshold were the parent's. For epoch retargeting this
+        // is exact. For the moving average, the threshold only changes by a fraction of a percent
+        // per block, so the difference is negligible.
+        let solve_time = (-rng.gen::<f64>().ln() * mean).round() as u64;
+        let timestamp = parent.timestamp + solve_time;
+        let (threshold, anchor) = adjustment.next(&parent, height, timestamp);
+        times.push(solve_time);
+        parent = PowDigest {
+            nonce: 0,
+            threshold,
+            timestamp,
+            anchor,
+        };
+    }
+    (parent, times)
+}
+
+#[cfg(test)]
+fn mean(times: &[u64]) -> f64 {
+    times.iter().sum::<u64>() as f64 / times.len() as f64
+}
+
+/// A threshold at which the given hashrate produces the given mean block time.
+#[cfg(test)]
+fn threshold_for(hashrate: f64, block_time: u64) -> u64 {
+    (2f64.powi(64) / (hashrate * block_time as f64)) as u64
+}
+
+#[test]
+fn cs_9_epoch_threshold_fixed_within_epoch() {
+    let adjustment = EpochRetarget {
+        target_block_time: 10,
+        epoch_length: 5,
+    };
+    let parent = AdjustingPow::<EpochRetarget>::genesis_digest(1000, 0);
+
+    assert_eq!(adjustment.next(&parent, 1, 1), (1000, 0));
+    assert_eq!(adjustment.next(&parent, 4, 1_000_000), (1000, 0));
+}
+
+#[test]
+fn cs_9_epoch_retarget_scales_and_clamps() {
+    let adjustment = EpochRetarget {
+        target_block_time: 10,
+        epoch_length: 4,
+    };
+    let parent = |timestamp| PowDigest {
+        nonce: 0,
+        threshold: 1000,
+        timestamp,
+        anchor: 100,
+    };
+
+    // The epoch took twice as long as it should, so the puzzle gets twice as easy
+    assert_eq!(adjustment.next(&parent(180), 4, 181), (2000, 180));
+    // The epoch was much too fast, but the change is limited to a factor of four
+    assert_eq!(adjustment.next(&parent(101), 8, 102), (250, 101));
+}
+
+#[test]
+fn cs_9_moving_average_nudges_each_block() {
+    let adjustment = MovingAverage {
+        target_block_time: 10,
+        window: 10,
+    };
+    let parent = PowDigest {
+        nonce: 0,
+        threshold: 1000,
+        timestamp: 50,
+        anchor: 0,
+    };
+
+    assert_eq!(adjustment.next(&parent, 7, 60).0, 1000);
+    assert_eq!(adjustment.next(&parent, 7, 70).0, 1100);
+    assert_eq!(adjustment.next(&parent, 7, 50).0, 900);
+    // A timestamp before the parent's counts as a zero second block
+    assert_eq!(adjustment.next(&parent, 7, 40).0, 900);
+}
+
+#[test]
+fn cs_9_extreme_parameters_do_not_panic() {
+    let parent = PowDigest {
+        nonce: 0,
+        threshold: 1000,
+        timestamp: 50,
+        anchor: 0,
+    };
+
+    // A window of one jumps straight to the value the last block time suggests, and so does zero.
+    for window in [0, 1] {
+        let adjustment = MovingAverage {
+            target_block_time: 10,
+            window,
+        };
+        assert_eq!(adjustment.next(&parent, 7, 70).0, 2000);
+    }
+    let adjustment = MovingAverage {
+        target_block_time: u64::MAX,
+        window: u64::MAX,
+    };
+    assert_eq!(adjustment.next(&parent, 7, 70).0, 1000);
+
+    // An epoch of zero blocks retargets every block, like an epoch of one. The 50 second block
+    // is clamped to four times the target.
+    let adjustment = EpochRetarget {
+        target_block_time: 10,
+        epoch_length: 0,
+    };
+    assert_eq!(adjustment.next(&parent, 7, 70), (4000, 50));
+    let adjustment = EpochRetarget {
+        target_block_time: u64::MAX,
+        epoch_length: u64::MAX,
+    };
+    assert_eq!(adjustment.next(&parent, u64::MAX, 70), (249, 50));
+}
+
+#[test]
+fn cs_9_seal_and_validate() {
+    let engine = AdjustingPow {
+        adjustment: MovingAverage {
+            target_block_time: 10,
+            window: 10,
+        },
+    };
+    let genesis = AdjustingPow::<MovingAverage>::genesis_digest(u64::MAX / 8, 0);
+
+    let header = engine
+        .seal(
+            &genesis,
+            Header {
+                timestamp: 20,
+                ..partial_header(1)
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        header.consensus_digest.threshold,
+        scale(u64::MAX / 8, 110, 100)
+    );
+    assert!(engine.validate(&genesis, &header));
+
+    // Claiming an easier threshold than the retarget allows is invalid, even with a good hash
+    let mut easier = header.clone();
+    easier.consensus_digest.threshold = u64::MAX;
+    assert!(!engine.validate(&genesis, &easier));
+
+    // The digest must agree with the header about the time
+    let mut wrong_time = header.clone();
+    wrong_time.timestamp = 21;
+    assert!(!engine.validate(&genesis, &wrong_time));
+}
+
+#[test]
+fn cs_9_epoch_retarget_converges_after_hashrate_change() {
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(35);
+    let adjustment = EpochRetarget {
+        target_block_time: 600,
+        epoch_length: 100,
+    };
+    let genesis = AdjustingPow::<EpochRetarget>::genesis_digest(threshold_for(1e6, 600), 0);
+
+    let (tip, before) = simulate(&adjustment, &mut rng, genesis, 1, 499, 1e6);
+    assert!((mean(&before) - 600.0).abs() < 60.0);
+
+    // The hashrate quadruples. Blocks come four times too fast until the next retarget.
+    let (tip, during) = simulate(&adjustment, &mut rng, tip, 500, 100, 4e6);
+    assert!(mean(&during) < 300.0);
+
+    // After a couple of epochs block times are back near the target.
+    let (_, after) = simulate(&adjustment, &mut rng, tip, 600, 400, 4e6);
+    assert!(
+        (mean(&after[100..]) - 600.0).abs() < 60.0,
+        "mean {}",
+        mean(&after[100..])
+    );
+}
+
+#[test]
+fn cs_9_moving_average_converges_after_hashrate_change() {
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(35);
+    let adjustment = MovingAverage {
+        target_block_time: 600,
+        window: 50,
+    };
+    let genesis = AdjustingPow::<MovingAverage>::genesis_digest(threshold_for(1e6, 600), 0);
+
+    let (tip, before) = simulate(&adjustment, &mut rng, genesis, 1, 500, 1e6);
+    assert!((mean(&before) - 600.0).abs() < 60.0);
+
+    // The hashrate drops to a quarter. The moving average recovers within a few windows.
+    let (_, after) = simulate(&adjustment, &mut rng, tip, 501, 1000, 0.25e6);
+    assert!(mean(&after[..20]) > 900.0);
+    assert!(
+        (mean(&after[300..]) - 600.0).abs() < 60.0,
+        "mean {}",
+        mean(&after[300..])
+    );
+}
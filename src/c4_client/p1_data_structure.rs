@@ -19,6 +19,7 @@
 use super::{Consensus, ForkChoice, Header, StateMachine};
 
 use super::FullClient;
+use crate::merkle::{merkle_proof, MerkleProof};
 type Hash = u64;
 
 impl<Digest> Header<Digest> {
@@ -55,6 +56,9 @@ impl<C: Consensus, SM: StateMachine> Block<C, SM> {
     }
 
     /// Create and return a valid child block.
+    ///
+    /// The extrinsics root should be `crate::merkle::merkle_root` of the extrinsics so that
+    /// `extrinsic_proof` below produces proofs that verify against the header.
     pub fn child(&self, pre_state: &SM::State, extrinsics: Vec<u8>) -> Self {
         todo!("Exercise 6")
     }
@@ -63,6 +67,15 @@ impl<C: Consensus, SM: StateMachine> Block<C, SM> {
     pub fn verify_sub_chain(&self, pre_state: &SM::State, chain: &[Self]) -> bool {
         todo!("Exercise 7")
     }
+
+    /// Build a proof that the extrinsic at the given index is included in this block.
+    /// Check it against a header with `Header::verify_extrinsic`.
+    pub fn extrinsic_proof(&self, index: usize) -> Option<MerkleProof>
+    where
+        SM::Transition: std::hash::Hash,
+    {
+        merkle_proof(&self.body, index)
+    }
 }
 
 /// Create and return a block chain that is n blocks long starting from the given genesis state.
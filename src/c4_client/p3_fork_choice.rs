@@ -41,6 +41,9 @@ impl<C: Consensus> ForkChoice<C> for LongestChain {
 /// The chain with the most accumulated proof of work is the best.
 /// This fork choice rule only makes sense with the PoW consensus engine
 /// and the generics reflect that.
+///
+/// Measure the work in each block with `crate::c2_blockchain::block_work`, and accumulate it in
+/// a `u128` so it can't overflow or go negative.
 pub struct HeaviestChain {
     // You may add fields here if you need to.
 }
@@ -75,6 +78,9 @@ impl ForkChoice<SimplePoa> for MostAliceSigs {
 /// In the Greedy Heaviest Observed Subtree rule, the fork choice is iterative.
 /// You start from the genesis block, and at each fork, you choose the side of the fork
 /// that has the most accumulated proof of work on _all_ of its descendants.
+///
+/// Use the same `block_work` measure as `HeaviestChain`. Chapter 2 part 5 has a version of this
+/// rule over a plain header tree.
 pub struct Ghost {
     // You may add fields here if you need to.
 }
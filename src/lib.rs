@@ -5,6 +5,11 @@ mod c1_state_machine;
 mod c2_blockchain;
 mod c3_consensus;
 mod c4_client;
+mod merkle;
+mod miner;
+
+// The library side of the chain explorer binary.
+pub use c2_blockchain::p14_chain_explorer as chain_explorer;
 
 // Simple helper to do some hashing.
 fn hash<T: Hash>(t: &T) -> u64 {
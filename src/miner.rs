@@ -0,0 +1,214 @@
+//! A multithreaded driver for proof of work mining.
+//!
+//! The first miners we wrote drew random nonces on a single thread until one of them happened to
+//! work. That's fine at toy difficulties, but it wastes all but one core, and random nonces can
+//! repeat so some of the work is done twice. A real miner instead splits the nonce space into
+//! disjoint ranges, gives one range to each worker thread, and tells every worker to stop as soon
+//! as any of them finds a solution.
+//!
+//! The driver knows nothing about headers. It only asks a predicate whether a nonce is good, so the
+//! same code can mine any header type from any chapter.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How many nonces a worker tries between checks of the shared stop flags. Checking on every nonce
+/// would make the workers fight over the flag's cache line.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// The result of a successful mining run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MiningOutcome {
+    /// The nonce that satisfied the predicate.
+    pub nonce: u64,
+    /// The total number of nonces tried across all threads.
+    pub hashes: u64,
+    /// Wall clock time spent mining.
+    pub elapsed: Duration,
+}
+
+impl MiningOutcome {
+    /// The number of hashes per second achieved during the run.
+    pub fn hashrate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Searches the nonce space with a fixed number of worker threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Miner {
+    threads: u64,
+}
+
+impl Default for Miner {
+    /// A miner with one thread per available core.
+    fn default() -> Self {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Miner::new(threads)
+    }
+}
+
+impl Miner {
+    /// Create a miner that uses the given number of threads. At least one thread is always used.
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1) as u64,
+        }
+    }
+
+    /// The number of worker threads this miner uses.
+    pub fn threads(&self) -> usize {
+        self.threads as usize
+    }
+
+    /// The first nonce in the range searched by the given worker. The 64-bit nonce space is split
+    /// into equal contiguous ranges, and the last worker also takes the remainder.
+    pub fn range_start(&self, worker: usize) -> u64 {
+        (u64::MAX / self.threads) * worker as u64
+    }
+
+    /// Mine until a nonce satisfying `is_solution` is found. Every nonce is eventually tried, so
+    /// this only gives up if no nonce at all is a solution.
+    pub fn mine<F>(&self, is_solution: F) -> Option<MiningOutcome>
+    where
+        F: Fn(u64) -> bool + Sync,
+    {
+        self.mine_cancellable(is_solution, &AtomicBool::new(false))
+    }
+
+    /// Mine until a nonce satisfying `is_solution` is found, or until `cancel` is set.
+    ///
+    /// The flag can be set from another thread, for example when a competing block arrives from
+    /// the network and there is no point finishing this one. Returns None if mining was cancelled
+    /// before a solution was found.
+    pub fn mine_cancellable<F>(&self, is_solution: F, cancel: &AtomicBool) -> Option<MiningOutcome>
+    where
+        F: Fn(u64) -> bool + Sync,
+    {
+        let start = Instant::now();
+        let found = AtomicBool::new(false);
+        let solution = Mutex::new(None);
+
+        let hashes: u64 = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads())
+                .map(|worker| {
+                    let first = self.range_start(worker);
+                    let last = if worker + 1 == self.threads() {
+                        u64::MAX
+                    } else {
+                        self.range_start(worker + 1) - 1
+                    };
+                    let (is_solution, found, solution) = (&is_solution, &found, &solution);
+                    scope.spawn(move || {
+                        let mut tried: u64 = 0;
+                        for nonce in first..=last {
+                            if tried.is_multiple_of(CHECK_INTERVAL)
+                                && (found.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed))
+                            {
+                                break;
+                            }
+                            tried += 1;
+                            if is_solution(nonce) {
+                                // Several workers may succeed at about the same time. The first
+                                // one to get here wins.
+                                if !found.swap(true, Ordering::Relaxed) {
+                                    *solution.lock().unwrap() = Some(nonce);
+                                }
+                                break;
+                            }
+                        }
+                        tried
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).sum()
+        });
+
+        let nonce = solution.into_inner().unwrap()?;
+        Some(MiningOutcome {
+            nonce,
+            hashes,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+use crate::hash;
+
+#[test]
+fn miner_finds_nonce_below_threshold() {
+    // About one nonce in a million is a solution.
+    let threshold = u64::MAX / 1_000_000;
+    let outcome = Miner::new(4)
+        .mine(|nonce| hash(&(42u64, nonce)) < threshold)
+        .unwrap();
+
+    assert!(hash(&(42u64, outcome.nonce)) < threshold);
+    assert!(outcome.hashes >= 1);
+}
+
+#[test]
+fn miner_splits_nonce_space_between_threads() {
+    let miner = Miner::new(4);
+    assert_eq!(miner.range_start(0), 0);
+    assert_eq!(miner.range_start(1), u64::MAX / 4);
+
+    // The only solution is deep in the last worker's range, so a single thread searching from
+    // zero would never reach it. The last worker finds it almost immediately.
+    let target = miner.range_start(3) + 10;
+    let outcome = miner.mine(|nonce| nonce == target).unwrap();
+    assert_eq!(outcome.nonce, target);
+}
+
+#[test]
+fn miner_stops_all_workers_on_first_solution() {
+    // Every nonce from the second worker's range onwards is a solution, but the first worker's
+    // range has none. It would search for centuries if it weren't told to stop.
+    let miner = Miner::new(8);
+    let boundary = miner.range_start(1);
+    let outcome = miner.mine(|nonce| nonce >= boundary).unwrap();
+
+    assert!(outcome.nonce >= boundary);
+}
+
+#[test]
+fn miner_can_be_cancelled() {
+    let cancel = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| Miner::new(2).mine_cancellable(|_| false, &cancel));
+        thread::sleep(Duration::from_millis(20));
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(handle.join().unwrap(), None);
+    });
+}
+
+#[test]
+fn miner_already_cancelled_does_no_work() {
+    let cancel = AtomicBool::new(true);
+    assert_eq!(Miner::new(3).mine_cancellable(|_| true, &cancel), None);
+}
+
+#[test]
+fn miner_reports_hashrate() {
+    let outcome = MiningOutcome {
+        nonce: 0,
+        hashes: 1_000,
+        elapsed: Duration::from_millis(500),
+    };
+    assert_eq!(outcome.hashrate(), 2_000.0);
+}
+
+#[test]
+fn miner_zero_threads_uses_one() {
+    assert_eq!(Miner::new(0).threads(), 1);
+    assert!(Miner::default().threads() >= 1);
+}